@@ -10,8 +10,6 @@ use embassy_nrf::{
     twim,
 };
 
-const PS_WEARING_THRESHOLD: u8 = 6;
-
 use embassy_time::{Duration, Timer};
 
 pub struct HrmRessources {
@@ -46,7 +44,8 @@ impl HrmRessources {
         let adjust_event = Some(AdjustEvent { last_value: 0 });
 
         let state = HrmState {
-            wearing: false,
+            wear_detector: WearDetector::default(),
+            wear_change: None,
             slots: 0,
             hrm_led_config: LedConfig::from_reg(reg_config.slot0_led_current),
             hrm_led_max_current: 0x6f,
@@ -57,7 +56,7 @@ impl HrmRessources {
             env_res_config: PdResConfig::from_reg(reg_config.slot2_env_sensitivity),
             adjust_event,
             sample_offset: 0,
-            sample_delay: 840,
+            sample_delay: NOMINAL_SAMPLE_DELAY,
         };
 
         let mut hrm = Hrm {
@@ -96,7 +95,9 @@ impl LedConfig {
 }
 
 struct HrmState {
-    wearing: bool,
+    wear_detector: WearDetector,
+    /// Not yet reported by `Hrm::wear_changed`
+    wear_change: Option<bool>,
     slots: u8,
     hrm_led_config: LedConfig,
     hrm_res_config: PdResConfig,
@@ -302,25 +303,6 @@ impl<'a> Hrm<'a> {
             current_value: [buf2[0] & 0x7F, buf2[1] & 0x7F, buf2[2] & 0x7F],
         };
 
-        // Update wear status, TODO: The interrupt does not seem to ever fire?
-        if (read_result.irq_status & INT_PS) != 0 {
-            // TODO: make more sophisticated
-            let now_wearing = read_result.ps_value > PS_WEARING_THRESHOLD;
-            match (self.state.wearing, now_wearing) {
-                (true, false) => {
-                    self.state
-                        .update_slots(&mut i2c, |s| (s & 0xF8) | 0x04 /* only env sens */);
-                }
-                (false, true) => {
-                    self.state
-                        .update_slots(&mut i2c, |s| (s & 0xF8) | 0x05 /* env sens + hrm */);
-                }
-                _ => {}
-            }
-
-            self.state.wearing = now_wearing;
-        }
-
         if (read_result.irq_status & INT_LED_OVERLOAD) != 0 {
             let overload = read_result.status & OVERLOAD_MASK;
             if (overload & 0b001) != 0 {
@@ -338,6 +320,8 @@ impl<'a> Hrm<'a> {
             }
         }
 
+        let mut dc_level = None;
+
         // Read sample
         let sample = if (read_result.irq_status & INT_FIFO) != 0 {
             let mut fifo_write_index = 0u8;
@@ -432,6 +416,12 @@ impl<'a> Hrm<'a> {
                 };
 
                 let max_current = self.state.hrm_led_max_current;
+                let current = self.state.hrm_led_config.current;
+                dc_level = Some(DcLevel {
+                    mean: (samples.iter().map(|v| *v as i32).sum::<i32>() / samples.len() as i32)
+                        as i16,
+                    led_current_at_limit: current == 0 || current >= max_current,
+                });
 
                 // Act based on adjust mode
                 match self.state.adjust_mode {
@@ -478,10 +468,28 @@ impl<'a> Hrm<'a> {
             None
         };
 
+        // The ps interrupt never seems to fire, so this is checked on every event. The ppg slot
+        // stays enabled when off the wrist, since its level is part of the detection.
+        if let Some(wearing) = self.state.wear_detector.update(&read_result, dc_level) {
+            self.state.wear_change = Some(wearing);
+        }
+
         //defmt::println!("HRM read result: {}", read_result);
         (read_result, sample)
     }
 
+    /// Whether the watch is worn, as of the last `wait_event`. Estimates from while it is not are
+    /// meaningless.
+    pub fn wearing(&self) -> bool {
+        self.state.wear_detector.wearing()
+    }
+
+    /// Returns the new state once after `wait_event` detected that the watch was put on or taken
+    /// off.
+    pub fn wear_changed(&mut self) -> Option<bool> {
+        self.state.wear_change.take()
+    }
+
     pub async fn update_hrm_res(&mut self, f: impl FnOnce(&mut PdResConfig)) {
         let mut i2c = self.i2c.bind().await;
         self.state.update_hrm_res(&mut i2c, f).await;
@@ -502,6 +510,11 @@ impl<'a> Hrm<'a> {
         self.state.update_sample_delay(&mut i2c, f).await;
     }
 
+    /// Current value of the sample delay register, see `NOMINAL_SAMPLE_DELAY`.
+    pub fn sample_delay(&self) -> u16 {
+        self.state.sample_delay
+    }
+
     pub async fn enable(&mut self) {
         let mut cfg = RegConfig::default();
 
@@ -9,6 +9,8 @@ use embassy_sync::mutex::Mutex;
 use embassy_time::Instant;
 use static_cell::StaticCell;
 
+pub use drivers_shared::shared::{Lease, Shared};
+
 use defmt_rtt as _; //logger
 
 use panic_persist as _;
@@ -85,9 +87,9 @@ pub struct Context {
     pub mag: mag::MagRessources,
     pub touch: touch::TouchRessources,
     pub accel: accel::AccelRessources,
-    pub hrm: hrm::HrmRessources,
+    pub hrm: &'static Shared<hrm::HrmRessources>,
     pub buzzer: buzz::Buzzer,
-    pub twi: twi::TWI,
+    pub twi: &'static twi::TWI,
     pub last_panic_msg: Option<&'static str>,
 }
 
@@ -137,6 +139,7 @@ async fn init(spawner: embassy_executor::Spawner) -> Context {
     let backlight = display::Backlight::new(&spawner, p.P0_08, p.PWM3);
 
     let touch = touch::TouchRessources::new(p.P1_01, p.P1_02, p.P1_03, p.P1_04, &mut twi).await;
+    let twi = TWI_CELL.init(twi);
 
     let gps = gps::GPSRessources::new(
         p.P0_29,
@@ -151,7 +154,9 @@ async fn init(spawner: embassy_executor::Spawner) -> Context {
     .await;
 
     spawner.spawn(gps::gps_task(gps)).unwrap();
+    time::restore_clock();
     spawner.spawn(time::clock_sync_task()).unwrap();
+    spawner.spawn(time::clock_checkpoint_task()).unwrap();
 
     let mag = mag::MagRessources::new(p.P1_12, p.P1_13);
     let accel = accel::AccelRessources::new(p.P1_06, p.P1_05);
@@ -167,7 +172,7 @@ async fn init(spawner: embassy_executor::Spawner) -> Context {
         mag,
         touch,
         accel,
-        hrm,
+        hrm: HRM.init(Shared::new(hrm)),
         buzzer,
         twi,
         last_panic_msg: panic_persist::get_panic_message_utf8(),
@@ -175,6 +180,8 @@ async fn init(spawner: embassy_executor::Spawner) -> Context {
 }
 
 pub fn sys_reset() -> ! {
+    // Keep the time across the reset
+    time::save_checkpoint(embassy_time::Duration::from_secs(0));
     cortex_m::peripheral::SCB::sys_reset()
 }
 
@@ -201,6 +208,9 @@ async fn main_task(main: impl Main) {
 }
 
 static EXECUTOR: StaticCell<embassy_executor::Executor> = StaticCell::new();
+// The hrm (and the bus it is on) are also used by background tasks next to the foreground.
+static HRM: StaticCell<Shared<hrm::HrmRessources>> = StaticCell::new();
+static TWI_CELL: StaticCell<twi::TWI> = StaticCell::new();
 
 pub fn run(main: impl Main) -> ! {
     let executor = EXECUTOR.init(embassy_executor::Executor::new());
@@ -1,16 +1,12 @@
-use core::{
-    cell::RefCell,
-    sync::atomic::{AtomicI32, AtomicU32, Ordering},
-};
+use core::cell::{Cell, RefCell};
 
 use crate::gps::{self, GPSReceiver};
 
 use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 pub use embassy_time::*;
-use util::ClockScale;
-
-const DRIFT_THRESHOLD_LONGER: i32 = 5;
-const DRIFT_THRESHOLD_SHORTER: i32 = 10;
+pub use util::clock::{ClockInfo, TimeSource};
+use util::clock::{ClockSync, Monotonic, SyncStats, TimeMessages};
+use util::{tz::TimeZone, ClockScale};
 
 enum ClockSyncCmd {
     SyncNow,
@@ -30,239 +26,267 @@ async fn sync_delay(delay: Duration) {
     }
 }
 
-#[embassy_executor::task]
-pub(crate) async fn clock_sync_task() {
-    const INITIAL_SYNC_TIME: Duration = Duration::from_secs(2 * 60);
-    const INCREMENTAL_SYNC_TIME: Duration = Duration::from_secs(1 * 60);
+struct BootClock;
+
+impl Monotonic for BootClock {
+    fn now_ms(&self) -> u64 {
+        Instant::now().as_millis()
+    }
+}
 
-    let mut success_wait_time = Duration::from_secs(60 * 60 * 4);
+impl TimeMessages for GPSReceiver<'_> {
+    async fn next_time(&mut self) -> gps::NavTimeUTC {
+        loop {
+            if let gps::CasicMsg::NavTimeUTC(c) = self.receive().await {
+                defmt::println!("GPS nav: {:?}", c);
+                return c;
+            }
+        }
+    }
+}
 
-    let mut sync_time = INITIAL_SYNC_TIME;
+static CLOCK_SYNC: Mutex<CriticalSectionRawMutex, RefCell<ClockSync>> =
+    Mutex::new(RefCell::new(ClockSync::new()));
 
-    sync_delay(Duration::from_secs(1 * 60)).await;
+#[embassy_executor::task]
+pub(crate) async fn clock_sync_task() {
+    sync_delay(Duration::from_millis(ClockSync::FIRST_SYNC_DELAY_MS)).await;
     loop {
-        let before_sync = Instant::now();
+        let start_ms = BootClock.now_ms();
+        let timeout_ms = CLOCK_SYNC.lock(|s| s.borrow().timeout_ms());
         let res = {
             let mut gps = GPSReceiver::new(drivers_shared::gps::CasicMsgConfig {
                 nav_time: 1,
                 ..Default::default()
             })
             .await;
-            sync_clock(&mut gps, sync_time).await
+            util::clock::receive_time(&mut gps, &BootClock, &clock_info(), timeout_ms).await
         };
-        let next_sync = if res.is_ok() {
-            let sync_duration = before_sync.elapsed();
-
-            LAST_SYNC_DURATION_S.store(sync_duration.as_secs() as _, Ordering::Relaxed);
-            LAST_SYNC_TS_S.store(Instant::now().as_secs() as _, Ordering::Relaxed);
-
-            sync_time = INCREMENTAL_SYNC_TIME;
-
-            let drift = last_drift_s().abs();
-
-            if drift < DRIFT_THRESHOLD_LONGER {
-                success_wait_time *= 2;
-            } else if drift > DRIFT_THRESHOLD_SHORTER {
-                success_wait_time /= 2;
-            }
-
-            success_wait_time
-        } else {
-            NUM_SYNC_FAILS.fetch_add(1, Ordering::Relaxed);
-
-            Duration::from_secs(60 * 60 * 1)
-        };
-
-        NEXT_SYNC_S.store(
-            (Instant::now() + next_sync).as_secs().try_into().unwrap(),
-            Ordering::Release,
-        );
-
-        sync_delay(next_sync).await;
+        let drift = res.map(|(boot_ms, time)| {
+            let drift = CLOCK_INFO.lock(|info| info.borrow_mut().sync(boot_ms, time));
+            save_checkpoint(Duration::from_secs(0));
+            drift
+        });
+        let next_sync_ms = CLOCK_SYNC.lock(|s| {
+            s.borrow_mut()
+                .finish(start_ms, BootClock.now_ms(), drift.ok_or(()))
+        });
+
+        sync_delay(Duration::from_millis(next_sync_ms)).await;
     }
 }
 pub fn force_sync() {
     CLOCK_SYNC_SIG.signal(ClockSyncCmd::SyncNow);
 }
 
-async fn sync_clock(gps: &mut gps::GPSReceiver<'_>, timeout: Duration) -> Result<(), ()> {
-    let give_up = Instant::now() + timeout;
-
-    let res = loop {
-        if give_up < Instant::now() {
-            break Err(());
-        }
-        match gps.receive().await {
-            gps::CasicMsg::NavTimeUTC(c) => {
-                defmt::println!("GPS nav: {:?}", c);
-                if let Ok(time) = reconstruct_time(c) {
-                    let now = Instant::now();
-                    break Ok((now, time));
-                }
-            }
-            _ => {} //gps::CasicMsg::Unknown(id) => {
-                    //    defmt::println!("GPS CASIC: {:?}", id);
-                    //}
-        }
-    };
-
-    if let Ok((now, time)) = res {
-        update_clock_info(now, time);
-        Ok(())
-    } else {
-        Err(())
-    }
-}
-
-fn reconstruct_time(data: gps::NavTimeUTC) -> Result<chrono::DateTime<chrono::Utc>, ()> {
-    if data.valid == 0 {
-        return Err(());
-    }
-
-    let time = chrono::NaiveTime::from_hms_opt(data.hour.into(), data.min.into(), data.sec.into())
-        .unwrap();
-
-    if data.date_valid != 0 {
-        let date =
-            chrono::NaiveDate::from_ymd_opt(data.year.into(), data.month.into(), data.day.into())
-                .unwrap();
+// Found experimentally (roughly). Clocks appear to run a tiny bit faster than real time in ambient
+// temperature. Rate is adjusted automatically, though.
+const DEFAULT_CLOCK_SCALE: ClockScale = ClockScale::new(500000 - 25, 500000);
 
-        Ok(date.and_time(time).and_utc())
-    } else if let Some(current_dt) = now_utc() {
-        util::resync_time(current_dt, time)
-    } else {
-        Err(())
-    }
+static CLOCK_INFO: Mutex<CriticalSectionRawMutex, RefCell<ClockInfo>> =
+    Mutex::new(RefCell::new(ClockInfo::new(DEFAULT_CLOCK_SCALE)));
+
+/// Set the time without gps (e.g. when there is no reception for days). The next gps sync
+/// overrides it.
+pub fn set_time_manually(datetime: chrono::DateTime<chrono::Utc>) {
+    let now_ms = Instant::now().as_millis();
+    CLOCK_INFO.lock(|info| info.borrow_mut().set_manually(now_ms, datetime));
+    save_checkpoint(Duration::from_secs(0));
 }
 
+/// Clock state that survives resets (but not power loss). It lives in ram that is not initialized
+/// at startup, so it has to be validated before use.
+#[repr(C)]
 #[derive(Copy, Clone)]
-pub struct ClockInfo {
-    pub scale: ClockScale,
-    pub offset_s: u64,
-    pub last_sync: Instant,
-    pub last_sync_time: chrono::DateTime<chrono::Utc>,
+struct RetainedClock {
+    magic: u32,
+    scale_numerator: i32,
+    scale_denominator: i32,
+    last_sync_unix_s: i64,
+    /// Time at which the checkpoint was written.
+    checkpoint_unix_ms: i64,
+    checkpoint_uncertainty_ms: u32,
+    /// Maximum time between writing the checkpoint and the reset.
+    max_delay_ms: u32,
+    checksum: u32,
 }
 
-impl ClockInfo {
-    fn valid_last_sync(&self) -> bool {
-        self.last_sync != Instant::from_secs(0)
+const RETAINED_CLOCK_MAGIC: u32 = u32::from_le_bytes(*b"CLK1");
+
+/// How often the retained clock state is updated in the background. Determines the uncertainty
+/// after an unexpected reset.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time from a reset until the clock is restored.
+const RESET_DURATION_MS: u32 = 500;
+
+impl RetainedClock {
+    fn compute_checksum(&self) -> u32 {
+        let words = [
+            self.magic,
+            self.scale_numerator as u32,
+            self.scale_denominator as u32,
+            self.last_sync_unix_s as u32,
+            (self.last_sync_unix_s >> 32) as u32,
+            self.checkpoint_unix_ms as u32,
+            (self.checkpoint_unix_ms >> 32) as u32,
+            self.checkpoint_uncertainty_ms,
+            self.max_delay_ms,
+        ];
+        words
+            .iter()
+            .fold(0x811c9dc5u32, |h, w| (h ^ w).wrapping_mul(0x01000193))
     }
 
-    fn to_utc(&self, instant: Instant) -> Option<chrono::DateTime<chrono::Utc>> {
-        let offset = self.offset_s;
-        if offset == 0 {
-            return None;
-        }
-
-        let boot_seconds = instant.as_secs();
-        let boot_seconds = self.scale.apply(boot_seconds as i32) as u64;
-
-        let unix_seconds = CONST_UTC_OFFSET_S + offset as u64 + boot_seconds;
-        chrono::DateTime::from_timestamp(unix_seconds as i64, 0)
+    fn is_valid(&self) -> bool {
+        self.magic == RETAINED_CLOCK_MAGIC
+            && self.checksum == self.compute_checksum()
+            && self.scale_numerator > 0
+            && self.scale_denominator > 0
     }
+}
 
-    fn to_instant<Tz: chrono::TimeZone>(&self, t: chrono::DateTime<Tz>) -> Option<Instant> {
-        let utc = t.naive_utc();
-        let unix_seconds = utc.timestamp();
-
-        let offset = self.offset_s;
-        if offset == 0 {
-            return None;
-        }
-
-        let boot_seconds_raw = unix_seconds - CONST_UTC_OFFSET_S as i64 - offset as i64;
-        let boot_seconds = self.scale.inverse().apply(boot_seconds_raw as i32);
+#[link_section = ".uninit.RETAINED_CLOCK"]
+static mut RETAINED_CLOCK: core::mem::MaybeUninit<RetainedClock> = core::mem::MaybeUninit::uninit();
 
-        Some(Instant::from_secs(boot_seconds as u64))
+/// Store the current clock state so that it can be restored after a reset. `max_delay` is the
+/// maximum time until the next checkpoint (or the reset).
+pub(crate) fn save_checkpoint(max_delay: Duration) {
+    let now = Instant::now();
+    // May be called from the panic handler, so the clock info could already be borrowed.
+    let Some(info) = CLOCK_INFO.lock(|i| i.try_borrow().ok().map(|i| *i)) else {
+        return;
+    };
+    let now_ms = now.as_millis();
+    let (Some(time), Some(uncertainty_ms)) = (info.to_utc(now_ms), info.uncertainty_ms(now_ms))
+    else {
+        return;
+    };
+    let mut retained = RetainedClock {
+        magic: RETAINED_CLOCK_MAGIC,
+        scale_numerator: info.scale.numerator,
+        scale_denominator: info.scale.denominator,
+        last_sync_unix_s: info.last_sync_time.timestamp(),
+        checkpoint_unix_ms: time.timestamp_millis(),
+        checkpoint_uncertainty_ms: uncertainty_ms as u32,
+        max_delay_ms: max_delay.as_millis() as u32,
+        checksum: 0,
+    };
+    retained.checksum = retained.compute_checksum();
+    // Safety: Plain data, a torn write (if interrupted by a panic) is caught by the checksum.
+    unsafe {
+        core::ptr::addr_of_mut!(RETAINED_CLOCK)
+            .cast::<RetainedClock>()
+            .write_volatile(retained);
     }
 }
 
-// Found experimentally (roughly). Clocks appear to run a tiny bit faster than real time in ambient
-// temperature. Rate is adjusted automatically, though.
-const DEFAULT_CLOCK_SCALE: ClockScale = ClockScale::new(500000 - 25, 500000);
-
-static CLOCK_INFO: Mutex<CriticalSectionRawMutex, RefCell<ClockInfo>> =
-    Mutex::new(RefCell::new(ClockInfo {
-        scale: DEFAULT_CLOCK_SCALE,
-        offset_s: 0,
-        last_sync: Instant::from_secs(0),
-        last_sync_time: chrono::DateTime::UNIX_EPOCH,
-    }));
+/// Restore the clock from the state before a reset (if any). Must be called during startup.
+pub(crate) fn restore_clock() {
+    // Safety: Any bit pattern is a valid `RetainedClock`; the content is validated below.
+    let retained = unsafe {
+        core::ptr::addr_of!(RETAINED_CLOCK)
+            .cast::<RetainedClock>()
+            .read_volatile()
+    };
+    if !retained.is_valid() {
+        return;
+    }
 
-fn update_clock_info(boot_time_now: Instant, datetime: chrono::DateTime<chrono::Utc>) {
-    let unix_seconds = datetime.timestamp() as u64;
+    // The reset happened somewhere between the checkpoint and `max_delay` later, so we guess the
+    // middle.
+    let half_delay_ms = retained.max_delay_ms / 2;
+    let now = Instant::now();
+    let estimate_ms = retained.checkpoint_unix_ms
+        + (half_delay_ms + RESET_DURATION_MS) as i64
+        + now.as_millis() as i64;
+    let Some(datetime) = chrono::DateTime::from_timestamp_millis(estimate_ms) else {
+        return;
+    };
 
     CLOCK_INFO.lock(|info| {
         let mut info = info.borrow_mut();
-
-        if info.valid_last_sync() {
-            let drift = datetime.timestamp() - info.to_utc(boot_time_now).unwrap().timestamp();
-            LAST_DRIFT_S.store(drift as i32, Ordering::Release);
-
-            // Only update if there is actually any drift, since for short sync periods our
-            // calculated clock scale may be actually be worse than the default (found
-            // experimentally after ~128h calibration period).
-            if drift != 0 {
-                let clock_time = (boot_time_now - info.last_sync).as_secs();
-                let real_time = (datetime - info.last_sync_time).num_seconds();
-
-                info.scale = ClockScale::new(real_time as i32, clock_time as i32);
-            }
-        }
-
-        let boot_seconds = boot_time_now.as_secs();
-        let boot_seconds = info.scale.apply(boot_seconds as i32) as u64;
-
-        let offset = unix_seconds - CONST_UTC_OFFSET_S - boot_seconds;
-        info.offset_s = offset;
-
-        info.last_sync_time = datetime;
-        info.last_sync = boot_time_now;
+        info.scale = ClockScale::new(retained.scale_numerator, retained.scale_denominator);
+        info.set_time(now.as_millis(), datetime);
+        info.last_sync_time = chrono::DateTime::from_timestamp(retained.last_sync_unix_s, 0)
+            .unwrap_or(chrono::DateTime::UNIX_EPOCH);
+        // Boot time of the last sync is lost, so the next sync cannot be used for calibration.
+        info.last_sync_ms = 0;
+        info.source = TimeSource::Restored;
+        info.base_uncertainty_ms =
+            retained.checkpoint_uncertainty_ms + half_delay_ms + RESET_DURATION_MS;
+        info.uncertainty_since_ms = now.as_millis();
     });
+    defmt::println!(
+        "Restored clock: {}, uncertainty {}ms",
+        estimate_ms,
+        retained.checkpoint_uncertainty_ms + half_delay_ms + RESET_DURATION_MS
+    );
 }
 
-static TZ_SECONDS_EAST: AtomicI32 = AtomicI32::new(0);
-pub fn set_utc_offset(seconds_east: i32) {
-    TZ_SECONDS_EAST.store(seconds_east, Ordering::Relaxed);
+#[embassy_executor::task]
+pub(crate) async fn clock_checkpoint_task() {
+    let mut ticker = Ticker::every(CHECKPOINT_INTERVAL);
+    loop {
+        save_checkpoint(CHECKPOINT_INTERVAL);
+        ticker.next().await;
+    }
 }
-const CONST_UTC_OFFSET_S: u64 = 1u64 << 30;
 
-static LAST_SYNC_TS_S: AtomicU32 = AtomicU32::new(0);
+static TIME_ZONE: Mutex<CriticalSectionRawMutex, Cell<TimeZone>> =
+    Mutex::new(Cell::new(TimeZone::UTC));
+pub fn set_time_zone(tz: TimeZone) {
+    TIME_ZONE.lock(|t| t.set(tz));
+}
+fn sync_stats() -> SyncStats {
+    CLOCK_SYNC.lock(|s| s.borrow().stats)
+}
 pub fn time_since_last_sync() -> Duration {
-    let last_sync_ts = Instant::from_secs(LAST_SYNC_TS_S.load(Ordering::Relaxed) as _);
-    last_sync_ts.elapsed()
+    Instant::from_millis(sync_stats().last_sync_ms).elapsed()
 }
-static LAST_SYNC_DURATION_S: AtomicU32 = AtomicU32::new(0);
 pub fn last_sync_duration() -> Duration {
-    Duration::from_secs(LAST_SYNC_DURATION_S.load(Ordering::Relaxed) as _)
+    Duration::from_millis(sync_stats().last_sync_duration_ms)
 }
-static NUM_SYNC_FAILS: AtomicU32 = AtomicU32::new(0);
 pub fn num_sync_fails() -> u32 {
-    NUM_SYNC_FAILS.load(Ordering::Relaxed)
+    sync_stats().num_fails
 }
-static LAST_DRIFT_S: AtomicI32 = AtomicI32::new(0);
-pub fn last_drift_s() -> i32 {
-    LAST_DRIFT_S.load(Ordering::Relaxed)
+pub fn last_drift_ms() -> i64 {
+    sync_stats().last_drift_ms
 }
-static NEXT_SYNC_S: AtomicU32 = AtomicU32::new(0);
 pub fn next_sync() -> Instant {
-    Instant::from_secs(NEXT_SYNC_S.load(Ordering::Relaxed) as _)
+    Instant::from_millis(sync_stats().next_sync_ms)
 }
 
 pub fn clock_info() -> ClockInfo {
     CLOCK_INFO.lock(|i| i.borrow().clone())
 }
 
+/// Worst case error of `now_utc`. `None` if the time is unknown.
+pub fn time_uncertainty() -> Option<Duration> {
+    let now_ms = Instant::now().as_millis();
+    CLOCK_INFO.lock(|info| {
+        info.borrow()
+            .uncertainty_ms(now_ms)
+            .map(Duration::from_millis)
+    })
+}
+
+pub fn time_source() -> TimeSource {
+    CLOCK_INFO.lock(|info| info.borrow().source)
+}
+
 pub fn now_utc() -> Option<chrono::DateTime<chrono::Utc>> {
-    CLOCK_INFO.lock(|info| info.borrow().to_utc(Instant::now()))
+    let now_ms = Instant::now().as_millis();
+    CLOCK_INFO.lock(|info| info.borrow().to_utc(now_ms))
 }
 
 pub fn now_local() -> Option<chrono::DateTime<chrono::FixedOffset>> {
     let now = now_utc()?;
-    let offset = chrono::FixedOffset::east_opt(TZ_SECONDS_EAST.load(Ordering::Relaxed)).unwrap();
-    Some(now.with_timezone(&offset))
+    Some(TIME_ZONE.lock(|t| t.get()).to_local(now))
 }
 
 pub fn to_instant<Tz: chrono::TimeZone>(t: chrono::DateTime<Tz>) -> Option<Instant> {
-    CLOCK_INFO.lock(|info| info.borrow().to_instant(t))
+    CLOCK_INFO
+        .lock(|info| info.borrow().to_boot_ms(t))
+        .map(Instant::from_millis)
 }
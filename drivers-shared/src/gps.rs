@@ -1,3 +1,13 @@
+mod control;
+mod framing;
+mod rawlog;
+mod task;
+
+pub use control::*;
+pub use framing::*;
+pub use rawlog::*;
+pub use task::*;
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct CASICPacketHeader {
@@ -17,37 +27,230 @@ pub struct RawCasicMsg<'a> {
 }
 
 impl<'a> RawCasicMsg<'a> {
+    /// Messages with a payload that does not fit their id are returned as `Unknown`.
     pub fn parse(self) -> CasicMsg {
-        match self.id {
-            NAV_TIME_UTC => CasicMsg::NavTimeUTC(*bytemuck::from_bytes(self.payload)),
-            NAV_PV => CasicMsg::NavPv(*bytemuck::from_bytes(self.payload)),
-            NAV_GPS_INFO => CasicMsg::NavGpsInfo(*bytemuck::from_bytes(
-                &self.payload[..core::mem::size_of::<NavGpsInfo>()],
-            )),
-            _ => CasicMsg::Unknown(self.id),
-        }
+        // The payload is not necessarily aligned in the receive buffer
+        let msg = match self.id {
+            NAV_TIME_UTC => bytemuck::try_pod_read_unaligned(self.payload)
+                .ok()
+                .map(CasicMsg::NavTimeUTC),
+            NAV_PV => bytemuck::try_pod_read_unaligned(self.payload)
+                .ok()
+                .map(CasicMsg::NavPv),
+            NAV_GPS_INFO => {
+                NavSvInfo::parse(GnssSystem::Gps, self.payload).map(CasicMsg::NavSvInfo)
+            }
+            NAV_BDS_INFO => {
+                NavSvInfo::parse(GnssSystem::Bds, self.payload).map(CasicMsg::NavSvInfo)
+            }
+            NAV_GLN_INFO => {
+                NavSvInfo::parse(GnssSystem::Glonass, self.payload).map(CasicMsg::NavSvInfo)
+            }
+            _ => None,
+        };
+        msg.unwrap_or(CasicMsg::Unknown(self.id))
     }
 }
 
 pub const NAV_TIME_UTC: CASICMessageIdentifier = [0x01, 0x10];
 pub const NAV_PV: CASICMessageIdentifier = [0x01, 0x03];
 pub const NAV_GPS_INFO: CASICMessageIdentifier = [0x01, 0x20];
+pub const NAV_BDS_INFO: CASICMessageIdentifier = [0x01, 0x21];
+pub const NAV_GLN_INFO: CASICMessageIdentifier = [0x01, 0x22];
 pub const CFG_MSG: CASICMessageIdentifier = [0x06, 0x01];
+pub const CFG_RATE: CASICMessageIdentifier = [0x06, 0x04];
+
+pub const CASIC_MAGIC_HEADER: [u8; 2] = [0xba, 0xce];
+
+/// Size of everything surrounding the payload of a casic packet: Magic, header and checksum.
+pub const CASIC_FRAME_OVERHEAD: usize =
+    CASIC_MAGIC_HEADER.len() + core::mem::size_of::<CASICPacketHeader>() + 4;
+
+pub fn casic_checksum(msg_id: CASICMessageIdentifier, payload: &[u8]) -> u32 {
+    let len = payload.len() as u32;
+    let mut checksum = ((msg_id[1] as u32) << 24) + ((msg_id[0] as u32) << 16) + len;
+
+    for bytes in payload.as_chunks::<4>().0 {
+        let val = u32::from_le_bytes(*bytes);
+        checksum = checksum.wrapping_add(val);
+    }
+    checksum
+}
+
+/// Magic bytes at the start of an assisted gps (ephemeris/almanac) file on flash.
+pub const AGPS_FILE_MAGIC: [u8; 4] = *b"AGPS";
+
+/// Header of an assisted gps file. It is followed by `data_len` bytes of complete casic packets
+/// (AID messages, ephemeris and almanac data) of at most `MAX_AID_PACKET_LEN` bytes each, which are
+/// sent to the receiver verbatim.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct AgpsFileHeader {
+    pub magic: [u8; 4],
+    /// Time at which the data was downloaded. Seconds since the unix epoch.
+    pub downloaded_unix_s: u32,
+    pub data_len: u32,
+}
+
+impl AgpsFileHeader {
+    pub fn new(downloaded_unix_s: u32, data_len: u32) -> Self {
+        Self {
+            magic: AGPS_FILE_MAGIC,
+            downloaded_unix_s,
+            data_len,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == AGPS_FILE_MAGIC
+    }
+}
 
 #[repr(C)]
-#[derive(Copy, Clone, Default, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+#[derive(
+    Copy, Clone, Default, Debug, PartialEq, defmt::Format, bytemuck::Zeroable, bytemuck::Pod,
+)]
 pub struct CasicMsgConfig {
     pub nav_time: u16,
     pub nav_pv: u16,
     pub nav_gps_info: u16,
+    pub nav_bds_info: u16,
+    pub nav_gln_info: u16,
 }
 
 impl CasicMsgConfig {
+    /// Output rate (in navigation solutions) for each of the configurable messages.
+    pub fn msg_rates(&self) -> [(CASICMessageIdentifier, u16); 5] {
+        [
+            (NAV_TIME_UTC, self.nav_time),
+            (NAV_PV, self.nav_pv),
+            (NAV_GPS_INFO, self.nav_gps_info),
+            (NAV_BDS_INFO, self.nav_bds_info),
+            (NAV_GLN_INFO, self.nav_gln_info),
+        ]
+    }
+
     pub fn merge(&self, other: &Self) -> Self {
         CasicMsgConfig {
             nav_time: self.nav_time.max(other.nav_time),
             nav_pv: self.nav_pv.max(other.nav_pv),
             nav_gps_info: self.nav_gps_info.max(other.nav_gps_info),
+            nav_bds_info: self.nav_bds_info.max(other.nav_bds_info),
+            nav_gln_info: self.nav_gln_info.max(other.nav_gln_info),
+        }
+    }
+}
+
+/// Payload of a `CFG_MSG` message
+#[repr(C, packed)]
+#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct CfgMsgPayload {
+    pub msg_id: CASICMessageIdentifier,
+    pub rate: u16,
+}
+
+/// Payload of a `CFG_RATE` message
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct CfgRatePayload {
+    pub interval_ms: u16,
+    pub _reserved: u16,
+}
+
+/// Satellite systems the receiver should use. Not requesting any system means "don't care",
+/// which results in gps only (that gets us the quickest fix).
+#[derive(Copy, Clone, Default, Debug, PartialEq, defmt::Format)]
+pub struct SatelliteConfig {
+    pub gps: bool,
+    pub bds: bool,
+    pub glonass: bool,
+}
+
+impl SatelliteConfig {
+    pub const GPS_ONLY: Self = SatelliteConfig {
+        gps: true,
+        bds: false,
+        glonass: false,
+    };
+
+    pub fn merge(&self, other: &Self) -> Self {
+        SatelliteConfig {
+            gps: self.gps || other.gps,
+            bds: self.bds || other.bds,
+            glonass: self.glonass || other.glonass,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !(self.gps || self.bds || self.glonass)
+    }
+
+    /// Bit mask as used by the PCAS04 command: gps = 1, bds = 2, glonass = 4
+    pub fn to_bits(&self) -> u8 {
+        (self.gps as u8) | (self.bds as u8) << 1 | (self.glonass as u8) << 2
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        SatelliteConfig {
+            gps: (bits & 0b001) != 0,
+            bds: (bits & 0b010) != 0,
+            glonass: (bits & 0b100) != 0,
+        }
+    }
+}
+
+/// Rate at which the receiver computes navigation solutions. Message rates (see
+/// `CasicMsgConfig`) are relative to this rate.
+#[repr(u8)]
+#[derive(
+    Copy,
+    Clone,
+    Default,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    defmt::Format,
+    num_enum::TryFromPrimitive,
+)]
+pub enum NavRate {
+    #[default]
+    Hz1 = 0,
+    Hz2 = 1,
+    Hz5 = 2,
+}
+
+impl NavRate {
+    pub fn interval_ms(&self) -> u16 {
+        match self {
+            NavRate::Hz1 => 1000,
+            NavRate::Hz2 => 500,
+            NavRate::Hz5 => 200,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug, PartialEq, defmt::Format)]
+pub struct ReceiverConfig {
+    pub satellites: SatelliteConfig,
+    pub nav_rate: NavRate,
+}
+
+impl ReceiverConfig {
+    pub fn merge(&self, other: &Self) -> Self {
+        ReceiverConfig {
+            satellites: self.satellites.merge(&other.satellites),
+            nav_rate: self.nav_rate.max(other.nav_rate),
+        }
+    }
+
+    /// The satellite systems that are actually activated for this config.
+    pub fn active_satellites(&self) -> SatelliteConfig {
+        if self.satellites.is_empty() {
+            SatelliteConfig::GPS_ONLY
+        } else {
+            self.satellites
         }
     }
 }
@@ -99,6 +302,18 @@ pub struct NavPv {
     pub variance_heading: f32,
 }
 
+impl NavPv {
+    /// Values of `pos_valid` from which on the position comes from a fix. Below are 0 (invalid) and
+    /// estimates: 1 (external input), 2 (rough), 3 (last position kept), 4 (dead reckoning) and 5
+    /// (fast mode). 8 is a combination of a fix and dead reckoning.
+    pub const POS_VALID_FIX_2D: u8 = 6;
+    pub const POS_VALID_FIX_3D: u8 = 7;
+
+    pub fn has_fix(&self) -> bool {
+        self.pos_valid >= Self::POS_VALID_FIX_2D
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct NavigationData {
@@ -131,6 +346,7 @@ impl From<NavPv> for NavigationData {
     }
 }
 
+/// Header of the satellite information messages (NAV-GPSINFO, NAV-BDSINFO and NAV-GLNINFO).
 #[repr(C)]
 #[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct NavGpsInfo {
@@ -141,10 +357,62 @@ pub struct NavGpsInfo {
     pub _reserved: u8,
 }
 
+/// Per satellite part of the satellite information messages
+#[repr(C)]
+#[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct SvInfo {
+    pub channel: u8,
+    pub sv_id: u8,
+    pub flags: u8,
+    pub quality: u8,
+    /// Carrier to noise ratio in dB-Hz
+    pub cn0: u8,
+    /// Elevation in degrees
+    pub elevation: i8,
+    /// Azimuth in degrees
+    pub azimuth: i16,
+    /// Pseudorange residual in m
+    pub pr_res: f32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format)]
+pub enum GnssSystem {
+    Gps,
+    Bds,
+    Glonass,
+}
+
+/// Maximum number of satellites per system that are kept from a satellite information message.
+pub const MAX_SV_INFOS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct NavSvInfo {
+    pub system: GnssSystem,
+    pub header: NavGpsInfo,
+    pub svs: arrayvec::ArrayVec<SvInfo, MAX_SV_INFOS>,
+}
+
+impl NavSvInfo {
+    fn parse(system: GnssSystem, payload: &[u8]) -> Option<Self> {
+        let (header, svs) = payload.split_at_checked(core::mem::size_of::<NavGpsInfo>())?;
+        Some(NavSvInfo {
+            system,
+            header: bytemuck::pod_read_unaligned(header),
+            svs: svs
+                .as_chunks::<{ core::mem::size_of::<SvInfo>() }>()
+                .0
+                .iter()
+                .take(MAX_SV_INFOS)
+                .map(|sv| bytemuck::pod_read_unaligned(sv))
+                .collect(),
+        })
+    }
+}
+
 #[derive(Clone)]
 pub enum CasicMsg {
     NavTimeUTC(NavTimeUTC),
     NavPv(NavPv),
-    NavGpsInfo(NavGpsInfo),
+    NavSvInfo(NavSvInfo),
     Unknown(CASICMessageIdentifier),
 }
@@ -0,0 +1,206 @@
+use arrayvec::ArrayVec;
+
+use super::{CasicMsgConfig, ReceiverConfig};
+
+pub const MAX_SUBSCRIBERS: usize = 3;
+
+/// Maximum length of a casic packet of assistance data (including the framing).
+pub const MAX_AID_PACKET_LEN: usize = 128;
+/// A complete casic packet of assistance data, e.g. ephemeris of one satellite.
+pub type AidPacket = ArrayVec<u8, MAX_AID_PACKET_LEN>;
+
+/// Requests of `GPSReceiver`s to the gps task.
+#[derive(Debug)]
+pub enum GPSControlMsg {
+    Subscribe(CasicMsgConfig, u32),
+    UpdateConfig(CasicMsgConfig, u32),
+    UpdateReceiverConfig(ReceiverConfig, u32),
+    Unsubscribe(u32),
+    InjectAid(AidPacket),
+}
+
+#[derive(Clone, Copy)]
+struct GpsSubscriber {
+    id: u32,
+    config: CasicMsgConfig,
+    receiver_config: ReceiverConfig,
+}
+
+fn compute_merged_config(subscribers: &[GpsSubscriber]) -> CasicMsgConfig {
+    let mut out = CasicMsgConfig::default();
+    for s in subscribers {
+        out = out.merge(&s.config);
+    }
+    out
+}
+
+fn compute_merged_receiver_config(subscribers: &[GpsSubscriber]) -> ReceiverConfig {
+    let mut out = ReceiverConfig::default();
+    for s in subscribers {
+        out = out.merge(&s.receiver_config);
+    }
+    out
+}
+
+/// Subscribers of the gps task and the configuration they require from the receiver.
+#[derive(Default)]
+pub struct GpsState {
+    subscribers: ArrayVec<GpsSubscriber, MAX_SUBSCRIBERS>,
+    merged_config: CasicMsgConfig,
+    merged_receiver_config: ReceiverConfig,
+}
+
+impl GpsState {
+    /// The receiver should be powered as long as there is at least one subscriber.
+    pub fn is_active(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn merged_config(&self) -> CasicMsgConfig {
+        self.merged_config
+    }
+
+    pub fn merged_receiver_config(&self) -> ReceiverConfig {
+        self.merged_receiver_config
+    }
+
+    /// Process a control message. Returns assistance data that should be forwarded to the
+    /// receiver, if any.
+    pub fn handle(&mut self, msg: GPSControlMsg) -> Option<AidPacket> {
+        match msg {
+            GPSControlMsg::Subscribe(config, id) => {
+                self.subscribers.push(GpsSubscriber {
+                    id,
+                    config,
+                    receiver_config: ReceiverConfig::default(),
+                });
+            }
+            GPSControlMsg::UpdateConfig(config, id) => {
+                self.subscriber(id).config = config;
+            }
+            GPSControlMsg::UpdateReceiverConfig(receiver_config, id) => {
+                self.subscriber(id).receiver_config = receiver_config;
+            }
+            GPSControlMsg::Unsubscribe(id) => {
+                self.subscribers.retain(|s| s.id != id);
+            }
+            GPSControlMsg::InjectAid(packet) => {
+                return Some(packet);
+            }
+        }
+        self.merged_config = compute_merged_config(&self.subscribers);
+        self.merged_receiver_config = compute_merged_receiver_config(&self.subscribers);
+        None
+    }
+
+    fn subscriber(&mut self, id: u32) -> &mut GpsSubscriber {
+        self.subscribers.iter_mut().find(|s| s.id == id).unwrap()
+    }
+}
+
+/// Keeps track of the configuration that has been sent to the receiver since it was powered on.
+#[derive(Default)]
+pub struct AppliedConfig {
+    config: Option<CasicMsgConfig>,
+    receiver_config: Option<ReceiverConfig>,
+}
+
+impl AppliedConfig {
+    /// Returns the receiver config if it differs from the one applied previously and marks it as
+    /// applied.
+    pub fn receiver_config_update(&mut self, state: &GpsState) -> Option<ReceiverConfig> {
+        let new = state.merged_receiver_config();
+        (self.receiver_config.replace(new) != Some(new)).then_some(new)
+    }
+
+    /// Returns the message config if it differs from the one applied previously and marks it as
+    /// applied.
+    pub fn config_update(&mut self, state: &GpsState) -> Option<CasicMsgConfig> {
+        let new = state.merged_config();
+        (self.config.replace(new) != Some(new)).then_some(new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gps::{NavRate, SatelliteConfig};
+
+    #[test]
+    fn test_merging() {
+        let mut state = GpsState::default();
+        assert!(!state.is_active());
+
+        state.handle(GPSControlMsg::Subscribe(
+            CasicMsgConfig {
+                nav_time: 1,
+                ..Default::default()
+            },
+            0,
+        ));
+        state.handle(GPSControlMsg::Subscribe(
+            CasicMsgConfig {
+                nav_pv: 2,
+                ..Default::default()
+            },
+            1,
+        ));
+        state.handle(GPSControlMsg::UpdateReceiverConfig(
+            ReceiverConfig {
+                satellites: SatelliteConfig::from_bits(0b011),
+                nav_rate: NavRate::Hz5,
+            },
+            1,
+        ));
+        assert!(state.is_active());
+        assert_eq!(
+            state.merged_config(),
+            CasicMsgConfig {
+                nav_time: 1,
+                nav_pv: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(state.merged_receiver_config().nav_rate, NavRate::Hz5);
+
+        state.handle(GPSControlMsg::Unsubscribe(1));
+        assert_eq!(state.merged_config().nav_pv, 0);
+        assert_eq!(state.merged_receiver_config(), ReceiverConfig::default());
+        assert_eq!(
+            state.merged_receiver_config().active_satellites(),
+            SatelliteConfig::GPS_ONLY
+        );
+
+        state.handle(GPSControlMsg::Unsubscribe(0));
+        assert!(!state.is_active());
+
+        // E.g. a receiver that was dropped before its subscription was sent
+        state.handle(GPSControlMsg::Unsubscribe(2));
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn test_applied_config() {
+        let mut state = GpsState::default();
+        let mut applied = AppliedConfig::default();
+        state.handle(GPSControlMsg::Subscribe(CasicMsgConfig::default(), 0));
+
+        assert!(applied.config_update(&state).is_some());
+        assert!(applied.receiver_config_update(&state).is_some());
+        assert!(applied.config_update(&state).is_none());
+
+        let aid = state.handle(GPSControlMsg::InjectAid([1, 2, 3].into_iter().collect()));
+        assert_eq!(aid.unwrap().as_slice(), &[1, 2, 3]);
+        assert!(applied.config_update(&state).is_none());
+
+        state.handle(GPSControlMsg::UpdateConfig(
+            CasicMsgConfig {
+                nav_pv: 1,
+                ..Default::default()
+            },
+            0,
+        ));
+        assert!(applied.config_update(&state).is_some());
+        assert!(applied.receiver_config_update(&state).is_none());
+    }
+}
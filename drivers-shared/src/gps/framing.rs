@@ -0,0 +1,330 @@
+use super::{
+    casic_checksum, CASICMessageIdentifier, CASICPacketHeader, RawCasicMsg, CASIC_FRAME_OVERHEAD,
+    CASIC_MAGIC_HEADER,
+};
+
+#[derive(Debug, defmt::Format)]
+pub enum Message<'a> {
+    Casic(RawCasicMsg<'a>),
+    Nmea(&'a [u8]),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Casic,
+    Nmea,
+    Free,
+}
+
+/// Splits the byte stream coming from the receiver into casic and nmea messages.
+///
+/// Bytes are written into `free_space` (and then `commit`ed) or `push`ed, complete messages are
+/// taken out via `with_next_message`.
+pub struct Framer<const N: usize> {
+    inner: [u8; N],
+    end: usize,
+    state: State,
+}
+
+impl<const N: usize> Default for Framer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Framer<N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: [0; N],
+            end: 0,
+            state: State::Free,
+        }
+    }
+
+    /// The part of the buffer that has not been filled, yet. Call `commit` after writing to it.
+    pub fn free_space(&mut self) -> &mut [u8] {
+        &mut self.inner[self.end..]
+    }
+
+    pub fn commit(&mut self, n: usize) {
+        assert!(self.end + n <= N);
+        self.end += n;
+    }
+
+    /// Copy as many bytes of `data` into the buffer as possible. Returns the number of bytes
+    /// copied.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let space = self.free_space();
+        let n = data.len().min(space.len());
+        space[..n].copy_from_slice(&data[..n]);
+        self.commit(n);
+        n
+    }
+
+    pub fn buf(&self) -> &[u8] {
+        &self.inner[..self.end]
+    }
+
+    pub fn consume(&mut self, n: usize) {
+        self.inner.copy_within(n..self.end, 0);
+        self.end -= n;
+    }
+
+    pub fn clear(&mut self) {
+        self.consume(self.end);
+        self.state = State::Free;
+    }
+
+    /// Call `f` with the next complete message in the buffer (if any) and remove it afterwards.
+    /// Returns `None` if more data is required.
+    pub fn with_next_message<R>(&mut self, f: impl FnOnce(Message) -> R) -> Option<R> {
+        loop {
+            let current_buf = &self.inner[..self.end];
+            match self.state {
+                State::Casic => {
+                    let header_len = core::mem::size_of::<CASICPacketHeader>();
+                    if current_buf.len() < header_len {
+                        break;
+                    }
+                    let header: &CASICPacketHeader =
+                        bytemuck::from_bytes(&current_buf[..header_len]);
+
+                    let checksum_len = 4;
+                    let payload_len = header.len as usize;
+                    let packet_len = header_len + payload_len + checksum_len;
+                    if packet_len + CASIC_MAGIC_HEADER.len() > N {
+                        // Cannot possibly be a valid packet, so we must have synced on garbage.
+                        self.state = State::Free;
+                        continue;
+                    }
+                    if current_buf.len() < packet_len {
+                        break;
+                    }
+                    //TODO: we could also check the checksum... meh...
+
+                    let payload_buf = &current_buf[header_len..][..payload_len];
+                    let res = f(Message::Casic(RawCasicMsg {
+                        id: header.msg_id,
+                        payload: payload_buf,
+                    }));
+
+                    self.consume(packet_len);
+                    self.state = State::Free;
+                    return Some(res);
+                }
+                State::Nmea => {
+                    let Some(newline_pos) = current_buf.iter().position(|b| *b == b'\n') else {
+                        if current_buf.len() == N {
+                            // Line does not fit into the buffer, drop it.
+                            self.clear();
+                        }
+                        break;
+                    };
+                    let after_newline = newline_pos + 1;
+                    let res = f(Message::Nmea(&current_buf[..after_newline]));
+
+                    self.consume(after_newline);
+                    self.state = State::Free;
+                    return Some(res);
+                }
+                State::Free => {
+                    let mut to_consume = 0;
+                    for (i, w) in current_buf.windows(2).enumerate() {
+                        let w: [u8; 2] = w.try_into().unwrap();
+                        match w {
+                            [b'$', _] => {
+                                self.state = State::Nmea;
+                                to_consume = i;
+                                break;
+                            }
+                            CASIC_MAGIC_HEADER => {
+                                self.state = State::Casic;
+                                to_consume = i + CASIC_MAGIC_HEADER.len();
+                                break;
+                            }
+                            _ => {
+                                to_consume = i + 1;
+                            }
+                        };
+                    }
+                    self.consume(to_consume);
+                    if self.state == State::Free {
+                        break;
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Magic and header of a casic packet, i.e., what is needed to determine its length.
+pub const CASIC_PACKET_START_LEN: usize =
+    CASIC_MAGIC_HEADER.len() + core::mem::size_of::<CASICPacketHeader>();
+
+/// Length (including the framing) of the casic packet that `start` is the beginning of. `None` if
+/// `start` is shorter than `CASIC_PACKET_START_LEN` or does not begin with the magic.
+pub fn casic_packet_len(start: &[u8]) -> Option<usize> {
+    let (magic, header) = start.get(..CASIC_PACKET_START_LEN)?.split_at(2);
+    if magic != CASIC_MAGIC_HEADER {
+        return None;
+    }
+    let header: CASICPacketHeader = bytemuck::pod_read_unaligned(header);
+    Some(header.len as usize + CASIC_FRAME_OVERHEAD)
+}
+
+/// Maximum casic payload length supported by `encode_casic`.
+pub const MAX_ENCODED_CASIC_PAYLOAD: usize = 128;
+pub type EncodedCasic =
+    arrayvec::ArrayVec<u8, { MAX_ENCODED_CASIC_PAYLOAD + CASIC_FRAME_OVERHEAD }>;
+
+pub fn encode_casic(msg_id: CASICMessageIdentifier, payload: &[u8]) -> EncodedCasic {
+    let len = payload.len();
+    assert!(len <= MAX_ENCODED_CASIC_PAYLOAD);
+    assert!(len.is_multiple_of(4));
+    let header = CASICPacketHeader {
+        msg_id,
+        len: len as u16,
+    };
+
+    let checksum = casic_checksum(msg_id, payload);
+
+    let mut out = EncodedCasic::new();
+    out.try_extend_from_slice(&CASIC_MAGIC_HEADER).unwrap();
+    out.try_extend_from_slice(bytemuck::bytes_of(&header))
+        .unwrap();
+    out.try_extend_from_slice(payload).unwrap();
+    out.try_extend_from_slice(&checksum.to_le_bytes()).unwrap();
+    out
+}
+
+/// Maximum nmea command length (without `$`, checksum and line break) supported by
+/// `encode_nmea`.
+pub const MAX_ENCODED_NMEA_CMD: usize = 64;
+pub type EncodedNmea = arrayvec::ArrayVec<u8, { MAX_ENCODED_NMEA_CMD + 6 }>;
+
+pub fn encode_nmea(cmd: &[u8]) -> EncodedNmea {
+    assert!(cmd.len() <= MAX_ENCODED_NMEA_CMD);
+    let check_sum = cmd.iter().fold(0u8, |a, b| a ^ b);
+    let hex = |v: u8| b"0123456789ABCDEF"[v as usize];
+
+    let mut out = EncodedNmea::new();
+    out.push(b'$');
+    out.try_extend_from_slice(cmd).unwrap();
+    out.try_extend_from_slice(&[
+        b'*',
+        hex(check_sum >> 4),
+        hex(check_sum & 0xf),
+        b'\r',
+        b'\n',
+    ])
+    .unwrap();
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gps::{CasicMsg, GnssSystem, NavGpsInfo, NAV_GPS_INFO, NAV_PV, NAV_TIME_UTC};
+
+    fn collect<const N: usize>(framer: &mut Framer<N>) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+        while let Some(m) = framer.with_next_message(|m| match m {
+            Message::Casic(c) => c.payload.to_vec(),
+            Message::Nmea(n) => n.to_vec(),
+        }) {
+            out.push(m);
+        }
+        out
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let info = NavGpsInfo {
+            run_time: 1234,
+            num_view_sv: 8,
+            num_fix_sv: 5,
+            system: 1,
+            _reserved: 0,
+        };
+        let frame = encode_casic(NAV_GPS_INFO, bytemuck::bytes_of(&info));
+
+        let mut framer = Framer::<64>::new();
+        framer.push(&[0x00, 0xba, 0x12]);
+        framer.push(&frame);
+        framer.push(b"garbage$GPTXT,hi*00\r\n");
+
+        let parsed = framer.with_next_message(|m| match m {
+            Message::Casic(c) => c.parse(),
+            Message::Nmea(_) => panic!("expected casic msg"),
+        });
+        let Some(CasicMsg::NavSvInfo(parsed)) = parsed else {
+            panic!("expected gps info");
+        };
+        assert_eq!(parsed.system, GnssSystem::Gps);
+        assert_eq!(parsed.header.run_time, 1234);
+        assert_eq!(parsed.header.num_fix_sv, 5);
+        assert!(parsed.svs.is_empty());
+
+        assert_eq!(collect(&mut framer), [b"$GPTXT,hi*00\r\n".to_vec()]);
+        assert!(framer.buf().is_empty());
+    }
+
+    #[test]
+    fn test_split_input() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&encode_nmea(b"PCAS04,1"));
+        stream.extend_from_slice(&encode_casic([0x06, 0x04], &[0xe8, 0x03, 0, 0]));
+        stream.extend_from_slice(&encode_nmea(b"PCAS04,3"));
+
+        let mut framer = Framer::<32>::new();
+        let mut out = Vec::new();
+        for b in stream.chunks(3) {
+            assert_eq!(framer.push(b), b.len());
+            out.extend(collect(&mut framer));
+        }
+        assert_eq!(
+            out,
+            [
+                b"$PCAS04,1*18\r\n".to_vec(),
+                vec![0xe8, 0x03, 0, 0],
+                b"$PCAS04,3*1A\r\n".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packet_len() {
+        let frame = encode_casic([0x06, 0x04], &[0xe8, 0x03, 0, 0]);
+        assert_eq!(casic_packet_len(&frame), Some(frame.len()));
+        assert_eq!(
+            casic_packet_len(&frame[..CASIC_PACKET_START_LEN]),
+            Some(frame.len())
+        );
+        assert_eq!(casic_packet_len(&frame[..CASIC_PACKET_START_LEN - 1]), None);
+        assert_eq!(casic_packet_len(&frame[1..]), None);
+    }
+
+    #[test]
+    fn test_short_payloads_are_unknown() {
+        for id in [NAV_GPS_INFO, NAV_PV, NAV_TIME_UTC] {
+            let msg = RawCasicMsg {
+                id,
+                payload: &[1, 2, 3],
+            };
+            assert!(matches!(msg.parse(), CasicMsg::Unknown(i) if i == id));
+        }
+    }
+
+    #[test]
+    fn test_oversized_frames_are_dropped() {
+        let mut framer = Framer::<16>::new();
+        framer.push(&[0xba, 0xce, 0xff, 0x00, 0x01, 0x03]);
+        assert!(collect(&mut framer).is_empty());
+
+        framer.clear();
+        framer.push(b"$GPTXT,very long line");
+        assert!(collect(&mut framer).is_empty());
+        assert!(framer.buf().is_empty());
+    }
+}
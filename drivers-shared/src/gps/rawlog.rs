@@ -0,0 +1,148 @@
+use arrayvec::ArrayVec;
+
+pub const RAW_LOG_MAGIC: [u8; 2] = *b"RL";
+
+/// Largest number of uart bytes stored in a single record.
+pub const MAX_RAW_LOG_CHUNK_LEN: usize = 256;
+
+/// Raw logs of the uart traffic of the receiver are a sequence of records, each consisting of this
+/// header followed by `len` bytes exactly as they were received.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct RawLogRecordHeader {
+    pub magic: [u8; 2],
+    pub len: u16,
+    /// Time at which the bytes were received. Milliseconds since boot.
+    pub timestamp_ms: u32,
+}
+
+impl RawLogRecordHeader {
+    pub fn new(timestamp_ms: u32, len: u16) -> Self {
+        Self {
+            magic: RAW_LOG_MAGIC,
+            len,
+            timestamp_ms,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == RAW_LOG_MAGIC && self.len as usize <= MAX_RAW_LOG_CHUNK_LEN
+    }
+}
+
+/// Bytes received from the receiver in one go.
+#[derive(Clone, Debug)]
+pub struct RawLogChunk {
+    pub timestamp_ms: u32,
+    pub data: ArrayVec<u8, MAX_RAW_LOG_CHUNK_LEN>,
+}
+
+impl RawLogChunk {
+    /// Split `data` into chunks that fit into a record.
+    pub fn split(timestamp_ms: u32, data: &[u8]) -> impl Iterator<Item = Self> + '_ {
+        data.chunks(MAX_RAW_LOG_CHUNK_LEN).map(move |c| Self {
+            timestamp_ms,
+            data: c.try_into().unwrap(),
+        })
+    }
+
+    pub fn header(&self) -> RawLogRecordHeader {
+        RawLogRecordHeader::new(self.timestamp_ms, self.data.len() as u16)
+    }
+
+    /// Size of the record in the log file.
+    pub fn record_len(&self) -> usize {
+        core::mem::size_of::<RawLogRecordHeader>() + self.data.len()
+    }
+}
+
+/// Iterates over the records of a raw log as `(timestamp_ms, data)`. Stops at the first invalid or
+/// truncated record (e.g. if the watch lost power while writing).
+pub struct RawLogRecords<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> RawLogRecords<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { rest: data }
+    }
+
+    /// Data that has not been consumed (yet). Non-empty after iteration has finished if the log
+    /// contains garbage.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for RawLogRecords<'a> {
+    type Item = (u32, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header_len = core::mem::size_of::<RawLogRecordHeader>();
+        if self.rest.len() < header_len {
+            return None;
+        }
+        let header: RawLogRecordHeader = bytemuck::pod_read_unaligned(&self.rest[..header_len]);
+        let record_len = header_len + header.len as usize;
+        if !header.is_valid() || self.rest.len() < record_len {
+            return None;
+        }
+        let data = &self.rest[header_len..record_len];
+        self.rest = &self.rest[record_len..];
+        Some((header.timestamp_ms, data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(chunks: impl Iterator<Item = RawLogChunk>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for c in chunks {
+            out.extend_from_slice(bytemuck::bytes_of(&c.header()));
+            out.extend_from_slice(&c.data);
+        }
+        out
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = (0..600).map(|i| i as u8).collect::<Vec<_>>();
+        let log = encode(
+            RawLogChunk::split(10, &data[..300]).chain(RawLogChunk::split(20, &data[300..])),
+        );
+
+        let mut records = RawLogRecords::new(&log);
+        let decoded = records.by_ref().collect::<Vec<_>>();
+        assert!(records.remaining().is_empty());
+        assert_eq!(
+            decoded
+                .iter()
+                .map(|(t, d)| (*t, d.len()))
+                .collect::<Vec<_>>(),
+            [(10, 256), (10, 44), (20, 256), (20, 44)]
+        );
+        assert_eq!(
+            decoded
+                .iter()
+                .flat_map(|(_, d)| d.iter().copied())
+                .collect::<Vec<_>>(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let log = encode(RawLogChunk::split(5, b"$GPTXT,01,01,02*00\r\n"));
+        let truncated = &log[..log.len() - 1];
+
+        let mut records = RawLogRecords::new(truncated);
+        assert!(records.next().is_none());
+        assert_eq!(records.remaining(), truncated);
+
+        let mut records = RawLogRecords::new(&log);
+        assert_eq!(records.next().unwrap().0, 5);
+        assert!(records.next().is_none());
+    }
+}
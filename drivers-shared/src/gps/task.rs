@@ -0,0 +1,262 @@
+//! The gps task and its `GPSReceiver`s, shared by the hardware driver and the simulator, which
+//! only differ in how they power the receiver and what is on the other side of the uart.
+//!
+//! The task loop of a driver waits in `wait_until_needed`, powers on the receiver and then runs
+//! `run_while_needed` until the last `GPSReceiver` is gone.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use arrayvec::ArrayString;
+use embassy_futures::{
+    select::{select, Either},
+    yield_now,
+};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    pubsub::{PubSubChannel, Subscriber, WaitResult},
+    signal::Signal,
+};
+use embedded_io_async::{Read, Write};
+
+use super::*;
+
+const MAX_MSGS: usize = 4;
+
+type GpsPubSubChannel =
+    PubSubChannel<CriticalSectionRawMutex, CasicMsg, MAX_MSGS, MAX_SUBSCRIBERS, 1>;
+static GPS_PUB_SUB_CHANNEL: GpsPubSubChannel = GpsPubSubChannel::new();
+
+type GpsControlChannel = Channel<CriticalSectionRawMutex, GPSControlMsg, 1>;
+static GPS_CONTROL_CHANNEL: GpsControlChannel = GpsControlChannel::new();
+
+// Each `GPSReceiver` uses one of the lowest `MAX_SUBSCRIBERS` bits as its id. A dropped receiver
+// can't wait for room in the control channel, so it only marks its id as dropped. The id is reused
+// once the gps task has unsubscribed it.
+static USED_IDS: AtomicU32 = AtomicU32::new(0);
+static DROPPED_IDS: AtomicU32 = AtomicU32::new(0);
+static RECEIVER_DROPPED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Commands to the receiver over the uart.
+pub struct GpsTransmitter<W> {
+    uart: W,
+}
+
+impl<W: Write> GpsTransmitter<W> {
+    pub fn new(uart: W) -> Self {
+        Self { uart }
+    }
+
+    pub async fn write_raw(&mut self, data: &[u8]) {
+        self.uart.write_all(data).await.unwrap();
+        self.uart.flush().await.unwrap();
+    }
+
+    pub async fn casic_msg(&mut self, msg_id: CASICMessageIdentifier, payload: &[u8]) {
+        self.write_raw(&encode_casic(msg_id, payload)).await;
+    }
+
+    pub async fn nmea_cmd(&mut self, cmd: &[u8]) {
+        self.write_raw(&encode_nmea(cmd)).await;
+    }
+
+    pub async fn set_active_satellites(&mut self, cfg: SatelliteConfig) {
+        use core::fmt::Write;
+        let mut cmd = ArrayString::<10>::new();
+        write!(cmd, "PCAS04,{}", cfg.to_bits()).unwrap();
+        self.nmea_cmd(cmd.as_bytes()).await;
+    }
+
+    pub async fn set_nav_rate(&mut self, rate: NavRate) {
+        let payload = CfgRatePayload {
+            interval_ms: rate.interval_ms(),
+            _reserved: 0,
+        };
+        self.casic_msg(CFG_RATE, bytemuck::bytes_of(&payload)).await
+    }
+
+    pub async fn set_receiver_config(&mut self, cfg: ReceiverConfig) {
+        self.set_active_satellites(cfg.active_satellites()).await;
+        self.set_nav_rate(cfg.nav_rate).await;
+    }
+
+    pub async fn set_msg_freq(&mut self, msg_id: CASICMessageIdentifier, rate: u16) {
+        let payload = CfgMsgPayload { msg_id, rate };
+        self.casic_msg(CFG_MSG, bytemuck::bytes_of(&payload)).await
+    }
+
+    pub async fn set_casic_msg_config(&mut self, config: CasicMsgConfig) {
+        for (msg_id, rate) in config.msg_rates() {
+            self.set_msg_freq(msg_id, rate).await;
+        }
+    }
+}
+
+/// Read from the receiver and publish its casic messages to the `GPSReceiver`s. All bytes are
+/// passed to `tee` as well. Nmea output is disabled on startup, so nmea messages are dropped.
+/// Never returns.
+async fn publish_messages<const N: usize>(
+    rx: &mut impl Read,
+    framer: &mut Framer<N>,
+    tee: impl Fn(&[u8]),
+) {
+    let publisher = GPS_PUB_SUB_CHANNEL.immediate_publisher();
+    loop {
+        while let Some(()) = framer.with_next_message(|msg| {
+            if let Message::Casic(msg) = msg {
+                publisher.publish_immediate(msg.parse());
+            }
+        }) {}
+
+        let n = rx.read(framer.free_space()).await.unwrap();
+        tee(&framer.free_space()[..n]);
+        framer.commit(n);
+    }
+}
+
+/// Wait for the next request of a `GPSReceiver` (including dropping it) and process it. Returns
+/// assistance data that should be forwarded to the receiver, if any.
+async fn handle_next_request(state: &mut GpsState) -> Option<AidPacket> {
+    match select(GPS_CONTROL_CHANNEL.receive(), RECEIVER_DROPPED.wait()).await {
+        Either::First(msg) => state.handle(msg),
+        Either::Second(()) => {
+            let dropped = DROPPED_IDS.swap(0, Ordering::AcqRel);
+            // A request that a receiver sent before being dropped has to be handled first. There
+            // is room for only one in the channel.
+            let aid = GPS_CONTROL_CHANNEL
+                .try_receive()
+                .ok()
+                .and_then(|msg| state.handle(msg));
+
+            for id in 0..MAX_SUBSCRIBERS as u32 {
+                if dropped & (1 << id) != 0 {
+                    state.handle(GPSControlMsg::Unsubscribe(id));
+                }
+            }
+            USED_IDS.fetch_and(!dropped, Ordering::AcqRel);
+            aid
+        }
+    }
+}
+
+/// Process the requests of `GPSReceiver`s until one of them needs the receiver.
+pub async fn wait_until_needed(state: &mut GpsState) {
+    while !state.is_active() {
+        // Assistance data is only useful while the receiver is powered, so we drop it here.
+        let _ = handle_next_request(state).await;
+    }
+}
+
+/// Talk to the powered receiver on the other side of `rx` and `tx` until no `GPSReceiver` needs it
+/// any more.
+pub async fn run_while_needed<const N: usize>(
+    state: &mut GpsState,
+    mut rx: impl Read,
+    framer: &mut Framer<N>,
+    mut tx: GpsTransmitter<impl Write>,
+    tee: impl Fn(&[u8]),
+) {
+    let publish = publish_messages(&mut rx, framer, tee);
+    let mut publish = core::pin::pin!(publish);
+
+    // The receiver does not keep its configuration while powered off, so everything has to be
+    // applied again after powering on.
+    let mut applied = AppliedConfig::default();
+    while state.is_active() {
+        if let Some(config) = applied.receiver_config_update(state) {
+            tx.set_receiver_config(config).await;
+        }
+        if let Some(config) = applied.config_update(state) {
+            tx.set_casic_msg_config(config).await;
+        }
+        if let Either::First(Some(aid)) = select(handle_next_request(state), &mut publish).await {
+            tx.write_raw(&aid).await;
+        }
+    }
+}
+
+/// Reserve an unused id. `None` if all are in use, i.e., there are `MAX_SUBSCRIBERS` receivers or
+/// the gps task has not yet unsubscribed a dropped one.
+fn allocate_id() -> Option<u32> {
+    let mut used = USED_IDS.load(Ordering::Acquire);
+    loop {
+        let id = used.trailing_ones();
+        if id as usize >= MAX_SUBSCRIBERS {
+            return None;
+        }
+        match USED_IDS.compare_exchange_weak(
+            used,
+            used | (1 << id),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return Some(id),
+            Err(current) => used = current,
+        }
+    }
+}
+
+pub struct GPSReceiver<'a> {
+    msgs: Subscriber<'a, CriticalSectionRawMutex, CasicMsg, MAX_MSGS, MAX_SUBSCRIBERS, 1>,
+    id: u32,
+}
+
+impl Drop for GPSReceiver<'_> {
+    fn drop(&mut self) {
+        DROPPED_IDS.fetch_or(1 << self.id, Ordering::AcqRel);
+        RECEIVER_DROPPED.signal(());
+    }
+}
+
+impl<'a> GPSReceiver<'a> {
+    pub async fn new(config: CasicMsgConfig) -> GPSReceiver<'a> {
+        let id = loop {
+            if let Some(id) = allocate_id() {
+                break id;
+            }
+            // Let the gps task catch up on dropped receivers.
+            yield_now().await;
+        };
+        // Created before subscribing, so that its drop unsubscribes again if this is cancelled.
+        let receiver = GPSReceiver {
+            msgs: GPS_PUB_SUB_CHANNEL.subscriber().unwrap(),
+            id,
+        };
+        GPS_CONTROL_CHANNEL
+            .send(GPSControlMsg::Subscribe(config, id))
+            .await;
+        receiver
+    }
+
+    pub async fn update_config(&mut self, config: CasicMsgConfig) {
+        GPS_CONTROL_CHANNEL
+            .send(GPSControlMsg::UpdateConfig(config, self.id))
+            .await;
+    }
+
+    /// Request satellite systems and navigation rate. The receiver uses the union of systems and
+    /// the highest rate requested by all receivers.
+    pub async fn update_receiver_config(&mut self, config: ReceiverConfig) {
+        GPS_CONTROL_CHANNEL
+            .send(GPSControlMsg::UpdateReceiverConfig(config, self.id))
+            .await;
+    }
+
+    /// Send a packet of assistance data (e.g. ephemeris) to the receiver. It is written in one
+    /// piece, so configuration commands can't end up in the middle of it. Has no effect if the
+    /// receiver is not powered.
+    pub async fn inject_aid(&mut self, packet: AidPacket) {
+        GPS_CONTROL_CHANNEL
+            .send(GPSControlMsg::InjectAid(packet))
+            .await;
+    }
+
+    pub async fn receive(&mut self) -> CasicMsg {
+        loop {
+            match self.msgs.next_message().await {
+                WaitResult::Lagged(_) => {}
+                WaitResult::Message(r) => return r,
+            }
+        }
+    }
+}
@@ -1,3 +1,10 @@
+mod recording;
+
+pub use recording::*;
+
+/// Value of the sample delay register of the hrm for (nominally) 25Hz
+pub const NOMINAL_SAMPLE_DELAY: u16 = 840;
+
 #[derive(defmt::Format)]
 pub struct ReadResult {
     pub status: u8,
@@ -27,3 +34,167 @@ impl PdResConfig {
         (self.res & 0x07) << 4 | self.res_set & 0x8f
     }
 }
+
+/// Proximity values at or above this mean the sensor is close to the skin ...
+const PS_ON_THRESHOLD: u8 = 7;
+/// ... and at or below this that it is not. In between, the proximity is no evidence either way.
+const PS_OFF_THRESHOLD: u8 = 3;
+/// Ambient light (on any slot) at or above this only reaches the photodiode when it is not covered
+/// by the wrist.
+const ENV_OFF_THRESHOLD: u8 = 10;
+/// Range of the raw ppg samples in which the led current control leaves them alone. Outside of it
+/// with the current already at its limit, there is no skin reflecting the light.
+const DC_RANGE: core::ops::Range<i16> = 320..3776;
+/// Number of events in a row that have to point to the other state before switching to it.
+/// There is one about every 300ms.
+const WEAR_SWITCH_EVENTS: u8 = 5;
+
+/// Raw level of the ppg signal in one batch of samples.
+#[derive(Copy, Clone)]
+pub struct DcLevel {
+    /// Mean of the raw samples, before the driver offsets them to hide led current changes
+    pub mean: i16,
+    /// Whether the led current is at zero or at its maximum, so the level can't be adjusted
+    /// further.
+    pub led_current_at_limit: bool,
+}
+
+/// Decides whether the watch is worn from the proximity, ambient light and ppg level of the
+/// sensor. Single readings are unreliable, so it only switches after several events agree.
+pub struct WearDetector {
+    wearing: bool,
+    disagreeing_events: u8,
+}
+
+impl Default for WearDetector {
+    fn default() -> Self {
+        Self {
+            // Apps start the sensor when they want to measure, which is usually on the wrist.
+            wearing: true,
+            disagreeing_events: 0,
+        }
+    }
+}
+
+impl WearDetector {
+    pub fn wearing(&self) -> bool {
+        self.wearing
+    }
+
+    /// `Some(true)` if the readings point to the watch being worn, `Some(false)` if not.
+    fn evidence(r: &ReadResult, dc: Option<DcLevel>) -> Option<bool> {
+        let bright = r.env_value.iter().any(|v| *v >= ENV_OFF_THRESHOLD);
+        let saturated =
+            dc.is_some_and(|dc| dc.led_current_at_limit && !DC_RANGE.contains(&dc.mean));
+        if bright || saturated {
+            return Some(false);
+        }
+        if r.ps_value >= PS_ON_THRESHOLD {
+            return Some(true);
+        }
+        match dc {
+            // Something reflects the light well enough. A low proximity alone is not trusted
+            // then, as its interrupt never fires and it may not be measured reliably.
+            Some(dc) if DC_RANGE.contains(&dc.mean) => Some(true),
+            _ => (r.ps_value <= PS_OFF_THRESHOLD).then_some(false),
+        }
+    }
+
+    /// Returns the new state if it changed.
+    pub fn update(&mut self, r: &ReadResult, dc: Option<DcLevel>) -> Option<bool> {
+        match Self::evidence(r, dc) {
+            Some(wearing) if wearing != self.wearing => {
+                self.disagreeing_events += 1;
+                if self.disagreeing_events >= WEAR_SWITCH_EVENTS {
+                    self.wearing = wearing;
+                    self.disagreeing_events = 0;
+                    return Some(wearing);
+                }
+            }
+            Some(_) => self.disagreeing_events = 0,
+            // Keep counting, but don't count this one.
+            None => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn reading(ps_value: u8, env: u8) -> ReadResult {
+        ReadResult {
+            status: 0,
+            irq_status: 0,
+            env_value: [env; 3],
+            pre_value: [0; 2],
+            ps_value,
+            pd_res_value: [0; 3],
+            current_value: [0; 3],
+        }
+    }
+
+    #[test]
+    fn switches_after_consistent_evidence() {
+        let mut d = WearDetector::default();
+        assert!(d.wearing());
+        for _ in 0..WEAR_SWITCH_EVENTS - 1 {
+            assert_eq!(d.update(&reading(0, 0), None), None);
+        }
+        assert_eq!(d.update(&reading(0, 0), None), Some(false));
+        assert!(!d.wearing());
+
+        for _ in 0..WEAR_SWITCH_EVENTS - 1 {
+            assert_eq!(d.update(&reading(12, 0), None), None);
+        }
+        assert_eq!(d.update(&reading(12, 0), None), Some(true));
+    }
+
+    #[test]
+    fn ignores_short_glitches() {
+        let mut d = WearDetector::default();
+        for _ in 0..10 {
+            for _ in 0..WEAR_SWITCH_EVENTS - 1 {
+                assert_eq!(d.update(&reading(0, 0), None), None);
+            }
+            assert_eq!(d.update(&reading(12, 0), None), None);
+        }
+        assert!(d.wearing());
+    }
+
+    #[test]
+    fn light_or_saturated_signal_means_off_wrist() {
+        let saturated = DcLevel {
+            mean: 4000,
+            led_current_at_limit: true,
+        };
+        let adjusting = DcLevel {
+            mean: 4000,
+            led_current_at_limit: false,
+        };
+        assert_eq!(
+            WearDetector::evidence(&reading(12, 0), Some(saturated)),
+            Some(false)
+        );
+        assert_eq!(
+            WearDetector::evidence(&reading(12, 0), Some(adjusting)),
+            Some(true)
+        );
+        assert_eq!(WearDetector::evidence(&reading(12, 15), None), Some(false));
+        assert_eq!(WearDetector::evidence(&reading(5, 0), None), None);
+    }
+
+    #[test]
+    fn good_signal_outweighs_low_proximity() {
+        let good = DcLevel {
+            mean: 2000,
+            led_current_at_limit: false,
+        };
+        assert_eq!(
+            WearDetector::evidence(&reading(0, 0), Some(good)),
+            Some(true)
+        );
+        assert_eq!(WearDetector::evidence(&reading(0, 0), None), Some(false));
+    }
+}
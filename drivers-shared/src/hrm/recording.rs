@@ -0,0 +1,316 @@
+use arrayvec::ArrayVec;
+
+use super::ReadResult;
+
+pub const RECORDING_MAGIC: u8 = b'H';
+pub const RECORDING_VERSION: u16 = 1;
+
+/// Largest number of samples stored in a single record. Longer batches are split.
+pub const MAX_HRM_SAMPLES_PER_RECORD: usize = 64;
+pub const MAX_ACCEL_SAMPLES_PER_RECORD: usize = 32;
+
+/// Recordings of the hrm app are a sequence of records, each consisting of this header followed
+/// by `len` bytes of payload (little endian, like the watch), depending on the `kind`. New kinds
+/// can be added without breaking older decoders, which skip records they don't know.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct RecordHeader {
+    pub magic: u8,
+    pub kind: u8,
+    pub len: u16,
+    /// Time at which the record was written. Milliseconds since the start of the recording. For
+    /// samples that is when the batch was read, i.e. shortly after the last sample was taken.
+    pub timestamp_ms: u32,
+}
+
+impl RecordHeader {
+    /// Records of unknown kinds can have any length, they are skipped as a whole.
+    pub fn is_valid(&self) -> bool {
+        self.magic == RECORDING_MAGIC
+            && RecordKind::try_from(self.kind)
+                .map_or(true, |kind| self.len as usize <= kind.max_payload_len())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, num_enum::TryFromPrimitive)]
+#[repr(u8)]
+enum RecordKind {
+    Info = 0,
+    Settings = 1,
+    HrmSamples = 2,
+    AccelSamples = 3,
+}
+
+impl RecordKind {
+    fn max_payload_len(self) -> usize {
+        match self {
+            RecordKind::Info => core::mem::size_of::<RecordingInfo>(),
+            RecordKind::Settings => core::mem::size_of::<SensorSettings>(),
+            RecordKind::HrmSamples => MAX_HRM_SAMPLES_PER_RECORD * 2,
+            RecordKind::AccelSamples => MAX_ACCEL_SAMPLES_PER_RECORD * 6,
+        }
+    }
+}
+
+/// Written at the start and whenever the sample rate changes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct RecordingInfo {
+    pub version: u16,
+    /// Value of the sample delay register of the hrm, see `NOMINAL_SAMPLE_DELAY`
+    pub hrm_sample_delay: u16,
+    /// Time between hrm samples as measured against the system clock, 0 if not known (yet)
+    pub hrm_ms_per_sample: f32,
+    pub accel_ms_per_sample: f32,
+}
+
+/// Settings of the hrm that change the signal level. Written at the start and whenever they
+/// change.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, defmt::Format, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct SensorSettings {
+    pub pd_res: u8,
+    pub led_current: u8,
+}
+
+impl SensorSettings {
+    pub fn from_read_result(r: &ReadResult) -> Self {
+        Self {
+            pd_res: r.pd_res_value[0],
+            led_current: r.current_value[0],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Info(RecordingInfo),
+    Settings(SensorSettings),
+    HrmSamples(ArrayVec<i16, MAX_HRM_SAMPLES_PER_RECORD>),
+    /// x, y, z
+    AccelSamples(ArrayVec<[i16; 3], MAX_ACCEL_SAMPLES_PER_RECORD>),
+}
+
+impl Record {
+    /// Split `samples` into records.
+    pub fn hrm_samples(samples: &[i16]) -> impl Iterator<Item = Self> + '_ {
+        samples
+            .chunks(MAX_HRM_SAMPLES_PER_RECORD)
+            .map(|c| Self::HrmSamples(c.try_into().unwrap()))
+    }
+
+    /// Split `samples` into records.
+    pub fn accel_samples(samples: &[[i16; 3]]) -> impl Iterator<Item = Self> + '_ {
+        samples
+            .chunks(MAX_ACCEL_SAMPLES_PER_RECORD)
+            .map(|c| Self::AccelSamples(c.try_into().unwrap()))
+    }
+
+    fn kind(&self) -> RecordKind {
+        match self {
+            Record::Info(_) => RecordKind::Info,
+            Record::Settings(_) => RecordKind::Settings,
+            Record::HrmSamples(_) => RecordKind::HrmSamples,
+            Record::AccelSamples(_) => RecordKind::AccelSamples,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Record::Info(i) => bytemuck::bytes_of(i),
+            Record::Settings(s) => bytemuck::bytes_of(s),
+            Record::HrmSamples(s) => bytemuck::cast_slice(s),
+            Record::AccelSamples(s) => bytemuck::cast_slice(s),
+        }
+    }
+
+    pub fn header(&self, timestamp_ms: u32) -> RecordHeader {
+        RecordHeader {
+            magic: RECORDING_MAGIC,
+            kind: self.kind() as u8,
+            len: self.payload().len() as u16,
+            timestamp_ms,
+        }
+    }
+
+    /// Size of the record in the recording.
+    pub fn record_len(&self) -> usize {
+        core::mem::size_of::<RecordHeader>() + self.payload().len()
+    }
+
+    /// `None` if the payload does not fit the kind.
+    fn decode(kind: RecordKind, payload: &[u8]) -> Option<Self> {
+        Some(match kind {
+            RecordKind::Info => Record::Info(bytemuck::try_pod_read_unaligned(payload).ok()?),
+            RecordKind::Settings => {
+                Record::Settings(bytemuck::try_pod_read_unaligned(payload).ok()?)
+            }
+            RecordKind::HrmSamples => {
+                let (samples, rest) = payload.as_chunks::<2>();
+                if !rest.is_empty() || samples.len() > MAX_HRM_SAMPLES_PER_RECORD {
+                    return None;
+                }
+                Record::HrmSamples(samples.iter().map(|b| i16::from_le_bytes(*b)).collect())
+            }
+            RecordKind::AccelSamples => {
+                let (samples, rest) = payload.as_chunks::<6>();
+                if !rest.is_empty() || samples.len() > MAX_ACCEL_SAMPLES_PER_RECORD {
+                    return None;
+                }
+                let xyz = |b: &[u8; 6]| {
+                    core::array::from_fn(|i| i16::from_le_bytes([b[2 * i], b[2 * i + 1]]))
+                };
+                Record::AccelSamples(samples.iter().map(xyz).collect())
+            }
+        })
+    }
+}
+
+/// Iterates over the records of a recording as `(timestamp_ms, record)`, skipping those of unknown
+/// kinds. Stops at the first invalid or truncated record (e.g. if the watch lost power while
+/// writing).
+pub struct Records<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Records<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { rest: data }
+    }
+
+    /// Data that has not been consumed (yet). Non-empty after iteration has finished if the
+    /// recording contains garbage.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = (u32, Record);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header_len = core::mem::size_of::<RecordHeader>();
+        loop {
+            if self.rest.len() < header_len {
+                return None;
+            }
+            let header: RecordHeader = bytemuck::pod_read_unaligned(&self.rest[..header_len]);
+            let record_len = header_len + header.len as usize;
+            if !header.is_valid() || self.rest.len() < record_len {
+                return None;
+            }
+            let payload = &self.rest[header_len..record_len];
+            let record = match RecordKind::try_from(header.kind) {
+                Ok(kind) => Some(Record::decode(kind, payload)?),
+                Err(_) => None,
+            };
+            self.rest = &self.rest[record_len..];
+            if let Some(record) = record {
+                return Some((header.timestamp_ms, record));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(records: impl Iterator<Item = (u32, Record)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (t, r) in records {
+            out.extend_from_slice(bytemuck::bytes_of(&r.header(t)));
+            out.extend_from_slice(r.payload());
+        }
+        out
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let info = Record::Info(RecordingInfo {
+            version: RECORDING_VERSION,
+            hrm_sample_delay: 840,
+            hrm_ms_per_sample: 40.1,
+            accel_ms_per_sample: 40.0,
+        });
+        let settings = Record::Settings(SensorSettings {
+            pd_res: 5,
+            led_current: 0x2f,
+        });
+        let hrm = (0..100).map(|i| i * 100 - 5000).collect::<Vec<i16>>();
+        let accel = (0..70).map(|i| [i, -i, 16384]).collect::<Vec<_>>();
+        let records = [(0, info), (3, settings)]
+            .into_iter()
+            .chain(Record::hrm_samples(&hrm).map(|r| (10, r)))
+            .chain(Record::accel_samples(&accel).map(|r| (12, r)))
+            .collect::<Vec<_>>();
+        let recording = encode(records.iter().cloned());
+        assert_eq!(
+            recording.len(),
+            records.iter().map(|(_, r)| r.record_len()).sum::<usize>()
+        );
+
+        let mut decoded = Records::new(&recording);
+        assert_eq!(decoded.by_ref().collect::<Vec<_>>(), records);
+        assert!(decoded.remaining().is_empty());
+    }
+
+    #[test]
+    fn test_truncated_and_unknown() {
+        let mut recording = encode(Record::hrm_samples(&[1, 2, 3]).map(|r| (5, r)));
+        // A record of a future kind
+        recording.extend_from_slice(bytemuck::bytes_of(&RecordHeader {
+            magic: RECORDING_MAGIC,
+            kind: 200,
+            len: 1,
+            timestamp_ms: 6,
+        }));
+        recording.push(0);
+        recording.extend(encode(Record::hrm_samples(&[4]).map(|r| (7, r))));
+
+        let decoded = Records::new(&recording).collect::<Vec<_>>();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(
+            decoded[1],
+            (7, Record::HrmSamples([4].into_iter().collect()))
+        );
+
+        let truncated = &recording[..recording.len() - 1];
+        let mut records = Records::new(truncated);
+        assert_eq!(records.by_ref().count(), 1);
+        assert_eq!(records.remaining().len(), 8 + 2 - 1);
+    }
+
+    #[test]
+    fn test_large_records() {
+        // Larger than any known kind
+        let mut recording = encode(Record::hrm_samples(&[1]).map(|r| (5, r)));
+        recording.extend_from_slice(bytemuck::bytes_of(&RecordHeader {
+            magic: RECORDING_MAGIC,
+            kind: 200,
+            len: 1000,
+            timestamp_ms: 6,
+        }));
+        recording.extend_from_slice(&[RECORDING_MAGIC; 1000]);
+        recording.extend(encode(Record::hrm_samples(&[2]).map(|r| (7, r))));
+        let decoded = Records::new(&recording).map(|(t, _)| t).collect::<Vec<_>>();
+        assert_eq!(decoded, [5, 7]);
+
+        // A known kind is limited to what it can hold
+        let too_long = RecordHeader {
+            magic: RECORDING_MAGIC,
+            kind: RecordKind::HrmSamples as u8,
+            len: (MAX_HRM_SAMPLES_PER_RECORD * 2 + 2) as u16,
+            timestamp_ms: 8,
+        };
+        assert!(!too_long.is_valid());
+        recording.extend_from_slice(bytemuck::bytes_of(&too_long));
+        recording.extend_from_slice(&[0; MAX_HRM_SAMPLES_PER_RECORD * 2 + 2]);
+        let mut records = Records::new(&recording);
+        assert_eq!(records.by_ref().count(), 2);
+        assert_eq!(
+            records.remaining().len(),
+            8 + MAX_HRM_SAMPLES_PER_RECORD * 2 + 2
+        );
+    }
+}
@@ -7,6 +7,7 @@ pub mod display;
 pub mod gps;
 pub mod hrm;
 pub mod lpm013m1126c;
+pub mod shared;
 pub mod touch;
 
 pub use num_enum;
@@ -0,0 +1,120 @@
+//! A resource that the foreground (through `Context`) and background tasks take turns using.
+//!
+//! The foreground always gets it: `take` asks a background task that holds it to give it back and
+//! waits until it did. Background tasks only get it while nobody else uses it (`try_take`) and
+//! should stop as soon as `wanted` resolves.
+
+use core::cell::Cell;
+use core::ops::{Deref, DerefMut};
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+
+pub struct Shared<T> {
+    value: Cell<Option<T>>,
+    wanted: Signal<CriticalSectionRawMutex, ()>,
+    returned: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<T> Shared<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value: Cell::new(Some(value)),
+            wanted: Signal::new(),
+            returned: Signal::new(),
+        }
+    }
+
+    /// Wait until the resource is free, asking a background task that uses it to return it.
+    pub async fn take(&self) -> Lease<'_, T> {
+        loop {
+            if let Some(value) = self.value.take() {
+                return Lease {
+                    shared: self,
+                    value: Some(value),
+                };
+            }
+            self.wanted.signal(());
+            self.returned.wait().await;
+        }
+    }
+
+    /// For background tasks: `None` while the resource is in use.
+    pub fn try_take(&self) -> Option<Lease<'_, T>> {
+        let value = self.value.take()?;
+        // Only requests made while this lease is held count.
+        self.wanted.reset();
+        Some(Lease {
+            shared: self,
+            value: Some(value),
+        })
+    }
+
+    /// Resolves when `take` waits for the resource.
+    pub async fn wanted(&self) {
+        self.wanted.wait().await
+    }
+}
+
+/// The resource is returned when this is dropped.
+pub struct Lease<'a, T> {
+    shared: &'a Shared<T>,
+    value: Option<T>,
+}
+
+impl<T> Deref for Lease<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for Lease<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for Lease<'_, T> {
+    fn drop(&mut self) {
+        self.shared.value.set(self.value.take());
+        self.shared.returned.signal(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embassy_futures::{
+        block_on,
+        select::{select, Either},
+    };
+
+    #[test]
+    fn test_try_take() {
+        let shared = Shared::new(1);
+        let mut lease = shared.try_take().unwrap();
+        *lease += 1;
+        assert!(shared.try_take().is_none());
+        drop(lease);
+        assert_eq!(*shared.try_take().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_take_from_background() {
+        let shared = Shared::new(());
+        block_on(async {
+            let background = async {
+                let _lease = shared.try_take().unwrap();
+                shared.wanted().await;
+            };
+            // The background gets the resource first and gives it up once it is wanted.
+            match select(background, shared.take()).await {
+                Either::First(()) => {}
+                Either::Second(_) => panic!("taken while in use"),
+            }
+            assert!(shared.try_take().is_some());
+            let _lease = shared.take().await;
+        });
+    }
+}
@@ -1,5 +1,9 @@
 pub use drivers_shared::accel::*;
 
+use std::time::Instant;
+
+use crate::sensor_replay;
+
 pub struct AccelRessources {}
 
 impl AccelRessources {
@@ -7,6 +11,8 @@ impl AccelRessources {
         Accel {
             ressources: self,
             config,
+            start: Instant::now(),
+            num_read: 0,
         }
     }
 }
@@ -15,14 +21,39 @@ impl AccelRessources {
 pub struct Accel<'a> {
     ressources: &'a mut AccelRessources,
     config: Config,
+    start: Instant,
+    /// Samples returned by `read_buffer` so far
+    num_read: u64,
 }
 
 impl<'a> Accel<'a> {
+    /// Replayed (see `sensor_replay`), or at rest.
+    fn reading_at(index: u64) -> Reading {
+        let [x, y, z] = sensor_replay::accel_sample(index).unwrap_or([0; 3]);
+        Reading { x, y, z }
+    }
+
+    fn num_available(&self) -> u64 {
+        (self.start.elapsed().as_secs_f32() * 1000.0 / sensor_replay::accel_ms_per_sample()) as u64
+    }
+
     pub async fn reading_hf(&mut self) -> Reading {
-        Reading { x: 0, y: 0, z: 0 }
+        Self::reading_at(self.num_available())
     }
 
     pub async fn reading_nf(&mut self) -> Reading {
-        Reading { x: 0, y: 0, z: 0 }
+        Self::reading_at(self.num_available())
+    }
+
+    /// Like the buffer of the real sensor: All samples (at `sensor_replay::accel_ms_per_sample`)
+    /// since the last call, as many as fit into `out`.
+    pub async fn read_buffer<'b>(&mut self, out: &'b mut [Reading]) -> &'b mut [Reading] {
+        let available = self.num_available().saturating_sub(self.num_read);
+        let num = (available as usize).min(out.len());
+        for r in &mut out[..num] {
+            *r = Self::reading_at(self.num_read);
+            self.num_read += 1;
+        }
+        &mut out[..num]
     }
 }
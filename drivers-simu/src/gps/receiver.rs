@@ -0,0 +1,402 @@
+//! Simulation of the gps chip on the other side of the uart: It produces the same byte stream that
+//! the real receiver would send and understands (a subset of) the configuration commands.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    convert::Infallible,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use drivers_shared::gps::*;
+
+use super::replay::Replay;
+
+/// Bytes per second at 9600 baud (8N1)
+const UART_BYTES_PER_S: usize = 960;
+
+pub enum SimulatedReceiver {
+    /// Replay raw uart data captured from a real receiver (see env var `GPS_UART_LOG`), either
+    /// plain or as recorded by the gps log app.
+    Log(LogReplay),
+    /// Synthesize messages according to the current receiver configuration.
+    Generator(Generator),
+}
+
+/// Bytes sent to the receiver that it has not processed, yet.
+type Commands = Rc<RefCell<Vec<u8>>>;
+
+impl SimulatedReceiver {
+    /// Returns both ends of the uart: The receiver, which is read from, and what is written to.
+    pub fn power_on() -> (Self, UartTx) {
+        match LogReplay::from_env() {
+            Ok(Some(l)) => return (SimulatedReceiver::Log(l), UartTx { commands: None }),
+            Ok(None) => {}
+            Err(e) => eprintln!("Error reading gps uart log: {}", e),
+        }
+        let commands = Commands::default();
+        let generator = Generator::new(commands.clone());
+        let tx = UartTx {
+            commands: Some(commands),
+        };
+        (SimulatedReceiver::Generator(generator), tx)
+    }
+}
+
+impl embedded_io_async::ErrorType for SimulatedReceiver {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for SimulatedReceiver {
+    /// Bytes sent by the receiver. Cancel safe.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        Ok(match self {
+            SimulatedReceiver::Log(l) => l.read(buf).await,
+            SimulatedReceiver::Generator(g) => g.read(buf).await,
+        })
+    }
+}
+
+/// Sends bytes to the receiver as fast as the real uart does. Commands are ignored when replaying
+/// a log.
+pub struct UartTx {
+    commands: Option<Commands>,
+}
+
+impl embedded_io_async::ErrorType for UartTx {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Write for UartTx {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        let duration = Duration::from_secs_f64(buf.len() as f64 / UART_BYTES_PER_S as f64);
+        smol::Timer::after(duration).await;
+        if let Some(commands) = &self.commands {
+            commands.borrow_mut().extend_from_slice(buf);
+        }
+        Ok(buf.len())
+    }
+}
+
+pub struct LogReplay {
+    /// Uart data and the time (relative to the start of the replay) at which it arrives.
+    chunks: VecDeque<(Duration, Vec<u8>)>,
+    start: Instant,
+}
+
+impl LogReplay {
+    fn from_env() -> Result<Option<Self>, std::io::Error> {
+        let Ok(file_name) = std::env::var("GPS_UART_LOG") else {
+            return Ok(None);
+        };
+        let data = std::fs::read(file_name)?;
+
+        let chunks = if data.starts_with(&RAW_LOG_MAGIC) {
+            // Raw log recorded on the watch: Replay with the original timing.
+            let mut records = RawLogRecords::new(&data);
+            let mut first_timestamp_ms = None;
+            let chunks = records
+                .by_ref()
+                .map(|(timestamp_ms, d)| {
+                    let first = *first_timestamp_ms.get_or_insert(timestamp_ms);
+                    let t = Duration::from_millis(timestamp_ms.wrapping_sub(first) as u64);
+                    (t, d.to_vec())
+                })
+                .collect();
+            if !records.remaining().is_empty() {
+                eprintln!(
+                    "Ignoring {} bytes of invalid gps log data",
+                    records.remaining().len()
+                );
+            }
+            chunks
+        } else {
+            // Plain uart data: Deliver it at the speed of the uart in chunks of 100ms.
+            data.chunks(UART_BYTES_PER_S / 10)
+                .enumerate()
+                .map(|(i, d)| (Duration::from_millis(100 * i as u64), d.to_vec()))
+                .collect()
+        };
+
+        Ok(Some(LogReplay {
+            chunks,
+            start: Instant::now(),
+        }))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> usize {
+        let Some((t, _)) = self.chunks.front() else {
+            println!("End of gps uart log");
+            return std::future::pending().await;
+        };
+        smol::Timer::at(self.start + *t).await;
+
+        let chunk = &mut self.chunks.front_mut().unwrap().1;
+        let n = buf.len().min(chunk.len());
+        buf[..n].copy_from_slice(&chunk[..n]);
+        if n == chunk.len() {
+            self.chunks.pop_front();
+        } else {
+            chunk.drain(..n);
+        }
+        n
+    }
+}
+
+pub struct Generator {
+    powered_on: Instant,
+    epoch: u64,
+    /// Advanced by the current interval, so that a new rate only applies from the next epoch on.
+    next_epoch: Instant,
+    config: CasicMsgConfig,
+    nav_rate: NavRate,
+    satellites: SatelliteConfig,
+    received: Commands,
+    commands: Framer<256>,
+    send_queue: VecDeque<u8>,
+    replay: Option<Replay>,
+}
+
+impl Generator {
+    fn new(received: Commands) -> Self {
+        let replay = match Replay::from_env() {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Error reading replay data: {}", e);
+                None
+            }
+        };
+
+        let powered_on = Instant::now();
+        Generator {
+            powered_on,
+            epoch: 0,
+            next_epoch: powered_on,
+            config: CasicMsgConfig::default(),
+            nav_rate: NavRate::default(),
+            satellites: SatelliteConfig::GPS_ONLY,
+            received,
+            commands: Framer::new(),
+            send_queue: Default::default(),
+            replay,
+        }
+    }
+
+    /// Apply the commands that have been sent to the receiver so far.
+    fn process_commands(&mut self) {
+        let received = std::mem::take(&mut *self.received.borrow_mut());
+        let mut data = received.as_slice();
+        while !data.is_empty() {
+            let n = self.commands.push(data);
+            data = &data[n..];
+
+            let config = &mut self.config;
+            let nav_rate = &mut self.nav_rate;
+            let satellites = &mut self.satellites;
+            while let Some(()) = self.commands.with_next_message(|m| match m {
+                Message::Casic(c) if c.id == CFG_MSG => {
+                    let p: CfgMsgPayload = bytemuck::pod_read_unaligned(c.payload);
+                    match p.msg_id {
+                        NAV_TIME_UTC => config.nav_time = p.rate,
+                        NAV_PV => config.nav_pv = p.rate,
+                        NAV_GPS_INFO => config.nav_gps_info = p.rate,
+                        NAV_BDS_INFO => config.nav_bds_info = p.rate,
+                        NAV_GLN_INFO => config.nav_gln_info = p.rate,
+                        _ => {}
+                    }
+                }
+                Message::Casic(c) if c.id == CFG_RATE => {
+                    let p: CfgRatePayload = bytemuck::pod_read_unaligned(c.payload);
+                    *nav_rate = [NavRate::Hz1, NavRate::Hz2, NavRate::Hz5]
+                        .into_iter()
+                        .find(|r| r.interval_ms() == p.interval_ms)
+                        .unwrap_or_default();
+                }
+                Message::Casic(c) => {
+                    println!("Ignoring gps command {:?}", c.id);
+                }
+                Message::Nmea(s) => {
+                    let s = String::from_utf8_lossy(s);
+                    if let Some(bits) = s
+                        .strip_prefix("$PCAS04,")
+                        .and_then(|s| s.split('*').next())
+                        .and_then(|s| s.parse().ok())
+                    {
+                        *satellites = SatelliteConfig::from_bits(bits);
+                    } else {
+                        print!("Ignoring gps command {}", s);
+                    }
+                }
+            }) {}
+        }
+    }
+
+    fn push_msg<T: bytemuck::Pod>(&mut self, msg_id: CASICMessageIdentifier, msg: &T) {
+        self.push_payload(msg_id, bytemuck::bytes_of(msg));
+    }
+
+    fn push_payload(&mut self, msg_id: CASICMessageIdentifier, payload: &[u8]) {
+        self.send_queue.extend(encode_casic(msg_id, payload));
+    }
+
+    /// Satellites of a made up sky that slowly moves and improves in signal strength over time.
+    fn push_sv_info(&mut self, system: GnssSystem, run_time: u32, sec: u64) {
+        let (msg_id, system_id, offset) = match system {
+            GnssSystem::Gps => (NAV_GPS_INFO, 0, 0),
+            GnssSystem::Bds => (NAV_BDS_INFO, 1, 40),
+            GnssSystem::Glonass => (NAV_GLN_INFO, 2, 80),
+        };
+        let num_view = sec.min(10) as u8;
+        let num_fix = sec.saturating_sub(1).min(7) as u8;
+        let header = NavGpsInfo {
+            run_time,
+            num_view_sv: num_view,
+            num_fix_sv: num_fix,
+            system: system_id,
+            _reserved: 0,
+        };
+        let mut payload = bytemuck::bytes_of(&header).to_vec();
+        for i in 0..num_view as u64 {
+            let sv = SvInfo {
+                channel: i as u8,
+                sv_id: (i * 3 + 1) as u8,
+                flags: (i < num_fix as u64) as u8,
+                quality: 7,
+                cn0: (15 + (i * 7 + offset) % 20 + sec.min(60) / 4) as u8,
+                elevation: (10 + (i * 23 + offset) % 80) as i8,
+                azimuth: ((i * 47 + offset + sec / 10) % 360) as i16,
+                pr_res: 0.0,
+            };
+            payload.extend_from_slice(bytemuck::bytes_of(&sv));
+        }
+        self.push_payload(msg_id, &payload);
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.process_commands();
+        if self.send_queue.is_empty() {
+            let interval = Duration::from_millis(self.nav_rate.interval_ms() as _);
+            let next_epoch = self.next_epoch + interval;
+            smol::Timer::at(next_epoch).await;
+            self.next_epoch = next_epoch;
+            // Sent while waiting
+            self.process_commands();
+            self.epoch += 1;
+            self.generate_epoch();
+        }
+
+        let n = buf.len().min(self.send_queue.len());
+        for (o, i) in buf.iter_mut().zip(self.send_queue.drain(..n)) {
+            *o = i;
+        }
+        n
+    }
+
+    fn generate_epoch(&mut self) {
+        let elapsed = self.powered_on.elapsed();
+        let sec = elapsed.as_secs();
+        let epoch = self.epoch;
+        let time_to_send = |period: u16| period != 0 && (epoch % period as u64) == 0;
+
+        let sat_in_fix = sec.saturating_sub(1).min(7) as u8;
+        let run_time = elapsed.as_millis() as u32;
+        if time_to_send(self.config.nav_pv) {
+            if let Some(replay) = &mut self.replay {
+                // No message at all while the fix is lost
+                if let Some(s) = replay.sample(elapsed.as_secs_f64()) {
+                    let [north, east, up] = s.velocity_m_s;
+                    let speed_2d = north.hypot(east);
+                    let msg = NavPv {
+                        run_time: (s.t_s * 1000.0) as u32,
+                        pos_valid: (sat_in_fix > 0) as u8 * NavPv::POS_VALID_FIX_3D,
+                        vel_valid: (sat_in_fix > 0) as u8 * 7,
+                        system: 1,
+                        num_sv: sat_in_fix,
+                        num_sv_gps: sat_in_fix,
+                        num_sv_bds: 0,
+                        num_sv_gln: 0,
+                        _reserved: 0,
+                        location_dop: 1.0,
+                        longitude: s.lon,
+                        latitude: s.lat,
+                        height_m: s.height_m,
+                        height_anomaly: s.height_anomaly,
+                        horizontal_variance: s.horizontal_variance,
+                        vertical_variance: s.vertical_variance,
+                        north_velocity_m_s: north,
+                        east_velocity_m_s: east,
+                        heavenly_velocity_m_s: up,
+                        speed_3d: speed_2d.hypot(up),
+                        speed_2d,
+                        heading: east.atan2(north).to_degrees().rem_euclid(360.0),
+                        variance_speed_2d: s.variance_speed_2d,
+                        variance_heading: 1.0,
+                    };
+                    self.push_msg(NAV_PV, &msg);
+                }
+            } else {
+                let msg = NavPv {
+                    run_time,
+                    pos_valid: (sat_in_fix > 0) as u8 * NavPv::POS_VALID_FIX_3D,
+                    vel_valid: (sat_in_fix > 0) as u8 * 7,
+                    system: 1,
+                    num_sv: sat_in_fix,
+                    num_sv_gps: sat_in_fix,
+                    num_sv_bds: 0,
+                    num_sv_gln: 0,
+                    _reserved: 0,
+                    location_dop: 1.0,
+                    longitude: sec as f64 / 1000.0,
+                    latitude: sec as f64 / 1000.0,
+                    height_m: 0.0,
+                    height_anomaly: 0.0,
+                    horizontal_variance: 1.0,
+                    vertical_variance: 1.0,
+                    north_velocity_m_s: 1.0,
+                    east_velocity_m_s: 1.0,
+                    heavenly_velocity_m_s: 0.0,
+                    speed_3d: 0.0,
+                    speed_2d: 0.0,
+                    heading: 0.0,
+                    variance_speed_2d: 1.0,
+                    variance_heading: 1.0,
+                };
+                self.push_msg(NAV_PV, &msg);
+            }
+        }
+        if time_to_send(self.config.nav_time) {
+            // The true time is the one of the host. The clock of the simulated watch drifts away
+            // from it.
+            use chrono::{Datelike, Timelike};
+            let now = chrono::Utc::now();
+            let valid = (sat_in_fix > 0) as u8;
+            let msg = NavTimeUTC {
+                run_time,
+                t_acc: 0.0,
+                mse: 1.0,
+                ms: now.timestamp_subsec_millis() as u16,
+                year: now.year() as u16,
+                month: now.month() as u8,
+                day: now.day() as u8,
+                hour: now.hour() as u8,
+                min: now.minute() as u8,
+                sec: now.second() as u8,
+                valid,
+                time_src: 0,
+                date_valid: valid,
+            };
+            self.push_msg(NAV_TIME_UTC, &msg);
+        }
+        let satellites = self.satellites;
+        if satellites.gps && time_to_send(self.config.nav_gps_info) {
+            self.push_sv_info(GnssSystem::Gps, run_time, sec);
+        }
+        if satellites.bds && time_to_send(self.config.nav_bds_info) {
+            self.push_sv_info(GnssSystem::Bds, run_time, sec);
+        }
+        if satellites.glonass && time_to_send(self.config.nav_gln_info) {
+            self.push_sv_info(GnssSystem::Glonass, run_time, sec);
+        }
+    }
+}
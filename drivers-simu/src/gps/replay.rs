@@ -0,0 +1,518 @@
+//! Replay of recorded tracks by the simulated receiver.
+//!
+//! The track is read from the file in `REPLAY_NAVIGATION_DATA`, which can either be a binary dump
+//! of `NavigationData` (as written by the track app), a gpx file or a csv file with the columns
+//! `time` (seconds), `lat`, `lon` and (optionally) `ele`. The following env vars modify the replay:
+//!
+//! - `GPS_REPLAY_TIME_SCALE`: Play the track faster (> 1) or slower (< 1) than recorded.
+//! - `GPS_REPLAY_POS_NOISE_M`: Standard deviation of noise added to the horizontal position.
+//! - `GPS_REPLAY_VEL_NOISE_M_S`: Standard deviation of noise added to the velocity.
+//! - `GPS_REPLAY_DROPOUTS_PER_MIN`: Average number of times per minute that the fix is lost.
+//! - `GPS_REPLAY_DROPOUT_S`: Duration of a lost fix.
+//! - `GPS_REPLAY_SEED`: Seed for the noise and dropouts.
+
+use std::{error::Error, path::Path};
+
+use drivers_shared::gps::NavigationData;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// Variances that are reported if neither the track nor the noise model provide any.
+const MIN_POS_VARIANCE: f32 = 1.0;
+const MIN_VEL_VARIANCE: f32 = 0.1;
+
+#[derive(Clone, Debug)]
+struct TrackPoint {
+    t_s: f64,
+    lon: f64,
+    lat: f64,
+    height_m: f32,
+    height_anomaly: f32,
+    /// north, east, up
+    velocity_m_s: Option<[f32; 3]>,
+    horizontal_variance: Option<f32>,
+    vertical_variance: Option<f32>,
+    variance_speed_2d: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Time since the start of the replay as seen by the receiver, i.e., already scaled.
+    pub t_s: f64,
+    pub lon: f64,
+    pub lat: f64,
+    pub height_m: f32,
+    pub height_anomaly: f32,
+    /// north, east, up
+    pub velocity_m_s: [f32; 3],
+    pub horizontal_variance: f32,
+    pub vertical_variance: f32,
+    pub variance_speed_2d: f32,
+}
+
+struct Options {
+    time_scale: f64,
+    pos_noise_m: f32,
+    vel_noise_m_s: f32,
+    dropouts_per_min: f32,
+    dropout_s: f64,
+    seed: u64,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value for {}: {}", name, v);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+impl Options {
+    fn from_env() -> Self {
+        Options {
+            time_scale: env_or("GPS_REPLAY_TIME_SCALE", 1.0),
+            pos_noise_m: env_or("GPS_REPLAY_POS_NOISE_M", 0.0),
+            vel_noise_m_s: env_or("GPS_REPLAY_VEL_NOISE_M_S", 0.0),
+            dropouts_per_min: env_or("GPS_REPLAY_DROPOUTS_PER_MIN", 0.0),
+            dropout_s: env_or("GPS_REPLAY_DROPOUT_S", 5.0),
+            seed: env_or("GPS_REPLAY_SEED", 0x2545_f491_4f6c_dd1d),
+        }
+    }
+}
+
+/// Xorshift generator. Good enough for simulating noise and does not require another dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// Uniform in (0, 1]
+    fn uniform(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal distribution (Box-Muller)
+    fn normal(&mut self) -> f64 {
+        let u1 = self.uniform();
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+pub struct Replay {
+    points: Vec<TrackPoint>,
+    options: Options,
+    rng: Rng,
+    dropout_until_s: f64,
+    last_sample_s: f64,
+}
+
+impl Replay {
+    /// Load the track specified via env vars, if any.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn Error>> {
+        let Ok(file_name) = std::env::var("REPLAY_NAVIGATION_DATA") else {
+            return Ok(None);
+        };
+        let path = Path::new(&file_name);
+        let points = match path.extension().and_then(|e| e.to_str()) {
+            Some("gpx") => read_gpx(std::fs::File::open(path)?)?,
+            Some("csv") => read_csv(std::fs::File::open(path)?)?,
+            _ => read_navigation_data(path)?,
+        };
+
+        let replay = Self::new(points, Options::from_env())?;
+        println!(
+            "Replaying {} gps points ({:.0}s) from {}",
+            replay.points.len(),
+            replay.points.last().unwrap().t_s,
+            file_name
+        );
+        Ok(Some(replay))
+    }
+
+    fn new(mut points: Vec<TrackPoint>, options: Options) -> Result<Self, Box<dyn Error>> {
+        if points.is_empty() {
+            return Err("Track does not contain any points".into());
+        }
+        points.sort_by(|l, r| l.t_s.total_cmp(&r.t_s));
+        let t0 = points[0].t_s;
+        for p in &mut points {
+            p.t_s -= t0;
+        }
+
+        Ok(Replay {
+            points,
+            rng: Rng(options.seed.max(1)),
+            options,
+            dropout_until_s: 0.0,
+            last_sample_s: 0.0,
+        })
+    }
+
+    /// The position at `elapsed_s` (wall clock time since starting the replay). `None` if the
+    /// fix is (simulated to be) lost.
+    pub fn sample(&mut self, elapsed_s: f64) -> Option<Sample> {
+        let t_s = elapsed_s * self.options.time_scale;
+        let dt = t_s - self.last_sample_s;
+        self.last_sample_s = t_s;
+
+        let dropout_probability = self.options.dropouts_per_min as f64 * dt / 60.0;
+        if t_s >= self.dropout_until_s && self.rng.uniform() < dropout_probability {
+            self.dropout_until_s = t_s + self.options.dropout_s;
+        }
+        if t_s < self.dropout_until_s {
+            return None;
+        }
+
+        let mut s = self.interpolate(t_s);
+
+        let pos_noise = self.options.pos_noise_m as f64;
+        let north_m = self.rng.normal() * pos_noise;
+        let east_m = self.rng.normal() * pos_noise;
+        s.lat += (north_m / EARTH_RADIUS_M).to_degrees();
+        s.lon += (east_m / (EARTH_RADIUS_M * s.lat.to_radians().cos())).to_degrees();
+
+        let vel_noise = self.options.vel_noise_m_s as f64;
+        for v in &mut s.velocity_m_s[..2] {
+            *v += (self.rng.normal() * vel_noise) as f32;
+        }
+
+        let pos_variance = self.options.pos_noise_m.powi(2);
+        let vel_variance = self.options.vel_noise_m_s.powi(2);
+        s.horizontal_variance = s.horizontal_variance.max(pos_variance);
+        s.vertical_variance = s.vertical_variance.max(pos_variance);
+        s.variance_speed_2d = s.variance_speed_2d.max(vel_variance);
+
+        Some(s)
+    }
+
+    fn interpolate(&self, t_s: f64) -> Sample {
+        let i = self.points.partition_point(|p| p.t_s <= t_s);
+        let (a, b) = match i {
+            0 => (&self.points[0], &self.points[0]),
+            i if i == self.points.len() => {
+                // Stay at the end of the track
+                let last = &self.points[i - 1];
+                let mut s = self.interpolate_between(last, last, 0.0, t_s);
+                s.velocity_m_s = [0.0; 3];
+                return s;
+            }
+            i => (&self.points[i - 1], &self.points[i]),
+        };
+        let alpha = if b.t_s > a.t_s {
+            (t_s - a.t_s) / (b.t_s - a.t_s)
+        } else {
+            0.0
+        };
+        self.interpolate_between(a, b, alpha, t_s)
+    }
+
+    fn interpolate_between(&self, a: &TrackPoint, b: &TrackPoint, alpha: f64, t_s: f64) -> Sample {
+        let lerp = |l: f64, r: f64| l + (r - l) * alpha;
+        let lerpf = |l: f32, r: f32| lerp(l as f64, r as f64) as f32;
+        let lerpo = |l: Option<f32>, r: Option<f32>, default: f32| {
+            lerpf(l.unwrap_or(default), r.unwrap_or(default))
+        };
+
+        let lat = lerp(a.lat, b.lat);
+        let velocity_m_s = match (a.velocity_m_s, b.velocity_m_s) {
+            (Some(va), Some(vb)) => core::array::from_fn(|i| lerpf(va[i], vb[i])),
+            _ => derive_velocity(a, b),
+        };
+
+        Sample {
+            t_s,
+            lon: lerp(a.lon, b.lon),
+            lat,
+            height_m: lerpf(a.height_m, b.height_m),
+            height_anomaly: lerpf(a.height_anomaly, b.height_anomaly),
+            velocity_m_s,
+            horizontal_variance: lerpo(
+                a.horizontal_variance,
+                b.horizontal_variance,
+                MIN_POS_VARIANCE,
+            ),
+            vertical_variance: lerpo(a.vertical_variance, b.vertical_variance, MIN_POS_VARIANCE),
+            variance_speed_2d: lerpo(a.variance_speed_2d, b.variance_speed_2d, MIN_VEL_VARIANCE),
+        }
+    }
+}
+
+/// Velocity (north, east, up) required to get from `a` to `b`.
+fn derive_velocity(a: &TrackPoint, b: &TrackPoint) -> [f32; 3] {
+    let dt = b.t_s - a.t_s;
+    if dt <= 0.0 {
+        return [0.0; 3];
+    }
+    let mean_lat = ((a.lat + b.lat) * 0.5).to_radians();
+    let north_m = (b.lat - a.lat).to_radians() * EARTH_RADIUS_M;
+    let east_m = (b.lon - a.lon).to_radians() * EARTH_RADIUS_M * mean_lat.cos();
+    let up_m = (b.height_m - a.height_m) as f64;
+    [
+        (north_m / dt) as f32,
+        (east_m / dt) as f32,
+        (up_m / dt) as f32,
+    ]
+}
+
+fn read_navigation_data(path: &Path) -> Result<Vec<TrackPoint>, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let entries: Vec<NavigationData> = data
+        .chunks_exact(std::mem::size_of::<NavigationData>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect();
+
+    Ok(entries
+        .into_iter()
+        .map(|d| TrackPoint {
+            t_s: d.run_time as f64 / 1000.0,
+            lon: d.longitude,
+            lat: d.latitude,
+            height_m: 0.0,
+            height_anomaly: d.height_anomaly,
+            velocity_m_s: Some([
+                d.north_velocity_m_s,
+                d.east_velocity_m_s,
+                d.heavenly_velocity_m_s,
+            ]),
+            horizontal_variance: Some(d.horizontal_variance),
+            vertical_variance: Some(d.vertical_variance),
+            variance_speed_2d: Some(d.variance_speed_2d),
+        })
+        .collect())
+}
+
+/// Times of the points in seconds since the first one, from their timestamps (in any unit of
+/// seconds). Points of tracks without timestamps are assumed to be one second apart. Tracks in
+/// which only some points have a timestamp are rejected, there is no telling where the others
+/// belong.
+fn point_times(timestamps_s: &[Option<f64>]) -> Result<Vec<f64>, Box<dyn Error>> {
+    if timestamps_s.iter().all(Option::is_none) {
+        return Ok((0..timestamps_s.len()).map(|i| i as f64).collect());
+    }
+    let Some(timestamps_s) = timestamps_s.iter().copied().collect::<Option<Vec<f64>>>() else {
+        return Err("Track contains points with and without timestamps".into());
+    };
+    let start = timestamps_s[0];
+    Ok(timestamps_s.into_iter().map(|t| t - start).collect())
+}
+
+fn read_gpx(reader: impl std::io::Read) -> Result<Vec<TrackPoint>, Box<dyn Error>> {
+    let gpx = gpx::read(std::io::BufReader::new(reader))?;
+
+    let waypoints = gpx
+        .tracks
+        .iter()
+        .flat_map(|t| &t.segments)
+        .flat_map(|s| &s.points)
+        .collect::<Vec<_>>();
+    let timestamps_s = waypoints
+        .iter()
+        .map(|p| {
+            let t = time::OffsetDateTime::from(p.time.clone()?);
+            Some(t.unix_timestamp_nanos() as f64 * 1e-9)
+        })
+        .collect::<Vec<_>>();
+
+    let mut points = Vec::new();
+    for (p, t_s) in waypoints.into_iter().zip(point_times(&timestamps_s)?) {
+        let pos = p.point();
+        points.push(TrackPoint {
+            t_s,
+            lon: pos.x(),
+            lat: pos.y(),
+            height_m: p.elevation.unwrap_or(0.0) as f32,
+            height_anomaly: 0.0,
+            velocity_m_s: None,
+            horizontal_variance: None,
+            vertical_variance: None,
+            variance_speed_2d: None,
+        });
+    }
+    Ok(points)
+}
+
+fn read_csv(reader: impl std::io::Read) -> Result<Vec<TrackPoint>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.trim() == name);
+    let (Some(time), Some(lat), Some(lon)) = (column("time"), column("lat"), column("lon")) else {
+        return Err("csv file requires the columns time, lat and lon".into());
+    };
+    let ele = column("ele");
+
+    let mut points = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |i: usize| -> Result<f64, Box<dyn Error>> {
+            Ok(record.get(i).ok_or("Missing field")?.trim().parse()?)
+        };
+        points.push(TrackPoint {
+            t_s: field(time)?,
+            lon: field(lon)?,
+            lat: field(lat)?,
+            height_m: ele.map(&field).transpose()?.unwrap_or(0.0) as f32,
+            height_anomaly: 0.0,
+            velocity_m_s: None,
+            horizontal_variance: None,
+            vertical_variance: None,
+            variance_speed_2d: None,
+        });
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            time_scale: 1.0,
+            pos_noise_m: 0.0,
+            vel_noise_m_s: 0.0,
+            dropouts_per_min: 0.0,
+            dropout_s: 5.0,
+            seed: 1,
+        }
+    }
+
+    fn point(t_s: f64, lat: f64, lon: f64, height_m: f32) -> TrackPoint {
+        TrackPoint {
+            t_s,
+            lon,
+            lat,
+            height_m,
+            height_anomaly: 0.0,
+            velocity_m_s: None,
+            horizontal_variance: None,
+            vertical_variance: None,
+            variance_speed_2d: None,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not {} +- {}",
+            actual,
+            expected,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn test_interpolate() {
+        // 0.001 degrees north in 10s, starting at 100s
+        let points = vec![point(110.0, 0.001, 0.0, 10.0), point(100.0, 0.0, 0.0, 0.0)];
+        let replay = Replay::new(points, options()).unwrap();
+        let north_m_s = 0.001f64.to_radians() * EARTH_RADIUS_M / 10.0;
+
+        let s = replay.interpolate(2.5);
+        assert_close(s.lat, 0.00025, 1e-12);
+        assert_close(s.height_m as f64, 2.5, 1e-6);
+        assert_close(s.velocity_m_s[0] as f64, north_m_s, 1e-3);
+        assert_close(s.velocity_m_s[1] as f64, 0.0, 1e-6);
+        assert_close(s.velocity_m_s[2] as f64, 1.0, 1e-6);
+        assert_eq!(s.horizontal_variance, MIN_POS_VARIANCE);
+
+        // Before the start there is no movement (yet)
+        let s = replay.interpolate(-1.0);
+        assert_eq!((s.lat, s.velocity_m_s), (0.0, [0.0; 3]));
+
+        // After the end, the position stays
+        let s = replay.interpolate(20.0);
+        assert_eq!((s.lat, s.t_s, s.velocity_m_s), (0.001, 20.0, [0.0; 3]));
+    }
+
+    #[test]
+    fn test_interpolate_between() {
+        let replay = Replay::new(vec![point(0.0, 0.0, 0.0, 0.0)], options()).unwrap();
+        let mut a = point(0.0, 10.0, 20.0, 0.0);
+        let mut b = point(2.0, 11.0, 22.0, 4.0);
+        a.velocity_m_s = Some([1.0, 2.0, 0.0]);
+        b.velocity_m_s = Some([3.0, 4.0, 1.0]);
+        a.horizontal_variance = Some(2.0);
+        b.horizontal_variance = Some(4.0);
+
+        let s = replay.interpolate_between(&a, &b, 0.25, 0.5);
+        assert_eq!(s.t_s, 0.5);
+        assert_close(s.lat, 10.25, 1e-9);
+        assert_close(s.lon, 20.5, 1e-9);
+        assert_eq!(s.height_m, 1.0);
+        // Recorded velocities are interpolated, not derived from the positions
+        assert_eq!(s.velocity_m_s, [1.5, 2.5, 0.25]);
+        assert_eq!(s.horizontal_variance, 2.5);
+        assert_eq!(s.vertical_variance, MIN_POS_VARIANCE);
+    }
+
+    #[test]
+    fn test_derive_velocity() {
+        // At 60 degrees, a degree of longitude is half as long as one of latitude.
+        let a = point(0.0, 60.0, 10.0, 100.0);
+        let b = point(100.0, 60.0, 10.01, 90.0);
+        let [north, east, up] = derive_velocity(&a, &b);
+        let east_expected = 0.01f64.to_radians() * EARTH_RADIUS_M * 0.5 / 100.0;
+        assert_close(north as f64, 0.0, 1e-6);
+        assert_close(east as f64, east_expected, 1e-3);
+        assert_close(up as f64, -0.1, 1e-6);
+
+        // Two points at the same time
+        assert_eq!(derive_velocity(&a, &a), [0.0; 3]);
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let csv = "lat, lon, time, ele\n48.5,11.25,3.5,520\n48.6,11.5,4.5,521.5\n";
+        let points = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(points.len(), 2);
+        let p = &points[1];
+        assert_eq!((p.t_s, p.lat, p.lon, p.height_m), (4.5, 48.6, 11.5, 521.5));
+
+        // Elevation is optional
+        let points = read_csv("time,lat,lon\n0,1,2\n".as_bytes()).unwrap();
+        assert_eq!(points[0].height_m, 0.0);
+
+        assert!(read_csv("time,lat\n0,1\n".as_bytes()).is_err());
+        assert!(read_csv("time,lat,lon\n0,north,2\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_point_times() {
+        assert_eq!(point_times(&[None, None, None]).unwrap(), [0.0, 1.0, 2.0]);
+        assert_eq!(
+            point_times(&[Some(1000.0), Some(1002.5), Some(1001.0)]).unwrap(),
+            [0.0, 2.5, 1.0]
+        );
+        assert!(point_times(&[Some(1000.0), None, Some(1010.0)]).is_err());
+        assert!(point_times(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_gpx() {
+        let gpx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="48.5" lon="11.25"><ele>520</ele><time>2024-05-01T10:00:00Z</time></trkpt>
+    <trkpt lat="48.6" lon="11.5"><time>2024-05-01T10:00:02.5Z</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+        let points = read_gpx(gpx.as_bytes()).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!((points[0].t_s, points[0].height_m), (0.0, 520.0));
+        assert_close(points[1].t_s, 2.5, 1e-6);
+        assert_eq!((points[1].lat, points[1].lon), (48.6, 11.5));
+
+        let mixed = gpx.replace("<time>2024-05-01T10:00:00Z</time>", "");
+        assert!(read_gpx(mixed.as_bytes()).is_err());
+    }
+}
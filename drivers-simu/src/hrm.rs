@@ -2,22 +2,43 @@ pub struct HrmRessources;
 
 use std::time::Duration;
 
+use once_cell::sync::Lazy;
+
+use crate::sensor_replay;
 pub use drivers_shared::hrm::*;
 
+/// If set (env var `HRM_WEAR_PERIOD_S`), the watch is alternately worn and taken off for this many
+/// seconds, starting with worn.
+static WEAR_PERIOD_S: Lazy<Option<u64>> = Lazy::new(|| {
+    std::env::var("HRM_WEAR_PERIOD_S")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|p| *p > 0)
+});
+
 impl HrmRessources {
     pub async fn on<'a>(&'a mut self, _i2c: &'a crate::TWI) -> Hrm<'a> {
         Hrm {
             _res: self,
-            elapsed_millis: 0,
+            num_samples: 0,
             hrm_res_config: PdResConfig::from_reg(0x57),
+            sample_delay: sensor_replay::hrm_sample_delay().unwrap_or(NOMINAL_SAMPLE_DELAY),
+            wear_detector: WearDetector::default(),
+            wear_change: None,
         }
     }
 }
 
 pub struct Hrm<'a> {
     _res: &'a HrmRessources,
-    elapsed_millis: u64,
+    /// Returned by `wait_event` so far
+    num_samples: u64,
     hrm_res_config: PdResConfig,
+    /// Only reported back (initially the recorded one when replaying), the samples always come at
+    /// `sensor_replay::hrm_ms_per_sample`.
+    sample_delay: u16,
+    wear_detector: WearDetector,
+    wear_change: Option<bool>,
 }
 
 impl<'a> Hrm<'a> {
@@ -30,34 +51,77 @@ impl<'a> Hrm<'a> {
     pub async fn disable(&mut self) {
         println!("Hrm disable");
     }
+    /// Returns batches of 8 samples, either from a recording (see `sensor_replay`) or generated.
     pub async fn wait_event(&mut self) -> (ReadResult, Option<Vec<i16>>) {
         let num_samples = 8;
-        let delay_per_sample_ms = 40;
-        smol::Timer::after(Duration::from_millis(delay_per_sample_ms * 8)).await;
+        let ms_per_sample = sensor_replay::hrm_ms_per_sample();
+        smol::Timer::after(Duration::from_secs_f32(
+            ms_per_sample * num_samples as f32 / 1000.0,
+        ))
+        .await;
+        let elapsed_s = (self.num_samples as f32 * ms_per_sample / 1000.0) as u64;
+        let worn = WEAR_PERIOD_S.map_or(true, |p| (elapsed_s / p) % 2 == 0);
         let mut vals = Vec::new();
+        let mut settings = None;
         for _ in 0..num_samples {
-            let ms = self.elapsed_millis as f32;
-            self.elapsed_millis += delay_per_sample_ms;
+            let ms = self.num_samples as f32 * ms_per_sample;
+            let replayed = sensor_replay::hrm_sample(self.num_samples);
+            self.num_samples += 1;
             let beats_per_ms = 2.1 / 1000.0;
             let beat = ms * beats_per_ms;
             let norm_val = (beat * std::f32::consts::TAU).sin() * 0.2 + (beat * 0.1).sin() * 3.0;
-            let val = ((norm_val * 0.1 + 1.0) * 1024.0) as i16;
+            let val = if !worn {
+                // Nothing reflects the light, even at full led current
+                4095
+            } else if let Some(replayed) = replayed {
+                settings = replayed.settings.or(settings);
+                replayed.value
+            } else {
+                ((norm_val * 0.1 + 1.0) * 1024.0) as i16
+            };
             vals.push(val);
         }
-        (
-            ReadResult {
-                status: 0,
-                irq_status: 0,
-                env_value: [0; 3],
-                pre_value: [0; 2],
-                ps_value: 0,
-                pd_res_value: [self.hrm_res_config.res, 0, 0],
-                current_value: [0; 3],
-            },
-            Some(vals),
-        )
+        let read_result = ReadResult {
+            status: 0,
+            irq_status: 0,
+            env_value: [if worn { 0 } else { 12 }; 3],
+            pre_value: [0; 2],
+            ps_value: if worn { 10 } else { 0 },
+            // As recorded, if known
+            pd_res_value: [settings.map_or(self.hrm_res_config.res, |s| s.pd_res), 0, 0],
+            current_value: [settings.map_or(0, |s| s.led_current), 0, 0],
+        };
+        let dc_level = DcLevel {
+            mean: (vals.iter().map(|v| *v as i32).sum::<i32>() / vals.len() as i32) as i16,
+            led_current_at_limit: !worn,
+        };
+        if let Some(wearing) = self.wear_detector.update(&read_result, Some(dc_level)) {
+            println!("Hrm wearing: {}", wearing);
+            self.wear_change = Some(wearing);
+        }
+        (read_result, Some(vals))
+    }
+
+    /// Whether the watch is worn, as of the last `wait_event`.
+    pub fn wearing(&self) -> bool {
+        self.wear_detector.wearing()
+    }
+
+    /// Returns the new state once after `wait_event` detected that the watch was put on or taken
+    /// off.
+    pub fn wear_changed(&mut self) -> Option<bool> {
+        self.wear_change.take()
     }
     pub async fn update_hrm_res(&mut self, f: impl FnOnce(&mut PdResConfig)) {
         f(&mut self.hrm_res_config);
     }
+
+    pub async fn update_sample_delay(&mut self, f: impl FnOnce(&mut u16)) {
+        f(&mut self.sample_delay);
+    }
+
+    /// Current value of the sample delay register, see `NOMINAL_SAMPLE_DELAY`.
+    pub fn sample_delay(&self) -> u16 {
+        self.sample_delay
+    }
 }
@@ -4,10 +4,12 @@ pub mod button;
 pub mod display;
 pub mod flash;
 pub mod gps;
+mod sensor_replay;
 mod util;
 use std::sync::Arc;
 
 pub use drivers_shared::lpm013m1126c;
+pub use drivers_shared::shared::{Lease, Shared};
 use once_cell::sync::Lazy;
 use smol::{
     channel::{Receiver, Sender},
@@ -59,9 +61,9 @@ pub struct Context {
     pub mag: mag::MagRessources,
     pub touch: touch::TouchRessources,
     pub accel: accel::AccelRessources,
-    pub hrm: hrm::HrmRessources,
+    pub hrm: &'static Shared<hrm::HrmRessources>,
     pub buzzer: buzz::Buzzer,
-    pub twi: TWI,
+    pub twi: &'static TWI,
     pub last_panic_msg: Option<&'static str>,
 }
 
@@ -101,10 +103,13 @@ pub fn run(main: impl Main) -> ! {
         },
         accel: accel::AccelRessources {},
         buzzer: buzz::Buzzer::new(),
-        hrm: hrm::HrmRessources,
-        twi: TWI,
+        // Also used by background tasks next to the foreground
+        hrm: Box::leak(Box::new(Shared::new(hrm::HrmRessources))),
+        twi: &TWI,
         last_panic_msg: None,
     };
+    executor.spawn(gps::gps_task()).detach();
+    executor.spawn(time::clock_sync_task()).detach();
     let _ = smol::block_on(executor.run(main.build(context)));
     panic!("Main should never return");
 }
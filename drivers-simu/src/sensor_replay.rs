@@ -0,0 +1,148 @@
+//! Replay of heart rate and acceleration recordings by the simulated sensors.
+//!
+//! A recording of the hrm app (`/hrm/recN.bin`, see `drivers_shared::hrm::Record`) is taken from
+//! `REPLAY_HRM_RECORDING` and provides the samples of both sensors and the settings of the hrm.
+//! Samples can also be taken from `REPLAY_HRM_SAMPLES` and `REPLAY_ACCEL_SAMPLES` (which take
+//! precedence), as text with one sample per line (hrm) or `x,y,z` per line (accel), optionally
+//! with a csv header (as used by `tools/analyze_hrm`).
+//!
+//! The samples are played from when the sensor is turned on and start over at the end. They come
+//! at the sample rates of the recording, as measured by the watch (the last one if they changed),
+//! and at 25Hz otherwise.
+
+use std::{error::Error, path::Path};
+
+use drivers_shared::hrm::{Record, Records, SensorSettings};
+use once_cell::sync::Lazy;
+
+const NOMINAL_MS_PER_SAMPLE: f32 = 40.0;
+
+#[derive(Copy, Clone)]
+pub struct HrmSample {
+    pub value: i16,
+    /// As recorded, if known
+    pub settings: Option<SensorSettings>,
+}
+
+#[derive(Default)]
+struct Replay {
+    hrm: Option<Vec<HrmSample>>,
+    accel: Option<Vec<[i16; 3]>>,
+    /// From the recording, if known
+    hrm_sample_delay: Option<u16>,
+    hrm_ms_per_sample: Option<f32>,
+    accel_ms_per_sample: Option<f32>,
+}
+
+static REPLAY: Lazy<Replay> = Lazy::new(|| {
+    let mut replay = load("REPLAY_HRM_RECORDING", read_recording).unwrap_or_default();
+    if let Some(rows) = load("REPLAY_HRM_SAMPLES", |p| read_rows(p, 1)) {
+        let samples = rows.into_iter().map(|r| HrmSample {
+            value: r[0],
+            settings: None,
+        });
+        replay.hrm = Some(samples.collect());
+        replay.hrm_sample_delay = None;
+        replay.hrm_ms_per_sample = None;
+    }
+    if let Some(rows) = load("REPLAY_ACCEL_SAMPLES", |p| read_rows(p, 3)) {
+        replay.accel = Some(rows.into_iter().map(|r| [r[0], r[1], r[2]]).collect());
+        replay.accel_ms_per_sample = None;
+    }
+    replay.hrm = replay.hrm.filter(|s| !s.is_empty());
+    replay.accel = replay.accel.filter(|s| !s.is_empty());
+    replay
+});
+
+fn load<T>(env_var: &str, read: impl FnOnce(&Path) -> Result<T, Box<dyn Error>>) -> Option<T> {
+    let file_name = std::env::var(env_var).ok()?;
+    read(Path::new(&file_name))
+        .map_err(|e| eprintln!("Error reading {}: {}", file_name, e))
+        .ok()
+}
+
+fn read_recording(path: &Path) -> Result<Replay, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let mut records = Records::new(&data);
+    let mut settings = None;
+    let mut info = None;
+    let mut hrm = Vec::new();
+    let mut accel = Vec::new();
+    for (_, record) in records.by_ref() {
+        match record {
+            Record::Info(i) => info = Some(i),
+            Record::Settings(s) => settings = Some(s),
+            Record::HrmSamples(s) => {
+                hrm.extend(s.iter().map(|value| HrmSample {
+                    value: *value,
+                    settings,
+                }));
+            }
+            Record::AccelSamples(s) => accel.extend_from_slice(&s),
+        }
+    }
+    if !records.remaining().is_empty() {
+        eprintln!(
+            "Ignoring {} bytes at the end of {}, which are not a valid record",
+            records.remaining().len(),
+            path.display()
+        );
+    }
+    // 0 if not measured (yet)
+    let measured = |ms: f32| Some(ms).filter(|ms| *ms > 0.0);
+    Ok(Replay {
+        hrm: Some(hrm),
+        accel: Some(accel),
+        hrm_sample_delay: info.map(|i| i.hrm_sample_delay),
+        hrm_ms_per_sample: info.and_then(|i| measured(i.hrm_ms_per_sample)),
+        accel_ms_per_sample: info.and_then(|i| measured(i.accel_ms_per_sample)),
+    })
+}
+
+fn read_rows(path: &Path, columns: usize) -> Result<Vec<Vec<i16>>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut rows = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = line
+            .split(',')
+            .map(|v| v.trim().parse::<i16>())
+            .collect::<Result<Vec<_>, _>>();
+        match row {
+            Ok(row) if row.len() == columns => rows.push(row),
+            // csv header
+            Err(_) if i == 0 => {}
+            _ => return Err(format!("line {}: expected {} numbers", i + 1, columns).into()),
+        }
+    }
+    Ok(rows)
+}
+
+/// The hrm sample at `index` (counted from when the sensor was turned on), if replaying.
+pub fn hrm_sample(index: u64) -> Option<HrmSample> {
+    let samples = REPLAY.hrm.as_ref()?;
+    Some(samples[(index % samples.len() as u64) as usize])
+}
+
+/// The acceleration at `index` (counted from when the sensor was turned on), if replaying.
+pub fn accel_sample(index: u64) -> Option<[i16; 3]> {
+    let samples = REPLAY.accel.as_ref()?;
+    Some(samples[(index % samples.len() as u64) as usize])
+}
+
+/// Value of the sample delay register during the recording, if replaying one.
+pub fn hrm_sample_delay() -> Option<u16> {
+    REPLAY.hrm_sample_delay
+}
+
+/// Time between the hrm samples, as recorded if known.
+pub fn hrm_ms_per_sample() -> f32 {
+    REPLAY.hrm_ms_per_sample.unwrap_or(NOMINAL_MS_PER_SAMPLE)
+}
+
+/// Time between the accel samples, as recorded if known.
+pub fn accel_ms_per_sample() -> f32 {
+    REPLAY.accel_ms_per_sample.unwrap_or(NOMINAL_MS_PER_SAMPLE)
+}
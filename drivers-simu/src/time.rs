@@ -1,75 +1,165 @@
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
 pub use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
-use util::ClockScale;
+use smol::channel::{Receiver, Sender};
+pub use util::clock::{ClockInfo, TimeSource};
+use util::clock::{ClockSync, Monotonic, SyncStats, TimeMessages};
+use util::{tz::TimeZone, ClockScale};
+
+use crate::gps::{self, GPSReceiver};
 
 pub static BOOT: Lazy<Instant> = Lazy::new(|| Instant::now());
 
-pub fn time_since_last_sync() -> Duration {
-    BOOT.elapsed()
+/// Rate error of the simulated clock in ppm (positive: too fast), can be set with the env var
+/// `CLOCK_DRIFT_PPM`. The default is roughly what the watch hardware does.
+static DRIFT_PPM: Lazy<f64> = Lazy::new(|| {
+    std::env::var("CLOCK_DRIFT_PPM")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(50.0)
+});
+
+/// Time since boot as counted by the drifting clock of the simulated watch.
+fn virtual_ms(instant: Instant) -> u64 {
+    let real_ms = instant.saturating_duration_since(*BOOT).as_secs_f64() * 1000.0;
+    (real_ms * (1.0 + *DRIFT_PPM / 1e6)) as u64
 }
-pub fn last_sync_duration() -> Duration {
-    Duration::from_secs(1)
+
+fn instant_from_virtual_ms(ms: u64) -> Instant {
+    *BOOT + Duration::from_secs_f64(ms as f64 / 1000.0 / (1.0 + *DRIFT_PPM / 1e6))
 }
-pub fn num_sync_fails() -> u32 {
-    0
+
+struct VirtualClock;
+
+impl Monotonic for VirtualClock {
+    fn now_ms(&self) -> u64 {
+        virtual_ms(Instant::now())
+    }
 }
-pub fn last_drift_s() -> i32 {
-    0
+
+impl TimeMessages for GPSReceiver<'_> {
+    async fn next_time(&mut self) -> gps::NavTimeUTC {
+        loop {
+            if let gps::CasicMsg::NavTimeUTC(c) = self.receive().await {
+                return c;
+            }
+        }
+    }
+}
+
+static FORCE_SYNC: Lazy<(Sender<()>, Receiver<()>)> = Lazy::new(|| smol::channel::bounded(1));
+
+async fn sync_delay(delay: Duration) {
+    embassy_futures::select::select(FORCE_SYNC.1.recv(), Timer::after(delay)).await;
 }
+
+static CLOCK_SYNC: Mutex<ClockSync> = Mutex::new(ClockSync::new());
+
+/// Same as the clock sync task of the hardware driver, but with the virtual clock.
+pub(crate) async fn clock_sync_task() {
+    sync_delay(Duration::from_millis(ClockSync::FIRST_SYNC_DELAY_MS)).await;
+    loop {
+        let start_ms = VirtualClock.now_ms();
+        let timeout_ms = CLOCK_SYNC.lock().unwrap().timeout_ms();
+        let res = {
+            let mut gps = GPSReceiver::new(gps::CasicMsgConfig {
+                nav_time: 1,
+                ..Default::default()
+            })
+            .await;
+            util::clock::receive_time(&mut gps, &VirtualClock, &clock_info(), timeout_ms).await
+        };
+        let drift = res.map(|(boot_ms, time)| CLOCK_INFO.lock().unwrap().sync(boot_ms, time));
+        println!("Time sync: {:?}", drift);
+        let next_sync_ms =
+            CLOCK_SYNC
+                .lock()
+                .unwrap()
+                .finish(start_ms, VirtualClock.now_ms(), drift.ok_or(()));
+
+        sync_delay(Duration::from_millis(next_sync_ms)).await;
+    }
+}
+
 pub fn force_sync() {
     println!("Forced time sync");
+    let _ = FORCE_SYNC.0.try_send(());
 }
 
+/// The simulated watch starts as if it was reset with the host time retained, so that the time is
+/// known right away. Syncs then correct the drift of the virtual clock.
+static CLOCK_INFO: Lazy<Mutex<ClockInfo>> = Lazy::new(|| {
+    let mut info = ClockInfo::new(ClockScale::one());
+    let now_ms = VirtualClock.now_ms();
+    info.set_time(now_ms, chrono::Utc::now());
+    info.source = TimeSource::Restored;
+    info.base_uncertainty_ms = 1000;
+    info.uncertainty_since_ms = now_ms;
+    Mutex::new(info)
+});
+
+fn sync_stats() -> SyncStats {
+    CLOCK_SYNC.lock().unwrap().stats
+}
+pub fn time_since_last_sync() -> Duration {
+    instant_from_virtual_ms(sync_stats().last_sync_ms).elapsed()
+}
+pub fn last_sync_duration() -> Duration {
+    Duration::from_millis(sync_stats().last_sync_duration_ms)
+}
+pub fn num_sync_fails() -> u32 {
+    sync_stats().num_fails
+}
+pub fn last_drift_ms() -> i64 {
+    sync_stats().last_drift_ms
+}
 pub fn next_sync() -> Instant {
-    Instant::now()
+    instant_from_virtual_ms(sync_stats().next_sync_ms)
 }
 
-#[derive(Copy, Clone)]
-pub struct ClockInfo {
-    pub scale: ClockScale,
-    pub offset_s: u64,
-    pub last_sync: Instant,
-    pub last_sync_time: chrono::DateTime<chrono::Utc>,
+pub fn clock_info() -> ClockInfo {
+    *CLOCK_INFO.lock().unwrap()
 }
 
-pub fn clock_info() -> ClockInfo {
-    ClockInfo {
-        scale: ClockScale::one(),
-        offset_s: 0,
-        last_sync: Instant::now(),
-        last_sync_time: chrono::DateTime::UNIX_EPOCH,
-    }
+pub fn time_uncertainty() -> Option<Duration> {
+    let now_ms = VirtualClock.now_ms();
+    CLOCK_INFO
+        .lock()
+        .unwrap()
+        .uncertainty_ms(now_ms)
+        .map(Duration::from_millis)
+}
+
+pub fn time_source() -> TimeSource {
+    CLOCK_INFO.lock().unwrap().source
+}
+
+pub fn set_time_manually(datetime: chrono::DateTime<chrono::Utc>) {
+    let now_ms = VirtualClock.now_ms();
+    CLOCK_INFO.lock().unwrap().set_manually(now_ms, datetime);
 }
 
 pub fn now_utc() -> Option<chrono::DateTime<chrono::Utc>> {
-    Some(chrono::Utc::now())
+    let now_ms = VirtualClock.now_ms();
+    CLOCK_INFO.lock().unwrap().to_utc(now_ms)
 }
 
-static TZ_SECONDS_EAST: AtomicI32 = AtomicI32::new(0);
-pub fn set_utc_offset(seconds_east: i32) {
-    TZ_SECONDS_EAST.store(seconds_east, Ordering::Relaxed);
+static TIME_ZONE: Mutex<TimeZone> = Mutex::new(TimeZone::UTC);
+pub fn set_time_zone(tz: TimeZone) {
+    *TIME_ZONE.lock().unwrap() = tz;
 }
 pub fn now_local() -> Option<chrono::DateTime<chrono::FixedOffset>> {
     let now = now_utc()?;
-    let offset = chrono::FixedOffset::east_opt(TZ_SECONDS_EAST.load(Ordering::Relaxed)).unwrap();
-    Some(now.with_timezone(&offset))
+    Some(TIME_ZONE.lock().unwrap().to_local(now))
 }
 
 pub fn to_instant<Tz: chrono::TimeZone>(t: chrono::DateTime<Tz>) -> Option<Instant> {
-    let utc = t.to_utc();
-    let now_utc = now_utc().unwrap();
-    let now = Instant::now();
-    let diff = utc - now_utc;
-
-    let mut neg = false;
-    let diff = diff.to_std().unwrap_or_else(|_| {
-        neg = true;
-        diff.abs().to_std().unwrap()
-    });
-
-    Some(if neg { now - diff } else { now + diff })
+    CLOCK_INFO
+        .lock()
+        .unwrap()
+        .to_boot_ms(t)
+        .map(instant_from_virtual_ms)
 }
 
 pub use smol::Timer;
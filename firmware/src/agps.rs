@@ -0,0 +1,101 @@
+use drivers::flash::FlashRessources;
+use drivers::gps::{
+    casic_packet_len, AgpsFileHeader, AidPacket, GPSReceiver, CASIC_PACKET_START_LEN,
+    MAX_AID_PACKET_LEN,
+};
+use littlefs2::io::{Read, SeekFrom};
+use littlefs2::path::Path;
+
+use crate::Filesystem;
+
+/// Assistance data (ephemeris/almanac) built on a pc via `tools/build_agps` and copied to flash.
+pub const AGPS_FILE: &Path = &Path::from_str_with_nul("/agps/casic.bin\0");
+
+const HEADER_LEN: usize = core::mem::size_of::<AgpsFileHeader>();
+
+// Ephemeris data (the precise orbits, which save most of the time to a fix) is only valid for a
+// couple of hours. Almanac data (the coarse orbits) still helps for some days. After that the
+// receiver is faster on its own.
+const EPHEMERIS_VALID_S: i64 = 4 * 60 * 60;
+const MAX_AGPS_AGE_S: i64 = 3 * 24 * 60 * 60;
+
+pub enum AgpsStatus {
+    Missing,
+    /// The file is truncated or does not consist of casic packets.
+    Invalid,
+    Stale {
+        age_s: i64,
+    },
+    Injected {
+        age_s: Option<i64>,
+    },
+}
+
+/// Whether data of this age only helps with the almanac.
+pub fn ephemeris_expired(age_s: i64) -> bool {
+    age_s > EPHEMERIS_VALID_S
+}
+
+/// Reads the header. Fails if the file does not contain as much data as announced in the header.
+fn read_header(fs: &Filesystem) -> littlefs2::io::Result<AgpsFileHeader> {
+    fs.open_file_and_then(AGPS_FILE, |file| {
+        let mut header = AgpsFileHeader::new(0, 0);
+        file.read_exact(bytemuck::bytes_of_mut(&mut header))?;
+        if !header.is_valid() || file.len()? < HEADER_LEN + header.data_len as usize {
+            return Err(littlefs2::io::Error::Io);
+        }
+        Ok(header)
+    })
+}
+
+/// Reads the casic packet at `offset` in the file. Fails if it extends past `end`.
+fn read_packet(fs: &Filesystem, offset: usize, end: usize) -> littlefs2::io::Result<AidPacket> {
+    fs.open_file_and_then(AGPS_FILE, |file| {
+        file.seek(SeekFrom::Start(offset as u32))?;
+        let mut buf = [0u8; MAX_AID_PACKET_LEN];
+        file.read_exact(&mut buf[..CASIC_PACKET_START_LEN])?;
+        let len = casic_packet_len(&buf).ok_or(littlefs2::io::Error::Io)?;
+        if len > MAX_AID_PACKET_LEN || offset + len > end {
+            return Err(littlefs2::io::Error::Io);
+        }
+        file.read_exact(&mut buf[CASIC_PACKET_START_LEN..len])?;
+        Ok(buf[..len].iter().copied().collect())
+    })
+}
+
+fn age_s(header: &AgpsFileHeader) -> Option<i64> {
+    let now = drivers::time::now_utc()?;
+    Some(now.timestamp() - header.downloaded_unix_s as i64)
+}
+
+/// Stream the assistance data stored on flash (if any and not outdated) to the gps receiver.
+pub async fn inject(flash: &mut FlashRessources, gps: &mut GPSReceiver<'_>) -> AgpsStatus {
+    let header = match flash.with_fs(read_header).await {
+        Ok(header) => header,
+        Err(littlefs2::io::Error::NoSuchEntry) => return AgpsStatus::Missing,
+        Err(_) => return AgpsStatus::Invalid,
+    };
+
+    let age_s = age_s(&header);
+    if let Some(age_s) = age_s {
+        if age_s > MAX_AGPS_AGE_S {
+            return AgpsStatus::Stale { age_s };
+        }
+    }
+
+    // The filesystem can't be kept open while waiting for the receiver, so the file is read one
+    // packet at a time.
+    let end = HEADER_LEN + header.data_len as usize;
+    let mut offset = HEADER_LEN;
+    while offset < end {
+        let Ok(packet) = flash.with_fs(|fs| read_packet(fs, offset, end)).await else {
+            crate::println!("Invalid agps data at offset {}", offset);
+            return AgpsStatus::Invalid;
+        };
+        offset += packet.len();
+        gps.inject_aid(packet).await;
+    }
+    crate::println!("Injected {} bytes of agps data", header.data_len);
+
+    AgpsStatus::Injected { age_s }
+}
@@ -1,12 +1,19 @@
 pub mod accel;
+pub mod alarm;
 pub mod batinfo;
 pub mod clockinfo;
+pub mod daylight;
 pub mod draw;
 pub mod files;
+pub mod gpslog;
+pub mod hrlog;
 pub mod hrm;
 pub mod idle;
 pub mod menu;
 pub mod panic_msg;
+pub mod readiness;
+pub mod skyview;
 pub mod stopwatch;
 pub mod timer;
 pub mod track;
+pub mod worldclock;
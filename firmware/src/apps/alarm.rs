@@ -0,0 +1,515 @@
+use arrayvec::ArrayVec;
+use arrform::*;
+use chrono::{NaiveDateTime, NaiveTime, Weekday};
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use drivers::{
+    futures::select,
+    lpm013m1126c::Rgb111,
+    time::{self, Duration, Instant, Ticker},
+    Context,
+};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    mono_font::MonoTextStyle,
+    text::Text,
+    Drawable as _,
+};
+use embedded_layout::{
+    align::{horizontal, vertical, Align as _},
+    layout::linear::LinearLayout,
+    object_chain::Chain,
+};
+use littlefs2::path::Path;
+use util::alarm::{next_occurrence, Weekdays};
+
+use crate::{
+    apps::menu::Page,
+    render_top_bar,
+    ui::{textbox, Button, ButtonStyle, EventHandler as _, TouchResult},
+    Filesystem,
+};
+
+pub const MAX_ALARMS: usize = 7;
+
+const ALARMS_FILE: &Path = &Path::from_str_with_nul("alarms.bin\0");
+
+/// Entering text is not practical on the watch, so alarms are named from this list.
+const NAMES: [&str; 6] = ["Alarm", "Wake up", "Meds", "Meeting", "Training", "Nap"];
+
+const DAY_LETTERS: [char; 7] = ['M', 'T', 'W', 'T', 'F', 'S', 'S'];
+
+const SNOOZE_MINUTES: i64 = 9;
+
+/// An alarm that is not dismissed is snoozed after ringing for this long.
+const RING_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Alarm {
+    pub hour: u8,
+    pub minute: u8,
+    /// See `util::alarm::Weekdays`
+    pub days: u8,
+    pub enabled: u8,
+    /// Index into `NAMES`
+    pub name: u8,
+    /// Space for future fields, so that the file format stays the same
+    _reserved: [u8; 3],
+}
+
+impl Default for Alarm {
+    fn default() -> Self {
+        Self {
+            hour: 7,
+            minute: 0,
+            days: Weekdays::ONCE.0,
+            enabled: 1,
+            name: 0,
+            _reserved: [0; 3],
+        }
+    }
+}
+
+impl Alarm {
+    pub fn name(&self) -> &'static str {
+        NAMES.get(self.name as usize).unwrap_or(&NAMES[0])
+    }
+
+    pub fn time(&self) -> NaiveTime {
+        NaiveTime::from_hms_opt(self.hour.min(23) as u32, self.minute.min(59) as u32, 0).unwrap()
+    }
+
+    pub fn days(&self) -> Weekdays {
+        Weekdays(self.days)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != 0
+    }
+}
+
+/// All alarms together with their (not persisted) snooze state.
+#[derive(Default)]
+pub struct Alarms {
+    list: ArrayVec<Alarm, MAX_ALARMS>,
+    snoozed: [Option<NaiveDateTime>; MAX_ALARMS],
+}
+
+impl Alarms {
+    pub fn load(fs: &Filesystem) -> littlefs2::io::Result<Self> {
+        fs.open_file_with_options_and_then(
+            |options| options.read(true).create(true),
+            ALARMS_FILE,
+            |file| {
+                let mut buf = [Alarm::default(); MAX_ALARMS];
+                let n = file.read(bytemuck::cast_slice_mut(&mut buf))?;
+                Ok(Self {
+                    list: buf[..n / core::mem::size_of::<Alarm>()]
+                        .iter()
+                        .copied()
+                        .collect(),
+                    snoozed: Default::default(),
+                })
+            },
+        )
+    }
+
+    pub fn save(&self, fs: &Filesystem) -> littlefs2::io::Result<()> {
+        fs.open_file_with_options_and_then(
+            |options| options.write(true).create(true).truncate(true),
+            ALARMS_FILE,
+            |file| {
+                let s = bytemuck::cast_slice(&self.list);
+                let written = file.write(s)?;
+                assert_eq!(written, s.len());
+                Ok(())
+            },
+        )
+    }
+
+    /// The alarm (or snoozed alarm) that rings first after `after` (local time).
+    pub fn next_due(&self, after: NaiveDateTime) -> Option<(usize, NaiveDateTime)> {
+        let scheduled = self
+            .list
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.is_enabled())
+            .filter_map(|(i, a)| Some((i, next_occurrence(after, a.time(), a.days())?)));
+        let snoozed = self
+            .snoozed
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| Some((i, (*s)?)))
+            .filter(|(_, s)| *s > after);
+        scheduled.chain(snoozed).min_by_key(|(_, t)| *t)
+    }
+
+    fn snooze(&mut self, i: usize, now: NaiveDateTime) {
+        self.snoozed[i] = Some(now + chrono::Duration::minutes(SNOOZE_MINUTES));
+    }
+
+    /// Returns whether the alarms have to be saved.
+    fn dismiss(&mut self, i: usize) -> bool {
+        self.snoozed[i] = None;
+        let alarm = &mut self.list[i];
+        if alarm.days().is_once() {
+            alarm.enabled = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Replace (or add if `i` is out of range) an alarm, `None` deletes it.
+    fn set(&mut self, i: usize, alarm: Option<Alarm>) {
+        // Indices change when deleting, so we simply forget about snoozing.
+        self.snoozed = Default::default();
+        match (alarm, i < self.list.len()) {
+            (Some(a), true) => self.list[i] = a,
+            (Some(a), false) => self.list.push(a),
+            (None, true) => {
+                self.list.remove(i);
+            }
+            (None, false) => {}
+        }
+    }
+}
+
+/// Show a ringing alarm until it is dismissed or snoozed.
+pub async fn ring(ctx: &mut Context, alarms: &RefCell<Alarms>, i: usize) {
+    let alarm = alarms.borrow().list[i];
+    let msg = arrform!(
+        20,
+        "{}\n{:0>2}:{:0>2}",
+        alarm.name(),
+        alarm.hour,
+        alarm.minute
+    );
+
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let mut ticker = Ticker::every(Duration::from_millis(1500));
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+
+    let s = Size::new(80, 80);
+
+    let mut snooze_btn = Button::lazy(&button_style, s, "Snooze");
+    let mut dismiss_btn = Button::lazy(&button_style, s, "Dismiss");
+
+    let mut buzzer = ctx.buzzer.on();
+
+    ctx.lcd.on().await;
+
+    let bg = Rgb111::black();
+    ctx.lcd.fill(bg);
+
+    let start = Instant::now();
+    let snooze = loop {
+        if start.elapsed() > RING_TIMEOUT {
+            break true;
+        }
+        buzzer.pattern([100, 200, 100, 200, 100, 0, 0]);
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+        let message = textbox(msg.as_str(), Size::new(170, 50), bg, sl);
+
+        let layout = LinearLayout::vertical(
+            Chain::new(message).append(
+                LinearLayout::horizontal(Chain::new(&mut snooze_btn).append(&mut dismiss_btn))
+                    .with_spacing(embedded_layout::layout::linear::FixedMargin(5))
+                    .arrange(),
+            ),
+        )
+        .with_alignment(horizontal::Center)
+        .arrange()
+        .align_to(&crate::BELOW_BAR_AREA, horizontal::Center, vertical::Center);
+        layout.draw(&mut *ctx.lcd).unwrap();
+        match ctx
+            .lcd
+            .present_and(select::select3(
+                ctx.button.wait_for_press(),
+                touch.wait_for_action(),
+                ticker.next(),
+            ))
+            .await
+        {
+            select::Either3::First(_d) => {
+                break false;
+            }
+            select::Either3::Second(e) => {
+                ctx.backlight.active().await;
+                if snooze_btn.clicked(&e) {
+                    break true;
+                }
+                if dismiss_btn.clicked(&e) {
+                    break false;
+                }
+            }
+            select::Either3::Third(_) => {}
+        }
+    };
+    drop(buzzer);
+
+    let now = time::now_local().map(|t| t.naive_local());
+    let changed = {
+        let mut alarms = alarms.borrow_mut();
+        match now {
+            Some(now) if snooze => {
+                alarms.snooze(i, now);
+                false
+            }
+            _ => alarms.dismiss(i),
+        }
+    };
+    if changed {
+        ctx.flash
+            .with_fs(|fs| alarms.borrow().save(fs))
+            .await
+            .unwrap();
+    }
+}
+
+pub async fn alarm_app(ctx: &mut Context, alarms: &RefCell<Alarms>) {
+    #[derive(Copy, Clone)]
+    enum Entry {
+        Edit(usize),
+        New,
+    }
+
+    let mut page = Page::zero();
+
+    loop {
+        let labels = alarms
+            .borrow()
+            .list
+            .iter()
+            .map(|a| {
+                arrform!(
+                    16,
+                    "{:0>2}:{:0>2}\n{}",
+                    a.hour,
+                    a.minute,
+                    if a.is_enabled() { a.name() } else { "off" }
+                )
+            })
+            .collect::<ArrayVec<_, MAX_ALARMS>>();
+        let mut options = labels
+            .iter()
+            .enumerate()
+            .map(|(i, l)| (l.as_str(), Entry::Edit(i)))
+            .collect::<ArrayVec<_, { MAX_ALARMS + 1 }>>();
+        if !labels.is_full() {
+            options.push(("New", Entry::New));
+        }
+
+        let crate::apps::menu::MenuSelection::Item(last_page, (_, entry)) =
+            crate::apps::menu::paginated_grid_menu::<4, _, _>(
+                &mut ctx.touch,
+                &ctx.twi,
+                &mut ctx.button,
+                &mut ctx.lcd,
+                &mut ctx.battery,
+                &mut ctx.backlight,
+                options.as_slice(),
+                page,
+            )
+            .await
+        else {
+            break;
+        };
+        page = last_page;
+
+        let (i, alarm) = match entry {
+            Entry::Edit(i) => (i, alarms.borrow().list[i]),
+            Entry::New => (MAX_ALARMS, Alarm::default()),
+        };
+        if let Some(result) = edit_alarm(ctx, alarm).await {
+            alarms.borrow_mut().set(i, result);
+            ctx.flash
+                .with_fs(|fs| alarms.borrow().save(fs))
+                .await
+                .unwrap();
+        }
+    }
+}
+
+enum Action {
+    Continue,
+    Save,
+    Delete,
+}
+
+fn toggle_day(alarm: &mut Alarm, day: Weekday) -> Action {
+    let mut days = alarm.days();
+    days.toggle(day);
+    alarm.days = days.0;
+    Action::Continue
+}
+
+fn add_minutes(alarm: &mut Alarm, minutes: i32) -> Action {
+    let t = (alarm.hour as i32 * 60 + alarm.minute as i32 + minutes).rem_euclid(24 * 60);
+    alarm.hour = (t / 60) as u8;
+    alarm.minute = (t % 60) as u8;
+    Action::Continue
+}
+
+/// Returns `None` if the alarm should stay as it was, `Some(None)` if it should be deleted.
+async fn edit_alarm(ctx: &mut Context, mut alarm: Alarm) -> Option<Option<Alarm>> {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font: &embedded_graphics::mono_font::ascii::FONT_8X13,
+    };
+
+    let h = 20;
+    let time_size = Size::new(40, h);
+    let mut minus_hour =
+        Button::eager(&button_style, time_size, "-h").on_click(|a: &mut Alarm| add_minutes(a, -60));
+    let mut plus_hour =
+        Button::eager(&button_style, time_size, "+h").on_click(|a: &mut Alarm| add_minutes(a, 60));
+    let mut minus_min =
+        Button::eager(&button_style, time_size, "-m").on_click(|a: &mut Alarm| add_minutes(a, -1));
+    let mut plus_min =
+        Button::eager(&button_style, time_size, "+m").on_click(|a: &mut Alarm| add_minutes(a, 1));
+
+    let day_size = Size::new(22, h);
+    let mut mon = Button::eager(&button_style, day_size, "M")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Mon));
+    let mut tue = Button::eager(&button_style, day_size, "T")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Tue));
+    let mut wed = Button::eager(&button_style, day_size, "W")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Wed));
+    let mut thu = Button::eager(&button_style, day_size, "T")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Thu));
+    let mut fri = Button::eager(&button_style, day_size, "F")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Fri));
+    let mut sat = Button::eager(&button_style, day_size, "S")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Sat));
+    let mut sun = Button::eager(&button_style, day_size, "S")
+        .on_click(|a: &mut Alarm| toggle_day(a, Weekday::Sun));
+
+    let size = Size::new(38, h);
+    let mut name_button = Button::eager(&button_style, size, "Name").on_click(|a: &mut Alarm| {
+        a.name = (a.name + 1) % NAMES.len() as u8;
+        Action::Continue
+    });
+    let mut on_off_button =
+        Button::eager(&button_style, Size::new(48, h), "On/Off").on_click(|a: &mut Alarm| {
+            a.enabled = (a.enabled == 0) as u8;
+            Action::Continue
+        });
+    let mut save_button =
+        Button::eager(&button_style, size, "Save").on_click(|_: &mut Alarm| Action::Save);
+    let mut delete_button =
+        Button::eager(&button_style, size, "Del").on_click(|_: &mut Alarm| Action::Delete);
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let title = arrform!(
+            16,
+            "{} ({})",
+            alarm.name(),
+            if alarm.is_enabled() { "on" } else { "off" }
+        );
+        let time_text = arrform!(5, "{:0>2}:{:0>2}", alarm.hour, alarm.minute);
+        let days_text = if alarm.days().is_once() {
+            arrform!(7, "once")
+        } else {
+            let mut t = ArrForm::<7>::new();
+            for (i, c) in DAY_LETTERS.iter().enumerate() {
+                let _ = t.write_char(if alarm.days & (1 << i) != 0 { *c } else { '-' });
+            }
+            t
+        };
+
+        let row_spacing = embedded_layout::layout::linear::FixedMargin(2);
+        let mut layout = LinearLayout::vertical(
+            Chain::new(Text::new(title.as_str(), Point::zero(), sl))
+                .append(Text::new(time_text.as_str(), Point::zero(), sl))
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut minus_hour)
+                            .append(&mut plus_hour)
+                            .append(&mut minus_min)
+                            .append(&mut plus_min),
+                    )
+                    .with_spacing(row_spacing)
+                    .arrange(),
+                )
+                .append(Text::new(days_text.as_str(), Point::zero(), sl))
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut mon)
+                            .append(&mut tue)
+                            .append(&mut wed)
+                            .append(&mut thu)
+                            .append(&mut fri)
+                            .append(&mut sat)
+                            .append(&mut sun),
+                    )
+                    .with_spacing(row_spacing)
+                    .arrange(),
+                )
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut name_button)
+                            .append(&mut on_off_button)
+                            .append(&mut save_button)
+                            .append(&mut delete_button),
+                    )
+                    .with_spacing(row_spacing)
+                    .arrange(),
+                ),
+        )
+        .with_alignment(horizontal::Center)
+        .with_spacing(embedded_layout::layout::linear::FixedMargin(4))
+        .arrange()
+        .align_to(&crate::BELOW_BAR_AREA, horizontal::Center, vertical::Center);
+
+        layout.draw(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either3::First(_d) => {
+                break None;
+            }
+            select::Either3::Second(_event) => {}
+            select::Either3::Third(e) => {
+                ctx.backlight.active().await;
+                match layout.touch(e, &mut alarm) {
+                    TouchResult::Done(Action::Continue) => {}
+                    TouchResult::Done(Action::Save) => break Some(Some(alarm)),
+                    TouchResult::Done(Action::Delete) => break Some(None),
+                    TouchResult::Continue => {}
+                }
+            }
+        }
+    }
+}
@@ -59,11 +59,22 @@ pub async fn clock_info(ctx: &mut Context) {
         let (_h, min, s) = hours_mins_secs(time::last_sync_duration());
         let _ = writeln!(w, "T_G: {:0>2}:{:0>2}", min, s);
 
-        let _ = writeln!(w, "Drift: {}", time::last_drift_s());
+        let _ = writeln!(w, "Drift: {}ms", time::last_drift_ms());
+
+        let source = match time::time_source() {
+            time::TimeSource::Unknown => "-",
+            time::TimeSource::Gps => "GPS",
+            time::TimeSource::Restored => "Rst",
+            time::TimeSource::User => "User",
+        };
+        if let Some(u) = time::time_uncertainty() {
+            let _ = writeln!(w, "{} +-{}ms", source, u.as_millis());
+        } else {
+            let _ = writeln!(w, "{}", source);
+        }
 
         let info = time::clock_info();
-        let _ = writeln!(w, "S_N: {}", info.scale.numerator);
-        let _ = writeln!(w, "S_D: {}", info.scale.denominator);
+        let _ = writeln!(w, "S: {}/{}", info.scale.numerator, info.scale.denominator);
 
         force_sync_button.render(&mut *ctx.lcd).unwrap();
 
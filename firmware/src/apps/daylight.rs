@@ -0,0 +1,157 @@
+use arrform::*;
+use chrono::{DateTime, FixedOffset, Timelike, Utc};
+use core::fmt::Write;
+use drivers::futures::select;
+use drivers::lpm013m1126c::Rgb111;
+use drivers::time::{self, Duration, Ticker};
+use embedded_graphics::mono_font::MonoTextStyle;
+use littlefs2::path::Path;
+use util::gps::LonLat;
+use util::sun::Daylight;
+
+use crate::{render_top_bar, ui::TextWriter, Context, Filesystem};
+
+const POSITION_FILE: &Path = &Path::from_str_with_nul("position.bin\0");
+
+/// Last known position, used when there is no fix (which is almost always).
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LastPosition {
+    pub longitude: f64,
+    pub latitude: f64,
+}
+
+impl LastPosition {
+    /// `None` if there was never a fix.
+    pub fn load(fs: &Filesystem) -> littlefs2::io::Result<Option<Self>> {
+        fs.open_file_with_options_and_then(
+            |options| options.read(true).create(true),
+            POSITION_FILE,
+            |file| {
+                let mut p = Self::default();
+                let n = file.read(bytemuck::bytes_of_mut(&mut p))?;
+                Ok((n == core::mem::size_of::<Self>()).then_some(p))
+            },
+        )
+    }
+
+    pub fn save(&self, fs: &Filesystem) -> littlefs2::io::Result<()> {
+        fs.open_file_with_options_and_then(
+            |options| options.write(true).create(true).truncate(true),
+            POSITION_FILE,
+            |file| {
+                let s = bytemuck::bytes_of(self);
+                let written = file.write(s)?;
+                assert_eq!(written, s.len());
+                Ok(())
+            },
+        )
+    }
+
+    fn lon_lat(&self) -> LonLat {
+        LonLat {
+            lon: self.longitude,
+            lat: self.latitude,
+        }
+    }
+
+    /// Sun events of the local day of `now`.
+    pub fn daylight(&self, now: DateTime<FixedOffset>) -> Option<Daylight> {
+        util::sun::daylight(now.date_naive(), &self.lon_lat())
+    }
+}
+
+fn hours_minutes(d: chrono::Duration) -> ArrForm<6> {
+    let minutes = d.num_minutes();
+    arrform!(6, "{}:{:0>2}", minutes / 60, minutes % 60)
+}
+
+/// Short note on the remaining daylight for the clock face, e.g. "Sunset in 1:23".
+pub fn countdown(position: &LastPosition, now: DateTime<FixedOffset>) -> Option<ArrForm<16>> {
+    let d = position.daylight(now)?;
+    let now = now.with_timezone(&Utc);
+    let (what, at) = if d.sunset.is_some_and(|t| t > now) {
+        ("Sunset", d.sunset?)
+    } else if d.dusk.is_some_and(|t| t > now) {
+        ("Dark", d.dusk?)
+    } else {
+        return None;
+    };
+    Some(arrform!(
+        16,
+        "{} in {}",
+        what,
+        hours_minutes(at - now).as_str()
+    ))
+}
+
+fn local_time(t: Option<DateTime<Utc>>, offset: &FixedOffset) -> ArrForm<5> {
+    match t {
+        Some(t) => {
+            let t = t.with_timezone(offset);
+            arrform!(5, "{:0>2}:{:0>2}", t.hour(), t.minute())
+        }
+        None => arrform!(5, "--:--"),
+    }
+}
+
+pub async fn daylight_info(ctx: &mut Context) {
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
+
+    let position = ctx
+        .flash
+        .with_fs(|fs| LastPosition::load(fs))
+        .await
+        .ok()
+        .flatten();
+
+    let mut ticker = Ticker::every(Duration::from_secs(60));
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let mut w = TextWriter::new(&mut ctx.lcd, sl).y(10 + font.character_size.height as i32);
+
+        match (&position, time::now_local()) {
+            (None, _) => {
+                let _ = writeln!(w, "No position yet.");
+                let _ = writeln!(w, "Get a GPS fix");
+                let _ = writeln!(w, "in Track.");
+            }
+            (Some(_), None) => {
+                let _ = writeln!(w, "Time unknown");
+            }
+            (Some(p), Some(now)) => {
+                let _ = writeln!(w, "{:.2} {:.2}", p.latitude, p.longitude);
+                if let Some(d) = p.daylight(now) {
+                    let offset = now.offset();
+                    let _ = writeln!(w, "Dawn:    {}", local_time(d.dawn, offset).as_str());
+                    let _ = writeln!(w, "Sunrise: {}", local_time(d.sunrise, offset).as_str());
+                    let _ = writeln!(w, "Noon:    {}", local_time(Some(d.noon), offset).as_str());
+                    let _ = writeln!(w, "Sunset:  {}", local_time(d.sunset, offset).as_str());
+                    let _ = writeln!(w, "Dusk:    {}", local_time(d.dusk, offset).as_str());
+                    let _ = writeln!(w, "Day: {}", hours_minutes(d.day_length()).as_str());
+                }
+            }
+        }
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ticker.next(),
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+        )
+        .await
+        {
+            select::Either3::First(_) => {}
+            select::Either3::Second(_d) => {
+                break;
+            }
+            select::Either3::Third(_event) => {}
+        }
+    }
+}
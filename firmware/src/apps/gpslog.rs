@@ -0,0 +1,179 @@
+use arrayvec::ArrayVec;
+use arrform::*;
+use core::fmt::Write;
+use drivers::flash::FlashRessources;
+use drivers::futures::select;
+use drivers::gps::{CasicMsg, CasicMsgConfig, GPSRawLog, GPSReceiver, RawLogChunk};
+use drivers::lpm013m1126c::Rgb111;
+use drivers::time::{Duration, Instant, Ticker};
+use embedded_graphics::mono_font::MonoTextStyle;
+use littlefs2::path::{Path, PathBuf};
+
+use crate::util::hours_mins_secs;
+use crate::{render_top_bar, ui::TextWriter, Context, Filesystem};
+
+/// Raw logs are rotated: `0.bin` is the current one, `1.bin` the one before and so on. Use
+/// `tools/gpslog` to turn them into a stream for the simulator.
+const LOG_DIR: &Path = &Path::from_str_with_nul("/gpslog\0");
+const MAX_LOG_FILES: usize = 4;
+const MAX_LOG_FILE_LEN: usize = 128 * 1024;
+
+const WRITE_BUF_LEN: usize = 1024;
+
+fn log_path(i: usize) -> PathBuf {
+    PathBuf::from(arrform!(20, "/gpslog/{}.bin", i).as_str())
+}
+
+/// Shift all logs by one (dropping the oldest) so that a fresh `0.bin` can be started.
+fn rotate(fs: &Filesystem) -> littlefs2::io::Result<()> {
+    fs.create_dir_all(LOG_DIR)?;
+    for i in (0..MAX_LOG_FILES - 1).rev() {
+        let from = log_path(i);
+        if fs.metadata(&from).is_ok() {
+            fs.rename(&from, &log_path(i + 1))?;
+        }
+    }
+    Ok(())
+}
+
+struct LogWriter {
+    buf: ArrayVec<u8, WRITE_BUF_LEN>,
+    file_len: usize,
+    bytes_logged: usize,
+}
+
+impl LogWriter {
+    async fn start(flash: &mut FlashRessources) -> Self {
+        flash.with_fs(|fs| rotate(fs)).await.unwrap();
+        Self {
+            buf: ArrayVec::new(),
+            file_len: 0,
+            bytes_logged: 0,
+        }
+    }
+
+    async fn add(&mut self, chunk: &RawLogChunk, flash: &mut FlashRessources) {
+        if self.buf.remaining_capacity() < chunk.record_len() {
+            self.flush(flash).await;
+        }
+        self.buf
+            .try_extend_from_slice(bytemuck::bytes_of(&chunk.header()))
+            .unwrap();
+        self.buf.try_extend_from_slice(&chunk.data).unwrap();
+        self.bytes_logged += chunk.data.len();
+    }
+
+    async fn flush(&mut self, flash: &mut FlashRessources) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let rotate_first = self.file_len + self.buf.len() > MAX_LOG_FILE_LEN;
+        let buf = &self.buf;
+        flash
+            .with_fs(|fs| {
+                if rotate_first {
+                    rotate(fs)?;
+                }
+                fs.open_file_with_options_and_then(
+                    |o| o.write(true).create(true).append(true),
+                    &log_path(0),
+                    |file| {
+                        use littlefs2::io::Write;
+                        file.write_all(buf)?;
+                        Ok(())
+                    },
+                )
+            })
+            .await
+            .unwrap();
+        if rotate_first {
+            self.file_len = 0;
+        }
+        self.file_len += self.buf.len();
+        self.buf.clear();
+    }
+}
+
+/// Record everything the gps receiver sends (with the same configuration as the track app) to
+/// flash for debugging.
+pub async fn gps_log(ctx: &mut Context) {
+    let _busy = crate::background::busy();
+    let mut writer = LogWriter::start(&mut ctx.flash).await;
+    let mut raw_log = GPSRawLog::start();
+
+    let mut gps = GPSReceiver::new(CasicMsgConfig {
+        nav_pv: 1,
+        ..Default::default()
+    })
+    .await;
+
+    let settings = ctx
+        .flash
+        .with_fs(|fs| crate::settings::Settings::load(fs))
+        .await
+        .unwrap_or_default();
+    gps.update_receiver_config(settings.track_receiver_config())
+        .await;
+    crate::agps::inject(&mut ctx.flash, &mut gps).await;
+
+    let sl = MonoTextStyle::new(
+        &embedded_graphics::mono_font::ascii::FONT_8X13,
+        embedded_graphics::pixelcolor::BinaryColor::On,
+    );
+
+    let start = Instant::now();
+    let mut fix = None;
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+
+    ctx.lcd.on().await;
+    let mut redraw = true;
+    loop {
+        // Data arrives many times per second, but the screen only has to be updated once.
+        if redraw {
+            redraw = false;
+            ctx.lcd.fill(Rgb111::black());
+            render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+            let (h, m, s) = hours_mins_secs(start.elapsed());
+            let mut w = TextWriter::new(&mut ctx.lcd, sl).y(26);
+            let _ = writeln!(w, "GPS log {}:{:0>2}:{:0>2}", h, m, s);
+            let _ = writeln!(w, "logged: {} kB", writer.bytes_logged / 1024);
+            let _ = writeln!(w, "dropped: {} B", raw_log.num_dropped());
+            match fix {
+                Some((pos_valid, num_sv)) => {
+                    let _ = writeln!(w, "fix: {} ({} sv)", pos_valid, num_sv);
+                }
+                None => {
+                    let _ = writeln!(w, "fix: -");
+                }
+            }
+            let _ = writeln!(w, "\nPress button to stop");
+            ctx.lcd.present().await;
+        }
+
+        match select::select4(
+            raw_log.receive(),
+            gps.receive(),
+            ticker.next(),
+            ctx.button.wait_for_press(),
+        )
+        .await
+        {
+            select::Either4::First(chunk) => {
+                writer.add(&chunk, &mut ctx.flash).await;
+            }
+            select::Either4::Second(CasicMsg::NavPv(pv)) => {
+                fix = Some((pv.pos_valid, pv.num_sv));
+            }
+            select::Either4::Second(_) => {}
+            select::Either4::Third(_) => {
+                redraw = true;
+            }
+            select::Either4::Fourth(_) => {
+                break;
+            }
+        }
+    }
+
+    writer.flush(&mut ctx.flash).await;
+}
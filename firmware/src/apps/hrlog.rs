@@ -0,0 +1,413 @@
+//! Heart rate measured every few minutes in the background, and a chart of the last day.
+//!
+//! `background::log_heart_rate` takes the measurements next to whatever is in the foreground. It
+//! only gets the sensor while no app uses it, and gives it back as soon as one asks for it. The
+//! flash belongs to the foreground, so finished measurements are queued until the foreground
+//! writes them with `save_pending`.
+
+use arrayvec::ArrayVec;
+use arrform::*;
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Timelike, Utc};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+use drivers::flash::FlashRessources;
+use drivers::futures::select;
+use drivers::hrm::ReadResult;
+use drivers::lpm013m1126c::{Buffer, Rgb111};
+use drivers::time::{self, Duration, Instant, Ticker};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::{Channel, TrySendError},
+};
+use embedded_graphics::{
+    mono_font::MonoTextStyle,
+    prelude::*,
+    primitives::{Line, PrimitiveStyle},
+    text::Text,
+};
+use hrm::hrv::ArtifactRejection;
+use hrm::HeartbeatDetector;
+use littlefs2::path::PathBuf;
+
+use crate::util::SampleCountingEstimator;
+use crate::{render_top_bar, Context, Filesystem};
+
+const MEASUREMENT_INTERVAL_S: u32 = 10 * 60;
+const MEASUREMENT_DURATION_S: u32 = 30;
+/// Beats before the detector has tuned its filter to the heart rate are ignored.
+const WARMUP_S: u32 = 8;
+/// Fewer accepted beats than this usually means that the watch isn't worn.
+const MIN_BEATS: u32 = 10;
+/// A bit more than a day of measurements every `MEASUREMENT_INTERVAL_S`.
+const MAX_ENTRIES_PER_DAY: usize = 160;
+/// Five hours of measurements, for when an app keeps the foreground from saving them.
+const MAX_PENDING: usize = 32;
+/// Old logs are removed a few at a time.
+const MAX_REMOVED_PER_SAVE: usize = 4;
+
+/// Seconds since boot (see `Context::start_time`) at which the next measurement is due.
+static NEXT_MEASUREMENT_S: AtomicU32 = AtomicU32::new(0);
+
+/// Measurements that were finished in the background, but not written yet.
+static PENDING: Channel<CriticalSectionRawMutex, (DateTime<Utc>, LogEntry), MAX_PENDING> =
+    Channel::new();
+
+/// One measurement, stored in one file per (UTC) day.
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LogEntry {
+    /// Start of the measurement in minutes since midnight (UTC)
+    pub minute: u16,
+    /// Of the individual beats
+    pub min_bpm: u8,
+    /// Of the individual beats
+    pub max_bpm: u8,
+    pub mean_bpm: u8,
+    _padding: u8,
+}
+
+fn log_file_name(date: NaiveDate) -> ArrForm<12> {
+    use chrono::Datelike;
+    arrform!(
+        12,
+        "{:0>4}{:0>2}{:0>2}.bin",
+        date.year(),
+        date.month(),
+        date.day()
+    )
+}
+
+fn log_path(date: NaiveDate) -> PathBuf {
+    PathBuf::from(arrform!(24, "/hrlog/{}", log_file_name(date).as_str()).as_str())
+}
+
+fn append(fs: &Filesystem, at: DateTime<Utc>, entry: LogEntry) -> littlefs2::io::Result<()> {
+    let entry = LogEntry {
+        minute: (at.hour() * 60 + at.minute()) as u16,
+        ..entry
+    };
+    fs.open_file_with_options_and_then(
+        |o| o.write(true).create(true).append(true),
+        &log_path(at.date_naive()),
+        |file| {
+            use littlefs2::io::Write;
+            file.write_all(bytemuck::bytes_of(&entry))
+        },
+    )
+}
+
+/// All entries of the (UTC) day, empty if there are none.
+fn load_day(fs: &Filesystem, date: NaiveDate) -> ArrayVec<LogEntry, MAX_ENTRIES_PER_DAY> {
+    let mut entries = [LogEntry::default(); MAX_ENTRIES_PER_DAY];
+    let num = fs
+        .open_file_with_options_and_then(
+            |o| o.read(true),
+            &log_path(date),
+            |file| file.read(bytemuck::cast_slice_mut(&mut entries)),
+        )
+        .map_or(0, |n| n / core::mem::size_of::<LogEntry>());
+    entries[..num].iter().copied().collect()
+}
+
+/// Remove the logs of the days before yesterday, only the last 24 hours are shown.
+fn remove_old(fs: &Filesystem, today: NaiveDate) -> littlefs2::io::Result<()> {
+    let oldest_kept = log_file_name(today - ChronoDuration::days(1));
+    // Collected first, since removing files while iterating the directory would skip entries.
+    let old = fs.read_dir_and_then(b"/hrlog\0".try_into().unwrap(), |dir_it| {
+        let mut old = ArrayVec::<PathBuf, MAX_REMOVED_PER_SAVE>::new();
+        for f in dir_it {
+            let f = f?;
+            let name: &str = f.file_name().as_ref();
+            // The names sort by date.
+            if f.file_type().is_file() && name < oldest_kept.as_str() && !old.is_full() {
+                old.push(f.path().into());
+            }
+        }
+        Ok(old)
+    })?;
+    for path in old {
+        fs.remove(&path)?;
+    }
+    Ok(())
+}
+
+/// Queue a finished measurement for `save_pending`, dropping the oldest one if the queue is full.
+pub fn queue(at: DateTime<Utc>, entry: LogEntry) {
+    if let Err(TrySendError::Full(e)) = PENDING.try_send((at, entry)) {
+        let _ = PENDING.try_receive();
+        let _ = PENDING.try_send(e);
+    }
+}
+
+/// Write the queued measurements to the flash and remove logs that are no longer shown, so that
+/// the flash does not run full.
+pub async fn save_pending(flash: &mut FlashRessources) {
+    let Some(today) = time::now_utc().map(|now| now.date_naive()) else {
+        return;
+    };
+    if PENDING.is_empty() {
+        return;
+    }
+    let res = flash
+        .with_fs(|fs| {
+            fs.create_dir_all(b"/hrlog/\0".try_into().unwrap())?;
+            remove_old(fs, today)?;
+            while let Ok((at, entry)) = PENDING.try_receive() {
+                append(fs, at, entry)?;
+            }
+            Ok(())
+        })
+        .await;
+    if res.is_err() {
+        crate::println!("Failed to save the heart rate log");
+    }
+}
+
+fn elapsed_s(start_time: Instant) -> u32 {
+    start_time.elapsed().as_secs() as u32
+}
+
+/// Time until the next measurement should start, zero if it is due.
+pub fn until_next_measurement(start_time: Instant) -> Duration {
+    let remaining = NEXT_MEASUREMENT_S
+        .load(Ordering::Relaxed)
+        .saturating_sub(elapsed_s(start_time));
+    Duration::from_secs(remaining as u64)
+}
+
+/// Wait for the next interval, e.g. because an app uses the sensor.
+pub fn skip_measurement(start_time: Instant) {
+    schedule_next_measurement(start_time);
+}
+
+fn schedule_next_measurement(start_time: Instant) {
+    NEXT_MEASUREMENT_S.store(
+        elapsed_s(start_time) + MEASUREMENT_INTERVAL_S,
+        Ordering::Relaxed,
+    );
+}
+
+/// A measurement in progress. Dropping it before it is done means that the next one is due
+/// immediately.
+pub struct Measurement {
+    start_s: u32,
+    detector: HeartbeatDetector<SampleCountingEstimator>,
+    rejection: ArtifactRejection,
+    last_res: u8,
+    num_beats: u32,
+    sum_ms: f32,
+    min_ms: f32,
+    max_ms: f32,
+}
+
+impl Measurement {
+    pub fn new(start_time: Instant) -> Self {
+        Self {
+            start_s: elapsed_s(start_time),
+            detector: HeartbeatDetector::new(SampleCountingEstimator::new()),
+            rejection: ArtifactRejection::default(),
+            last_res: 0,
+            num_beats: 0,
+            sum_ms: 0.0,
+            min_ms: f32::MAX,
+            max_ms: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, start_time: Instant, r: &ReadResult, samples: &[i16]) {
+        if r.pd_res_value[0] != self.last_res {
+            self.last_res = r.pd_res_value[0];
+            self.detector = HeartbeatDetector::new(SampleCountingEstimator::new());
+            self.rejection = ArtifactRejection::default();
+        }
+        let warm = elapsed_s(start_time) >= self.start_s + WARMUP_S;
+        for sample in samples {
+            let (_, Some(ms)) = self.detector.add_sample_interval(*sample) else {
+                continue;
+            };
+            // Beats found in noise (e.g. when the watch is not worn) are not logged.
+            let reliable = self.detector.quality().is_reliable();
+            if let Some(interval) = self.rejection.filter(ms).filter(|_| warm && reliable) {
+                self.num_beats += 1;
+                self.sum_ms += interval.ms;
+                self.min_ms = self.min_ms.min(interval.ms);
+                self.max_ms = self.max_ms.max(interval.ms);
+            }
+        }
+    }
+
+    pub fn is_done(&self, start_time: Instant) -> bool {
+        elapsed_s(start_time) >= self.start_s + MEASUREMENT_DURATION_S
+    }
+
+    /// Schedules the next measurement without logging anything, e.g. because the watch is not
+    /// worn.
+    pub fn cancel(self, start_time: Instant) {
+        schedule_next_measurement(start_time);
+    }
+
+    /// Schedules the next measurement, returns `None` if the heart rate could not be measured.
+    pub fn finish(self, start_time: Instant) -> Option<LogEntry> {
+        schedule_next_measurement(start_time);
+        if self.num_beats < MIN_BEATS {
+            return None;
+        }
+        let bpm = |ms: f32| (60.0 * 1000.0 / ms).min(u8::MAX as f32) as u8;
+        Some(LogEntry {
+            minute: 0,
+            // The longest interval is the lowest rate.
+            min_bpm: bpm(self.max_ms),
+            max_bpm: bpm(self.min_ms),
+            mean_bpm: bpm(self.sum_ms / self.num_beats as f32),
+            _padding: 0,
+        })
+    }
+}
+
+const CHART_LEFT: i32 = 24;
+const CHART_RIGHT: i32 = 172;
+const CHART_TOP: i32 = 36;
+const CHART_BOTTOM: i32 = 136;
+
+/// Entries of the last 24 hours with their age in minutes.
+fn last_day(fs: &Filesystem, now: DateTime<Utc>) -> ArrayVec<(i64, LogEntry), MAX_ENTRIES_PER_DAY> {
+    let today = now.date_naive();
+    let yesterday = today - ChronoDuration::days(1);
+    let mut entries = ArrayVec::new();
+    for date in [yesterday, today] {
+        for e in load_day(fs, date) {
+            let at = date.and_hms_opt(0, 0, 0).unwrap().and_utc()
+                + ChronoDuration::minutes(e.minute as i64);
+            let age = (now - at).num_minutes();
+            if (0..24 * 60).contains(&age) && !entries.is_full() {
+                entries.push((age, e));
+            }
+        }
+    }
+    entries
+}
+
+fn draw_chart(lcd: &mut Buffer, entries: &[(i64, LogEntry)]) {
+    let font = &embedded_graphics::mono_font::ascii::FONT_6X10;
+    let label_style = MonoTextStyle::new(font, Rgb111::white());
+
+    let low = entries.iter().map(|(_, e)| e.min_bpm).min().unwrap_or(50);
+    let high = entries.iter().map(|(_, e)| e.max_bpm).max().unwrap_or(100);
+    // Round to tens with some room, so that the labels are even.
+    let low = (low as i32 / 10) * 10;
+    let high = (high as i32 / 10 + 1) * 10;
+
+    let x = |age: i64| CHART_RIGHT - (age as i32 * (CHART_RIGHT - CHART_LEFT)) / (24 * 60);
+    let y = |bpm: u8| {
+        CHART_BOTTOM - ((bpm as i32 - low) * (CHART_BOTTOM - CHART_TOP)) / (high - low).max(1)
+    };
+
+    let axis = PrimitiveStyle::with_stroke(Rgb111::white(), 1);
+    Line::new(
+        Point::new(CHART_LEFT, CHART_BOTTOM),
+        Point::new(CHART_RIGHT, CHART_BOTTOM),
+    )
+    .into_styled(axis)
+    .draw(lcd)
+    .unwrap();
+    Line::new(
+        Point::new(CHART_LEFT, CHART_TOP),
+        Point::new(CHART_LEFT, CHART_BOTTOM),
+    )
+    .into_styled(axis)
+    .draw(lcd)
+    .unwrap();
+    for (bpm, at) in [(high, CHART_TOP), (low, CHART_BOTTOM)] {
+        let label = arrform!(3, "{}", bpm);
+        Text::new(label.as_str(), Point::new(0, at + 4), label_style)
+            .draw(lcd)
+            .unwrap();
+    }
+    for (hours, label) in [(24, "-24h"), (12, "-12h"), (0, "now")] {
+        let at = x(hours * 60) - (label.len() as i32 * 6) / 2;
+        Text::new(label, Point::new(at.max(0), CHART_BOTTOM + 12), label_style)
+            .draw(lcd)
+            .unwrap();
+    }
+
+    for (age, e) in entries {
+        let x = x(*age);
+        Line::new(Point::new(x, y(e.min_bpm)), Point::new(x, y(e.max_bpm)))
+            .into_styled(PrimitiveStyle::with_stroke(Rgb111::red(), 1))
+            .draw(lcd)
+            .unwrap();
+        lcd.set(y(e.mean_bpm), x, Rgb111::yellow());
+        lcd.set(y(e.mean_bpm), x + 1, Rgb111::yellow());
+    }
+}
+
+pub async fn hr_log(ctx: &mut Context) {
+    let font = &embedded_graphics::mono_font::ascii::FONT_8X13;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut ticker = Ticker::every(Duration::from_secs(MEASUREMENT_INTERVAL_S as u64));
+
+    ctx.lcd.on().await;
+    loop {
+        save_pending(&mut ctx.flash).await;
+
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        match time::now_utc() {
+            None => {
+                Text::new("Time unknown", Point::new(0, 40), sl)
+                    .draw(&mut *ctx.lcd)
+                    .unwrap();
+            }
+            Some(now) => {
+                let entries = ctx
+                    .flash
+                    .with_fs(|fs| Ok(last_day(fs, now)))
+                    .await
+                    .unwrap_or_default();
+
+                let summary = match (
+                    entries.iter().map(|(_, e)| e.min_bpm).min(),
+                    entries.iter().map(|(_, e)| e.max_bpm).max(),
+                    // The calmest measurement of the day
+                    entries.iter().map(|(_, e)| e.mean_bpm).min(),
+                ) {
+                    (Some(min), Some(max), Some(rest)) => {
+                        arrform!(24, "{}-{} rest {}", min, max, rest)
+                    }
+                    _ => arrform!(24, "No measurements"),
+                };
+                Text::new(summary.as_str(), Point::new(0, 28), sl)
+                    .draw(&mut *ctx.lcd)
+                    .unwrap();
+
+                draw_chart(&mut ctx.lcd, &entries);
+
+                if let Some(last) = entries.iter().min_by_key(|(age, _)| *age) {
+                    let mut w = ArrForm::<24>::new();
+                    let _ = write!(w, "Last {} ({}min ago)", last.1.mean_bpm, last.0);
+                    Text::new(w.as_str(), Point::new(0, 172), sl)
+                        .draw(&mut *ctx.lcd)
+                        .unwrap();
+                }
+            }
+        }
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ticker.next(),
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+        )
+        .await
+        {
+            select::Either3::First(_) => {}
+            select::Either3::Second(_d) => {
+                break;
+            }
+            select::Either3::Third(_event) => {}
+        }
+    }
+}
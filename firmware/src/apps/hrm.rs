@@ -1,8 +1,10 @@
+use arrayvec::ArrayVec;
 use arrform::*;
 use core::fmt::Write;
 use drivers::accel::Reading;
+use drivers::flash::FlashRessources;
+use drivers::hrm::{Record, RecordingInfo, SensorSettings, RECORDING_VERSION};
 use drivers::lpm013m1126c::Rgb111;
-use drivers::time::Duration;
 use drivers::{futures::select, time::Instant};
 use embedded_graphics::{
     geometry::{Point, Size},
@@ -24,11 +26,137 @@ struct DrawState {
     bpm_detector: HeartbeatDetector<SampleCountingEstimator>,
 }
 
+/// The accelerometer is configured for 25Hz below.
+const ACCEL_MS_PER_SAMPLE: f32 = 40.0;
+
+/// Records (see `drivers::hrm::Record`) are collected here and appended to the file when it is
+/// full, so a recording can go on for as long as there is space in the flash.
+struct Recording {
+    since: Instant,
+    path: PathBuf,
+    buffer: ArrayVec<u8, 2048>,
+    /// Last settings that were recorded
+    settings: Option<SensorSettings>,
+    bytes_written: usize,
+    /// Alarms must not end a recording without flushing it.
+    _busy: crate::background::Busy,
+}
+
+impl Recording {
+    async fn start(flash: &mut FlashRessources, info: RecordingInfo) -> Self {
+        let path = flash
+            .with_fs(|fs| {
+                fs.create_dir_all(b"/hrm/\0".try_into().unwrap())?;
+                for i in 0.. {
+                    let path = PathBuf::from(arrform!(40, "/hrm/rec{}.bin", i).as_str());
+                    if fs.metadata(&path) == Err(littlefs2::io::Error::NoSuchEntry) {
+                        return Ok(path);
+                    }
+                }
+                panic!("Too many recordings");
+            })
+            .await
+            .unwrap();
+        let mut recording = Recording {
+            since: Instant::now(),
+            path,
+            buffer: ArrayVec::new(),
+            settings: None,
+            bytes_written: 0,
+            _busy: crate::background::busy(),
+        };
+        recording.add(flash, Record::Info(info)).await;
+        recording
+    }
+
+    fn timestamp_ms(&self) -> u32 {
+        self.since.elapsed().as_millis() as u32
+    }
+
+    async fn add(&mut self, flash: &mut FlashRessources, record: Record) {
+        self.add_at(flash, self.timestamp_ms(), record).await;
+    }
+
+    async fn add_at(&mut self, flash: &mut FlashRessources, timestamp_ms: u32, record: Record) {
+        if self.buffer.remaining_capacity() < record.record_len() {
+            self.flush(flash).await;
+        }
+        self.buffer
+            .try_extend_from_slice(bytemuck::bytes_of(&record.header(timestamp_ms)))
+            .unwrap();
+        self.buffer.try_extend_from_slice(record.payload()).unwrap();
+    }
+
+    /// Records the settings if they changed and the samples of one event.
+    async fn add_event(
+        &mut self,
+        flash: &mut FlashRessources,
+        settings: SensorSettings,
+        samples: &[i16],
+        accel: &[Reading],
+    ) {
+        // The same for all records, a flush in between must not shift the samples in time.
+        let timestamp_ms = self.timestamp_ms();
+        if self.settings != Some(settings) {
+            self.settings = Some(settings);
+            self.add_at(flash, timestamp_ms, Record::Settings(settings))
+                .await;
+        }
+        for record in Record::hrm_samples(samples) {
+            self.add_at(flash, timestamp_ms, record).await;
+        }
+        let accel = accel
+            .iter()
+            .map(|r| [r.x, r.y, r.z])
+            .collect::<ArrayVec<_, 40>>();
+        for record in Record::accel_samples(&accel) {
+            self.add_at(flash, timestamp_ms, record).await;
+        }
+    }
+
+    async fn flush(&mut self, flash: &mut FlashRessources) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        flash
+            .with_fs(|fs| {
+                fs.open_file_with_options_and_then(
+                    |o| o.write(true).create(true).append(true),
+                    &self.path,
+                    |file| {
+                        use littlefs2::io::Write;
+                        file.write_all(&self.buffer)?;
+                        Ok(())
+                    },
+                )
+            })
+            .await
+            .unwrap();
+        self.bytes_written += self.buffer.len();
+        self.buffer.clear();
+    }
+}
+
+fn recording_info(hrm: &drivers::hrm::Hrm<'_>, draw_state: &mut DrawState) -> RecordingInfo {
+    let ms_per_sample = draw_state.bpm_detector.millis_per_sample();
+    RecordingInfo {
+        version: RECORDING_VERSION,
+        hrm_sample_delay: hrm.sample_delay(),
+        // Not measured yet right after (re)starting the detector
+        hrm_ms_per_sample: if ms_per_sample.is_finite() && ms_per_sample > 0.0 {
+            ms_per_sample
+        } else {
+            0.0
+        },
+        accel_ms_per_sample: ACCEL_MS_PER_SAMPLE,
+    }
+}
+
 impl Default for DrawState {
     fn default() -> Self {
         DrawState {
             filtered: Default::default(),
-            bpm_detector: hrm::HeartbeatDetector::new(SampleCountingEstimator::new()),
+            bpm_detector: HeartbeatDetector::new(SampleCountingEstimator::new()),
         }
     }
 }
@@ -40,7 +168,8 @@ pub async fn hrm(ctx: &mut Context) {
     //let sl = TextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
     let sl = MonoTextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
 
-    let mut hrm = ctx.hrm.on(&ctx.twi).await;
+    let mut sensor = ctx.hrm.take().await;
+    let mut hrm = sensor.on(ctx.twi).await;
     hrm.enable().await;
 
     let mut last_bpm = None;
@@ -72,20 +201,14 @@ pub async fn hrm(ctx: &mut Context) {
         text: "-",
     });
 
-    enum State {
-        Idle,
-        Recording {
-            since: Instant,
-            path: PathBuf,
-            samples: [i16; 2000],
-            sample: usize,
-            accel_path: PathBuf,
-            accel_samples: [Reading; 2000],
-            accel_sample: usize,
-        },
-    }
+    let mut stop_button = crate::ui::Button::from(crate::ui::ButtonDefinition {
+        position: Point::new(0, 126),
+        size: Size::new(50, 50),
+        style: &button_style,
+        text: "Stop",
+    });
 
-    let mut state = State::Idle;
+    let mut recording: Option<Recording> = None;
 
     let mut draw_state = DrawState::default();
     let mut last_res = 0u8;
@@ -118,6 +241,12 @@ pub async fn hrm(ctx: &mut Context) {
                     last_res = r.pd_res_value[0];
                     draw_state = Default::default();
                 }
+                // Start over when the watch is put on, the estimate from before is meaningless.
+                if hrm.wear_changed() == Some(true) {
+                    draw_state = Default::default();
+                    last_bpm = None;
+                }
+                let wearing = hrm.wearing();
 
                 if let Some(sample_vals) = s {
                     //let _ = writeln!(w, "s: {:?}", sample_vals);
@@ -128,84 +257,26 @@ pub async fn hrm(ctx: &mut Context) {
                     //    crate::println!("{}", r);
                     //}
 
-                    for sample in &sample_vals {
+                    for sample in sample_vals.iter() {
+                        if !wearing {
+                            // Still recorded below, but not estimated from.
+                            continue;
+                        }
                         let (filtered, bpm) = draw_state.bpm_detector.add_sample(*sample);
-                        if let Some(bpm) = bpm {
-                            last_bpm = Some(bpm);
+                        if let Some(estimate) = bpm {
+                            if estimate.quality.is_reliable() {
+                                crate::faces::note_heart_rate(ctx.start_time, estimate.bpm.0);
+                            }
+                            last_bpm = Some(estimate);
                         }
 
                         draw_state.filtered.add(filtered);
                     }
-                    if let State::Recording {
-                        since,
-                        path,
-                        sample,
-                        samples,
-                        accel_path,
-                        accel_samples,
-                        accel_sample,
-                    } = &mut state
-                    {
-                        crate::println!("HRM: {}, Accel: {}", sample_vals.len(), accel_read.len());
-                        for val in sample_vals {
-                            if *sample == samples.len() {
-                                break;
-                            }
-                            samples[*sample] = val;
-                            *sample += 1;
-                        }
-
-                        for val in accel_read {
-                            if *accel_sample == accel_samples.len() {
-                                break;
-                            }
-                            accel_samples[*accel_sample] = *val;
-                            *accel_sample += 1;
-                        }
-                        if since.elapsed() > Duration::from_secs(1000)
-                            || *sample == samples.len()
-                            || *accel_sample == accel_samples.len()
-                        {
-                            ctx.flash
-                                .with_fs(|fs| {
-                                    fs.open_file_with_options_and_then(
-                                        |o| o.write(true).create(true).append(true),
-                                        &path,
-                                        |file| {
-                                            use littlefs2::io::Write;
-                                            for i in 0..*sample {
-                                                let sample_val = samples[i];
-                                                let content = arrform!(40, "{}\n", sample_val);
-                                                file.write_all(content.as_bytes())?;
-                                            }
-                                            Ok(())
-                                        },
-                                    )?;
-                                    fs.open_file_with_options_and_then(
-                                        |o| o.write(true).create(true).append(true),
-                                        &accel_path,
-                                        |file| {
-                                            use littlefs2::io::Write;
-                                            for i in 0..*accel_sample {
-                                                let sample_val = accel_samples[i];
-                                                let content = arrform!(
-                                                    120,
-                                                    "{},{},{}\n",
-                                                    sample_val.x,
-                                                    sample_val.y,
-                                                    sample_val.z
-                                                );
-                                                file.write_all(content.as_bytes())?;
-                                            }
-                                            Ok(())
-                                        },
-                                    )
-                                })
-                                .await
-                                .unwrap();
-
-                            state = State::Idle;
-                        }
+                    if let Some(recording) = &mut recording {
+                        let settings = SensorSettings::from_read_result(&r);
+                        recording
+                            .add_event(&mut ctx.flash, settings, &sample_vals, accel_read)
+                            .await;
                     }
                 }
 
@@ -238,15 +309,27 @@ pub async fn hrm(ctx: &mut Context) {
                 let mut w =
                     TextWriter::new(&mut ctx.lcd, sl).y(10 + font.character_size.height as i32);
 
-                if let State::Idle = state {
+                if recording.is_none() {
                     let valid_vals = draw_state.filtered.valid_values();
                     if !valid_vals.is_empty() {
                         let min = *min.unwrap();
                         let max = *max.unwrap();
                         let _ = writeln!(w, "range: [{}, {}]", min, max);
                     }
-                    if let Some(bpm) = last_bpm.as_ref() {
-                        let _ = writeln!(w, "bpm: {}", bpm.0);
+                    if !wearing {
+                        let _ = writeln!(w, "bpm: not worn");
+                    } else if let Some(estimate) = last_bpm.as_ref() {
+                        // Unreliable values are marked, as they are likely the cadence or noise.
+                        let mark = if estimate.quality.is_reliable() {
+                            ""
+                        } else {
+                            "?"
+                        };
+                        let _ = writeln!(
+                            w,
+                            "bpm: {}{} q: {:.2}",
+                            estimate.bpm.0, mark, estimate.quality.0
+                        );
                     } else {
                         let _ = writeln!(w, "bpm: ??");
                     }
@@ -260,9 +343,18 @@ pub async fn hrm(ctx: &mut Context) {
                     let _ = writeln!(w, "cur: {:?}", r.current_value);
                     let _ = writeln!(w, "pre: {:?}", r.pre_value);
                     let _ = writeln!(w, "irq_status: {}", r.irq_status);
+                } else if let Some(recording) = &recording {
+                    let _ = writeln!(
+                        w,
+                        "rec: {}s {}kB",
+                        recording.since.elapsed().as_secs(),
+                        (recording.bytes_written + recording.buffer.len()) / 1024
+                    );
                 }
 
-                if matches!(state, State::Idle) {
+                if recording.is_some() {
+                    stop_button.render(&mut *ctx.lcd).unwrap();
+                } else {
                     record_button.render(&mut *ctx.lcd).unwrap();
                 }
                 plus_button.render(&mut *ctx.lcd).unwrap();
@@ -271,56 +363,43 @@ pub async fn hrm(ctx: &mut Context) {
                 ctx.lcd.present().await;
             }
             select::Either3::Second(_d) => {
+                if let Some(mut recording) = recording.take() {
+                    recording.flush(&mut ctx.flash).await;
+                }
                 break;
             }
             select::Either3::Third(e) => {
                 ctx.backlight.active().await;
-                if record_button.clicked(&e) {
-                    let (path, accel_path) = ctx
-                        .flash
-                        .with_fs(|fs| {
-                            fs.create_dir_all(b"/hrm/\0".try_into().unwrap())?;
-                            for i in 0.. {
-                                let path =
-                                    PathBuf::from(arrform!(40, "/hrm/samples{}.bin", i).as_str());
-                                let accel_path = PathBuf::from(
-                                    arrform!(40, "/hrm/accel_samples{}.bin", i).as_str(),
-                                );
-                                if fs.metadata(&path) == Err(littlefs2::io::Error::NoSuchEntry)
-                                    && fs.metadata(&accel_path)
-                                        == Err(littlefs2::io::Error::NoSuchEntry)
-                                {
-                                    return Ok((path, accel_path));
-                                }
-                            }
-                            panic!("Too many recordings");
-                        })
-                        .await
-                        .unwrap();
-                    state = State::Recording {
-                        since: Instant::now(),
-                        path,
-                        samples: [0; 2000],
-                        sample: 0,
-                        accel_path,
-                        accel_samples: [Default::default(); 2000],
-                        accel_sample: 0,
+                if recording.is_none() {
+                    if record_button.clicked(&e) {
+                        let info = recording_info(&hrm, &mut draw_state);
+                        recording = Some(Recording::start(&mut ctx.flash, info).await);
                     }
+                } else if stop_button.clicked(&e) {
+                    recording.take().unwrap().flush(&mut ctx.flash).await;
                 }
-                if plus_button.clicked(&e) {
+                let plus = plus_button.clicked(&e);
+                let minus = minus_button.clicked(&e);
+                if plus {
                     hrm.update_sample_delay(|c| {
                         *c += 1;
                         defmt::println!("Delay: {}", *c);
                     })
                     .await;
                 }
-                if minus_button.clicked(&e) {
+                if minus {
                     hrm.update_sample_delay(|c| {
                         *c -= 1;
                         defmt::println!("Delay: {}", *c);
                     })
                     .await;
                 }
+                if plus || minus {
+                    if let Some(recording) = &mut recording {
+                        let info = recording_info(&hrm, &mut draw_state);
+                        recording.add(&mut ctx.flash, Record::Info(info)).await;
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,309 @@
+use core::fmt::Write;
+use drivers::futures::select;
+use drivers::lpm013m1126c::Rgb111;
+use drivers::time::{self, Instant};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    mono_font::MonoTextStyle,
+    pixelcolor::BinaryColor,
+};
+use hrm::hrv::{ArtifactRejection, HrvMetrics, HrvWindow};
+use hrm::HeartbeatDetector;
+use littlefs2::path::Path;
+
+use crate::util::SampleCountingEstimator;
+use crate::{
+    render_top_bar,
+    ui::{ButtonStyle, TextWriter},
+    Context, Filesystem,
+};
+
+const RESULTS_FILE: &Path = &Path::from_str_with_nul("readiness.bin\0");
+
+const MEASUREMENT_SECS: u64 = 120;
+/// Room for the whole measurement even at a high heart rate.
+const MAX_INTERVALS: usize = 256;
+/// Results are only stored when the accepted intervals cover at least this much of the
+/// measurement.
+const MIN_COVERAGE: f32 = 0.6;
+/// Number of previous results the new one is compared to.
+const HISTORY: usize = 7;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ReadinessResult {
+    /// Unix time, 0 if the time was unknown
+    pub timestamp: i64,
+    pub rmssd_ms: f32,
+    pub sdnn_ms: f32,
+    pub pnn50: f32,
+    pub heart_rate: f32,
+}
+
+impl ReadinessResult {
+    fn is_valid(&self) -> bool {
+        self.rmssd_ms > 0.0
+    }
+}
+
+/// The last `HISTORY` results, the oldest is overwritten first.
+#[repr(C)]
+#[derive(Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Results {
+    results: [ReadinessResult; HISTORY],
+    next: u32,
+    _padding: u32,
+}
+
+impl Results {
+    pub fn load(fs: &Filesystem) -> littlefs2::io::Result<Self> {
+        fs.open_file_with_options_and_then(
+            |options| options.read(true).create(true),
+            RESULTS_FILE,
+            |file| {
+                let mut r = Self::default();
+                file.read(bytemuck::bytes_of_mut(&mut r))?;
+                Ok(r)
+            },
+        )
+    }
+
+    pub fn save(&self, fs: &Filesystem) -> littlefs2::io::Result<()> {
+        fs.open_file_with_options_and_then(
+            |options| options.write(true).create(true).truncate(true),
+            RESULTS_FILE,
+            |file| {
+                let s = bytemuck::bytes_of(self);
+                let written = file.write(s)?;
+                assert_eq!(written, s.len());
+                Ok(())
+            },
+        )
+    }
+
+    pub fn add(&mut self, result: ReadinessResult) {
+        let next = self.next as usize % HISTORY;
+        self.results[next] = result;
+        self.next = ((next + 1) % HISTORY) as u32;
+    }
+
+    fn last_index(&self) -> usize {
+        (self.next as usize + HISTORY - 1) % HISTORY
+    }
+
+    pub fn last(&self) -> Option<&ReadinessResult> {
+        Some(&self.results[self.last_index()]).filter(|r| r.is_valid())
+    }
+
+    /// Mean RMSSD of all results but the last one, if there are enough of them to be meaningful.
+    pub fn baseline_rmssd_ms(&self) -> Option<f32> {
+        let last = self.last_index();
+        let (n, sum) = self
+            .results
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| *i != last && r.is_valid())
+            .fold((0, 0.0), |(n, sum), (_, r)| (n + 1, sum + r.rmssd_ms));
+        (n >= 3).then(|| sum / n as f32)
+    }
+}
+
+/// A lower RMSSD than usual is a sign of stress, illness or missing recovery.
+fn verdict(rmssd_ms: f32, baseline_ms: f32) -> &'static str {
+    let ratio = rmssd_ms / baseline_ms;
+    if ratio < 0.85 {
+        "Take it easy"
+    } else if ratio > 1.15 {
+        "Well rested"
+    } else {
+        "Normal"
+    }
+}
+
+fn write_result(w: &mut TextWriter<'_, MonoTextStyle<'_, BinaryColor>>, results: &Results) {
+    let Some(r) = results.last() else {
+        let _ = writeln!(w, "No reading yet");
+        return;
+    };
+    let _ = writeln!(w, "RMSSD: {:.0}ms", r.rmssd_ms);
+    let _ = writeln!(w, "SDNN:  {:.0}ms", r.sdnn_ms);
+    let _ = writeln!(w, "pNN50: {:.0}%", r.pnn50);
+    let _ = writeln!(w, "HR:    {:.0}", r.heart_rate);
+    if let Some(baseline) = results.baseline_rmssd_ms() {
+        let _ = writeln!(w, "Usual: {:.0}ms", baseline);
+        let _ = writeln!(w, "{}", verdict(r.rmssd_ms, baseline));
+    }
+}
+
+/// Morning readiness: heart rate variability from a two minute reading while lying or sitting
+/// still.
+pub async fn readiness(ctx: &mut Context) {
+    let mut results = ctx
+        .flash
+        .with_fs(|fs| Results::load(fs))
+        .await
+        .unwrap_or_default();
+    let mut message = None;
+
+    while show_results(ctx, &results, message).await {
+        message = match measure(ctx).await {
+            Reading::Done(metrics) => {
+                results.add(ReadinessResult {
+                    timestamp: time::now_utc().map_or(0, |t| t.timestamp()),
+                    rmssd_ms: metrics.rmssd_ms,
+                    sdnn_ms: metrics.sdnn_ms,
+                    pnn50: metrics.pnn50,
+                    heart_rate: metrics.heart_rate(),
+                });
+                ctx.flash.with_fs(|fs| results.save(fs)).await.unwrap();
+                None
+            }
+            Reading::Cancelled => None,
+            Reading::Failed(message) => Some(message),
+        };
+    }
+}
+
+/// Shows the last result (or `message` instead), returns whether a measurement should be started.
+async fn show_results(ctx: &mut Context, results: &Results, message: Option<&str>) -> bool {
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, BinaryColor::On);
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+    let mut start_button = crate::ui::Button::from(crate::ui::ButtonDefinition {
+        position: Point::new(48, 140),
+        size: Size::new(80, 32),
+        style: &button_style,
+        text: "Start",
+    });
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let mut w = TextWriter::new(&mut ctx.lcd, sl).y(10 + font.character_size.height as i32);
+        match message {
+            Some(message) => {
+                let _ = writeln!(w, "{}", message);
+            }
+            None => write_result(&mut w, results),
+        }
+
+        start_button.render(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either3::First(_d) => {
+                break false;
+            }
+            select::Either3::Second(_event) => {}
+            select::Either3::Third(e) => {
+                if start_button.clicked(&e) {
+                    break true;
+                }
+            }
+        }
+    }
+}
+
+/// Too few beats were detected, or not for long enough.
+const TOO_MANY_ARTIFACTS: &str = "Too many\nartifacts,\nplease retry";
+const NOT_WORN: &str = "Watch not\nworn,\nplease retry";
+
+enum Reading {
+    Done(HrvMetrics),
+    /// Stopped with the button, the results are shown as before.
+    Cancelled,
+    /// With the message to show instead of the results.
+    Failed(&'static str),
+}
+
+async fn measure(ctx: &mut Context) -> Reading {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, BinaryColor::On);
+
+    let mut sensor = ctx.hrm.take().await;
+    let mut hrm = sensor.on(ctx.twi).await;
+    hrm.enable().await;
+
+    let mut detector = HeartbeatDetector::new(SampleCountingEstimator::new());
+    let mut rejection = ArtifactRejection::default();
+    let mut window = HrvWindow::<MAX_INTERVALS>::new((MEASUREMENT_SECS * 1000) as f32);
+    let mut last_res = 0u8;
+
+    let start = Instant::now();
+    loop {
+        let elapsed_s = start.elapsed().as_secs();
+        if elapsed_s >= MEASUREMENT_SECS {
+            break;
+        }
+
+        match select::select(hrm.wait_event(), ctx.button.wait_for_press()).await {
+            select::Either::First((r, s)) => {
+                if !hrm.wearing() {
+                    return Reading::Failed(NOT_WORN);
+                }
+                if r.pd_res_value[0] != last_res {
+                    // The signal jumps, start over with the beat detection.
+                    last_res = r.pd_res_value[0];
+                    detector = HeartbeatDetector::new(SampleCountingEstimator::new());
+                    rejection = ArtifactRejection::default();
+                }
+                for sample in s.iter().flatten() {
+                    if let (_, Some(ms)) = detector.add_sample_interval(*sample) {
+                        if let Some(interval) = rejection.filter(ms) {
+                            window.add(interval);
+                        }
+                    }
+                }
+            }
+            select::Either::Second(_d) => {
+                return Reading::Cancelled;
+            }
+        }
+
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let mut w = TextWriter::new(&mut ctx.lcd, sl).y(10 + font.character_size.height as i32);
+        let _ = writeln!(w, "Keep still");
+        let remaining_s = MEASUREMENT_SECS.saturating_sub(elapsed_s);
+        let _ = writeln!(w, "{}:{:0>2} left", remaining_s / 60, remaining_s % 60);
+        match window.metrics() {
+            Some(m) => {
+                let _ = writeln!(w, "HR:    {:.0}", m.heart_rate());
+                let _ = writeln!(w, "RMSSD: {:.0}ms", m.rmssd_ms);
+                let _ = writeln!(w, "Beats: {}", m.num_intervals);
+            }
+            None => {
+                let _ = writeln!(w, "Waiting for beats");
+            }
+        }
+
+        ctx.lcd.present().await;
+    }
+
+    let coverage = window.duration_ms() / (MEASUREMENT_SECS * 1000) as f32;
+    if coverage < MIN_COVERAGE {
+        return Reading::Failed(TOO_MANY_ARTIFACTS);
+    }
+    match window.metrics() {
+        Some(metrics) => Reading::Done(metrics),
+        None => Reading::Failed(TOO_MANY_ARTIFACTS),
+    }
+}
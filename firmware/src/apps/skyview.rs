@@ -0,0 +1,214 @@
+use arrayvec::ArrayVec;
+use core::fmt::Write;
+use drivers::futures::select;
+use drivers::gps::{CasicMsg, CasicMsgConfig, GPSReceiver, GnssSystem, SvInfo, MAX_SV_INFOS};
+use drivers::lpm013m1126c::{Buffer, Rgb111, WIDTH};
+use drivers::time::{Duration, Instant, Ticker};
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Circle, Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::Text;
+
+#[cfg(target_arch = "arm")]
+use micromath::F32Ext;
+
+use crate::{render_top_bar, ui::TextWriter, Context};
+
+const SYSTEMS: [GnssSystem; 3] = [GnssSystem::Gps, GnssSystem::Bds, GnssSystem::Glonass];
+
+const PLOT_CENTER: Point = Point::new(WIDTH as i32 / 2, 78);
+const PLOT_RADIUS: i32 = 58;
+
+const BAR_BASE: i32 = 175;
+const BAR_MAX_HEIGHT: i32 = 32;
+const BAR_WIDTH: i32 = 4;
+/// C/N0 (in dB-Hz) that corresponds to a full bar. Anything above 45 is very good.
+const MAX_CN0: i32 = 50;
+/// C/N0 (in dB-Hz) below which a signal hardly contributes to a fix.
+const WEAK_CN0: u8 = 30;
+
+fn color(system: GnssSystem) -> Rgb111 {
+    match system {
+        GnssSystem::Gps => Rgb111::green(),
+        GnssSystem::Bds => Rgb111::red(),
+        GnssSystem::Glonass => Rgb111::cyan(),
+    }
+}
+
+/// Colored by constellation. The display has no shades, so weak signals are only outlined.
+fn style(system: GnssSystem, cn0: u8) -> PrimitiveStyle<Rgb111> {
+    if cn0 < WEAK_CN0 {
+        PrimitiveStyle::with_stroke(color(system), 1)
+    } else {
+        PrimitiveStyle::with_fill(color(system))
+    }
+}
+
+fn short_name(system: GnssSystem) -> &'static str {
+    match system {
+        GnssSystem::Gps => "G",
+        GnssSystem::Bds => "B",
+        GnssSystem::Glonass => "R",
+    }
+}
+
+#[derive(Default)]
+struct SystemState {
+    num_view: u8,
+    num_fix: u8,
+    svs: ArrayVec<SvInfo, MAX_SV_INFOS>,
+}
+
+fn sky_position(sv: &SvInfo) -> Point {
+    let r = PLOT_RADIUS as f32 * (90 - sv.elevation.clamp(0, 90)) as f32 / 90.0;
+    let azimuth = (sv.azimuth as f32).to_radians();
+    PLOT_CENTER + Point::new((r * azimuth.sin()) as i32, -(r * azimuth.cos()) as i32)
+}
+
+fn draw_sky(lcd: &mut Buffer, systems: &[SystemState; 3]) {
+    let grid = PrimitiveStyle::with_stroke(Rgb111::white(), 1);
+    for elevation in [0, 30, 60] {
+        let r = PLOT_RADIUS * (90 - elevation) / 90;
+        Circle::with_center(PLOT_CENTER, 2 * r as u32 + 1)
+            .into_styled(grid)
+            .draw(lcd)
+            .unwrap();
+    }
+    Line::new(
+        PLOT_CENTER - Point::new(0, PLOT_RADIUS),
+        PLOT_CENTER + Point::new(0, PLOT_RADIUS),
+    )
+    .into_styled(grid)
+    .draw(lcd)
+    .unwrap();
+    Line::new(
+        PLOT_CENTER - Point::new(PLOT_RADIUS, 0),
+        PLOT_CENTER + Point::new(PLOT_RADIUS, 0),
+    )
+    .into_styled(grid)
+    .draw(lcd)
+    .unwrap();
+
+    let sl = MonoTextStyle::new(
+        &embedded_graphics::mono_font::ascii::FONT_6X10,
+        Rgb111::white(),
+    );
+    Text::new("N", PLOT_CENTER + Point::new(3, 8 - PLOT_RADIUS), sl)
+        .draw(lcd)
+        .unwrap();
+
+    for (system, state) in SYSTEMS.iter().zip(systems) {
+        for sv in &state.svs {
+            // Stronger signals are drawn larger
+            let diameter = 3 + sv.cn0.min(MAX_CN0 as u8) as u32 / 8;
+            Circle::with_center(sky_position(sv), diameter)
+                .into_styled(style(*system, sv.cn0))
+                .draw(lcd)
+                .unwrap();
+        }
+    }
+}
+
+fn draw_signal_bars(lcd: &mut Buffer, systems: &[SystemState; 3]) {
+    let mut bars = SYSTEMS
+        .iter()
+        .zip(systems)
+        .flat_map(|(system, state)| state.svs.iter().map(|sv| (*system, sv.cn0)))
+        .collect::<ArrayVec<_, { 3 * MAX_SV_INFOS }>>();
+    bars.sort_unstable_by_key(|(_, cn0)| core::cmp::Reverse(*cn0));
+
+    let max_bars = WIDTH as i32 / (BAR_WIDTH + 1);
+    for (i, (system, cn0)) in bars.iter().take(max_bars as usize).enumerate() {
+        let height = (*cn0 as i32).min(MAX_CN0) * BAR_MAX_HEIGHT / MAX_CN0;
+        Rectangle::new(
+            Point::new(i as i32 * (BAR_WIDTH + 1), BAR_BASE - height),
+            Size::new(BAR_WIDTH as u32, height as u32),
+        )
+        .into_styled(style(*system, *cn0))
+        .draw(lcd)
+        .unwrap();
+    }
+}
+
+pub async fn skyview(ctx: &mut Context) {
+    let mut gps = GPSReceiver::new(CasicMsgConfig {
+        nav_pv: 1,
+        nav_gps_info: 1,
+        nav_bds_info: 1,
+        nav_gln_info: 1,
+        ..Default::default()
+    })
+    .await;
+
+    // Same satellite systems as when tracking, so that we see what the track app would see.
+    let settings = ctx
+        .flash
+        .with_fs(|fs| crate::settings::Settings::load(fs))
+        .await
+        .unwrap_or_default();
+    gps.update_receiver_config(settings.track_receiver_config())
+        .await;
+
+    let start = Instant::now();
+    crate::agps::inject(&mut ctx.flash, &mut gps).await;
+
+    let sl = MonoTextStyle::new(
+        &embedded_graphics::mono_font::ascii::FONT_6X10,
+        embedded_graphics::pixelcolor::BinaryColor::On,
+    );
+
+    let mut systems: [SystemState; 3] = Default::default();
+    let mut ttff = None;
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        draw_sky(&mut ctx.lcd, &systems);
+        draw_signal_bars(&mut ctx.lcd, &systems);
+
+        let mut w = TextWriter::new(&mut ctx.lcd, sl).y(26);
+        match ttff {
+            Some(ttff) => {
+                let _ = writeln!(w, "fix\n{}s", ttff.as_secs());
+            }
+            None => {
+                let _ = writeln!(w, "no fix\n{}s", start.elapsed().as_secs());
+            }
+        }
+        for (system, state) in SYSTEMS.iter().zip(&systems) {
+            let _ = writeln!(
+                w,
+                "{} {}/{}",
+                short_name(*system),
+                state.num_fix,
+                state.num_view
+            );
+        }
+
+        ctx.lcd.present().await;
+
+        match select::select3(gps.receive(), ticker.next(), ctx.button.wait_for_press()).await {
+            select::Either3::First(msg) => match msg {
+                CasicMsg::NavSvInfo(i) => {
+                    let i_system = SYSTEMS.iter().position(|s| *s == i.system).unwrap();
+                    systems[i_system] = SystemState {
+                        num_view: i.header.num_view_sv,
+                        num_fix: i.header.num_fix_sv,
+                        svs: i.svs,
+                    };
+                }
+                CasicMsg::NavPv(pv) if ttff.is_none() && pv.has_fix() => {
+                    ttff = Some(start.elapsed());
+                }
+                _ => {}
+            },
+            select::Either3::Second(_) => {}
+            select::Either3::Third(_) => {
+                break;
+            }
+        }
+    }
+}
@@ -77,6 +77,9 @@ pub async fn stopwatch(ctx: &mut Context) {
     ctx.lcd.fill(bg);
     ctx.lcd.on().await;
     loop {
+        // Only a stopwatch that was never started can be interrupted by an alarm.
+        let _busy = (!matches!(state, State::Stopped)).then(crate::background::busy);
+
         render_top_bar(&mut ctx.lcd, &ctx.battery).await;
 
         let duration = match state {
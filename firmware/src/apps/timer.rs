@@ -34,6 +34,7 @@ pub async fn timer(ctx: &mut Context) {
         if let Flow::Stop = configure_timer(ctx, &mut timer_duration).await {
             break;
         }
+        let _busy = crate::background::busy();
         if let TimerResult::Finished = run_timer(ctx, timer_duration).await {
             buzz_msg(ctx, "Timer elapsed!").await
         }
@@ -3,7 +3,7 @@ use bitmap_font::TextStyle;
 use bytemuck::Zeroable;
 use core::fmt::Write;
 use drivers::flash::FlashRessources;
-use drivers::gps::{CasicMsg, GPSReceiver, NavGpsInfo, NavigationData};
+use drivers::gps::{CasicMsg, GPSReceiver, GnssSystem, NavGpsInfo, NavigationData};
 use drivers::lpm013m1126c::{Rgb111, WIDTH};
 use drivers::time::{Duration, Instant};
 use drivers::{futures::select, gps::CasicMsgConfig};
@@ -21,6 +21,8 @@ use embedded_icon::mdi::size24px::HeartOutline as Heart;
 use embedded_icon::mdi::size24px::Speedometer as Speed;
 use embedded_icon::mdi::size24px::TransitDetour as Distance;
 
+use crate::agps::AgpsStatus;
+use crate::apps::daylight::LastPosition;
 use crate::ui::ButtonStyle;
 use crate::util::{hours_mins_secs, SampleCountingEstimator};
 use crate::{render_top_bar, ui::TextWriter, Context};
@@ -29,6 +31,8 @@ struct RecordingData {
     path: PathBuf,
     samples: [NavigationData; 32],
     sample: usize,
+    /// Alarms must not end a recording without flushing it.
+    _busy: crate::background::Busy,
 }
 
 impl RecordingData {
@@ -68,7 +72,17 @@ enum RecordingState {
 pub async fn track_app(ctx: &mut Context) {
     let mut gps = drivers::gps::GPSReceiver::new(CasicMsgConfig::default()).await;
 
-    if wait_for_fix(ctx, &mut gps).await.is_ok() {
+    let settings = ctx
+        .flash
+        .with_fs(|fs| crate::settings::Settings::load(fs))
+        .await
+        .unwrap_or_default();
+    gps.update_receiver_config(settings.track_receiver_config())
+        .await;
+
+    let agps = crate::agps::inject(&mut ctx.flash, &mut gps).await;
+
+    if wait_for_fix(ctx, &mut gps, &agps).await.is_ok() {
         show_pos(ctx, &mut gps).await
     }
 }
@@ -144,6 +158,10 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
         last_pos: Vector2<f32>,
         last_pos_smooth: Vector2<f32>,
         bpm: u16,
+        /// Whether `bpm` was estimated from a clean enough signal to be trusted.
+        bpm_reliable: bool,
+        /// The watch is not worn, so there is no heart rate.
+        bpm_paused: bool,
     }
     let mut state = State::default();
 
@@ -168,7 +186,8 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
         text: "Stop",
     });
 
-    let mut hrm = ctx.hrm.on(&ctx.twi).await;
+    let mut sensor = ctx.hrm.take().await;
+    let mut hrm = sensor.on(ctx.twi).await;
     hrm.enable().await;
     let mut bpm_detector = hrm::HeartbeatDetector::new(SampleCountingEstimator::new());
 
@@ -190,6 +209,7 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
     let col2_start = WIDTH as i32 / 2;
 
     let mut start = Instant::now();
+    let mut last_position = None;
 
     loop {
         ctx.lcd.fill(Rgb111::black());
@@ -216,7 +236,12 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
         )
         .draw(&mut **w.display())
         .unwrap();
-        let _ = writeln!(w, "{}", state.bpm);
+        if state.bpm_paused {
+            let _ = writeln!(w, "-");
+        } else {
+            let mark = if state.bpm_reliable { "" } else { "?" };
+            let _ = writeln!(w, "{}{}", state.bpm, mark);
+        }
 
         Image::new(
             &dist_icon,
@@ -263,6 +288,10 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
         .await
         {
             select::Either4::First(msg) => match msg {
+                CasicMsg::NavPv(s) if !s.has_fix() => {
+                    // Estimates (e.g. the last position kept) would distort the track.
+                    state.num_satellites = s.num_sv;
+                }
                 CasicMsg::NavPv(s) => {
                     state.num_satellites = s.num_sv;
                     state.height = s.height_m;
@@ -270,6 +299,10 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
                     let s: NavigationData = s.into();
 
                     crate::println!("pv msg: {:?}", s);
+                    last_position = Some(LastPosition {
+                        longitude: s.longitude,
+                        latitude: s.latitude,
+                    });
                     let r = ref_converter.to_relative_full(&s);
                     state.speed = r.vel.norm();
 
@@ -329,6 +362,7 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
                                 path,
                                 samples: [NavigationData::zeroed(); 32],
                                 sample: 0,
+                                _busy: crate::background::busy(),
                             });
                             state.distance = 0.0;
                             state.distance_smooth = 0.0;
@@ -347,10 +381,19 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
                 }
             }
             select::Either4::Fourth(batch) => {
-                for sample in batch.1.into_iter().flatten() {
-                    if let Some(b) = bpm_detector.add_sample(sample).1 {
+                if hrm.wear_changed() == Some(true) {
+                    bpm_detector = hrm::HeartbeatDetector::new(SampleCountingEstimator::new());
+                }
+                state.bpm_paused = !hrm.wearing();
+                let samples = if state.bpm_paused { None } else { batch.1 };
+                for sample in samples.into_iter().flatten() {
+                    if let Some(e) = bpm_detector.add_sample(sample).1 {
                         //crate::println!("Samples ms: {}:", bpm_detector.millis_per_sample());
-                        state.bpm = b.0;
+                        state.bpm = e.bpm.0;
+                        state.bpm_reliable = e.quality.is_reliable();
+                        if state.bpm_reliable {
+                            crate::faces::note_heart_rate(ctx.start_time, e.bpm.0);
+                        }
                     }
                 }
             }
@@ -360,9 +403,17 @@ pub async fn show_pos(ctx: &mut Context, gps: &mut GPSReceiver<'_>) {
     if let RecordingState::Recording(data) = &mut recording_state {
         data.flush(&mut ctx.flash).await;
     }
+    // Only once per session to spare the flash, that's precise enough for sunrise and sunset.
+    if let Some(p) = last_position {
+        let _ = ctx.flash.with_fs(|fs| p.save(fs)).await;
+    }
 }
 
-pub async fn wait_for_fix(ctx: &mut Context, gps: &mut GPSReceiver<'_>) -> Result<(), ()> {
+pub async fn wait_for_fix(
+    ctx: &mut Context,
+    gps: &mut GPSReceiver<'_>,
+    agps: &AgpsStatus,
+) -> Result<(), ()> {
     gps.update_config(CasicMsgConfig {
         nav_gps_info: 1,
         ..Default::default()
@@ -384,13 +435,36 @@ pub async fn wait_for_fix(ctx: &mut Context, gps: &mut GPSReceiver<'_>) -> Resul
         let mut w = TextWriter::new(&mut ctx.lcd, sl).y(10 + font.character_size.height as i32);
         let _ = writeln!(w, "sat_v: {:?}", state.num_view_sv);
         let _ = writeln!(w, "sat_f: {:?}", state.num_fix_sv);
+        match agps {
+            AgpsStatus::Missing => {
+                let _ = writeln!(w, "agps: -");
+            }
+            AgpsStatus::Invalid => {
+                let _ = writeln!(w, "agps: bad");
+            }
+            AgpsStatus::Stale { age_s } => {
+                let _ = writeln!(w, "agps: old ({}d)", age_s / (24 * 60 * 60));
+            }
+            AgpsStatus::Injected { age_s: Some(age_s) } => {
+                // Only the almanac is of use after a couple of hours
+                let kind = if crate::agps::ephemeris_expired(*age_s) {
+                    " alm"
+                } else {
+                    ""
+                };
+                let _ = writeln!(w, "agps: {}h{}", age_s / (60 * 60), kind);
+            }
+            AgpsStatus::Injected { age_s: None } => {
+                let _ = writeln!(w, "agps: ?h");
+            }
+        }
 
         ctx.lcd.present().await;
 
         match select::select(gps.receive(), ctx.button.wait_for_press()).await {
             select::Either::First(msg) => match msg {
-                CasicMsg::NavGpsInfo(i) => {
-                    state = i;
+                CasicMsg::NavSvInfo(i) if i.system == GnssSystem::Gps => {
+                    state = i.header;
                 }
                 _ => {}
             },
@@ -0,0 +1,307 @@
+use arrform::*;
+use chrono::{DateTime, Timelike, Utc};
+use core::fmt::Write;
+use drivers::{
+    futures::select,
+    lpm013m1126c::Rgb111,
+    time::{self, Duration, Ticker},
+    Context,
+};
+use embedded_graphics::{
+    geometry::{Point, Size},
+    mono_font::MonoTextStyle,
+    text::Text,
+    Drawable as _,
+};
+use embedded_layout::{
+    align::{horizontal, vertical, Align as _},
+    layout::linear::LinearLayout,
+    object_chain::Chain,
+};
+use util::tz::{step_zone_id, TimeZone, Zone};
+
+use crate::{
+    render_top_bar,
+    settings::Settings,
+    ui::{Button, ButtonStyle, EventHandler as _, TextWriter, TouchResult},
+};
+
+pub const MAX_WORLD_ZONES: usize = 4;
+
+/// Zone of a `Settings::world_zones` entry.
+fn zone(entry: u8) -> Option<&'static Zone> {
+    Zone::by_id(entry)
+}
+
+fn offset_s(zone: &Zone, now: DateTime<Utc>) -> Option<i32> {
+    Some(TimeZone::parse(zone.tz)?.offset_s(now))
+}
+
+/// Time in `zone` as "HH:MM".
+fn time_in(zone: &Zone, now: DateTime<Utc>) -> Option<ArrForm<5>> {
+    let t = TimeZone::parse(zone.tz)?.to_local(now);
+    Some(arrform!(5, "{:0>2}:{:0>2}", t.hour(), t.minute()))
+}
+
+/// Difference to home like "+5:30", "-6" or "0".
+fn format_difference(difference_s: i32) -> ArrForm<6> {
+    let minutes = difference_s / 60;
+    let sign = if minutes < 0 { '-' } else { '+' };
+    let (h, m) = (minutes.abs() / 60, minutes.abs() % 60);
+    match (h, m) {
+        (0, 0) => arrform!(6, "0"),
+        (h, 0) => arrform!(6, "{}{}", sign, h),
+        (h, m) => arrform!(6, "{}{}:{:0>2}", sign, h, m),
+    }
+}
+
+/// The zone pinned to the clock face, e.g. "Tokyo 21:30".
+pub fn pinned(settings: &Settings, now: DateTime<Utc>) -> Option<ArrForm<16>> {
+    let slot = settings.pinned_world_zone.checked_sub(1)?;
+    let zone = zone(*settings.world_zones.get(slot as usize)?)?;
+    let name = zone.name.get(..10).unwrap_or(zone.name);
+    Some(arrform!(16, "{} {}", name, time_in(zone, now)?.as_str()))
+}
+
+pub async fn world_clock(ctx: &mut Context) {
+    let mut settings = ctx
+        .flash
+        .with_fs(|fs| Settings::load(fs))
+        .await
+        .unwrap_or_default();
+
+    while let Flow::Edit = show_zones(ctx, &settings).await {
+        if let Some(s) = edit_world_clock(ctx, settings).await {
+            settings = s;
+        }
+    }
+}
+
+enum Flow {
+    Edit,
+    Exit,
+}
+
+async fn show_zones(ctx: &mut Context, settings: &Settings) -> Flow {
+    let font = &embedded_graphics::mono_font::ascii::FONT_8X13;
+    let sl = MonoTextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
+
+    let mut ticker = Ticker::every(Duration::from_secs(60));
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font: &embedded_graphics::mono_font::ascii::FONT_10X20,
+    };
+    let mut edit_button = crate::ui::Button::from(crate::ui::ButtonDefinition {
+        position: Point::new(58, 140),
+        size: Size::new(60, 32),
+        style: &button_style,
+        text: "Edit",
+    });
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let mut w = TextWriter::new(&mut ctx.lcd, sl).y(24);
+
+        match time::now_utc() {
+            None => {
+                let _ = writeln!(w, "Time unknown");
+            }
+            Some(now) => {
+                let home_offset_s = time::now_local().map_or(0, |t| t.offset().local_minus_utc());
+                let mut empty = true;
+                for (slot, entry) in settings.world_zones.iter().enumerate() {
+                    let Some(zone) = zone(*entry) else {
+                        continue;
+                    };
+                    let (Some(t), Some(offset_s)) = (time_in(zone, now), offset_s(zone, now))
+                    else {
+                        continue;
+                    };
+                    let pinned = settings.pinned_world_zone as usize == slot + 1;
+                    let _ = writeln!(
+                        w,
+                        "{:<8}{}{} {:>6}",
+                        zone.name.get(..8).unwrap_or(zone.name),
+                        if pinned { '*' } else { ' ' },
+                        t.as_str(),
+                        format_difference(offset_s - home_offset_s).as_str()
+                    );
+                    empty = false;
+                }
+                if empty {
+                    let _ = writeln!(w, "No zones yet");
+                }
+            }
+        }
+
+        edit_button.render(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select4(
+            ticker.next(),
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either4::First(_) => {}
+            select::Either4::Second(_d) => {
+                break Flow::Exit;
+            }
+            select::Either4::Third(_event) => {}
+            select::Either4::Fourth(event) => {
+                if edit_button.clicked(&event) {
+                    break Flow::Edit;
+                }
+            }
+        }
+    }
+}
+
+/// Settings being edited, one slot at a time.
+struct Edit {
+    settings: Settings,
+    slot: usize,
+}
+
+enum Action {
+    Continue,
+    Save,
+}
+
+fn change_zone(e: &mut Edit, delta: i32) -> Action {
+    // 0 (empty) followed by all zones
+    let entry = &mut e.settings.world_zones[e.slot];
+    *entry = step_zone_id(*entry, delta);
+    Action::Continue
+}
+
+async fn edit_world_clock(ctx: &mut Context, settings: Settings) -> Option<Settings> {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+
+    let h = 30;
+    let mut prev_button = Button::eager(&button_style, Size::new(40, h), "<")
+        .on_click(|e: &mut Edit| change_zone(e, -1));
+    let mut next_button = Button::eager(&button_style, Size::new(40, h), ">")
+        .on_click(|e: &mut Edit| change_zone(e, 1));
+    let mut slot_button =
+        Button::eager(&button_style, Size::new(50, h), "Slot").on_click(|e: &mut Edit| {
+            e.slot = (e.slot + 1) % MAX_WORLD_ZONES;
+            Action::Continue
+        });
+    let mut pin_button =
+        Button::eager(&button_style, Size::new(50, h), "Pin").on_click(|e: &mut Edit| {
+            let slot = e.slot as u8 + 1;
+            let pinned = &mut e.settings.pinned_world_zone;
+            *pinned = if *pinned == slot { 0 } else { slot };
+            Action::Continue
+        });
+    let mut save_button = Button::eager(&button_style, Size::new(50, h), "Save")
+        .on_click(|_: &mut Edit| Action::Save);
+
+    let mut edit = Edit { settings, slot: 0 };
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let title = arrform!(
+            16,
+            "Zone {}/{}{}",
+            edit.slot + 1,
+            MAX_WORLD_ZONES,
+            if edit.settings.pinned_world_zone as usize == edit.slot + 1 {
+                " (pin)"
+            } else {
+                ""
+            }
+        );
+        let name = zone(edit.settings.world_zones[edit.slot]).map_or("-", |z| z.name);
+
+        let row_spacing = embedded_layout::layout::linear::FixedMargin(4);
+        let mut layout = LinearLayout::vertical(
+            Chain::new(Text::new(title.as_str(), Point::zero(), sl))
+                .append(Text::new(name, Point::zero(), sl))
+                .append(
+                    LinearLayout::horizontal(Chain::new(&mut prev_button).append(&mut next_button))
+                        .with_spacing(row_spacing)
+                        .arrange(),
+                )
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut slot_button)
+                            .append(&mut pin_button)
+                            .append(&mut save_button),
+                    )
+                    .with_spacing(row_spacing)
+                    .arrange(),
+                ),
+        )
+        .with_alignment(horizontal::Center)
+        .with_spacing(embedded_layout::layout::linear::FixedMargin(6))
+        .arrange()
+        .align_to(&crate::BELOW_BAR_AREA, horizontal::Center, vertical::Center);
+
+        layout.draw(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either3::First(_d) => {
+                break None;
+            }
+            select::Either3::Second(_event) => {}
+            select::Either3::Third(e) => {
+                ctx.backlight.active().await;
+                match layout.touch(e, &mut edit) {
+                    TouchResult::Done(Action::Continue) => {}
+                    TouchResult::Done(Action::Save) => {
+                        // Reload, other settings may have changed since the app was opened.
+                        let settings = edit.settings;
+                        let saved = ctx
+                            .flash
+                            .with_fs(|fs| {
+                                let mut s = Settings::load(fs)?;
+                                s.world_zones = settings.world_zones;
+                                s.pinned_world_zone = settings.pinned_world_zone;
+                                s.save(fs)?;
+                                Ok(s)
+                            })
+                            .await
+                            .unwrap();
+                        break Some(saved);
+                    }
+                    TouchResult::Continue => {}
+                }
+            }
+        }
+    }
+}
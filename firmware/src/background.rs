@@ -0,0 +1,138 @@
+//! Things that have to happen no matter which app is in the foreground.
+//!
+//! The main loop runs the foreground (clock face and apps) together with `wait_for_alarm`. Once an
+//! alarm is due, the foreground future is dropped and the alarm takes over the screen. Apps hold a
+//! `Busy` token, which defers alarms until it is dropped, for as long as they have state that would
+//! be lost otherwise: recordings, running stopwatches and timers, unsaved edits and measurements.
+//!
+//! `log_heart_rate` runs next to both of them and does not take the screen, see `apps::hrlog`.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use drivers::futures::select;
+use drivers::hrm::HrmRessources;
+use drivers::time::{self, Duration, Instant, Timer};
+use drivers::{Shared, TWI};
+
+use crate::apps::alarm::Alarms;
+use crate::apps::hrlog::{self, Measurement};
+
+static BUSY: AtomicU32 = AtomicU32::new(0);
+
+/// Alarms that are more than this late (e.g. because the clock was set forward) are skipped.
+const MAX_ALARM_DELAY_MINUTES: i64 = 10;
+
+/// Poll interval while an alarm is deferred by a busy app.
+const BUSY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Busy {
+    _marker: (),
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Defer background interruptions for as long as the token is kept.
+pub fn busy() -> Busy {
+    BUSY.fetch_add(1, Ordering::Relaxed);
+    Busy { _marker: () }
+}
+
+fn is_busy() -> bool {
+    BUSY.load(Ordering::Relaxed) > 0
+}
+
+/// Wait until one of `alarms` is due and return its index.
+///
+/// Alarms are compared in local time, starting from the moment this was called, so that an alarm
+/// rings once even if the clock is adjusted. Edits of `alarms` are picked up within a minute.
+pub async fn wait_for_alarm(alarms: &RefCell<Alarms>) -> usize {
+    let mut since = None;
+    loop {
+        let Some(now) = time::now_local() else {
+            // Without a clock we can't know when alarms are due.
+            Timer::after(Duration::from_secs(60)).await;
+            continue;
+        };
+        let now_naive = now.naive_local();
+        let after = *since.get_or_insert(now_naive);
+
+        let due = alarms.borrow().next_due(after);
+        if let Some((i, at)) = due {
+            if at <= now_naive {
+                if now_naive - at > chrono::Duration::minutes(MAX_ALARM_DELAY_MINUTES) {
+                    crate::println!("Skipping late alarm {}", i);
+                    since = Some(at);
+                    continue;
+                }
+                while is_busy() {
+                    Timer::after(BUSY_POLL_INTERVAL).await;
+                }
+                return i;
+            }
+        }
+
+        // Wake up at the alarm or at the start of the next minute, whichever comes first. The
+        // latter catches changes of the alarms and of the clock.
+        use chrono::{TimeZone, Timelike};
+        let next_minute =
+            now.with_second(0).unwrap().with_nanosecond(0).unwrap() + chrono::Duration::minutes(1);
+        let wakeup = due
+            .and_then(|(_, at)| now.offset().from_local_datetime(&at).single())
+            .filter(|at| *at > now)
+            .map_or(next_minute, |at| at.min(next_minute));
+        match time::to_instant(wakeup) {
+            Some(instant) => Timer::at(instant).await,
+            None => Timer::after(Duration::from_secs(60)).await,
+        }
+    }
+}
+
+/// Measure the heart rate every `hrlog::MEASUREMENT_INTERVAL_S`. A measurement is skipped if an
+/// app uses the sensor when it is due, and given up when an app asks for the sensor.
+pub async fn log_heart_rate(hrm: &Shared<HrmRessources>, twi: &TWI, start_time: Instant) -> ! {
+    loop {
+        Timer::after(hrlog::until_next_measurement(start_time)).await;
+        match hrm.try_take() {
+            Some(mut sensor) => {
+                // If given up, the next measurement is due immediately, but is skipped since the
+                // app now has the sensor.
+                select::select(
+                    measure_heart_rate(&mut sensor, twi, start_time),
+                    hrm.wanted(),
+                )
+                .await;
+            }
+            None => hrlog::skip_measurement(start_time),
+        }
+    }
+}
+
+async fn measure_heart_rate(sensor: &mut HrmRessources, twi: &TWI, start_time: Instant) {
+    // Switched off again when dropped.
+    let mut hrm = sensor.on(twi).await;
+    hrm.enable().await;
+    let mut measurement = Measurement::new(start_time);
+    loop {
+        let (r, samples) = hrm.wait_event().await;
+        if !hrm.wearing() {
+            // Try again at the next interval instead of logging noise.
+            measurement.cancel(start_time);
+            return;
+        }
+        if let Some(samples) = &samples {
+            measurement.add(start_time, &r, samples);
+        }
+        if measurement.is_done(start_time) {
+            if let (Some(entry), Some(now)) = (measurement.finish(start_time), time::now_utc()) {
+                hrlog::queue(now, entry);
+                crate::faces::note_heart_rate(start_time, entry.mean_bpm as u16);
+            }
+            return;
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Watch faces for the clock screen.
+//!
+//! A face only draws. Everything it shows besides the time (the complications) is collected once
+//! per redraw into `Complications`, so that all faces share the same data. Faces are registered
+//! in `FACES` and chosen in the settings by their index.
+
+pub mod analog;
+pub mod digital;
+
+use arrform::{arrform, ArrForm};
+use bitmap_font::TextStyle;
+use chrono::{DateTime, FixedOffset, NaiveTime};
+use core::sync::atomic::{AtomicU32, Ordering};
+use drivers::battery::{AsyncBattery, ChargeState};
+use drivers::lpm013m1126c::{BWConfig, Buffer, Rgb111, WIDTH};
+use drivers::time::{self, Instant, TimeSource};
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::Text;
+
+use crate::apps::alarm::Alarms;
+use crate::apps::daylight::LastPosition;
+use crate::settings::Settings;
+
+pub trait WatchFace {
+    /// Shown in the settings
+    fn name(&self) -> &'static str;
+
+    /// Draw the whole screen. `now` is `None` while the time is unknown.
+    fn draw(&self, lcd: &mut Buffer, now: Option<DateTime<FixedOffset>>, data: &Complications);
+}
+
+pub const FACES: [&dyn WatchFace; 2] = [&digital::Digital, &analog::Analog];
+
+/// The face stored in the settings, falling back to the first one.
+pub fn face(index: u8) -> &'static dyn WatchFace {
+    FACES.get(index as usize).copied().unwrap_or(FACES[0])
+}
+
+/// Heart rates older than this are not shown anymore.
+const MAX_HEART_RATE_AGE_S: u32 = 60 * 60;
+
+static HEART_RATE: AtomicU32 = AtomicU32::new(0);
+static HEART_RATE_AT_S: AtomicU32 = AtomicU32::new(0);
+
+/// Remember a heart rate measured by any app for the complications. `start_time` is the one of
+/// the `Context`.
+pub fn note_heart_rate(start_time: Instant, bpm: u16) {
+    HEART_RATE_AT_S.store(start_time.elapsed().as_secs() as u32, Ordering::Relaxed);
+    HEART_RATE.store(bpm as u32, Ordering::Relaxed);
+}
+
+fn heart_rate(start_time: Instant) -> Option<u16> {
+    let bpm = HEART_RATE.load(Ordering::Relaxed);
+    let age_s = start_time.elapsed().as_secs() as u32 - HEART_RATE_AT_S.load(Ordering::Relaxed);
+    (bpm != 0 && age_s < MAX_HEART_RATE_AGE_S).then_some(bpm as u16)
+}
+
+pub struct Complications {
+    pub battery_percent: i32,
+    pub charge_state: ChargeState,
+    /// Only if it rings within the next day
+    pub next_alarm: Option<NaiveTime>,
+    pub heart_rate: Option<u16>,
+    /// There is no step counter yet, so this is always `None`.
+    pub steps: Option<u32>,
+    /// See `apps::daylight::countdown`
+    pub sunset: Option<ArrForm<16>>,
+    /// See `apps::worldclock::pinned`
+    pub world_clock: Option<ArrForm<16>>,
+    pub time_source: TimeSource,
+}
+
+impl Complications {
+    pub fn gather(
+        start_time: Instant,
+        battery: &AsyncBattery,
+        settings: &Settings,
+        alarms: &Alarms,
+        position: Option<&LastPosition>,
+        now: Option<DateTime<FixedOffset>>,
+    ) -> Self {
+        let next_alarm = now.and_then(|now| {
+            let now = now.naive_local();
+            let (_, at) = alarms.next_due(now)?;
+            (at - now < chrono::Duration::days(1)).then_some(at.time())
+        });
+        Self {
+            battery_percent: battery.read().percentage() as i32,
+            charge_state: battery.state(),
+            next_alarm,
+            heart_rate: heart_rate(start_time),
+            steps: None,
+            sunset: position
+                .zip(now)
+                .and_then(|(p, now)| crate::apps::daylight::countdown(p, now)),
+            world_clock: now.and_then(|now| {
+                crate::apps::worldclock::pinned(settings, now.with_timezone(&chrono::Utc))
+            }),
+            time_source: time::time_source(),
+        }
+    }
+
+    pub fn sync_label(&self) -> &'static str {
+        match self.time_source {
+            TimeSource::Unknown => "-",
+            TimeSource::Gps => "GPS",
+            TimeSource::Restored => "Rst",
+            TimeSource::User => "User",
+        }
+    }
+
+    /// Alarm, heart rate and steps on one line, e.g. "A07:00 HR72", skipping what is unknown.
+    pub fn summary(&self) -> ArrForm<24> {
+        use chrono::Timelike;
+        use core::fmt::Write;
+        let mut s = ArrForm::<24>::new();
+        if let Some(t) = self.next_alarm {
+            let _ = write!(s, "A{:0>2}:{:0>2} ", t.hour(), t.minute());
+        }
+        if let Some(bpm) = self.heart_rate {
+            let _ = write!(s, "HR{} ", bpm);
+        }
+        if let Some(steps) = self.steps {
+            let _ = write!(s, "S{} ", steps);
+        }
+        s
+    }
+}
+
+pub fn draw_centered(
+    lcd: &mut Buffer,
+    text: &str,
+    font: &bitmap_font::BitmapFont,
+    y: i32,
+    bw_config: BWConfig,
+) {
+    draw_centered_at(lcd, text, font, Point::new(WIDTH as i32 / 2, y), bw_config)
+}
+
+/// Draw `text` horizontally centered around `at`, which is the top of the text.
+pub fn draw_centered_at(
+    lcd: &mut Buffer,
+    text: &str,
+    font: &bitmap_font::BitmapFont,
+    at: Point,
+    bw_config: BWConfig,
+) {
+    let style = TextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
+    let width = text.len() as i32 * font.width() as i32;
+    Text::new(text, Point::new(at.x - width / 2, at.y), style)
+        .draw(&mut lcd.binary(bw_config))
+        .unwrap();
+}
+
+/// Battery level and charge state in the top right corner, colored when full or low.
+pub fn draw_battery(lcd: &mut Buffer, data: &Complications) {
+    let tiny_font = bitmap_font::tamzen::FONT_12x24;
+    let tiny_style = TextStyle::new(&tiny_font, embedded_graphics::pixelcolor::BinaryColor::On);
+
+    let perc = data.battery_percent;
+    let bat = arrform!(
+        5,
+        "{: >3}%{}",
+        perc,
+        match data.charge_state {
+            ChargeState::Full => 'F',
+            ChargeState::Charging => 'C',
+            ChargeState::Draining => 'D',
+        },
+    );
+    let col = if perc > 95 {
+        Rgb111::green()
+    } else if perc > 10 {
+        Rgb111::white()
+    } else {
+        Rgb111::red()
+    };
+
+    let bw_config = BWConfig {
+        off: Rgb111::black(),
+        on: col,
+    };
+
+    let x = WIDTH - bat.as_str().len() * tiny_font.width() as usize;
+    Text::new(bat.as_str(), Point::new(x as _, 0), tiny_style)
+        .draw(&mut lcd.binary(bw_config))
+        .unwrap();
+}
+
+/// How the time was set in the top left corner.
+pub fn draw_sync_status(lcd: &mut Buffer, data: &Complications) {
+    let font = bitmap_font::tamzen::FONT_8x16_BOLD;
+    let style = TextStyle::new(&font, embedded_graphics::pixelcolor::BinaryColor::On);
+    let bw_config = BWConfig {
+        off: Rgb111::black(),
+        on: Rgb111::white(),
+    };
+    Text::new(data.sync_label(), Point::new(0, 0), style)
+        .draw(&mut lcd.binary(bw_config))
+        .unwrap();
+}
@@ -0,0 +1,115 @@
+use arrform::{arrform, ArrForm};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike};
+use drivers::lpm013m1126c::{BWConfig, Buffer, Rgb111, WIDTH};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Circle, Line, PrimitiveStyle};
+
+#[cfg(target_arch = "arm")]
+use micromath::F32Ext;
+
+use super::{draw_battery, draw_centered_at, draw_sync_status, Complications, WatchFace};
+
+const CENTER: Point = Point::new(WIDTH as i32 / 2, 96);
+const RADIUS: i32 = 78;
+const TICK_LENGTH: i32 = 8;
+const HOUR_HAND_LENGTH: i32 = 42;
+const MINUTE_HAND_LENGTH: i32 = 66;
+
+/// Dial with hour and minute hands, the complications are inside the dial.
+pub struct Analog;
+
+/// Point at `length` from the center in the direction of `fraction` of a full turn, clockwise from
+/// twelve o'clock.
+fn on_dial(fraction: f32, length: i32) -> Point {
+    let angle = fraction * 2.0 * core::f32::consts::PI;
+    let length = length as f32;
+    CENTER
+        + Point::new(
+            (length * angle.sin()) as i32,
+            -(length * angle.cos()) as i32,
+        )
+}
+
+fn draw_hand(lcd: &mut Buffer, fraction: f32, length: i32, width: u32) {
+    Line::new(CENTER, on_dial(fraction, length))
+        .into_styled(PrimitiveStyle::with_stroke(Rgb111::white(), width))
+        .draw(lcd)
+        .unwrap();
+}
+
+impl WatchFace for Analog {
+    fn name(&self) -> &'static str {
+        "Analog"
+    }
+
+    fn draw(&self, lcd: &mut Buffer, now: Option<DateTime<FixedOffset>>, data: &Complications) {
+        let font = bitmap_font::tamzen::FONT_8x16_BOLD;
+        let bw_config = BWConfig {
+            off: Rgb111::black(),
+            on: Rgb111::white(),
+        };
+
+        for hour in 0..12 {
+            // Thicker marks at the quarters
+            let width = if hour % 3 == 0 { 3 } else { 1 };
+            let fraction = hour as f32 / 12.0;
+            Line::new(
+                on_dial(fraction, RADIUS - TICK_LENGTH),
+                on_dial(fraction, RADIUS),
+            )
+            .into_styled(PrimitiveStyle::with_stroke(Rgb111::white(), width))
+            .draw(lcd)
+            .unwrap();
+        }
+
+        // Above the center, where the hands are least in the way most of the time.
+        let above = [&data.sunset, &data.world_clock];
+        for (i, text) in above.into_iter().flatten().enumerate() {
+            draw_centered_at(
+                lcd,
+                text.as_str(),
+                &font,
+                CENTER - Point::new(0, 46 - 18 * i as i32),
+                bw_config,
+            );
+        }
+        draw_centered_at(
+            lcd,
+            data.summary().as_str().trim_end(),
+            &font,
+            CENTER + Point::new(0, 30),
+            bw_config,
+        );
+
+        let Some(now) = now else {
+            draw_centered_at(lcd, "SYNC", &font, CENTER - Point::new(0, 8), bw_config);
+            draw_sync_status(lcd, data);
+            draw_battery(lcd, data);
+            return;
+        };
+
+        let day = arrform!(2, "{}", now.day());
+        draw_centered_at(
+            lcd,
+            day.as_str(),
+            &font,
+            CENTER + Point::new(RADIUS / 2 + 8, -8),
+            BWConfig {
+                off: Rgb111::black(),
+                on: Rgb111::cyan(),
+            },
+        );
+
+        let minutes = now.minute() as f32 / 60.0;
+        let hours = (now.hour() % 12) as f32 / 12.0 + minutes / 12.0;
+        draw_hand(lcd, hours, HOUR_HAND_LENGTH, 5);
+        draw_hand(lcd, minutes, MINUTE_HAND_LENGTH, 3);
+        Circle::with_center(CENTER, 9)
+            .into_styled(PrimitiveStyle::with_fill(Rgb111::red()))
+            .draw(lcd)
+            .unwrap();
+
+        draw_sync_status(lcd, data);
+        draw_battery(lcd, data);
+    }
+}
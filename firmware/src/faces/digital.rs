@@ -0,0 +1,51 @@
+use arrform::{arrform, ArrForm};
+use chrono::{DateTime, Datelike, FixedOffset, Timelike};
+use drivers::lpm013m1126c::{BWConfig, Buffer, Rgb111};
+
+use super::{draw_battery, draw_centered, draw_sync_status, Complications, WatchFace};
+
+/// Large time with the date below.
+pub struct Digital;
+
+impl WatchFace for Digital {
+    fn name(&self) -> &'static str {
+        "Digital"
+    }
+
+    fn draw(&self, lcd: &mut Buffer, now: Option<DateTime<FixedOffset>>, data: &Complications) {
+        let large_font = bitmap_font::tamzen::FONT_16x32_BOLD.pixel_double();
+        let small_font = bitmap_font::tamzen::FONT_16x32_BOLD;
+        // Up to 22 characters fit in this one.
+        let tiny_font = bitmap_font::tamzen::FONT_8x16_BOLD;
+
+        let bw_config = BWConfig {
+            off: Rgb111::black(),
+            on: Rgb111::white(),
+        };
+
+        if let Some(c) = now {
+            let time = arrform!(5, "{:0>2}:{:0>2}", c.hour(), c.minute());
+            let date = arrform!(10, "{:0>2}.{:0>2}.{:0>4}", c.day(), c.month(), c.year());
+
+            draw_centered(lcd, time.as_str(), &large_font, 32, bw_config);
+            draw_centered(lcd, date.as_str(), &small_font, 92, bw_config);
+        } else {
+            draw_centered(lcd, "SYNC", &large_font, 32, bw_config);
+        }
+
+        let summary = data.summary();
+        let lines = [
+            data.sunset.as_ref().map(|s| s.as_str()),
+            data.world_clock.as_ref().map(|s| s.as_str()),
+            Some(summary.as_str().trim_end()),
+        ];
+        let mut y = 128;
+        for line in lines.into_iter().flatten().filter(|l| !l.is_empty()) {
+            draw_centered(lcd, line, &tiny_font, y, bw_config);
+            y += 16;
+        }
+
+        draw_sync_status(lcd, data);
+        draw_battery(lcd, data);
+    }
+}
@@ -10,7 +10,10 @@
 // Plots
 // https://crates.io/crates/embedded-plots
 
+mod agps;
 mod apps;
+mod background;
+mod faces;
 mod settings;
 mod ui;
 mod util;
@@ -31,11 +34,13 @@ mod log {
     pub(crate) use println;
 }
 
+use apps::alarm::Alarms;
 use apps::menu::Page;
 use log::println;
 
 use arrform::{arrform, ArrForm};
 use bitmap_font::TextStyle;
+use core::cell::RefCell;
 use drivers::{time, Context};
 use embedded_graphics::prelude::*;
 use embedded_graphics::text::Text;
@@ -104,84 +109,37 @@ async fn render_top_bar(lcd: &mut drivers::display::Display, bat: &drivers::batt
         .unwrap();
 }
 
-fn draw_centered(
-    ctx: &mut Context,
-    text: &str,
-    font: &bitmap_font::BitmapFont,
-    y: i32,
-    bw_config: BWConfig,
-) {
-    // TODO: Remove this terrible sin. I'm so sorry.
-    let text_len = text.len();
-
-    let style = TextStyle::new(&font, embedded_graphics::pixelcolor::BinaryColor::On);
-
-    let x = (drivers::lpm013m1126c::WIDTH - text_len * font.width() as usize) / 2;
-    Text::new(text, Point::new(x as i32, y), style)
-        .draw(&mut ctx.lcd.binary(bw_config))
-        .unwrap();
-}
-
-async fn clock(ctx: &mut Context) {
-    let large_font = bitmap_font::tamzen::FONT_16x32_BOLD.pixel_double();
-    let small_font = bitmap_font::tamzen::FONT_16x32_BOLD;
-
-    let bw_config = BWConfig {
-        off: Rgb111::black(),
-        on: Rgb111::white(),
-    };
+async fn clock(ctx: &mut Context, alarms: &RefCell<Alarms>) {
+    let (settings, position) = ctx
+        .flash
+        .with_fs(|fs| {
+            Ok((
+                settings::Settings::load(fs).unwrap_or_default(),
+                apps::daylight::LastPosition::load(fs).ok().flatten(),
+            ))
+        })
+        .await
+        .unwrap_or_default();
+    let face = faces::face(settings.watch_face);
+    let start_time = ctx.start_time;
 
     ctx.lcd.on().await;
     loop {
-        ctx.lcd.fill(bw_config.off);
-
-        if let Some(c) = time::now_local() {
-            use chrono::{Datelike, Timelike};
-            let time = arrform!(5, "{:0>2}:{:0>2}", c.hour(), c.minute());
-            let date = arrform!(10, "{:0>2}.{:0>2}.{:0>4}", c.day(), c.month(), c.year());
-
-            draw_centered(ctx, time.as_str(), &large_font, 40, bw_config);
-            draw_centered(ctx, date.as_str(), &small_font, 100, bw_config);
-        } else {
-            draw_centered(ctx, "SYNC", &large_font, 40, bw_config);
-        };
-
-        {
-            let tiny_font = bitmap_font::tamzen::FONT_12x24;
-            let tiny_style =
-                TextStyle::new(&tiny_font, embedded_graphics::pixelcolor::BinaryColor::On);
-
-            let v = ctx.battery.read();
-            let perc = v.percentage() as i32;
-
-            let bat = arrform!(
-                5,
-                "{: >3}%{}",
-                perc,
-                match ctx.battery.state() {
-                    drivers::battery::ChargeState::Full => 'F',
-                    drivers::battery::ChargeState::Charging => 'C',
-                    drivers::battery::ChargeState::Draining => 'D',
-                },
-            );
-            let col = if perc > 95 {
-                Rgb111::green()
-            } else if perc > 10 {
-                Rgb111::white()
-            } else {
-                Rgb111::red()
-            };
-
-            let bw_config = BWConfig {
-                off: Rgb111::black(),
-                on: col,
-            };
-
-            let x = drivers::lpm013m1126c::WIDTH - bat.as_str().len() * tiny_font.width() as usize;
-            Text::new(bat.as_str(), Point::new(x as _, 0), tiny_style)
-                .draw(&mut ctx.lcd.binary(bw_config))
-                .unwrap();
-        }
+        // Saves the heart rate measurements taken in the background, see `apps::hrlog`.
+        apps::hrlog::save_pending(&mut ctx.flash).await;
+
+        ctx.lcd.fill(Rgb111::black());
+
+        let now = time::now_local();
+        let data = faces::Complications::gather(
+            start_time,
+            &ctx.battery,
+            &settings,
+            &alarms.borrow(),
+            position.as_ref(),
+            now,
+        );
+        face.draw(&mut ctx.lcd, now, &data);
 
         ctx.lcd.present().await;
 
@@ -237,10 +195,12 @@ async fn system_menu(ctx: &mut Context) {
         Panic,
         FormatFlash,
         Files,
+        GpsLog,
     }
 
     let options = [
         ("Files", App::Files),
+        ("GPS log", App::GpsLog),
         ("Panic Msg", App::PanicMsg),
         ("Reset", App::Reset),
         ("Panic", App::Panic),
@@ -266,6 +226,7 @@ async fn system_menu(ctx: &mut Context) {
             page = last_page;
             match app {
                 App::Files => apps::files::files(ctx).await,
+                App::GpsLog => apps::gpslog::gps_log(ctx).await,
                 App::Reset => reset(ctx).await,
                 App::PanicMsg => apps::panic_msg::panic_msg(ctx).await,
                 App::Panic => panic!("as you choose"),
@@ -276,28 +237,40 @@ async fn system_menu(ctx: &mut Context) {
         }
     }
 }
-async fn app_menu(ctx: &mut Context) {
+async fn app_menu(ctx: &mut Context, alarms: &RefCell<Alarms>) {
     #[derive(Copy, Clone)]
     enum App {
         Draw,
         Stopwatch,
         ClockInfo,
+        Daylight,
         BatInfo,
         Timer,
+        Alarm,
+        WorldClock,
         Idle,
         Accel,
         Hrm,
+        HrLog,
+        Readiness,
         Track,
+        SkyView,
         Settings,
         System,
     }
 
     let options = [
         ("Hrm", App::Hrm),
+        ("HR log", App::HrLog),
+        ("Ready", App::Readiness),
         ("Track", App::Track),
+        ("Sky", App::SkyView),
         ("Stop\nwatch", App::Stopwatch),
         ("Timer", App::Timer),
+        ("Alarm", App::Alarm),
+        ("World\nclock", App::WorldClock),
         ("Clock", App::ClockInfo),
+        ("Sun", App::Daylight),
         ("Bat", App::BatInfo),
         ("Draw", App::Draw),
         ("Idle", App::Idle),
@@ -325,14 +298,20 @@ async fn app_menu(ctx: &mut Context) {
             page = last_page;
             match app {
                 App::ClockInfo => apps::clockinfo::clock_info(ctx).await,
+                App::Daylight => apps::daylight::daylight_info(ctx).await,
                 App::BatInfo => apps::batinfo::battery_info(ctx).await,
                 App::Draw => apps::draw::touch_playground(ctx).await,
                 App::Stopwatch => apps::stopwatch::stopwatch(ctx).await,
                 App::Timer => apps::timer::timer(ctx).await,
+                App::Alarm => apps::alarm::alarm_app(ctx, alarms).await,
+                App::WorldClock => apps::worldclock::world_clock(ctx).await,
                 App::Idle => apps::idle::idle(ctx).await,
                 App::Accel => apps::accel::accel(ctx).await,
                 App::Hrm => apps::hrm::hrm(ctx).await,
+                App::HrLog => apps::hrlog::hr_log(ctx).await,
+                App::Readiness => apps::readiness::readiness(ctx).await,
                 App::Track => apps::track::track_app(ctx).await,
+                App::SkyView => apps::skyview::skyview(ctx).await,
                 App::Settings => settings::settings_ui(ctx).await,
                 App::System => system_menu(ctx).await,
             }
@@ -385,10 +364,32 @@ fn main() -> ! {
             }
         }
 
-        loop {
-            clock(&mut ctx).await;
-            app_menu(&mut ctx).await;
-        }
+        let alarms = ctx
+            .flash
+            .with_fs(|fs| Alarms::load(fs))
+            .await
+            .unwrap_or_default();
+        let alarms = RefCell::new(alarms);
+
+        let (hrm, twi, start_time) = (ctx.hrm, ctx.twi, ctx.start_time);
+        let main_loop = async {
+            loop {
+                let foreground = async {
+                    loop {
+                        clock(&mut ctx, &alarms).await;
+                        app_menu(&mut ctx, &alarms).await;
+                    }
+                };
+                // The foreground (and whatever app is running) is dropped when an alarm is due.
+                let alarm = select::select(foreground, background::wait_for_alarm(&alarms)).await;
+                if let select::Either::Second(i) = alarm {
+                    apps::alarm::ring(&mut ctx, &alarms, i).await;
+                }
+            }
+        };
+        // Neither of them returns.
+        select::select(main_loop, background::log_heart_rate(hrm, twi, start_time)).await;
+        unreachable!()
     });
 }
 
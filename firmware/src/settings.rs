@@ -1,6 +1,8 @@
+use arrayvec::ArrayVec;
 use arrform::*;
 use drivers::{
     futures::select,
+    gps::{NavRate, ReceiverConfig, SatelliteConfig},
     lpm013m1126c::Rgb111,
     time::{Duration, Ticker},
     Context,
@@ -18,6 +20,7 @@ use embedded_layout::{
     object_chain::Chain,
 };
 use littlefs2::path::Path;
+use util::tz::{step_zone_id, TimeZone, Zone};
 
 use crate::{
     render_top_bar,
@@ -30,6 +33,18 @@ use crate::{
 pub struct Settings {
     pub utc_offset_hours: i8,
     pub utc_offset_minutes: i8,
+    /// Satellite systems used while tracking (see `SatelliteConfig::to_bits`). 0 means default.
+    pub track_satellites: u8,
+    /// `NavRate` used while tracking
+    pub track_nav_rate: u8,
+    /// 0: Fixed offset (`utc_offset_hours`/`utc_offset_minutes`), otherwise a `util::tz::Zone::id`.
+    pub time_zone: u8,
+    /// Index into `faces::FACES`
+    pub watch_face: u8,
+    /// Zones of the world clock app, each 0 for an empty slot or a `util::tz::Zone::id`
+    pub world_zones: [u8; crate::apps::worldclock::MAX_WORLD_ZONES],
+    /// 0 or 1 + index into `world_zones` of the zone shown on the clock face
+    pub pinned_world_zone: u8,
 }
 
 const SETTINGS_FILE: &Path = &Path::from_str_with_nul("settings.bin\0");
@@ -61,13 +76,97 @@ impl Settings {
     }
 
     pub fn apply(&self) {
-        drivers::time::set_utc_offset(
+        drivers::time::set_time_zone(self.time_zone());
+    }
+
+    pub fn time_zone_name(&self) -> &'static str {
+        match self.time_zone {
+            0 => "UTC offset",
+            id => Zone::by_id(id).map(|z| z.name).unwrap_or("?"),
+        }
+    }
+
+    pub fn time_zone(&self) -> TimeZone {
+        let fixed = TimeZone::fixed(
             (self.utc_offset_hours as i32 * 60 + self.utc_offset_minutes as i32) * 60,
         );
+        Zone::by_id(self.time_zone)
+            .and_then(|z| TimeZone::parse(z.tz))
+            .unwrap_or(fixed)
+    }
+
+    pub fn track_receiver_config(&self) -> ReceiverConfig {
+        ReceiverConfig {
+            satellites: SatelliteConfig::from_bits(self.track_satellites),
+            nav_rate: NavRate::try_from(self.track_nav_rate).unwrap_or_default(),
+        }
     }
 }
 
 pub async fn settings_ui(ctx: &mut Context) {
+    #[derive(Copy, Clone)]
+    enum Page {
+        DateTime,
+        TimeZone,
+        UtcOffset,
+        Gnss,
+        WatchFace,
+    }
+
+    let options = [
+        ("Date/\ntime", Page::DateTime),
+        ("Time\nzone", Page::TimeZone),
+        ("UTC\noffset", Page::UtcOffset),
+        ("GNSS", Page::Gnss),
+        ("Watch\nface", Page::WatchFace),
+    ];
+
+    let mut page = crate::apps::menu::Page::zero();
+
+    while let crate::apps::menu::MenuSelection::Item(last_page, (_, option)) =
+        crate::apps::menu::paginated_grid_menu::<4, _, _>(
+            &mut ctx.touch,
+            &ctx.twi,
+            &mut ctx.button,
+            &mut ctx.lcd,
+            &mut ctx.battery,
+            &mut ctx.backlight,
+            options.as_slice(),
+            page,
+        )
+        .await
+    {
+        page = last_page;
+        match option {
+            Page::DateTime => date_time_ui(ctx).await,
+            Page::TimeZone => time_zone_ui(ctx).await,
+            Page::UtcOffset => utc_offset_ui(ctx).await,
+            Page::Gnss => gnss_ui(ctx).await,
+            Page::WatchFace => watch_face_ui(ctx).await,
+        }
+    }
+}
+
+async fn watch_face_ui(ctx: &mut Context) {
+    let options = crate::faces::FACES
+        .iter()
+        .enumerate()
+        .map(|(i, face)| (face.name(), Some(i as u8)))
+        .collect::<ArrayVec<_, 4>>();
+    if let Some(i) = crate::apps::menu::grid_menu(ctx, options, None).await {
+        ctx.flash
+            .with_fs(|fs| {
+                let mut settings = Settings::load(fs)?;
+                settings.watch_face = i;
+                settings.save(fs)
+            })
+            .await
+            .unwrap();
+    }
+}
+
+async fn utc_offset_ui(ctx: &mut Context) {
+    let _busy = crate::background::busy();
     let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
     //let sl = TextStyle::new(font, embedded_graphics::pixelcolor::BinaryColor::On);
     let sl = MonoTextStyle::new(font, Rgb111::white());
@@ -98,23 +197,28 @@ pub async fn settings_ui(ctx: &mut Context) {
     let plus_button = ButtonDefinition::new(&button_style, size, "+");
     let minus_button = ButtonDefinition::new(&button_style, size, "-");
 
+    // Setting an offset manually means that the zone rules are not wanted anymore.
     let mut plus_button_hours = Button::from(plus_button).on_click(|ctx: &mut Settings| {
         ctx.utc_offset_hours = (ctx.utc_offset_hours + 1).min(23);
+        ctx.time_zone = 0;
         Action::Continue
     });
 
     let mut minus_button_hours = Button::from(minus_button).on_click(|ctx: &mut Settings| {
         ctx.utc_offset_hours = (ctx.utc_offset_hours - 1).max(-23);
+        ctx.time_zone = 0;
         Action::Continue
     });
 
     let mut plus_button_minutes = Button::from(plus_button).on_click(|ctx: &mut Settings| {
         ctx.utc_offset_minutes = (ctx.utc_offset_minutes + 1).min(59);
+        ctx.time_zone = 0;
         Action::Continue
     });
 
     let mut minus_button_minutes = Button::from(minus_button).on_click(|ctx: &mut Settings| {
         ctx.utc_offset_minutes = (ctx.utc_offset_minutes - 1).max(-59);
+        ctx.time_zone = 0;
         Action::Continue
     });
 
@@ -195,3 +299,438 @@ pub async fn settings_ui(ctx: &mut Context) {
         }
     }
 }
+
+/// Local date and time being edited, one field at a time.
+struct DateTimeEdit {
+    /// Day, month, year, hour, minute
+    fields: [i32; 5],
+    selected: usize,
+}
+
+impl DateTimeEdit {
+    const NAMES: [&'static str; 5] = ["day", "month", "year", "hour", "minute"];
+    const RANGES: [(i32, i32); 5] = [(1, 31), (1, 12), (2024, 2099), (0, 23), (0, 59)];
+
+    fn new(t: chrono::NaiveDateTime) -> Self {
+        use chrono::{Datelike, Timelike};
+        Self {
+            fields: [
+                t.day() as i32,
+                t.month() as i32,
+                t.year(),
+                t.hour() as i32,
+                t.minute() as i32,
+            ],
+            selected: 0,
+        }
+    }
+
+    fn change(&mut self, delta: i32) {
+        let (min, max) = Self::RANGES[self.selected];
+        let v = &mut self.fields[self.selected];
+        *v = min + (*v - min + delta).rem_euclid(max - min + 1);
+    }
+
+    /// Days that don't exist in the month (e.g. 31.04.) are moved to the last day of the month.
+    fn to_datetime(&self) -> chrono::NaiveDateTime {
+        let [day, month, year, hour, minute] = self.fields;
+        let date = (1..=day)
+            .rev()
+            .find_map(|d| chrono::NaiveDate::from_ymd_opt(year, month as u32, d as u32))
+            .unwrap();
+        date.and_hms_opt(hour as u32, minute as u32, 0).unwrap()
+    }
+}
+
+async fn date_time_ui(ctx: &mut Context) {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+
+    enum Action {
+        Continue,
+        Stop,
+    }
+
+    let now = drivers::time::now_local()
+        .map(|t| t.naive_local())
+        .unwrap_or_else(|| {
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+        });
+    let mut edit = DateTimeEdit::new(now);
+
+    let w = 30;
+    let h = sl.font.character_size.height;
+    let size = Size::new(w, h);
+
+    let mut prev_button =
+        Button::eager(&button_style, size, "<").on_click(|ctx: &mut DateTimeEdit| {
+            ctx.selected = (ctx.selected + ctx.fields.len() - 1) % ctx.fields.len();
+            Action::Continue
+        });
+    let mut minus_button =
+        Button::eager(&button_style, size, "-").on_click(|ctx: &mut DateTimeEdit| {
+            ctx.change(-1);
+            Action::Continue
+        });
+    let mut plus_button =
+        Button::eager(&button_style, size, "+").on_click(|ctx: &mut DateTimeEdit| {
+            ctx.change(1);
+            Action::Continue
+        });
+    let mut next_button =
+        Button::eager(&button_style, size, ">").on_click(|ctx: &mut DateTimeEdit| {
+            ctx.selected = (ctx.selected + 1) % ctx.fields.len();
+            Action::Continue
+        });
+
+    let mut save_button =
+        Button::eager(&button_style, Size::new(2 * w, 2 * h), "Set").on_click(|_ctx| Action::Stop);
+
+    let display_area = Rectangle::new(Point::new(0, 0), Size::new(176, 176));
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let [day, month, year, hour, minute] = edit.fields;
+        let date_text = arrform!(10, "{:0>2}.{:0>2}.{}", day, month, year);
+        let time_text = arrform!(5, "{:0>2}:{:0>2}", hour, minute);
+        let mut layout = LinearLayout::vertical(
+            Chain::new(Text::new(date_text.as_str(), Point::zero(), sl))
+                .append(Text::new(time_text.as_str(), Point::zero(), sl))
+                .append(Text::new(
+                    DateTimeEdit::NAMES[edit.selected],
+                    Point::zero(),
+                    sl,
+                ))
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut prev_button)
+                            .append(&mut minus_button)
+                            .append(&mut plus_button)
+                            .append(&mut next_button),
+                    )
+                    .with_spacing(embedded_layout::layout::linear::FixedMargin(5))
+                    .arrange(),
+                )
+                .append(&mut save_button),
+        )
+        .with_alignment(horizontal::Center)
+        .with_spacing(embedded_layout::layout::linear::FixedMargin(5))
+        .arrange()
+        .align_to(&display_area, horizontal::Center, vertical::Center);
+
+        layout.draw(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select3(
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either3::First(_d) => {
+                break;
+            }
+            select::Either3::Second(_event) => {}
+            select::Either3::Third(e) => {
+                ctx.backlight.active().await;
+                match layout.touch(e, &mut edit) {
+                    crate::ui::TouchResult::Done(Action::Continue) => {}
+                    crate::ui::TouchResult::Done(Action::Stop) => {
+                        let settings = ctx
+                            .flash
+                            .with_fs(|fs| Settings::load(fs))
+                            .await
+                            .unwrap_or_default();
+                        let utc = settings.time_zone().from_local(edit.to_datetime());
+                        drivers::time::set_time_manually(utc);
+                        break;
+                    }
+                    crate::ui::TouchResult::Continue => {}
+                }
+            }
+        }
+    }
+}
+
+async fn time_zone_ui(ctx: &mut Context) {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let mut ticker = Ticker::every(Duration::from_secs(60));
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+
+    enum Action {
+        Continue,
+        Stop,
+    }
+
+    let mut settings = ctx.flash.with_fs(|fs| Settings::load(fs)).await.unwrap();
+    let orig_settings = settings;
+
+    let w = 30;
+    let h = sl.font.character_size.height;
+    let size = Size::new(w, h);
+
+    // The fixed utc offset (0) is followed by all zones.
+    let mut prev_button = Button::eager(&button_style, size, "<").on_click(|ctx: &mut Settings| {
+        ctx.time_zone = step_zone_id(ctx.time_zone, -1);
+        Action::Continue
+    });
+    let mut next_button = Button::eager(&button_style, size, ">").on_click(|ctx: &mut Settings| {
+        ctx.time_zone = step_zone_id(ctx.time_zone, 1);
+        Action::Continue
+    });
+
+    let mut save_button =
+        Button::eager(&button_style, Size::new(2 * w, 2 * h), "Save").on_click(|_ctx| Action::Stop);
+
+    let display_area = Rectangle::new(Point::new(0, 0), Size::new(176, 176));
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let now_text = if let Some(now) = drivers::time::now_local() {
+            use chrono::Timelike;
+            let offset_min = now.offset().local_minus_utc() / 60;
+            arrform!(
+                20,
+                "{:0>2}:{:0>2} ({:+}:{:0>2})",
+                now.hour(),
+                now.minute(),
+                offset_min / 60,
+                offset_min.abs() % 60
+            )
+        } else {
+            arrform!(20, "--:--")
+        };
+        let mut layout = LinearLayout::vertical(
+            Chain::new(Text::new("Time zone", Point::zero(), sl))
+                .append(
+                    LinearLayout::horizontal(
+                        Chain::new(&mut prev_button)
+                            .append(Text::new(" ", Point::zero(), sl))
+                            .append(&mut next_button),
+                    )
+                    .arrange(),
+                )
+                .append(Text::new(settings.time_zone_name(), Point::zero(), sl))
+                .append(Text::new(now_text.as_str(), Point::zero(), sl))
+                .append(&mut save_button),
+        )
+        .with_alignment(horizontal::Left)
+        .with_spacing(embedded_layout::layout::linear::FixedMargin(5))
+        .arrange()
+        .align_to(&display_area, horizontal::Center, vertical::Center);
+
+        layout.draw(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select4(
+            ticker.next(),
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either4::First(_) => {}
+            select::Either4::Second(_d) => {
+                orig_settings.apply();
+                break;
+            }
+            select::Either4::Third(_event) => {}
+            select::Either4::Fourth(e) => {
+                ctx.backlight.active().await;
+                match layout.touch(e, &mut settings) {
+                    crate::ui::TouchResult::Done(Action::Continue) => {
+                        settings.apply();
+                    }
+                    crate::ui::TouchResult::Done(Action::Stop) => {
+                        ctx.flash.with_fs(|fs| settings.save(fs)).await.unwrap();
+                        break;
+                    }
+                    crate::ui::TouchResult::Continue => {}
+                }
+            }
+        }
+    }
+}
+
+async fn gnss_ui(ctx: &mut Context) {
+    let _busy = crate::background::busy();
+    let font = &embedded_graphics::mono_font::ascii::FONT_10X20;
+    let sl = MonoTextStyle::new(font, Rgb111::white());
+
+    let mut touch = ctx.touch.enabled(&ctx.twi).await;
+    ctx.backlight.active().await;
+
+    let mut ticker = Ticker::every(Duration::from_secs(60));
+
+    let button_style = ButtonStyle {
+        fill: Rgb111::blue(),
+        highlight: Rgb111::white(),
+        font,
+    };
+
+    enum Action {
+        Continue,
+        Stop,
+    }
+
+    let mut settings = ctx.flash.with_fs(|fs| Settings::load(fs)).await.unwrap();
+
+    let w = 50;
+    let h = sl.font.character_size.height;
+    let size = Size::new(w, h);
+
+    fn toggle(settings: &mut Settings, f: impl FnOnce(&mut SatelliteConfig)) -> Action {
+        let mut sats = settings.track_receiver_config().active_satellites();
+        f(&mut sats);
+        settings.track_satellites = sats.to_bits();
+        Action::Continue
+    }
+
+    let mut gps_button = Button::eager(&button_style, size, "GPS")
+        .on_click(|ctx: &mut Settings| toggle(ctx, |s| s.gps = !s.gps));
+    let mut bds_button = Button::eager(&button_style, size, "BDS")
+        .on_click(|ctx: &mut Settings| toggle(ctx, |s| s.bds = !s.bds));
+    let mut glonass_button = Button::eager(&button_style, size, "GLO")
+        .on_click(|ctx: &mut Settings| toggle(ctx, |s| s.glonass = !s.glonass));
+
+    let mut plus_button_rate =
+        Button::eager(&button_style, Size::new(30, h), "+").on_click(|ctx: &mut Settings| {
+            ctx.track_nav_rate = (ctx.track_nav_rate + 1).min(NavRate::Hz5 as u8);
+            Action::Continue
+        });
+    let mut minus_button_rate =
+        Button::eager(&button_style, Size::new(30, h), "-").on_click(|ctx: &mut Settings| {
+            ctx.track_nav_rate = ctx.track_nav_rate.saturating_sub(1);
+            Action::Continue
+        });
+
+    let mut save_button =
+        Button::eager(&button_style, Size::new(2 * w, 2 * h), "Save").on_click(|_ctx| Action::Stop);
+
+    let display_area = Rectangle::new(Point::new(0, 0), Size::new(176, 176));
+
+    fn on_off(b: bool) -> &'static str {
+        if b {
+            " on"
+        } else {
+            " off"
+        }
+    }
+
+    ctx.lcd.on().await;
+    loop {
+        ctx.lcd.fill(Rgb111::black());
+
+        render_top_bar(&mut ctx.lcd, &ctx.battery).await;
+
+        let config = settings.track_receiver_config();
+        let sats = config.active_satellites();
+        let rate_text = arrform!(4, "{}Hz", 1000 / config.nav_rate.interval_ms());
+        let mut layout = LinearLayout::vertical(
+            Chain::new(
+                LinearLayout::horizontal(Chain::new(&mut gps_button).append(Text::new(
+                    on_off(sats.gps),
+                    Point::zero(),
+                    sl,
+                )))
+                .arrange(),
+            )
+            .append(
+                LinearLayout::horizontal(Chain::new(&mut bds_button).append(Text::new(
+                    on_off(sats.bds),
+                    Point::zero(),
+                    sl,
+                )))
+                .arrange(),
+            )
+            .append(
+                LinearLayout::horizontal(Chain::new(&mut glonass_button).append(Text::new(
+                    on_off(sats.glonass),
+                    Point::zero(),
+                    sl,
+                )))
+                .arrange(),
+            )
+            .append(
+                LinearLayout::horizontal(
+                    Chain::new(Text::new("Rate: ", Point::zero(), sl))
+                        .append(&mut plus_button_rate)
+                        .append(Text::new(rate_text.as_str(), Point::zero(), sl))
+                        .append(&mut minus_button_rate),
+                )
+                .arrange(),
+            )
+            .append(&mut save_button),
+        )
+        .with_alignment(horizontal::Left)
+        .with_spacing(embedded_layout::layout::linear::FixedMargin(5))
+        .arrange()
+        .align_to(&display_area, horizontal::Center, vertical::Center);
+
+        layout.draw(&mut *ctx.lcd).unwrap();
+
+        ctx.lcd.present().await;
+
+        match select::select4(
+            ticker.next(),
+            ctx.button.wait_for_press(),
+            drivers::wait_display_event(),
+            touch.wait_for_action(),
+        )
+        .await
+        {
+            select::Either4::First(_) => {}
+            select::Either4::Second(_d) => {
+                break;
+            }
+            select::Either4::Third(_event) => {}
+            select::Either4::Fourth(e) => {
+                ctx.backlight.active().await;
+                match layout.touch(e, &mut settings) {
+                    crate::ui::TouchResult::Done(Action::Continue) => {}
+                    crate::ui::TouchResult::Done(Action::Stop) => {
+                        ctx.flash.with_fs(|fs| settings.save(fs)).await.unwrap();
+                        break;
+                    }
+                    crate::ui::TouchResult::Continue => {}
+                }
+            }
+        }
+    }
+}
@@ -11,17 +11,31 @@ pub fn hours_mins_secs(d: Duration) -> (u32, u32, u32) {
     (hours as _, min_clock as _, sec_clock as _)
 }
 
+/// Number of samples (about a minute at 25Hz) the sample rate is measured over. The samples come
+/// in batches, so the time they are noted at jitters by a few hundred ms, which must be small
+/// compared to the whole window.
+const RATE_WINDOW_SAMPLES: usize = 1500;
+
+/// Measures the time between hrm samples against the system clock. Starts over every
+/// `RATE_WINDOW_SAMPLES`, so that changes of the rate (e.g. of the sample delay) are followed.
 pub struct SampleCountingEstimator {
     num_samples: usize,
     start: Instant,
+    /// Over the last complete window
+    measured: Option<f32>,
 }
 impl SampleCountingEstimator {
     pub fn new() -> Self {
         Self {
             num_samples: 0,
             start: Instant::now(),
+            measured: None,
         }
     }
+
+    fn millis_per_sample_since_start(&self) -> f32 {
+        self.start.elapsed().as_millis() as f32 / (self.num_samples - 1) as f32
+    }
 }
 impl hrm::EstimateSampleRate for SampleCountingEstimator {
     fn note_sample(&mut self) {
@@ -29,9 +43,22 @@ impl hrm::EstimateSampleRate for SampleCountingEstimator {
             self.start = Instant::now();
         }
         self.num_samples += 1;
+        if self.num_samples > RATE_WINDOW_SAMPLES {
+            self.measured = Some(self.millis_per_sample_since_start());
+            // This sample is the first of the next window
+            self.start = Instant::now();
+            self.num_samples = 1;
+        }
     }
 
     fn millis_per_sample(&self) -> f32 {
-        self.start.elapsed().as_millis() as f32 / (self.num_samples - 1) as f32
+        self.measured
+            .unwrap_or_else(|| self.millis_per_sample_since_start())
+    }
+
+    /// Only once a whole window has been measured, before the estimate is too coarse to tune the
+    /// filters to.
+    fn sample_rate(&self) -> Option<hrm::SampleRate> {
+        hrm::SampleRate::from_ms_per_sample(self.measured?)
     }
 }
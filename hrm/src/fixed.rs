@@ -0,0 +1,295 @@
+//! Fixed-point version of the spectral estimation (`SparseFFTEstimator`, `spectrum_norm`,
+//! `SpectrumSmoother` and `peak_index`), which runs for every sample.
+//!
+//! The detectors use it instead of the float version when the `fixed-point` feature is enabled.
+//! The sliding DFT accumulates products of integer samples and Q15 twiddle factors in `i64`, so
+//! removing a sample from the window exactly cancels adding it and, unlike in the float version,
+//! rounding errors don't build up over time.
+
+use num_complex::Complex;
+use util::RingBuffer;
+
+use crate::{
+    SampleRate, Spectrum, SpectrumC, BASE_FREQ_INDEX, BPM, NUM_FFT_SAMPLES, SPECTRUM_SIZE,
+};
+
+/// Fractional bits of the samples fed to `SparseFFTEstimator` by `SpectralEstimator`, i.e. the
+/// high passed signal has a range of +-4096 counts. The quantization is what makes up most of the
+/// difference to the float version, each bit less doubles it.
+const SAMPLE_FRACTION_BITS: u32 = 3;
+/// The DFT sum is shifted right by this much, which is the least to keep it in an `i32` for a
+/// full scale signal. What is left are 8 fractional bits relative to the input samples.
+const SPECTRUM_SHIFT: u32 = 7;
+const SPECTRUM_FRACTION_BITS: u32 = 15 - SPECTRUM_SHIFT;
+/// Fractional bits of the (l2 normalized, so at most 1) smoothed spectrum
+const SMOOTH_FRACTION_BITS: u32 = 24;
+
+/// Like `SpectrumC`, with `SPECTRUM_FRACTION_BITS` fractional bits.
+pub type SpectrumCQ = [Complex<i32>; SPECTRUM_SIZE];
+/// Like `Spectrum`, magnitudes with `SPECTRUM_FRACTION_BITS` or (after smoothing)
+/// `SMOOTH_FRACTION_BITS` fractional bits.
+pub type SpectrumQ = [u32; SPECTRUM_SIZE];
+
+pub struct SparseFFTEstimator {
+    history: RingBuffer<NUM_FFT_SAMPLES, i16>,
+    spectrum: [Complex<i64>; SPECTRUM_SIZE],
+    /// exp(i * TAU * n / NUM_FFT_SAMPLES) in Q15
+    twiddles: [Complex<i16>; NUM_FFT_SAMPLES],
+}
+
+impl Default for SparseFFTEstimator {
+    fn default() -> Self {
+        Self {
+            history: RingBuffer::default(),
+            spectrum: [Complex::new(0, 0); SPECTRUM_SIZE],
+            twiddles: core::array::from_fn(|n| {
+                let phase = core::f32::consts::TAU * n as f32 / NUM_FFT_SAMPLES as f32;
+                let q15 = |v: f32| libm::roundf(v * i16::MAX as f32) as i16;
+                Complex::new(q15(libm::cosf(phase)), q15(libm::sinf(phase)))
+            }),
+        }
+    }
+}
+
+impl SparseFFTEstimator {
+    pub fn add_sample(&mut self, sample: i16) -> Option<SpectrumCQ> {
+        let pos = self.history.next();
+        let complete = self.history.is_full();
+
+        let old = self.history.add(sample);
+        let diff = sample as i64 - old as i64;
+
+        let mut twiddle_i = (pos * BASE_FREQ_INDEX) % NUM_FFT_SAMPLES;
+        for v in self.spectrum.iter_mut() {
+            let twiddle = self.twiddles[twiddle_i];
+            v.re += twiddle.re as i64 * diff;
+            v.im += twiddle.im as i64 * diff;
+            twiddle_i += pos;
+            if twiddle_i >= NUM_FFT_SAMPLES {
+                twiddle_i -= NUM_FFT_SAMPLES;
+            }
+        }
+
+        if complete {
+            Some(core::array::from_fn(|i| {
+                let v = self.spectrum[i];
+                Complex::new(
+                    (v.re >> SPECTRUM_SHIFT) as i32,
+                    (v.im >> SPECTRUM_SHIFT) as i32,
+                )
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+/// Magnitudes of the spectrum. They are computed with a precision of 15 bits relative to the
+/// largest component, which keeps everything in `u32`.
+pub fn spectrum_norm(s: &SpectrumCQ) -> SpectrumQ {
+    let max = s
+        .iter()
+        .map(|v| v.re.unsigned_abs().max(v.im.unsigned_abs()))
+        .max()
+        .unwrap_or(0);
+    let shift = (32 - max.leading_zeros()).saturating_sub(15);
+    core::array::from_fn(|i| {
+        let re = s[i].re.unsigned_abs() >> shift;
+        let im = s[i].im.unsigned_abs() >> shift;
+        magnitude(re, im) << shift
+    })
+}
+
+/// `sqrt(re^2 + im^2)` for values below 2^15
+fn magnitude(re: u32, im: u32) -> u32 {
+    let (hi, lo) = (re.max(im), re.min(im));
+    if hi == 0 {
+        return 0;
+    }
+    // Alpha max plus beta min is off by up to 5%, one Newton step brings that down to about 0.1%.
+    let estimate = (31 * hi + 13 * lo).div_ceil(32);
+    (estimate + (re * re + im * im) / estimate) / 2
+}
+
+/// Scales the spectrum to an l2 norm of 1 with `SMOOTH_FRACTION_BITS` fractional bits.
+fn normalize_spectrum_l2(s: &SpectrumQ) -> SpectrumQ {
+    let max = s.iter().copied().max().unwrap_or(0);
+    if max == 0 {
+        return *s;
+    }
+    // Scale the values to [2^15, 2^16) so that the squares can be summed up without overflow and
+    // the norm is large enough for its reciprocal to be precise.
+    let bits = 32 - max.leading_zeros();
+    let scaled = |v: u32| {
+        if bits > 16 {
+            v >> (bits - 16)
+        } else {
+            v << (16 - bits)
+        }
+    };
+    let sum_sq: u64 = s.iter().map(|v| (scaled(*v) as u64).pow(2)).sum();
+    let norm = sum_sq.isqrt();
+    let inv_norm = (1 << (SMOOTH_FRACTION_BITS + 16)) / norm;
+    core::array::from_fn(|i| ((scaled(s[i]) as u64 * inv_norm) >> 16) as u32)
+}
+
+fn kernel_smooth(s: &SpectrumQ) -> SpectrumQ {
+    core::array::from_fn(|i| {
+        let mut v = 100 * s[i];
+        if i > 0 {
+            v += s[i - 1];
+        }
+        if i < s.len() - 1 {
+            v += s[i + 1];
+        }
+        v / 120
+    })
+}
+
+pub struct SpectrumSmoother {
+    spectrum_agg: SpectrumQ,
+}
+
+impl Default for SpectrumSmoother {
+    fn default() -> Self {
+        Self {
+            spectrum_agg: [0; SPECTRUM_SIZE],
+        }
+    }
+}
+
+impl SpectrumSmoother {
+    pub fn add(&mut self, spectrum: &SpectrumQ) -> SpectrumQ {
+        let r = normalize_spectrum_l2(spectrum);
+        for (l, r) in self.spectrum_agg.iter_mut().zip(r) {
+            // Exponential mean with alpha = 0.99, rounded
+            *l = (*l * 99 + r + 50) / 100;
+        }
+        kernel_smooth(&self.spectrum_agg)
+    }
+}
+
+pub fn peak_index(spectrum: &SpectrumQ) -> usize {
+    // Like `max_by`, picks the last of equal values.
+    spectrum
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, v)| **v)
+        .unwrap()
+        .0
+}
+
+pub fn spectrum_c_to_float(s: &SpectrumCQ) -> SpectrumC {
+    let scale = 1.0 / (1 << (SPECTRUM_FRACTION_BITS + SAMPLE_FRACTION_BITS)) as f32;
+    core::array::from_fn(|i| Complex::new(s[i].re as f32 * scale, s[i].im as f32 * scale))
+}
+
+/// For a smoothed spectrum
+pub fn spectrum_to_float(s: &SpectrumQ) -> Spectrum {
+    let scale = 1.0 / (1 << SMOOTH_FRACTION_BITS) as f32;
+    core::array::from_fn(|i| s[i] as f32 * scale)
+}
+
+/// Drop-in replacement for `crate::SpectralEstimator`. The results are converted to float, which
+/// is cheap compared to computing them.
+#[derive(Default)]
+pub struct SpectralEstimator {
+    freq_detector: SparseFFTEstimator,
+    spec_smoother: SpectrumSmoother,
+    sample_rate: SampleRate,
+}
+
+impl SpectralEstimator {
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn add_sample(&mut self, sample: f32) -> Option<(SpectrumC, Spectrum, BPM)> {
+        // Saturates for huge artifacts, which are clipped anyway.
+        let sample = libm::roundf(sample * (1 << SAMPLE_FRACTION_BITS) as f32) as i16;
+        let spectrum_c = self.freq_detector.add_sample(sample)?;
+        let spectrum = self.spec_smoother.add(&spectrum_norm(&spectrum_c));
+        let bpm = self.sample_rate.index_to_bpm(peak_index(&spectrum));
+        Some((
+            spectrum_c_to_float(&spectrum_c),
+            spectrum_to_float(&spectrum),
+            BPM(libm::roundf(bpm) as _),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feeds a sine of `bpm` to both spectral estimators, returns their last estimates.
+    fn estimate_sine(bpm: f32, amplitude: f32) -> (BPM, BPM) {
+        let mut float = crate::SpectralEstimator::default();
+        let mut fixed = SpectralEstimator::default();
+        let ms_per_sample = SampleRate::default().ms_per_sample;
+        let mut result = None;
+        for n in 0..4 * NUM_FFT_SAMPLES {
+            let t_s = n as f32 * ms_per_sample / 1000.0;
+            let sample = amplitude * libm::sinf(core::f32::consts::TAU * bpm / 60.0 * t_s);
+            let float = float.add_sample(sample);
+            let fixed = fixed.add_sample(sample);
+            assert_eq!(float.is_some(), fixed.is_some());
+            result = float.zip(fixed).map(|(float, fixed)| (float.2, fixed.2));
+        }
+        result.unwrap()
+    }
+
+    #[test]
+    fn test_same_peak_as_float() {
+        for bpm in [60.0, 72.0, 95.0, 130.0, 170.0] {
+            let (float, fixed) = estimate_sine(bpm, 50.0);
+            assert_eq!(float.0, fixed.0, "{bpm} bpm");
+            assert!(
+                (float.0 as f32 - bpm).abs() <= 10.0,
+                "{bpm} bpm: {}",
+                float.0
+            );
+        }
+    }
+
+    #[test]
+    fn test_same_peak_as_float_full_scale() {
+        // Just below where the samples saturate
+        let amplitude = (i16::MAX >> SAMPLE_FRACTION_BITS) as f32;
+        for bpm in [60.0, 95.0, 170.0] {
+            let (float, fixed) = estimate_sine(bpm, amplitude);
+            assert_eq!(float.0, fixed.0, "{bpm} bpm");
+        }
+    }
+
+    fn assert_relative_error(value: u32, expected: f32, max_error: f32) {
+        let error = (value as f32 - expected).abs() / expected;
+        assert!(error <= max_error, "{value} vs {expected}: {error}");
+    }
+
+    #[test]
+    fn test_magnitude() {
+        assert_eq!(magnitude(0, 0), 0);
+        assert_eq!(magnitude(3, 4), 5);
+        let max = (1 << 15) - 1;
+        for (re, im) in [(max, max), (max, 0), (0, max), (max, 1), (max, max / 2)] {
+            let expected = libm::hypotf(re as f32, im as f32);
+            assert_relative_error(magnitude(re, im), expected, 0.002);
+        }
+    }
+
+    #[test]
+    fn test_spectrum_norm_full_scale() {
+        let mut s = [Complex::new(0, 0); SPECTRUM_SIZE];
+        s[0] = Complex::new(i32::MIN, i32::MIN);
+        s[1] = Complex::new(i32::MAX, -i32::MAX);
+        s[2] = Complex::new(i32::MIN, 0);
+        s[3] = Complex::new(1 << 20, -(1 << 24));
+        let norm = spectrum_norm(&s);
+        for (v, n) in s.iter().zip(norm).take(4) {
+            let expected = libm::hypotf(v.re as f32, v.im as f32);
+            assert_relative_error(n, expected, 0.002);
+        }
+        assert!(norm[4..].iter().all(|n| *n == 0));
+    }
+}
@@ -0,0 +1,300 @@
+//! Heart rate variability from the intervals between individual beats (RR intervals).
+//!
+//! Beats come from `HeartbeatDetector::add_sample_interval`. Missed or extra beats (e.g. from
+//! movement) produce intervals far off the recent ones, which would dominate all metrics, so they
+//! are dropped by `ArtifactRejection` before they enter an `HrvWindow`.
+
+use util::RingBuffer;
+
+/// Interval between two beats that passed the artifact rejection.
+#[derive(Clone, Copy, Default)]
+pub struct RrInterval {
+    pub ms: f32,
+    /// The interval(s) directly before this one were rejected, so the difference to the previous
+    /// accepted interval is not a beat-to-beat difference.
+    pub after_gap: bool,
+}
+
+/// Intervals differing more than this fraction from the median of the recent ones are rejected.
+const MAX_DEVIATION: f32 = 0.2;
+/// Number of recent intervals (accepted or not) the median is taken of.
+const REJECTION_HISTORY: usize = 7;
+/// Nothing is accepted before there are this many intervals to compare to.
+const MIN_REJECTION_HISTORY: usize = 3;
+
+/// Compares every interval to the median of the recent ones. The median includes rejected
+/// intervals, so that it follows a real change of the heart rate within a few beats.
+pub struct ArtifactRejection {
+    recent: RingBuffer<REJECTION_HISTORY, f32>,
+    gap: bool,
+}
+
+impl Default for ArtifactRejection {
+    fn default() -> Self {
+        Self {
+            recent: Default::default(),
+            // There is no previous interval to compute a difference with.
+            gap: true,
+        }
+    }
+}
+
+impl ArtifactRejection {
+    pub fn filter(&mut self, ms: f32) -> Option<RrInterval> {
+        self.recent.add(ms);
+        let mut sorted = *self.recent.inner();
+        let sorted = &mut sorted[..self.recent.num_valid()];
+        sorted.sort_unstable_by(f32::total_cmp);
+        let median = sorted[sorted.len() / 2];
+
+        if sorted.len() >= MIN_REJECTION_HISTORY && (ms - median).abs() <= MAX_DEVIATION * median {
+            let after_gap = core::mem::replace(&mut self.gap, false);
+            Some(RrInterval { ms, after_gap })
+        } else {
+            self.gap = true;
+            None
+        }
+    }
+}
+
+pub struct HrvMetrics {
+    pub mean_rr_ms: f32,
+    /// Standard deviation of the intervals
+    pub sdnn_ms: f32,
+    /// Root mean square of the successive differences
+    pub rmssd_ms: f32,
+    /// Percentage of successive differences larger than 50ms
+    pub pnn50: f32,
+    pub num_intervals: u16,
+}
+
+impl HrvMetrics {
+    pub fn heart_rate(&self) -> f32 {
+        60.0 * 1000.0 / self.mean_rr_ms
+    }
+}
+
+/// The accepted intervals of the last `window_ms`, at most `N` of them.
+pub struct HrvWindow<const N: usize> {
+    intervals: RingBuffer<N, RrInterval>,
+    window_ms: f32,
+}
+
+impl<const N: usize> HrvWindow<N> {
+    pub fn new(window_ms: f32) -> Self {
+        Self {
+            intervals: Default::default(),
+            window_ms,
+        }
+    }
+
+    pub fn add(&mut self, interval: RrInterval) {
+        self.intervals.add(interval);
+    }
+
+    /// Newest first
+    fn window(&self) -> impl Iterator<Item = RrInterval> + '_ {
+        let mut total_ms = 0.0;
+        (1..=self.intervals.num_valid())
+            .map(|i| *self.intervals.past_value(i))
+            .take_while(move |interval| {
+                total_ms += interval.ms;
+                total_ms <= self.window_ms
+            })
+    }
+
+    /// Total duration of the intervals in the window.
+    pub fn duration_ms(&self) -> f32 {
+        self.window().map(|interval| interval.ms).sum()
+    }
+
+    /// `None` until there are two successive intervals.
+    pub fn metrics(&self) -> Option<HrvMetrics> {
+        let mut num_intervals = 0;
+        let mut sum = 0.0;
+        let mut num_diffs = 0;
+        let mut sum_squared_diffs = 0.0;
+        let mut num_diffs_above_50 = 0;
+        let mut newer: Option<RrInterval> = None;
+        for interval in self.window() {
+            num_intervals += 1;
+            sum += interval.ms;
+            if let Some(newer) = newer.filter(|newer| !newer.after_gap) {
+                let diff = newer.ms - interval.ms;
+                num_diffs += 1;
+                sum_squared_diffs += diff * diff;
+                if diff.abs() > 50.0 {
+                    num_diffs_above_50 += 1;
+                }
+            }
+            newer = Some(interval);
+        }
+        if num_diffs == 0 {
+            return None;
+        }
+
+        let mean_rr_ms = sum / num_intervals as f32;
+        let variance = self
+            .window()
+            .map(|interval| (interval.ms - mean_rr_ms) * (interval.ms - mean_rr_ms))
+            .sum::<f32>()
+            / num_intervals as f32;
+
+        Some(HrvMetrics {
+            mean_rr_ms,
+            sdnn_ms: libm::sqrtf(variance),
+            rmssd_ms: libm::sqrtf(sum_squared_diffs / num_diffs as f32),
+            pnn50: 100.0 * num_diffs_above_50 as f32 / num_diffs as f32,
+            num_intervals,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn interval(ms: f32) -> RrInterval {
+        RrInterval {
+            ms,
+            after_gap: false,
+        }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn test_artifact_rejection() {
+        let mut rejection = ArtifactRejection::default();
+        // Not enough history yet
+        assert!(rejection.filter(1000.0).is_none());
+        assert!(rejection.filter(1010.0).is_none());
+
+        let first = rejection.filter(990.0).unwrap();
+        assert!(first.after_gap);
+        assert!(!rejection.filter(1000.0).unwrap().after_gap);
+
+        // A missed beat
+        assert!(rejection.filter(2000.0).is_none());
+        let after = rejection.filter(1000.0).unwrap();
+        assert_eq!(after.ms, 1000.0);
+        assert!(after.after_gap);
+        assert!(!rejection.filter(1005.0).unwrap().after_gap);
+    }
+
+    #[test]
+    fn test_artifact_rejection_follows_rate_change() {
+        let mut rejection = ArtifactRejection::default();
+        for _ in 0..REJECTION_HISTORY {
+            rejection.filter(1000.0);
+        }
+        let accepted = (0..REJECTION_HISTORY)
+            .filter_map(|_| rejection.filter(600.0))
+            .count();
+        assert!(accepted > 0);
+        assert!(rejection.filter(600.0).is_some());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut window = HrvWindow::<16>::new(60_000.0);
+        assert!(window.metrics().is_none());
+        window.add(interval(800.0));
+        assert!(window.metrics().is_none());
+        for ms in [900.0, 800.0, 900.0] {
+            window.add(interval(ms));
+        }
+
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.num_intervals, 4);
+        assert_close(metrics.mean_rr_ms, 850.0);
+        assert_close(metrics.sdnn_ms, 50.0);
+        assert_close(metrics.rmssd_ms, 100.0);
+        assert_close(metrics.pnn50, 100.0);
+        assert_close(metrics.heart_rate(), 60_000.0 / 850.0);
+        assert_close(window.duration_ms(), 3400.0);
+    }
+
+    #[test]
+    fn test_metrics_pnn50_is_strictly_above() {
+        let mut window = HrvWindow::<16>::new(60_000.0);
+        for ms in [800.0, 850.0, 800.0, 870.0] {
+            window.add(interval(ms));
+        }
+        let metrics = window.metrics().unwrap();
+        assert_close(metrics.pnn50, 100.0 / 3.0);
+    }
+
+    #[test]
+    fn test_window_truncation() {
+        let mut window = HrvWindow::<16>::new(2000.0);
+        for ms in [700.0, 800.0, 900.0, 800.0, 900.0] {
+            window.add(interval(ms));
+        }
+        // Only the newest 900 + 800 fit, the next one would exceed the window.
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.num_intervals, 2);
+        assert_close(metrics.mean_rr_ms, 850.0);
+        assert_close(metrics.rmssd_ms, 100.0);
+        assert_close(window.duration_ms(), 1700.0);
+    }
+
+    #[test]
+    fn test_window_capacity() {
+        let mut window = HrvWindow::<3>::new(60_000.0);
+        for ms in [500.0, 800.0, 900.0, 800.0] {
+            window.add(interval(ms));
+        }
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.num_intervals, 3);
+        assert_close(window.duration_ms(), 2500.0);
+    }
+
+    #[test]
+    fn test_metrics_skip_difference_across_gap() {
+        let mut window = HrvWindow::<16>::new(60_000.0);
+        window.add(interval(800.0));
+        window.add(interval(850.0));
+        window.add(RrInterval {
+            ms: 1000.0,
+            after_gap: true,
+        });
+        window.add(interval(1050.0));
+
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.num_intervals, 4);
+        // 850 -> 1000 is not a beat-to-beat difference
+        assert_close(metrics.rmssd_ms, 50.0);
+        assert_close(metrics.pnn50, 0.0);
+    }
+
+    #[test]
+    fn test_metrics_need_successive_intervals() {
+        let mut window = HrvWindow::<16>::new(60_000.0);
+        window.add(interval(800.0));
+        window.add(RrInterval {
+            ms: 900.0,
+            after_gap: true,
+        });
+        assert!(window.metrics().is_none());
+    }
+
+    #[test]
+    fn test_rejected_outlier_leaves_metrics_intact() {
+        let mut rejection = ArtifactRejection::default();
+        let mut window = HrvWindow::<16>::new(60_000.0);
+        for ms in [800.0, 900.0, 800.0, 900.0, 400.0, 800.0, 900.0] {
+            if let Some(interval) = rejection.filter(ms) {
+                window.add(interval);
+            }
+        }
+        // The first two only fill the history, the 400 is rejected and the 800 after it starts
+        // a new run of successive intervals.
+        let metrics = window.metrics().unwrap();
+        assert_eq!(metrics.num_intervals, 4);
+        assert_close(metrics.mean_rr_ms, 850.0);
+        assert_close(metrics.rmssd_ms, 100.0);
+    }
+}
@@ -2,6 +2,14 @@
 
 use util::RingBuffer;
 
+pub mod fixed;
+pub mod hrv;
+pub mod motion;
+pub mod quality;
+
+pub use motion::{AccelSample, MotionCompensatedDetector};
+pub use quality::{Estimate, Quality, QualityEstimator};
+
 /*
 
 FIR filter designed with
@@ -84,16 +92,24 @@ pub struct UnbiasedBiquadSampleFilter {
 
 impl UnbiasedBiquadSampleFilter {
     pub fn new() -> Self {
+        Self {
+            inner: biquad::DirectForm2Transposed::<f32>::new(Self::coefficients(
+                SampleRate::NOMINAL,
+            )),
+        }
+    }
+
+    fn coefficients(sample_rate: SampleRate) -> biquad::Coefficients<f32> {
         use biquad::*;
-        let fs = 25.hz();
+        let fs = sample_rate.fs();
         let f0 = 2.hz();
+        Coefficients::<f32>::from_params(biquad::Type::BandPass, fs, f0, Q_BUTTERWORTH_F32).unwrap()
+    }
 
-        let coefficients =
-            Coefficients::<f32>::from_params(biquad::Type::BandPass, fs, f0, Q_BUTTERWORTH_F32)
-                .unwrap();
-        Self {
-            inner: DirectForm2Transposed::<f32>::new(coefficients),
-        }
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        use biquad::*;
+        self.inner
+            .update_coefficients(Self::coefficients(sample_rate));
     }
 
     pub fn filter(&mut self, val: i16) -> f32 {
@@ -108,16 +124,24 @@ pub struct UnbiasedBiquadHighPass {
 
 impl UnbiasedBiquadHighPass {
     pub fn new() -> Self {
+        Self {
+            inner: biquad::DirectForm2Transposed::<f32>::new(Self::coefficients(
+                SampleRate::NOMINAL,
+            )),
+        }
+    }
+
+    fn coefficients(sample_rate: SampleRate) -> biquad::Coefficients<f32> {
         use biquad::*;
-        let fs = 25.hz();
+        let fs = sample_rate.fs();
         let f0 = 1.0.hz();
+        Coefficients::<f32>::from_params(biquad::Type::HighPass, fs, f0, Q_BUTTERWORTH_F32).unwrap()
+    }
 
-        let coefficients =
-            Coefficients::<f32>::from_params(biquad::Type::HighPass, fs, f0, Q_BUTTERWORTH_F32)
-                .unwrap();
-        Self {
-            inner: DirectForm2Transposed::<f32>::new(coefficients),
-        }
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        use biquad::*;
+        self.inner
+            .update_coefficients(Self::coefficients(sample_rate));
     }
 
     pub fn filter(&mut self, val: i16) -> f32 {
@@ -187,7 +211,71 @@ impl GradientClip {
     }
 }
 
-const SAMPLE_DELAY_MS: usize = 40;
+const NOMINAL_SAMPLE_DELAY_MS: usize = 40;
+
+/// Time between two samples of the PPG signal. The filters and the heart rate of each spectrum
+/// index are derived from it, so that they stay right when the sensor runs faster or slower than
+/// nominal (its oscillator drifts, and the sample delay can be changed).
+///
+/// The spectrum always covers `NUM_FFT_SAMPLES` samples, so its resolution and the range of heart
+/// rates it covers scale with the rate. So do the time constants of the running means, which are
+/// per sample. Neither matters for the few percent the rate is off in practice.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SampleRate {
+    ms_per_sample: f32,
+}
+
+impl Default for SampleRate {
+    fn default() -> Self {
+        Self::NOMINAL
+    }
+}
+
+impl SampleRate {
+    /// 25Hz
+    pub const NOMINAL: SampleRate = SampleRate {
+        ms_per_sample: NOMINAL_SAMPLE_DELAY_MS as f32,
+    };
+    /// Measurements outside of this range are broken, and the filters can't be designed for them.
+    const MIN_MS_PER_SAMPLE: f32 = 10.0;
+    const MAX_MS_PER_SAMPLE: f32 = 100.0;
+    /// Relative change of the rate below which the detectors don't bother to retune.
+    const TOLERANCE: f32 = 0.01;
+
+    /// `None` if `ms_per_sample` is not a plausible measurement (e.g. infinite, because there are
+    /// not enough samples yet).
+    pub fn from_ms_per_sample(ms_per_sample: f32) -> Option<Self> {
+        (Self::MIN_MS_PER_SAMPLE..=Self::MAX_MS_PER_SAMPLE)
+            .contains(&ms_per_sample)
+            .then_some(Self { ms_per_sample })
+    }
+
+    pub fn ms_per_sample(self) -> f32 {
+        self.ms_per_sample
+    }
+
+    fn fs(self) -> biquad::Hertz<f32> {
+        use biquad::ToHertz;
+        (1000.0 / self.ms_per_sample).hz()
+    }
+
+    /// Whether `other` is far enough off to retune the filters.
+    fn differs_from(self, other: SampleRate) -> bool {
+        libm::fabsf(other.ms_per_sample / self.ms_per_sample - 1.0) > Self::TOLERANCE
+    }
+
+    /// Like `bpm_to_index`, at this rate.
+    pub fn bpm_to_index(self, bpm: f32) -> usize {
+        let fft_index = bpm * (NUM_FFT_SAMPLES as f32 * self.ms_per_sample) / (60.0 * 1000.0);
+        (libm::roundf(fft_index) as usize).wrapping_sub(BASE_FREQ_INDEX)
+    }
+
+    /// Like `index_to_bpm`, at this rate.
+    pub fn index_to_bpm(self, index: usize) -> f32 {
+        (index + BASE_FREQ_INDEX) as f32 * (60.0 * 1000.0)
+            / (NUM_FFT_SAMPLES as f32 * self.ms_per_sample)
+    }
+}
 
 pub struct FFTEstimator {
     samples: RingBuffer<NUM_FFT_SAMPLES, f32>,
@@ -206,14 +294,18 @@ const NUM_FFT_SAMPLES: usize = 150;
 const BASE_FREQ_INDEX: usize = bpm_to_fft_index(MIN_BPM);
 pub const SPECTRUM_SIZE: usize = bpm_to_fft_index(MAX_BPM) - BASE_FREQ_INDEX;
 const fn bpm_to_fft_index(bpm: usize) -> usize {
-    (bpm * NUM_FFT_SAMPLES * SAMPLE_DELAY_MS) / (60 * 1000)
+    (bpm * NUM_FFT_SAMPLES * NOMINAL_SAMPLE_DELAY_MS) / (60 * 1000)
 }
+/// Index into the spectrum at the nominal sample rate, see `SampleRate` for the actual one.
 pub const fn bpm_to_index(bpm: f32) -> usize {
-    ((((bpm * NUM_FFT_SAMPLES as f32 * SAMPLE_DELAY_MS as f32) / (60.0 * 1000.0)) + 0.5) as usize)
+    ((((bpm * NUM_FFT_SAMPLES as f32 * NOMINAL_SAMPLE_DELAY_MS as f32) / (60.0 * 1000.0)) + 0.5)
+        as usize)
         .wrapping_sub(BASE_FREQ_INDEX)
 }
+/// Heart rate of an index into the spectrum at the nominal sample rate, see `SampleRate` for the
+/// actual one.
 pub const fn index_to_bpm(index: usize) -> f32 {
-    ((index + BASE_FREQ_INDEX) * 60 * 1000 / (NUM_FFT_SAMPLES * SAMPLE_DELAY_MS)) as f32
+    ((index + BASE_FREQ_INDEX) * 60 * 1000 / (NUM_FFT_SAMPLES * NOMINAL_SAMPLE_DELAY_MS)) as f32
 }
 pub type Spectrum = [f32; SPECTRUM_SIZE];
 pub type SpectrumC = [Complex; SPECTRUM_SIZE];
@@ -310,26 +402,39 @@ pub fn normalize_spectrum_l2(s: Spectrum) -> Spectrum {
     }
 }
 
-pub fn max_bpm(spectrum: Spectrum) -> BPM {
-    let spectrum_index = spectrum
+pub fn peak_index(spectrum: Spectrum) -> usize {
+    spectrum
         .iter()
         .enumerate()
         .max_by(|l, r| l.1.total_cmp(&r.1))
         .unwrap()
-        .0;
+        .0
+}
 
-    let max_bpm = index_to_bpm(spectrum_index);
+/// At the nominal sample rate
+pub fn max_bpm(spectrum: Spectrum) -> BPM {
+    let max_bpm = index_to_bpm(peak_index(spectrum));
     BPM(max_bpm as _)
 }
 
+/// Size of the fft in `FFTEstimator`, the samples are zero padded to it.
+const FFT_SIZE: usize = 256;
+
 impl FFTEstimator {
     pub fn add_sample(&mut self, sample: f32) -> Option<Spectrum> {
         self.samples.add(sample);
-        if self.samples.num_valid() == NUM_FFT_SAMPLES {
-            let mut samples = core::array::from_fn(|i| self.samples.inner()[i]);
+        if self.samples.is_full() {
+            let mut samples = [0.0; FFT_SIZE];
+            for (i, s) in samples[..NUM_FFT_SAMPLES].iter_mut().enumerate() {
+                *s = *self.samples.past_value(NUM_FFT_SAMPLES - i);
+            }
             let spectrum = microfft::real::rfft_256(&mut samples);
-            let spectrum: Spectrum =
-                core::array::from_fn(|i| spectrum[i + BASE_FREQ_INDEX].l1_norm());
+            // The bins of the fft are finer than those of `Spectrum`, the nearest one is taken.
+            let spectrum: Spectrum = core::array::from_fn(|i| {
+                let bin =
+                    ((i + BASE_FREQ_INDEX) * FFT_SIZE + NUM_FFT_SAMPLES / 2) / NUM_FFT_SAMPLES;
+                spectrum[bin].l1_norm()
+            });
             Some(spectrum)
         } else {
             None
@@ -467,21 +572,53 @@ impl SpectrumSmoother {
     }
 }
 
+/// The spectral part of the detectors: finds the dominant frequency of the recent signal, which
+/// the band pass in front of the beat detection is tuned to.
+#[derive(Default)]
+pub struct SpectralEstimator {
+    freq_detector: SparseFFTEstimator,
+    spec_smoother: SpectrumSmoother,
+    sample_rate: SampleRate,
+}
+
+impl SpectralEstimator {
+    /// The spectrum is kept, only the heart rate of its peak changes.
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Returns the spectrum, the smoothed spectrum and the heart rate at its peak once enough
+    /// samples have been seen.
+    pub fn add_sample(&mut self, sample: f32) -> Option<(SpectrumC, Spectrum, BPM)> {
+        let spectrum_c = self.freq_detector.add_sample(sample)?;
+        let spectrum = self.spec_smoother.add(spectrum_norm(spectrum_c));
+        let bpm = self.sample_rate.index_to_bpm(peak_index(spectrum));
+        Some((spectrum_c, spectrum, BPM(libm::roundf(bpm) as _)))
+    }
+}
+
+/// The spectral estimation the detectors use, see the `fixed-point` feature.
+#[cfg(not(feature = "fixed-point"))]
+type DetectorSpectralEstimator = SpectralEstimator;
+#[cfg(feature = "fixed-point")]
+type DetectorSpectralEstimator = fixed::SpectralEstimator;
+
 pub struct BiasedSampleFilter {
     inner: biquad::DirectForm1<f32>,
     bpm: Option<BPM>,
+    sample_rate: SampleRate,
 }
 
 impl BiasedSampleFilter {
-    fn coefficients_unbiased() -> biquad::Coefficients<f32> {
+    fn coefficients_unbiased(sample_rate: SampleRate) -> biquad::Coefficients<f32> {
         use biquad::*;
-        let fs = 25.hz();
+        let fs = sample_rate.fs();
         let f0 = 2.hz();
         Coefficients::<f32>::from_params(biquad::Type::BandPass, fs, f0, Q_BUTTERWORTH_F32).unwrap()
     }
-    fn coefficients_biased(bpm: BPM) -> biquad::Coefficients<f32> {
+    fn coefficients_biased(bpm: BPM, sample_rate: SampleRate) -> biquad::Coefficients<f32> {
         use biquad::*;
-        let fs = 25.hz();
+        let fs = sample_rate.fs();
         let f0 = ((bpm.0 as f32) / 60.0).hz();
 
         const FILTER_WIDTH_FACTOR: f32 = 100.0;
@@ -496,8 +633,11 @@ impl BiasedSampleFilter {
 
     pub fn new() -> Self {
         Self {
-            inner: biquad::DirectForm1::<f32>::new(Self::coefficients_unbiased()),
+            inner: biquad::DirectForm1::<f32>::new(Self::coefficients_unbiased(
+                SampleRate::NOMINAL,
+            )),
             bpm: None,
+            sample_rate: SampleRate::NOMINAL,
         }
     }
 
@@ -505,14 +645,31 @@ impl BiasedSampleFilter {
         use biquad::*;
         if self.bpm != Some(bpm) {
             self.inner
-                .update_coefficients(Self::coefficients_biased(bpm));
+                .update_coefficients(Self::coefficients_biased(bpm, self.sample_rate));
             self.bpm = Some(bpm);
         }
     }
 
+    /// Keeps the filter at the same frequency (in Hz), whether tuned or not.
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        use biquad::*;
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            let coefficients = match self.bpm {
+                Some(bpm) => Self::coefficients_biased(bpm, sample_rate),
+                None => Self::coefficients_unbiased(sample_rate),
+            };
+            self.inner.update_coefficients(coefficients);
+        }
+    }
+
     pub fn filter(&mut self, val: i16) -> f32 {
+        self.filter_f32(val as f32)
+    }
+
+    pub fn filter_f32(&mut self, val: f32) -> f32 {
         use biquad::*;
-        self.inner.run(val as f32)
+        self.inner.run(val)
     }
 }
 
@@ -540,9 +697,16 @@ impl<T: Ord + Clone> OutlierFilter<T> {
 pub struct ZeroCrossHeartbeatDetector<E> {
     region: BeatRegion,
     sample_count: usize,
+    /// Sample of the last beat. Kept apart from the fractional part of its position, which an f32
+    /// can't hold next to a large sample count.
     last_beat_sample: usize,
+    /// Offset of the last beat from `last_beat_sample`, from the interpolation
+    last_beat_offset: f32,
+    prev_sample: f32,
     min_since_cross: f32,
     min_sample: usize,
+    before_min: f32,
+    after_min: Option<f32>,
     sr_estimator: E,
 }
 
@@ -552,16 +716,36 @@ impl<E> ZeroCrossHeartbeatDetector<E> {
             region: BeatRegion::Below,
             sample_count: 0,
             last_beat_sample: 0,
+            last_beat_offset: 0.0,
+            prev_sample: 0.0,
             min_since_cross: f32::MAX,
             min_sample: 0,
+            before_min: 0.0,
+            after_min: None,
             sr_estimator,
         }
     }
 }
 
+/// Offset (in samples, within +-0.5) of the extremum of the parabola through three equidistant
+/// samples from the middle one.
+fn parabolic_offset(before: f32, middle: f32, after: f32) -> f32 {
+    let curvature = before - 2.0 * middle + after;
+    if curvature.abs() < f32::EPSILON {
+        return 0.0;
+    }
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
 pub trait EstimateSampleRate {
     fn note_sample(&mut self);
     fn millis_per_sample(&self) -> f32;
+
+    /// The rate the detectors tune their filters to. `None` until the measurement is precise
+    /// enough, in which case they stay at the nominal rate.
+    fn sample_rate(&self) -> Option<SampleRate> {
+        SampleRate::from_ms_per_sample(self.millis_per_sample())
+    }
 }
 
 pub struct UncalibratedEstimator;
@@ -569,64 +753,87 @@ impl EstimateSampleRate for UncalibratedEstimator {
     fn note_sample(&mut self) {}
 
     fn millis_per_sample(&self) -> f32 {
-        40.0
+        SampleRate::NOMINAL.ms_per_sample()
     }
 }
 
 #[derive(PartialEq, Clone, Copy)]
 pub struct BPM(pub u16);
 
+impl BPM {
+    pub fn from_interval_ms(ms: f32) -> Self {
+        BPM(((60.0 * 1000.0) / ms) as u16)
+    }
+}
+
 impl<E: EstimateSampleRate> ZeroCrossHeartbeatDetector<E> {
     pub fn millis_per_sample(&mut self) -> f32 {
         self.sr_estimator.millis_per_sample()
     }
+    pub fn sample_rate(&self) -> Option<SampleRate> {
+        self.sr_estimator.sample_rate()
+    }
     pub fn add_sample(&mut self, s: f32) -> Option<BPM> {
+        self.add_sample_interval(s).map(BPM::from_interval_ms)
+    }
+
+    /// Returns the time since the previous beat in ms once a beat is detected. The minimum before
+    /// the rising zero crossing marks the beat, its position is interpolated between samples.
+    pub fn add_sample_interval(&mut self, s: f32) -> Option<f32> {
         self.sample_count += 1;
         self.sr_estimator.note_sample();
-        let bpm = {
-            if s < self.min_since_cross {
-                self.min_since_cross = s;
-                self.min_sample = self.sample_count;
-            }
 
-            match (self.region, s > 0.0) {
-                (BeatRegion::Above, false) => {
-                    self.region = BeatRegion::Below;
+        if s < self.min_since_cross {
+            self.min_since_cross = s;
+            self.min_sample = self.sample_count;
+            self.before_min = self.prev_sample;
+            self.after_min = None;
+        } else if self.after_min.is_none() {
+            self.after_min = Some(s);
+        }
+        self.prev_sample = s;
+
+        match (self.region, s > 0.0) {
+            (BeatRegion::Above, false) => {
+                self.region = BeatRegion::Below;
+                None
+            }
+            (BeatRegion::Below, true) => {
+                let offset = self.after_min.map_or(0.0, |after| {
+                    parabolic_offset(self.before_min, self.min_since_cross, after)
+                });
+                let samples_since_last_beat = (self.min_sample - self.last_beat_sample) as f32
+                    + (offset - self.last_beat_offset);
+                self.last_beat_sample = self.min_sample;
+                self.last_beat_offset = offset;
+                self.min_since_cross = f32::MAX;
+
+                let beat_duration_millis = samples_since_last_beat * self.millis_per_sample();
+                let bpm = BPM::from_interval_ms(beat_duration_millis).0;
+
+                self.region = BeatRegion::Above;
+
+                if MIN_BPM as u16 <= bpm && bpm < MAX_BPM as u16 {
+                    Some(beat_duration_millis)
+                } else {
                     None
                 }
-                (BeatRegion::Below, true) => {
-                    let samples_since_last_beat = self.min_sample - self.last_beat_sample;
-                    self.last_beat_sample = self.min_sample;
-                    self.min_since_cross = f32::MAX;
-
-                    let beat_duration_millis =
-                        samples_since_last_beat as f32 * self.millis_per_sample();
-                    let bpm = ((60.0 * 1000.0) / beat_duration_millis) as u16;
-
-                    self.region = BeatRegion::Above;
-
-                    if MIN_BPM as u16 <= bpm && bpm < MAX_BPM as u16 {
-                        Some(BPM(bpm))
-                    } else {
-                        None
-                    }
-                }
-                _ => None,
             }
-        };
-
-        bpm
+            _ => None,
+        }
     }
 }
 
 pub struct HeartbeatDetector<E> {
     gradient_clip: GradientClip,
     high_pass: UnbiasedBiquadHighPass,
-    freq_detector: SparseFFTEstimator,
-    spec_smoother: SpectrumSmoother,
+    spectral: DetectorSpectralEstimator,
     biased_filter: BiasedSampleFilter,
     cross_detector: ZeroCrossHeartbeatDetector<E>,
     bpm_mean: ExpMean,
+    quality: QualityEstimator,
+    /// What the filters are tuned to
+    sample_rate: SampleRate,
 }
 
 impl<E> HeartbeatDetector<E> {
@@ -634,11 +841,12 @@ impl<E> HeartbeatDetector<E> {
         Self {
             gradient_clip: Default::default(),
             high_pass: UnbiasedBiquadHighPass::new(),
-            freq_detector: Default::default(),
-            spec_smoother: Default::default(),
+            spectral: Default::default(),
             biased_filter: BiasedSampleFilter::new(),
             cross_detector: ZeroCrossHeartbeatDetector::new(sr_estimator),
             bpm_mean: ExpMean::new(0.8),
+            quality: Default::default(),
+            sample_rate: SampleRate::NOMINAL,
         }
     }
 }
@@ -646,18 +854,104 @@ impl<E: EstimateSampleRate> HeartbeatDetector<E> {
     pub fn millis_per_sample(&mut self) -> f32 {
         self.cross_detector.millis_per_sample()
     }
-    pub fn add_sample(&mut self, s: i16) -> (f32, Option<BPM>) {
+    /// Quality of the current estimate, see `quality`. Without an accelerometer, it does not
+    /// include how much the arm moves.
+    pub fn quality(&self) -> Quality {
+        self.quality.quality()
+    }
+
+    /// Retunes to the measured sample rate when it is off by more than a little.
+    fn update_sample_rate(&mut self) {
+        let Some(sample_rate) = self.cross_detector.sample_rate() else {
+            return;
+        };
+        if self.sample_rate.differs_from(sample_rate) {
+            self.sample_rate = sample_rate;
+            self.high_pass.set_sample_rate(sample_rate);
+            self.spectral.set_sample_rate(sample_rate);
+            self.biased_filter.set_sample_rate(sample_rate);
+            self.quality.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn add_sample(&mut self, s: i16) -> (f32, Option<Estimate>) {
+        let (s_bp, interval) = self.add_sample_interval(s);
+        let bpm = interval.map(BPM::from_interval_ms);
+        let bpm = bpm.map(|bpm| Estimate {
+            bpm: BPM(libm::roundf(self.bpm_mean.add(bpm.0 as f32)) as u16),
+            quality: self.quality(),
+        });
+        (s_bp, bpm)
+    }
+
+    /// Like `add_sample`, but returns the interval to the previous beat in ms instead of the
+    /// averaged heart rate. See `hrv` for what to do with it.
+    pub fn add_sample_interval(&mut self, s: i16) -> (f32, Option<f32>) {
+        self.update_sample_rate();
         let s_clip = self.gradient_clip.add_value(s);
         let s_hp = self.high_pass.filter(s_clip);
-        if let Some(spectrum) = self.freq_detector.add_sample(s_hp) {
-            let spectrum = spectrum_norm(spectrum);
-            let spectrum = self.spec_smoother.add(spectrum);
-            let bpm = max_bpm(spectrum);
+        if let Some((spectrum_c, spectrum, bpm)) = self.spectral.add_sample(s_hp) {
             self.biased_filter.tune(bpm);
+            self.quality.add_spectrum(&spectrum_c, &spectrum, bpm);
         }
         let s_bp = self.biased_filter.filter(s);
-        let bpm = self.cross_detector.add_sample(s_bp);
-        let bpm = bpm.map(|bpm| BPM(libm::roundf(self.bpm_mean.add(bpm.0 as f32)) as u16));
-        (s_bp, bpm)
+        self.quality.add_sample(s_hp);
+        (s_bp, self.cross_detector.add_sample_interval(s_bp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parabolic_offset() {
+        // Samples of (x - 0.25)^2 at -1, 0 and 1
+        assert_eq!(parabolic_offset(1.5625, 0.0625, 0.5625), 0.25);
+        assert_eq!(parabolic_offset(0.5625, 0.0625, 1.5625), -0.25);
+        assert_eq!(parabolic_offset(1.0, 0.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_parabolic_offset_degenerate() {
+        // Flat, no curvature
+        assert_eq!(parabolic_offset(2.0, 2.0, 2.0), 0.0);
+        // Not a minimum at the middle sample, stays within half a sample
+        assert_eq!(parabolic_offset(10.0, 1.0, 0.0), 0.5);
+        assert_eq!(parabolic_offset(0.0, 1.0, 10.0), -0.5);
+    }
+
+    #[test]
+    fn test_fft_estimator() {
+        let mut estimator = FFTEstimator::default();
+        let mut spectrum = None;
+        // 90 bpm at 25Hz
+        for i in 0..NUM_FFT_SAMPLES + 10 {
+            let t_s = i as f32 * NOMINAL_SAMPLE_DELAY_MS as f32 / 1000.0;
+            spectrum = estimator.add_sample(libm::sinf(2.0 * core::f32::consts::PI * 1.5 * t_s));
+        }
+        assert_eq!(max_bpm(spectrum.unwrap()).0, 90);
+    }
+
+    #[test]
+    fn test_intervals_after_long_run() {
+        let mut detector = ZeroCrossHeartbeatDetector::new(UncalibratedEstimator);
+        // Almost two months at 25Hz, far beyond where an f32 counts single samples.
+        detector.sample_count = 125_000_000;
+
+        // 60 bpm, with the minima between samples
+        let period = 25.0;
+        let mut num_beats = 0;
+        for i in 0..10 * period as usize {
+            let phase = 2.0 * core::f32::consts::PI * (i as f32 + 0.3) / period;
+            if let Some(ms) = detector.add_sample_interval(-libm::cosf(phase)) {
+                num_beats += 1;
+                // The first beat was found without the sample before the minimum.
+                if num_beats > 1 {
+                    assert!((ms - 1000.0).abs() < 1.0, "{}", ms);
+                }
+            }
+        }
+        assert!(num_beats >= 8);
     }
 }
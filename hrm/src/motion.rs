@@ -0,0 +1,183 @@
+//! Removal of motion artifacts from the PPG signal using the accelerometer.
+//!
+//! Movement of the arm shows up in the PPG signal (blood sloshing around, the sensor moving on the
+//! skin), which is why the plain `HeartbeatDetector` often locks onto the cadence instead of the
+//! heart rate during workouts. The artifacts are roughly a linear, delayed function of the
+//! acceleration, so an adaptive NLMS filter can learn to predict them from the recent
+//! accelerometer samples. What it can't predict (the heartbeat) remains.
+
+use crate::{
+    BiasedSampleFilter, DetectorSpectralEstimator, Estimate, EstimateSampleRate, ExpMean,
+    GradientClip, Quality, QualityEstimator, SampleRate, UnbiasedBiquadHighPass,
+    ZeroCrossHeartbeatDetector, BPM,
+};
+
+/// Accelerometer sample (x, y, z) taken at the same time as a PPG sample.
+pub type AccelSample = [i16; 3];
+
+/// Number of past accelerometer samples per axis used to predict the artifacts, i.e. 0.64s at
+/// 25Hz. Long enough to cover the delay between movement and artifact.
+const NLMS_TAPS: usize = 16;
+/// Adaption rate of the NLMS filter. Larger values follow changes in movement faster, but also
+/// start to cancel parts of the heartbeat.
+const NLMS_STEP: f32 = 0.03;
+/// Keeps the update small while the arm is still and the reference is mostly sensor noise, which
+/// would otherwise be fitted to the heartbeat. In squared accelerometer counts summed over all taps.
+const NLMS_REGULARIZATION: f32 = 1e5;
+
+/// Removes the gravity (and any other constant offset) from the accelerometer axes.
+pub struct DcBlocker {
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Default for DcBlocker {
+    fn default() -> Self {
+        Self {
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+}
+
+impl DcBlocker {
+    /// Cutoff of about 0.1Hz at 25Hz, low enough to keep the cadence of slow movements.
+    const POLE: f32 = 0.975;
+
+    pub fn filter(&mut self, v: f32) -> f32 {
+        let out = v - self.prev_in + Self::POLE * self.prev_out;
+        self.prev_in = v;
+        self.prev_out = out;
+        out
+    }
+}
+
+/// Normalized least mean squares filter that removes the part of a signal that is a linear
+/// function of the last `TAPS` values of `CHANNELS` reference signals.
+pub struct NlmsFilter<const TAPS: usize, const CHANNELS: usize> {
+    weights: [[f32; TAPS]; CHANNELS],
+    /// Newest values first
+    history: [[f32; TAPS]; CHANNELS],
+    step: f32,
+    regularization: f32,
+}
+
+impl<const TAPS: usize, const CHANNELS: usize> NlmsFilter<TAPS, CHANNELS> {
+    pub fn new(step: f32, regularization: f32) -> Self {
+        Self {
+            weights: [[0.0; TAPS]; CHANNELS],
+            history: [[0.0; TAPS]; CHANNELS],
+            step,
+            regularization,
+        }
+    }
+
+    /// Returns `signal` minus its prediction from the references, and adapts the prediction.
+    pub fn filter(&mut self, signal: f32, reference: [f32; CHANNELS]) -> f32 {
+        let mut estimate = 0.0;
+        let mut power = self.regularization;
+        for ((history, weights), r) in self.history.iter_mut().zip(&self.weights).zip(reference) {
+            history.rotate_right(1);
+            history[0] = r;
+            for (h, w) in history.iter().zip(weights) {
+                estimate += h * w;
+                power += h * h;
+            }
+        }
+
+        let error = signal - estimate;
+        let gain = self.step * error / power;
+        for (history, weights) in self.history.iter().zip(&mut self.weights) {
+            for (h, w) in history.iter().zip(weights) {
+                *w += gain * h;
+            }
+        }
+        error
+    }
+}
+
+/// Like `HeartbeatDetector`, but the PPG signal is cleaned from motion artifacts before the heart
+/// rate is estimated.
+pub struct MotionCompensatedDetector<E> {
+    gradient_clip: GradientClip,
+    high_pass: UnbiasedBiquadHighPass,
+    accel_dc: [DcBlocker; 3],
+    nlms: NlmsFilter<NLMS_TAPS, 3>,
+    spectral: DetectorSpectralEstimator,
+    biased_filter: BiasedSampleFilter,
+    cross_detector: ZeroCrossHeartbeatDetector<E>,
+    bpm_mean: ExpMean,
+    quality: QualityEstimator,
+    /// What the filters are tuned to, see `HeartbeatDetector`
+    sample_rate: SampleRate,
+}
+
+impl<E> MotionCompensatedDetector<E> {
+    pub fn new(sr_estimator: E) -> Self {
+        Self {
+            gradient_clip: Default::default(),
+            high_pass: UnbiasedBiquadHighPass::new(),
+            accel_dc: Default::default(),
+            nlms: NlmsFilter::new(NLMS_STEP, NLMS_REGULARIZATION),
+            spectral: Default::default(),
+            biased_filter: BiasedSampleFilter::new(),
+            cross_detector: ZeroCrossHeartbeatDetector::new(sr_estimator),
+            bpm_mean: ExpMean::new(0.8),
+            quality: Default::default(),
+            sample_rate: SampleRate::NOMINAL,
+        }
+    }
+}
+
+impl<E: EstimateSampleRate> MotionCompensatedDetector<E> {
+    pub fn millis_per_sample(&mut self) -> f32 {
+        self.cross_detector.millis_per_sample()
+    }
+
+    /// Quality of the current estimate, see `quality`. Unlike for `HeartbeatDetector`, this
+    /// includes how much the arm moves.
+    pub fn quality(&self) -> Quality {
+        self.quality.quality()
+    }
+
+    /// Retunes to the measured sample rate when it is off by more than a little. The DC blocker
+    /// and the NLMS filter work in samples and don't need it.
+    fn update_sample_rate(&mut self) {
+        let Some(sample_rate) = self.cross_detector.sample_rate() else {
+            return;
+        };
+        if self.sample_rate.differs_from(sample_rate) {
+            self.sample_rate = sample_rate;
+            self.high_pass.set_sample_rate(sample_rate);
+            self.spectral.set_sample_rate(sample_rate);
+            self.biased_filter.set_sample_rate(sample_rate);
+            self.quality.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Returns the cleaned and filtered signal (for display) and the heart rate once a beat is
+    /// detected.
+    pub fn add_sample(&mut self, s: i16, accel: AccelSample) -> (f32, Option<Estimate>) {
+        self.update_sample_rate();
+        let s_clip = self.gradient_clip.add_value(s);
+        let s_hp = self.high_pass.filter(s_clip);
+        let reference: [f32; 3] =
+            core::array::from_fn(|i| self.accel_dc[i].filter(accel[i] as f32));
+        let squared_acceleration = reference.iter().map(|a| a * a).sum();
+        self.quality.add_motion(squared_acceleration);
+        let s_clean = self.nlms.filter(s_hp, reference);
+
+        if let Some((spectrum_c, spectrum, bpm)) = self.spectral.add_sample(s_clean) {
+            self.biased_filter.tune(bpm);
+            self.quality.add_spectrum(&spectrum_c, &spectrum, bpm);
+        }
+        let s_bp = self.biased_filter.filter_f32(s_clean);
+        self.quality.add_sample(s_clean);
+        let bpm = self.cross_detector.add_sample(s_bp);
+        let bpm = bpm.map(|bpm| Estimate {
+            bpm: BPM(libm::roundf(self.bpm_mean.add(bpm.0 as f32)) as u16),
+            quality: self.quality(),
+        });
+        (s_bp, bpm)
+    }
+}
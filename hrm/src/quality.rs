@@ -0,0 +1,188 @@
+//! How much a heart rate estimate can be trusted.
+//!
+//! Several indicators are scaled to 0 (useless) to 1 (good) and combined with a geometric mean, so
+//! that a single bad one (e.g. the watch is not worn and there is no pulse) pulls the quality
+//! down, while a few mediocre ones don't add up to a bad rating.
+
+use crate::{ExpMean, SampleRate, Spectrum, SpectrumC, BPM, SPECTRUM_SIZE};
+
+/// Mean absolute value of the high passed signal (in sensor counts) below which there is no usable
+/// pulse, and from which on it is fine. Rough values for the VC31B at the LED current the driver
+/// picks.
+const MIN_AMPLITUDE: f32 = 2.0;
+const GOOD_AMPLITUDE: f32 = 10.0;
+
+/// Accelerometer counts per g at the default range (+-2g, 16 bit).
+pub const ACCEL_COUNTS_PER_G: f32 = 16384.0;
+/// Movement (rms, without gravity) at which the estimate is considered worthless. Even with motion
+/// compensation, running is hit-and-miss.
+const MAX_MOTION_G: f32 = 1.0;
+
+/// From 0 (no confidence) to 1.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Quality(pub f32);
+
+impl Quality {
+    /// Estimates below this should not be presented as if they were correct.
+    pub const RELIABLE: Quality = Quality(0.5);
+
+    pub fn is_reliable(self) -> bool {
+        self >= Self::RELIABLE
+    }
+}
+
+/// A heart rate together with how much it can be trusted.
+#[derive(Clone, Copy)]
+pub struct Estimate {
+    pub bpm: BPM,
+    pub quality: Quality,
+}
+
+fn scale(v: f32, low: f32, high: f32) -> f32 {
+    ((v - low) / (high - low)).clamp(0.0, 1.0)
+}
+
+/// Part of the spectral energy in the peak (and its direct neighbors). A clear pulse is a narrow
+/// peak, noise is spread out.
+fn peak_sharpness(spectrum: &Spectrum) -> f32 {
+    let (peak, _) = spectrum
+        .iter()
+        .enumerate()
+        .max_by(|l, r| l.1.total_cmp(r.1))
+        .unwrap();
+    let energy = |s: &[f32]| s.iter().map(|v| v * v).sum::<f32>();
+    let total = energy(spectrum);
+    if total <= 0.0 {
+        return 0.0;
+    }
+    let around_peak = &spectrum[peak.saturating_sub(1)..(peak + 2).min(SPECTRUM_SIZE)];
+    let ratio = energy(around_peak) / total;
+    // What a flat spectrum would give
+    let uniform = around_peak.len() as f32 / SPECTRUM_SIZE as f32;
+    scale(ratio, uniform, 1.0)
+}
+
+/// Whether the first harmonic of `bpm` is in phase with it, as it is for the pulse shape but not for
+/// random noise. This is the correlation `hrm_enhance` is based on, normalized to the cosine of the
+/// phase difference and mapped to 0..1, so that noise ends up around 0.5.
+fn harmonic_agreement(spectrum: &SpectrumC, bpm: BPM, sample_rate: SampleRate) -> f32 {
+    let harmonic_i = sample_rate.bpm_to_index(2.0 * bpm.0 as f32);
+    if harmonic_i >= SPECTRUM_SIZE {
+        // Too fast to have a harmonic in the spectrum
+        return 0.5;
+    }
+    let base_i = sample_rate.bpm_to_index(sample_rate.index_to_bpm(harmonic_i) * 0.5);
+    let Some(&base) = spectrum.get(base_i) else {
+        return 0.5;
+    };
+    let harmonic = spectrum[harmonic_i];
+    let magnitude = base.norm_sqr() * harmonic.norm();
+    if magnitude <= 0.0 {
+        return 0.5;
+    }
+    let base = base * base;
+    let cos = (base.re * harmonic.re + base.im * harmonic.im) / magnitude;
+    0.5 + 0.5 * cos.clamp(-1.0, 1.0)
+}
+
+/// Keeps running averages of the indicators, which are updated per sample or per spectrum.
+///
+/// The motion only counts once `add_motion` was called. Without an accelerometer (as in
+/// `HeartbeatDetector`) nothing is known about it, which must not be mistaken for holding still.
+pub struct QualityEstimator {
+    sharpness: ExpMean,
+    harmonic: ExpMean,
+    amplitude: ExpMean,
+    motion: Option<ExpMean>,
+    sample_rate: SampleRate,
+}
+
+impl Default for QualityEstimator {
+    fn default() -> Self {
+        // All start at 0, i.e. at no confidence, until there is some data.
+        Self {
+            sharpness: ExpMean::new(0.95),
+            harmonic: ExpMean::new(0.95),
+            amplitude: ExpMean::new(0.95),
+            motion: None,
+            sample_rate: SampleRate::NOMINAL,
+        }
+    }
+}
+
+impl QualityEstimator {
+    /// Of the spectra passed to `add_spectrum`
+    pub fn set_sample_rate(&mut self, sample_rate: SampleRate) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// `smoothed` is the spectrum the heart rate `bpm` was taken from.
+    pub fn add_spectrum(&mut self, spectrum: &SpectrumC, smoothed: &Spectrum, bpm: BPM) {
+        self.sharpness.add(peak_sharpness(smoothed));
+        self.harmonic
+            .add(harmonic_agreement(spectrum, bpm, self.sample_rate));
+    }
+
+    /// `high_passed` is the signal without its DC part, its amplitude is a measure of the perfusion.
+    /// The band passed one is not used, because it is attenuated when the filter is tuned slightly
+    /// off.
+    pub fn add_sample(&mut self, high_passed: f32) {
+        self.amplitude.add(high_passed.abs());
+    }
+
+    /// Squared acceleration (without gravity) in accelerometer counts.
+    pub fn add_motion(&mut self, squared_acceleration: f32) {
+        self.motion
+            .get_or_insert(ExpMean::new(0.95))
+            .add(squared_acceleration);
+    }
+
+    pub fn quality(&self) -> Quality {
+        let motion = self.motion.as_ref().map(|motion| {
+            let motion_g = libm::sqrtf(motion.get()) / ACCEL_COUNTS_PER_G;
+            1.0 - scale(motion_g, 0.0, MAX_MOTION_G)
+        });
+        let indicators = [
+            Some(self.sharpness.get()),
+            Some(self.harmonic.get()),
+            Some(scale(self.amplitude.get(), MIN_AMPLITUDE, GOOD_AMPLITUDE)),
+            motion,
+        ];
+        let known = indicators.iter().flatten();
+        let product: f32 = known.clone().product();
+        Quality(libm::powf(product, 1.0 / known.count() as f32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn clear_pulse() -> QualityEstimator {
+        let mut q = QualityEstimator::default();
+        for _ in 0..200 {
+            q.sharpness.add(0.8);
+            q.harmonic.add(0.8);
+            q.add_sample(GOOD_AMPLITUDE);
+        }
+        q
+    }
+
+    #[test]
+    fn test_motion_counts_only_when_known() {
+        let without = clear_pulse().quality();
+        assert!(without.is_reliable());
+
+        let mut still = clear_pulse();
+        let mut running = clear_pulse();
+        for _ in 0..200 {
+            still.add_motion(0.0);
+            running.add_motion(
+                (ACCEL_COUNTS_PER_G * MAX_MOTION_G) * (ACCEL_COUNTS_PER_G * MAX_MOTION_G),
+            );
+        }
+        // Holding still is a good sign, but not knowing about the motion is no sign either way.
+        assert!(still.quality() > without);
+        assert!(!running.quality().is_reliable());
+    }
+}
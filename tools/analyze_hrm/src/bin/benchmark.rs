@@ -0,0 +1,318 @@
+//! Runs every heart rate estimator of the `hrm` crate over a directory of recordings and compares
+//! them to a reference heart rate (e.g. from a chest strap), so that changes to the algorithms can
+//! be judged by numbers instead of by looking at plots.
+//!
+//! Usage: benchmark <directory>
+//!
+//! For every `NAME.ref.csv` (columns `time_s,bpm`) in the directory, the hrm samples are read from
+//! `NAME.csv` (column `val`) and, if present, the acceleration from `NAME.accel.csv` (columns
+//! `x,y,z`). Both are assumed to start at the same time as the reference and to be sampled at
+//! 25Hz.
+//!
+//! The result is written as csv to stdout, one row per recording and estimator and one row per
+//! estimator with the recording `all`, in which the errors of all recordings are pooled:
+//!  - `mae_bpm`, `rmse_bpm`: Error of the last estimate compared to the (linearly interpolated)
+//!    reference, once per second
+//!  - `within_5_bpm_pct`: How often that error is at most 5 bpm
+//!  - `coverage_pct`: How often there is an estimate at all
+//!  - `latency_s`: Time from the start of the recording until the estimate is within 5 bpm for
+//!    the first time, the mean of the recordings in which that happens for `all`
+//!  - `ns_per_sample`: Processing time on the host
+
+use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+
+use analyze_hrm::{reference_at, ErrorSums, ReferenceRow};
+use hrm::{AccelSample, UncalibratedEstimator};
+
+const SAMPLE_DELAY_MS: f32 = 40.0;
+const EVALUATION_INTERVAL_S: f32 = 1.0;
+const WITHIN_BPM: f32 = 5.0;
+
+#[derive(serde::Deserialize)]
+struct Row {
+    val: i16,
+}
+#[derive(serde::Deserialize)]
+struct AccelRow {
+    x: i16,
+    y: i16,
+    z: i16,
+}
+
+struct Recording {
+    name: String,
+    samples: Vec<i16>,
+    accel: Option<Vec<AccelSample>>,
+    reference: Vec<ReferenceRow>,
+}
+
+fn read_csv<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Vec<T>, Box<dyn Error>> {
+    let rows = csv::Reader::from_path(path)?
+        .deserialize()
+        .collect::<Result<Vec<T>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(rows)
+}
+
+fn load_recordings(dir: &Path) -> Result<Vec<Recording>, Box<dyn Error>> {
+    let mut recordings = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let Some(name) = file_name.to_str().and_then(|n| n.strip_suffix(".ref.csv")) else {
+            continue;
+        };
+        let samples = read_csv::<Row>(&dir.join(format!("{name}.csv")))?;
+        let accel_path = dir.join(format!("{name}.accel.csv"));
+        let accel = if accel_path.exists() {
+            let rows = read_csv::<AccelRow>(&accel_path)?;
+            Some(rows.into_iter().map(|r| [r.x, r.y, r.z]).collect())
+        } else {
+            None
+        };
+        let reference = read_csv::<ReferenceRow>(&dir.join(&file_name))?;
+        recordings.push(Recording {
+            name: name.to_owned(),
+            samples: samples.into_iter().map(|r| r.val).collect(),
+            accel,
+            reference,
+        });
+    }
+    recordings.sort_by(|l, r| l.name.cmp(&r.name));
+    Ok(recordings)
+}
+
+/// Heart rate in bpm, if there is a new estimate after the sample.
+type EstimatorFn = Box<dyn FnMut(i16, AccelSample) -> Option<f32>>;
+
+struct Estimator {
+    name: &'static str,
+    /// Pointless to run without acceleration, it would just be another `detector`.
+    needs_accel: bool,
+    new: fn() -> EstimatorFn,
+}
+
+/// Gradient clipping and high pass, like in the detectors
+fn preprocessed(mut f: impl FnMut(f32) -> Option<f32> + 'static) -> EstimatorFn {
+    let mut gradient_clip = hrm::GradientClip::default();
+    let mut high_pass = hrm::UnbiasedBiquadHighPass::new();
+    Box::new(move |s, _| f(high_pass.filter(gradient_clip.add_value(s))))
+}
+
+const ESTIMATORS: &[Estimator] = &[
+    Estimator {
+        name: "zero_cross",
+        needs_accel: false,
+        new: || {
+            let mut filter = hrm::UnbiasedBiquadSampleFilter::new();
+            let mut detector = hrm::ZeroCrossHeartbeatDetector::new(UncalibratedEstimator);
+            Box::new(move |s, _| detector.add_sample(filter.filter(s)).map(|b| b.0 as f32))
+        },
+    },
+    Estimator {
+        name: "fft",
+        needs_accel: false,
+        new: || {
+            let mut estimator = hrm::FFTEstimator::default();
+            preprocessed(move |s| estimator.add_sample(s).map(|s| hrm::max_bpm(s).0 as f32))
+        },
+    },
+    Estimator {
+        name: "sparse_fft_short",
+        needs_accel: false,
+        new: || {
+            let mut estimator = hrm::SparseFFTEstimatorShort::default();
+            preprocessed(move |s| {
+                let spectrum = estimator.add_sample(s)?;
+                Some(hrm::max_bpm(hrm::spectrum_norm(spectrum)).0 as f32)
+            })
+        },
+    },
+    Estimator {
+        name: "spectral",
+        needs_accel: false,
+        new: || {
+            let mut estimator = hrm::SpectralEstimator::default();
+            preprocessed(move |s| estimator.add_sample(s).map(|(_, _, b)| b.0 as f32))
+        },
+    },
+    Estimator {
+        name: "spectral_fixed",
+        needs_accel: false,
+        new: || {
+            let mut estimator = hrm::fixed::SpectralEstimator::default();
+            preprocessed(move |s| estimator.add_sample(s).map(|(_, _, b)| b.0 as f32))
+        },
+    },
+    Estimator {
+        name: "detector",
+        needs_accel: false,
+        new: || {
+            let mut detector = hrm::HeartbeatDetector::new(UncalibratedEstimator);
+            Box::new(move |s, _| detector.add_sample(s).1.map(|e| e.bpm.0 as f32))
+        },
+    },
+    Estimator {
+        name: "detector_reliable",
+        needs_accel: false,
+        new: || {
+            let mut detector = hrm::HeartbeatDetector::new(UncalibratedEstimator);
+            Box::new(move |s, _| {
+                let e = detector.add_sample(s).1?;
+                e.quality.is_reliable().then_some(e.bpm.0 as f32)
+            })
+        },
+    },
+    Estimator {
+        name: "motion",
+        needs_accel: true,
+        new: || {
+            let mut detector = hrm::MotionCompensatedDetector::new(UncalibratedEstimator);
+            Box::new(move |s, a| detector.add_sample(s, a).1.map(|e| e.bpm.0 as f32))
+        },
+    },
+    Estimator {
+        name: "motion_reliable",
+        needs_accel: true,
+        new: || {
+            let mut detector = hrm::MotionCompensatedDetector::new(UncalibratedEstimator);
+            Box::new(move |s, a| {
+                let e = detector.add_sample(s, a).1?;
+                e.quality.is_reliable().then_some(e.bpm.0 as f32)
+            })
+        },
+    },
+];
+
+#[derive(Default)]
+struct Stats {
+    num_points: usize,
+    num_within: usize,
+    errors: ErrorSums,
+    latencies_s: Vec<f32>,
+    num_samples: usize,
+    nanos: u128,
+}
+
+impl Stats {
+    /// `estimates` are `(time_s, bpm)` in order.
+    fn evaluate(estimates: &[(f32, f32)], reference: &[ReferenceRow]) -> Self {
+        let mut stats = Stats::default();
+        let (Some(first), Some(last)) = (reference.first(), reference.last()) else {
+            return stats;
+        };
+        let mut t = first.time_s;
+        while t <= last.time_s {
+            if let Some(reference) = reference_at(reference, t) {
+                stats.num_points += 1;
+                let i = estimates.partition_point(|(time_s, _)| *time_s <= t);
+                if let Some((_, bpm)) = i.checked_sub(1).map(|i| estimates[i]) {
+                    stats.errors.add(bpm, reference);
+                    if (bpm - reference).abs() <= WITHIN_BPM {
+                        if stats.num_within == 0 {
+                            stats.latencies_s.push(t);
+                        }
+                        stats.num_within += 1;
+                    }
+                }
+            }
+            t += EVALUATION_INTERVAL_S;
+        }
+        stats
+    }
+
+    fn add(&mut self, other: &Stats) {
+        self.num_points += other.num_points;
+        self.num_within += other.num_within;
+        self.errors.merge(&other.errors);
+        self.latencies_s.extend_from_slice(&other.latencies_s);
+        self.num_samples += other.num_samples;
+        self.nanos += other.nanos;
+    }
+
+    fn row<'a>(&self, recording: &'a str, estimator: &'a str) -> ResultRow<'a> {
+        let percent =
+            |n: usize| (self.num_points > 0).then(|| 100.0 * n as f32 / self.num_points as f32);
+        ResultRow {
+            recording,
+            estimator,
+            mae_bpm: self.errors.mae_bpm(),
+            rmse_bpm: self.errors.rmse_bpm(),
+            within_5_bpm_pct: percent(self.num_within),
+            coverage_pct: percent(self.errors.num),
+            latency_s: (!self.latencies_s.is_empty())
+                .then(|| self.latencies_s.iter().sum::<f32>() / self.latencies_s.len() as f32),
+            ns_per_sample: self.nanos as f32 / self.num_samples.max(1) as f32,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ResultRow<'a> {
+    recording: &'a str,
+    estimator: &'a str,
+    mae_bpm: Option<f32>,
+    rmse_bpm: Option<f32>,
+    within_5_bpm_pct: Option<f32>,
+    coverage_pct: Option<f32>,
+    latency_s: Option<f32>,
+    ns_per_sample: f32,
+}
+
+fn run(estimator: &Estimator, recording: &Recording) -> Stats {
+    let mut f = (estimator.new)();
+    let mut estimates = Vec::new();
+    let mut accel = [0; 3];
+    let start = Instant::now();
+    for (i, s) in recording.samples.iter().enumerate() {
+        // Both run at 25Hz, so the samples are paired up by index.
+        if let Some(a) = recording.accel.as_ref().and_then(|a| a.get(i)) {
+            accel = *a;
+        }
+        if let Some(bpm) = f(*s, accel) {
+            estimates.push((i as f32 * SAMPLE_DELAY_MS / 1000.0, bpm));
+        }
+    }
+    let nanos = start.elapsed().as_nanos();
+
+    let mut stats = Stats::evaluate(&estimates, &recording.reference);
+    stats.num_samples = recording.samples.len();
+    stats.nanos = nanos;
+    stats
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let Some(dir) = args.get(1) else {
+        return Err("Usage: benchmark <directory>".into());
+    };
+    let recordings = load_recordings(Path::new(dir))?;
+    if recordings.is_empty() {
+        return Err(format!("No reference files (NAME.ref.csv) in {dir}").into());
+    }
+
+    let mut out = csv::Writer::from_writer(std::io::stdout());
+    let mut totals = ESTIMATORS
+        .iter()
+        .map(|_| Stats::default())
+        .collect::<Vec<_>>();
+    for recording in &recordings {
+        for (estimator, total) in ESTIMATORS.iter().zip(&mut totals) {
+            if estimator.needs_accel && recording.accel.is_none() {
+                continue;
+            }
+            let stats = run(estimator, recording);
+            out.serialize(stats.row(&recording.name, estimator.name))?;
+            total.add(&stats);
+        }
+    }
+    for (estimator, total) in ESTIMATORS.iter().zip(&totals) {
+        if total.num_samples > 0 {
+            out.serialize(total.row("all", estimator.name))?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
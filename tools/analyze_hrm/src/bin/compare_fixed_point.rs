@@ -0,0 +1,121 @@
+//! Compares the fixed-point spectral estimation (`hrm::fixed`) to the float one on a recording:
+//! How often both find the same heart rate, how much the spectra differ and how long they take.
+//!
+//! Usage: compare_fixed_point <hrm samples csv> [repetitions]
+//!
+//! The timings are taken on the host, where floats are about as fast as integers. They show the
+//! relative cost of changes, but whether the fixed-point version pays off on the watch (which has
+//! a single precision FPU) has to be measured there.
+
+use std::error::Error;
+use std::hint::black_box;
+use std::time::Instant;
+
+#[derive(serde::Deserialize)]
+struct Row {
+    val: i16,
+}
+
+/// Relative l2 distance of `b` to `a`
+fn relative_error(a: impl Iterator<Item = f32>, b: impl Iterator<Item = f32>) -> f32 {
+    let (diff, norm) = a.zip(b).fold((0.0, 0.0), |(diff, norm), (a, b)| {
+        (diff + (a - b) * (a - b), norm + a * a)
+    });
+    if norm > 0.0 {
+        (diff / norm).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Nanoseconds per sample
+fn time_per_sample(samples: &[f32], repetitions: usize, mut f: impl FnMut(f32)) -> f64 {
+    let start = Instant::now();
+    for _ in 0..repetitions {
+        for s in samples {
+            f(black_box(*s));
+        }
+    }
+    start.elapsed().as_nanos() as f64 / (samples.len() * repetitions) as f64
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let Some(path) = args.get(1) else {
+        return Err("Usage: compare_fixed_point <hrm samples csv> [repetitions]".into());
+    };
+    let repetitions = args.get(2).map(|r| r.parse()).transpose()?.unwrap_or(10);
+
+    // The same preprocessing as in the detectors
+    let mut gradient_clip = hrm::GradientClip::default();
+    let mut high_pass = hrm::UnbiasedBiquadHighPass::new();
+    let samples = csv::Reader::from_path(path)?
+        .deserialize()
+        .map(|row| {
+            let row: Row = row?;
+            Ok(high_pass.filter(gradient_clip.add_value(row.val)))
+        })
+        .collect::<Result<Vec<f32>, csv::Error>>()?;
+
+    let mut float = hrm::SpectralEstimator::default();
+    let mut fixed = hrm::fixed::SpectralEstimator::default();
+    let mut num_spectra = 0;
+    let mut num_same_bpm = 0;
+    let mut max_bpm_diff = 0;
+    let mut sum_error = 0.0;
+    let mut max_error: f32 = 0.0;
+    let mut sum_error_smooth = 0.0;
+    let mut max_error_smooth: f32 = 0.0;
+    for s in &samples {
+        let (Some(f), Some(q)) = (float.add_sample(*s), fixed.add_sample(*s)) else {
+            continue;
+        };
+        num_spectra += 1;
+        if f.2 == q.2 {
+            num_same_bpm += 1;
+        }
+        max_bpm_diff = max_bpm_diff.max(f.2 .0.abs_diff(q.2 .0));
+
+        let error = relative_error(
+            f.0.iter().flat_map(|c| [c.re, c.im]),
+            q.0.iter().flat_map(|c| [c.re, c.im]),
+        );
+        sum_error += error;
+        max_error = max_error.max(error);
+        let error = relative_error(f.1.into_iter(), q.1.into_iter());
+        sum_error_smooth += error;
+        max_error_smooth = max_error_smooth.max(error);
+    }
+    if num_spectra == 0 {
+        return Err("Recording too short for a spectrum".into());
+    }
+
+    println!("{} samples, {} spectra", samples.len(), num_spectra);
+    println!(
+        "same heart rate: {:.1}%, max difference: {} bpm",
+        100.0 * num_same_bpm as f32 / num_spectra as f32,
+        max_bpm_diff
+    );
+    println!(
+        "spectrum error: mean {:.4}, max {:.4}",
+        sum_error / num_spectra as f32,
+        max_error
+    );
+    println!(
+        "smoothed spectrum error: mean {:.4}, max {:.4}",
+        sum_error_smooth / num_spectra as f32,
+        max_error_smooth
+    );
+
+    let mut float = hrm::SpectralEstimator::default();
+    let float_ns = time_per_sample(&samples, repetitions, |s| {
+        black_box(float.add_sample(s));
+    });
+    let mut fixed = hrm::fixed::SpectralEstimator::default();
+    let fixed_ns = time_per_sample(&samples, repetitions, |s| {
+        black_box(fixed.add_sample(s));
+    });
+    println!("float: {float_ns:.0} ns/sample, fixed-point: {fixed_ns:.0} ns/sample");
+
+    Ok(())
+}
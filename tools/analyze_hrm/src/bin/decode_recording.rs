@@ -0,0 +1,113 @@
+//! Turns a recording of the hrm app (`/hrm/recN.bin`) into the csv files the other tools read.
+//!
+//! Usage: decode_recording <recording> <output name>
+//!
+//! Writes `<output name>.csv` (columns `time_ms,val,pd_res,led_current`) and
+//! `<output name>.accel.csv` (columns `time_ms,x,y,z`). The time of each sample is reconstructed
+//! from the time its batch was read and the sample rate in the recording.
+
+use std::error::Error;
+
+use drivers_shared::hrm::{Record, Records, SensorSettings};
+
+/// If the recording does not say otherwise
+const DEFAULT_MS_PER_SAMPLE: f32 = 40.0;
+
+#[derive(serde::Serialize)]
+struct Row {
+    time_ms: f32,
+    val: i16,
+    pd_res: Option<u8>,
+    led_current: Option<u8>,
+}
+
+#[derive(serde::Serialize)]
+struct AccelRow {
+    time_ms: f32,
+    x: i16,
+    y: i16,
+    z: i16,
+}
+
+/// Times of `n` samples of a batch that was read at `timestamp_ms`.
+fn sample_times(timestamp_ms: u32, n: usize, ms_per_sample: f32) -> impl Iterator<Item = f32> {
+    (0..n).map(move |i| timestamp_ms as f32 - (n - 1 - i) as f32 * ms_per_sample)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<_>>();
+    let (Some(input), Some(output)) = (args.get(1), args.get(2)) else {
+        return Err("Usage: decode_recording <recording> <output name>".into());
+    };
+    let data = std::fs::read(input)?;
+
+    let mut hrm_out = csv::Writer::from_path(format!("{output}.csv"))?;
+    let mut accel_out = csv::Writer::from_path(format!("{output}.accel.csv"))?;
+    let mut hrm_ms_per_sample = DEFAULT_MS_PER_SAMPLE;
+    let mut accel_ms_per_sample = DEFAULT_MS_PER_SAMPLE;
+    let mut settings: Option<SensorSettings> = None;
+    let mut num_hrm = 0;
+    let mut num_accel = 0;
+    let mut last_timestamp_ms = 0;
+
+    let mut records = Records::new(&data);
+    for (timestamp_ms, record) in records.by_ref() {
+        last_timestamp_ms = timestamp_ms;
+        match record {
+            Record::Info(info) => {
+                println!(
+                    "{:.1}s: version {}, hrm sample delay {}, {:.2} ms/sample, accel {:.2} ms/sample",
+                    timestamp_ms as f32 / 1000.0,
+                    info.version,
+                    info.hrm_sample_delay,
+                    info.hrm_ms_per_sample,
+                    info.accel_ms_per_sample
+                );
+                // 0 if it was not measured
+                if info.hrm_ms_per_sample > 0.0 {
+                    hrm_ms_per_sample = info.hrm_ms_per_sample;
+                }
+                if info.accel_ms_per_sample > 0.0 {
+                    accel_ms_per_sample = info.accel_ms_per_sample;
+                }
+            }
+            Record::Settings(s) => settings = Some(s),
+            Record::HrmSamples(samples) => {
+                let times = sample_times(timestamp_ms, samples.len(), hrm_ms_per_sample);
+                for (time_ms, val) in times.zip(samples) {
+                    hrm_out.serialize(Row {
+                        time_ms,
+                        val,
+                        pd_res: settings.map(|s| s.pd_res),
+                        led_current: settings.map(|s| s.led_current),
+                    })?;
+                    num_hrm += 1;
+                }
+            }
+            Record::AccelSamples(samples) => {
+                let times = sample_times(timestamp_ms, samples.len(), accel_ms_per_sample);
+                for (time_ms, [x, y, z]) in times.zip(samples) {
+                    accel_out.serialize(AccelRow { time_ms, x, y, z })?;
+                    num_accel += 1;
+                }
+            }
+        }
+    }
+    hrm_out.flush()?;
+    accel_out.flush()?;
+
+    println!(
+        "{:.1}s, {} hrm samples, {} accel samples",
+        last_timestamp_ms as f32 / 1000.0,
+        num_hrm,
+        num_accel
+    );
+    if !records.remaining().is_empty() {
+        println!(
+            "Ignored {} bytes at the end, which are not a valid record",
+            records.remaining().len()
+        );
+    }
+
+    Ok(())
+}
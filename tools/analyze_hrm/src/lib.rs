@@ -0,0 +1,50 @@
+//! Shared by the analysis tools in `src/bin` and `src/main.rs`.
+
+/// Heart rate from a reference device (e.g. a chest strap), used to judge the detectors.
+#[derive(serde::Deserialize)]
+pub struct ReferenceRow {
+    pub time_s: f32,
+    pub bpm: f32,
+}
+
+/// Reference heart rate at `t` (in s), linearly interpolated. `None` outside of the reference.
+pub fn reference_at(reference: &[ReferenceRow], t: f32) -> Option<f32> {
+    let i = reference.partition_point(|r| r.time_s <= t);
+    let (a, b) = (reference.get(i.checked_sub(1)?)?, reference.get(i)?);
+    let alpha = (t - a.time_s) / (b.time_s - a.time_s);
+    Some(a.bpm + alpha * (b.bpm - a.bpm))
+}
+
+/// Accumulates the errors of heart rate estimates against a reference.
+#[derive(Default, Clone, Copy)]
+pub struct ErrorSums {
+    pub num: usize,
+    sum_abs: f64,
+    sum_sq: f64,
+}
+
+impl ErrorSums {
+    pub fn add(&mut self, bpm: f32, reference_bpm: f32) {
+        let error = (bpm - reference_bpm) as f64;
+        self.num += 1;
+        self.sum_abs += error.abs();
+        self.sum_sq += error * error;
+    }
+
+    /// Pools the errors of both.
+    pub fn merge(&mut self, other: &ErrorSums) {
+        self.num += other.num;
+        self.sum_abs += other.sum_abs;
+        self.sum_sq += other.sum_sq;
+    }
+
+    /// Mean absolute error, `None` without estimates.
+    pub fn mae_bpm(&self) -> Option<f32> {
+        (self.num > 0).then(|| (self.sum_abs / self.num as f64) as f32)
+    }
+
+    /// Root mean square error, `None` without estimates.
+    pub fn rmse_bpm(&self) -> Option<f32> {
+        (self.num > 0).then(|| (self.sum_sq / self.num as f64).sqrt() as f32)
+    }
+}
@@ -6,6 +6,8 @@ use plotpy::{Curve, Plot};
 use realfft::RealFftPlanner;
 use std::error::Error;
 
+use analyze_hrm::{reference_at, ErrorSums, ReferenceRow};
+
 #[derive(serde::Deserialize)]
 struct Row {
     val: i16,
@@ -16,6 +18,17 @@ struct AccelRow {
     y: i16,
     z: i16,
 }
+/// Mean absolute and root mean square error of `(ms, bpm)` estimates against the reference, which
+/// is linearly interpolated. Estimates outside of the reference are ignored.
+fn estimate_error(estimates: &[(f32, f32)], reference: &[ReferenceRow]) -> Option<(f32, f32)> {
+    let mut errors = ErrorSums::default();
+    for (ms, bpm) in estimates {
+        if let Some(reference_bpm) = reference_at(reference, ms / 1000.0) {
+            errors.add(*bpm, reference_bpm);
+        }
+    }
+    Some((errors.mae_bpm()?, errors.rmse_bpm()?))
+}
 
 fn plot_values_multiple(vals: &[&[(f32, f32)]]) -> Result<(), Box<dyn Error>> {
     let mut plot = Plot::new();
@@ -117,6 +130,12 @@ fn main() -> Result<(), Box<dyn Error>> {
     let accel_file = std::fs::File::open(&args[2]).unwrap();
     let mut rdr = csv::Reader::from_reader(file);
     let mut accel_rdr = csv::Reader::from_reader(accel_file);
+    let reference = match args.get(3) {
+        Some(path) => csv::Reader::from_path(path)?
+            .deserialize()
+            .collect::<Result<Vec<ReferenceRow>, _>>()?,
+        None => Vec::new(),
+    };
 
     let fs = 25.hz();
     let f0 = 1.0.hz();
@@ -233,6 +252,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut accel_vals_freq_smooth = Vec::new();
 
     let mut baseline_filter = hrm::HeartbeatDetector::new(hrm::UncalibratedEstimator);
+    let mut motion_filter = hrm::MotionCompensatedDetector::new(hrm::UncalibratedEstimator);
+    let mut bpm_vals_motion = Vec::new();
+    // Only the estimates the detectors consider reliable
+    let mut bpm_vals_baseline_reliable = Vec::new();
+    let mut bpm_vals_motion_reliable = Vec::new();
 
     let mut window = std::collections::VecDeque::new();
     let mut prev = 0.0;
@@ -332,7 +356,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 }
 
                 if j % 50 == 0 {
-                    TODO: proper amplitude based accel filter
+                    // TODO: proper amplitude based accel filter
                     plot_values_multiple(&[
                         &hrm::spectrum_freqs(hrm::normalize_spectrum_max(hrm::spectrum_norm(
                             suppressed_spectrum,
@@ -364,8 +388,35 @@ fn main() -> Result<(), Box<dyn Error>> {
             bpm_vals_accel_supressed2.push((*i, bpm_suppressed2.0 as f32));
         }
 
-        if let Some(bpm) = baseline_filter.add_sample(*vr).1 {
-            bpm_vals_baseline.push((*i, bpm.0 as f32));
+        if let Some(e) = baseline_filter.add_sample(*vr).1 {
+            bpm_vals_baseline.push((*i, e.bpm.0 as f32));
+            if e.quality.is_reliable() {
+                bpm_vals_baseline_reliable.push((*i, e.bpm.0 as f32));
+            }
+        }
+        let accel = acv.map(|v| v as i16);
+        if let Some(e) = motion_filter.add_sample(*vr, accel).1 {
+            bpm_vals_motion.push((*i, e.bpm.0 as f32));
+            if e.quality.is_reliable() {
+                bpm_vals_motion_reliable.push((*i, e.bpm.0 as f32));
+            }
+        }
+    }
+
+    if !reference.is_empty() {
+        for (name, estimates) in [
+            ("baseline", &bpm_vals_baseline),
+            ("motion compensated", &bpm_vals_motion),
+            ("baseline, reliable only", &bpm_vals_baseline_reliable),
+            (
+                "motion compensated, reliable only",
+                &bpm_vals_motion_reliable,
+            ),
+        ] {
+            match estimate_error(estimates, &reference) {
+                Some((mae, rmse)) => println!("{name}: MAE {mae:.1} bpm, RMSE {rmse:.1} bpm"),
+                None => println!("{name}: no estimates overlap the reference"),
+            }
         }
     }
     //plot_phase_diffs(phase_diffs)?;
@@ -374,6 +425,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         &bpm_vals_freq,
         &bpm_vals_freq_smooth,
         &bpm_vals_baseline,
+        &bpm_vals_motion,
         //&accel_vals_freq,
         //&accel_vals_freq_smooth,
         &bpm_vals_accel_supressed,
@@ -0,0 +1,104 @@
+//! Build an assisted gps file for the watch from a downloaded casic AGNSS blob (for example the
+//! `casic.base64` file that Gadgetbridge/Espruino use). Copy the result to `/agps/casic.bin` on the
+//! watch flash.
+//!
+//! Usage: build_agps <input> <output> [<download time (unix seconds)>]
+
+use base64::Engine;
+use drivers_shared::gps::{
+    casic_checksum, AgpsFileHeader, CASICPacketHeader, CASIC_FRAME_OVERHEAD, CASIC_MAGIC_HEADER,
+    MAX_AID_PACKET_LEN,
+};
+use std::error::Error;
+use std::io::Write;
+
+fn decode_input(raw: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if raw.starts_with(&CASIC_MAGIC_HEADER) {
+        return Ok(raw);
+    }
+
+    let text = raw
+        .into_iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    Ok(base64::engine::general_purpose::STANDARD.decode(text)?)
+}
+
+/// Split the blob into casic packets and only keep those with a valid checksum that the watch can
+/// send to the receiver.
+fn valid_packets(data: &[u8]) -> Vec<u8> {
+    let header_len = std::mem::size_of::<CASICPacketHeader>();
+    let mut out = Vec::new();
+    let mut rest = data;
+    let mut num_packets = 0;
+    let mut num_invalid = 0;
+    let mut num_too_long = 0;
+
+    while rest.len() >= CASIC_FRAME_OVERHEAD {
+        if rest[..2] != CASIC_MAGIC_HEADER {
+            rest = &rest[1..];
+            num_invalid += 1;
+            continue;
+        }
+        let header: CASICPacketHeader = bytemuck::pod_read_unaligned(&rest[2..][..header_len]);
+        let packet_len = header.len as usize + CASIC_FRAME_OVERHEAD;
+        if rest.len() < packet_len {
+            break;
+        }
+        let payload = &rest[2 + header_len..][..header.len as usize];
+        let checksum = u32::from_le_bytes(rest[packet_len - 4..packet_len].try_into().unwrap());
+
+        if checksum == casic_checksum(header.msg_id, payload) {
+            if packet_len <= MAX_AID_PACKET_LEN {
+                out.extend_from_slice(&rest[..packet_len]);
+                num_packets += 1;
+            } else {
+                num_too_long += 1;
+            }
+            rest = &rest[packet_len..];
+        } else {
+            rest = &rest[1..];
+            num_invalid += 1;
+        }
+    }
+
+    println!(
+        "{} packets ({} bytes), skipped {} packets longer than {} bytes, {} invalid bytes, {} trailing bytes",
+        num_packets,
+        out.len(),
+        num_too_long,
+        MAX_AID_PACKET_LEN,
+        num_invalid,
+        rest.len()
+    );
+    out
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <input> <output> [<download time>]", args[0]);
+        std::process::exit(1);
+    }
+
+    let downloaded_unix_s = if let Some(t) = args.get(3) {
+        t.parse::<u32>()?
+    } else {
+        let modified = std::fs::metadata(&args[1])?.modified()?;
+        modified.duration_since(std::time::UNIX_EPOCH)?.as_secs() as u32
+    };
+
+    let data = decode_input(std::fs::read(&args[1])?)?;
+    let data = valid_packets(&data);
+    if data.is_empty() {
+        return Err("Input does not contain any valid casic packets".into());
+    }
+
+    let header = AgpsFileHeader::new(downloaded_unix_s, data.len() as u32);
+
+    let mut out = std::fs::File::create(&args[2])?;
+    out.write_all(bytemuck::bytes_of(&header))?;
+    out.write_all(&data)?;
+
+    Ok(())
+}
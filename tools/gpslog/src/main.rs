@@ -0,0 +1,122 @@
+//! Turn raw gps logs recorded on the watch (`/gpslog/*.bin`, see the "GPS log" app) into a single
+//! stream that the simulator replays via `GPS_UART_LOG`.
+//!
+//! Usage: gpslog [--raw] <output> <log files or directories...>
+//!
+//! Files are concatenated in the order given; directories are expanded oldest log first. With
+//! `--raw` the output contains only the uart bytes (without timing information).
+
+use drivers_shared::gps::{Framer, Message, RawLogChunk, RawLogRecords};
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Pauses between logging sessions are shortened to this when replaying.
+const MAX_GAP_MS: u32 = 1000;
+
+/// Log files of a `/gpslog` directory, oldest first.
+fn dir_logs(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut logs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(i) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            logs.push((i, path));
+        }
+    }
+    logs.sort_by_key(|(i, _)| std::cmp::Reverse(*i));
+    Ok(logs.into_iter().map(|(_, p)| p).collect())
+}
+
+#[derive(Default)]
+struct Stats {
+    bytes: usize,
+    casic: usize,
+    nmea: usize,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().collect::<Vec<_>>();
+    let raw = if let Some(i) = args.iter().position(|a| a == "--raw") {
+        args.remove(i);
+        true
+    } else {
+        false
+    };
+    if args.len() < 3 {
+        eprintln!(
+            "Usage: {} [--raw] <output> <log files or directories...>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+
+    let mut inputs = Vec::new();
+    for arg in &args[2..] {
+        let path = PathBuf::from(arg);
+        if path.is_dir() {
+            inputs.extend(dir_logs(&path)?);
+        } else {
+            inputs.push(path);
+        }
+    }
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&args[1])?);
+    let mut framer = Framer::<1024>::new();
+    let mut stats = Stats::default();
+
+    // Timestamps restart with every boot of the watch, so we build a new monotonic timeline.
+    let mut last_in_ms: Option<u32> = None;
+    let mut out_ms = 0u32;
+
+    for input in inputs {
+        let data = std::fs::read(&input)?;
+        let mut records = RawLogRecords::new(&data);
+        for (timestamp_ms, bytes) in records.by_ref() {
+            if let Some(last) = last_in_ms {
+                let gap = timestamp_ms.checked_sub(last).unwrap_or(MAX_GAP_MS);
+                out_ms += gap.min(MAX_GAP_MS);
+            }
+            last_in_ms = Some(timestamp_ms);
+
+            if raw {
+                out.write_all(bytes)?;
+            } else {
+                for chunk in RawLogChunk::split(out_ms, bytes) {
+                    out.write_all(bytemuck::bytes_of(&chunk.header()))?;
+                    out.write_all(&chunk.data)?;
+                }
+            }
+
+            let mut rest = bytes;
+            while !rest.is_empty() {
+                let n = framer.push(rest);
+                rest = &rest[n..];
+                while let Some(()) = framer.with_next_message(|m| match m {
+                    Message::Casic(_) => stats.casic += 1,
+                    Message::Nmea(_) => stats.nmea += 1,
+                }) {}
+            }
+            stats.bytes += bytes.len();
+        }
+        println!(
+            "{}: {} bytes, {} invalid trailing bytes",
+            input.display(),
+            data.len() - records.remaining().len(),
+            records.remaining().len()
+        );
+    }
+
+    println!(
+        "{} bytes ({} casic and {} nmea messages) over {:.1}s",
+        stats.bytes,
+        stats.casic,
+        stats.nmea,
+        out_ms as f64 / 1000.0
+    );
+
+    Ok(())
+}
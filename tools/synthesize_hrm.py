@@ -0,0 +1,89 @@
+#!/bin/python
+# Writes synthetic recordings with motion artifacts in the format of the hrm benchmark
+# (`cargo run --bin benchmark -- <directory>` in tools/analyze_hrm): NAME.csv, NAME.accel.csv
+# and NAME.ref.csv at 25 Hz.
+#
+# The pulse is a sum of three harmonics of the (known) heart rate. The artifact is a delayed,
+# smeared and slightly nonlinear function of the arm swing, which is what the accelerometer sees.
+# This is a sanity check of the algorithms, not a replacement for recordings with a chest strap:
+# real artifacts are not (only) a function of the acceleration.
+import argparse
+import math
+import os
+import random
+
+parser = argparse.ArgumentParser()
+parser.add_argument('directory')
+args = parser.parse_args()
+
+FS = 25
+# Accelerometer counts per g
+G = 16384
+
+def write(name, hr, cadence, artifact_gain, seed):
+    rnd = random.Random(seed)
+    phase_heart = 0.0
+    phase_arm = 0.0
+    swing_history = [0.0] * 8
+    wander = 0.0
+    vals = []
+    accel = []
+    for i in range(len(hr)):
+        t = i / FS
+        phase_heart += 2 * math.pi * hr[i] / 60 / FS
+        phase_arm += 2 * math.pi * cadence[i] / FS
+        amp = 12 * (1 + 0.1 * math.sin(2 * math.pi * t / 7))
+        pulse = -amp * (math.sin(phase_heart)
+                        + 0.4 * math.sin(2 * phase_heart + 0.8)
+                        + 0.15 * math.sin(3 * phase_heart + 1.2))
+
+        moving = cadence[i] > 0
+        ax = 0.6 * math.sin(phase_arm) + 0.2 * math.sin(2 * phase_arm + 0.5) if moving else 0.0
+        ay = 0.3 * math.sin(2 * phase_arm) if moving else 0.0
+        az = 0.2 * math.sin(phase_arm + 1.0) if moving else 0.0
+        swing_history = [ax + 0.5 * ay] + swing_history[:-1]
+        swing = 0.5 * swing_history[4] + 0.3 * swing_history[5] + 0.2 * swing_history[6]
+        artifact = artifact_gain[i] * (swing + 0.3 * swing * abs(swing))
+
+        wander = 0.995 * (wander + rnd.gauss(0, 0.3))
+        vals.append(round(2000 + pulse + artifact + wander + rnd.gauss(0, 1.5)))
+        accel.append([round(v * G + rnd.gauss(0, 60)) for v in (ax, ay, 1.0 + az)])
+
+    base = os.path.join(args.directory, name)
+    with open(base + ".csv", "w") as f:
+        f.write("val\n")
+        f.writelines(f"{v}\n" for v in vals)
+    with open(base + ".accel.csv", "w") as f:
+        f.write("x,y,z\n")
+        f.writelines(f"{x},{y},{z}\n" for x, y, z in accel)
+    with open(base + ".ref.csv", "w") as f:
+        f.write("time_s,bpm\n")
+        f.writelines(f"{s},{hr[s * FS]:.2f}\n" for s in range(len(hr) // FS))
+
+def ramp(start, end, secs):
+    n = secs * FS
+    return [start + (end - start) * i / n for i in range(n)]
+
+def const(v, secs):
+    return [v] * (secs * FS)
+
+os.makedirs(args.directory, exist_ok=True)
+
+write("rest", ramp(62, 68, 300), const(0, 300), const(0, 300), 1)
+write("walk", ramp(70, 105, 480), const(0.9, 480), const(15, 480), 2)
+write("run", ramp(100, 165, 600), ramp(1.3, 1.45, 600), const(35, 600), 3)
+
+# Alternating minutes of running and walking, the heart rate follows with a delay
+hr = []
+cadence = []
+gain = []
+current = 90.0
+for minute in range(10):
+    running = minute % 2 == 0
+    target = 160 if running else 110
+    for _ in range(60 * FS):
+        current += (target - current) / (20 * FS)
+        hr.append(current)
+    cadence += const(1.4 if running else 0.9, 60)
+    gain += const(35 if running else 15, 60)
+write("intervals", hr, cadence, gain, 4)
@@ -0,0 +1,95 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+
+/// Days of the week on which an alarm repeats, bit 0 is Monday. An empty set means that the alarm
+/// rings only once.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Weekdays(pub u8);
+
+impl Weekdays {
+    pub const ONCE: Self = Self(0);
+    pub const WORKDAYS: Self = Self(0b0011111);
+    pub const EVERY_DAY: Self = Self(0b1111111);
+
+    pub fn contains(&self, day: Weekday) -> bool {
+        self.0 & (1 << day.num_days_from_monday()) != 0
+    }
+
+    pub fn toggle(&mut self, day: Weekday) {
+        self.0 ^= 1 << day.num_days_from_monday();
+    }
+
+    pub fn is_once(&self) -> bool {
+        self.0 & Self::EVERY_DAY.0 == 0
+    }
+}
+
+/// First time strictly after `after` at which an alarm set to `time` on `days` rings. All times are
+/// local. `None` only if the date is out of range.
+pub fn next_occurrence(
+    after: NaiveDateTime,
+    time: NaiveTime,
+    days: Weekdays,
+) -> Option<NaiveDateTime> {
+    // Today's alarm may already be over, so we have to look up to a full week ahead.
+    (0..=7)
+        .map_while(|i| after.date().checked_add_days(chrono::Days::new(i)))
+        .map(|date| date.and_time(time))
+        .find(|t| *t > after && (days.is_once() || days.contains(t.weekday())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn t(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn hm(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_once() {
+        // 2024-03-01 is a friday
+        let after = t("2024-03-01 06:30");
+        assert_eq!(
+            next_occurrence(after, hm(7, 0), Weekdays::ONCE),
+            Some(t("2024-03-01 07:00"))
+        );
+        assert_eq!(
+            next_occurrence(after, hm(6, 0), Weekdays::ONCE),
+            Some(t("2024-03-02 06:00"))
+        );
+        // Strictly after, so an alarm does not ring twice in the same minute.
+        assert_eq!(
+            next_occurrence(after, hm(6, 30), Weekdays::ONCE),
+            Some(t("2024-03-02 06:30"))
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let after = t("2024-03-01 08:00");
+        assert_eq!(
+            next_occurrence(after, hm(7, 0), Weekdays::WORKDAYS),
+            Some(t("2024-03-04 07:00"))
+        );
+        assert_eq!(
+            next_occurrence(after, hm(9, 0), Weekdays::WORKDAYS),
+            Some(t("2024-03-01 09:00"))
+        );
+        assert_eq!(
+            next_occurrence(after, hm(7, 0), Weekdays::EVERY_DAY),
+            Some(t("2024-03-02 07:00"))
+        );
+
+        let mut friday = Weekdays::ONCE;
+        friday.toggle(Weekday::Fri);
+        assert!(friday.contains(Weekday::Fri) && !friday.contains(Weekday::Sat));
+        assert_eq!(
+            next_occurrence(after, hm(7, 0), friday),
+            Some(t("2024-03-08 07:00"))
+        );
+    }
+}
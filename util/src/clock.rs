@@ -0,0 +1,545 @@
+//! Keeping the clock in sync with gps time, independent of the hardware so that the simulator
+//! runs the same logic.
+//!
+//! The clock (`Monotonic`) counts the time since boot. `ClockInfo` maps that to wall clock time
+//! and is calibrated on every sync, since the clock runs a little faster or slower than real time.
+//! `ClockSync` decides when to sync next.
+
+use core::future::Future;
+
+use chrono::{DateTime, Utc};
+use drivers_shared::gps::NavTimeUTC;
+
+use crate::ClockScale;
+
+/// Monotonic time since boot.
+pub trait Monotonic {
+    fn now_ms(&self) -> u64;
+}
+
+/// Time messages of the gps receiver.
+pub trait TimeMessages {
+    fn next_time(&mut self) -> impl Future<Output = NavTimeUTC>;
+}
+
+/// Drift between syncs below which the sync interval is doubled.
+const DRIFT_THRESHOLD_LONGER_MS: i64 = 5000;
+/// Drift between syncs above which the sync interval is halved.
+const DRIFT_THRESHOLD_SHORTER_MS: i64 = 10000;
+
+const INITIAL_SYNC_TIMEOUT_MS: u64 = 2 * 60 * 1000;
+const INCREMENTAL_SYNC_TIMEOUT_MS: u64 = 60 * 1000;
+const INITIAL_SUCCESS_WAIT_MS: u64 = 4 * 60 * 60 * 1000;
+const FAILURE_WAIT_MS: u64 = 60 * 60 * 1000;
+
+/// Worst case rate error of the (calibrated) clock, including temperature effects.
+const MAX_DRIFT_PPM: u64 = 50;
+
+/// Time from the start of an epoch (to which the time of NAV-TIMEUTC refers) until the message has
+/// been received: Computation of the solution and transmission over the uart at 9600 baud.
+const NAV_TIME_LATENCY_MS: u64 = 60;
+
+/// How much the actual latency of NAV-TIMEUTC varies, e.g. depending on which other messages are
+/// sent before it in the same epoch.
+pub const GPS_SYNC_UNCERTAINTY_MS: u32 = 100;
+
+/// Drift between syncs from which on the clock scale is recalibrated. A multiple of
+/// `GPS_SYNC_UNCERTAINTY_MS`, so that the jitter of the latency doesn't make up much of it.
+const MIN_CALIBRATION_DRIFT_MS: i64 = 10 * GPS_SYNC_UNCERTAINTY_MS as i64;
+
+/// Users set the time to the minute (by looking at another clock).
+pub const USER_SET_UNCERTAINTY_MS: u32 = 60 * 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    Unknown,
+    Gps,
+    /// Restored after a reset from the state before.
+    Restored,
+    /// Set manually, see `ClockInfo::set_manually`.
+    User,
+}
+
+/// Mapping from the time since boot to wall clock time. All boot times are in ms.
+#[derive(Copy, Clone)]
+pub struct ClockInfo {
+    pub scale: ClockScale,
+    /// Unix time (in ms) at boot, `None` if the time is unknown.
+    pub offset_ms: Option<i64>,
+    /// Boot time of the last sync, 0 if there is none that the clock can be calibrated against.
+    pub last_sync_ms: u64,
+    pub last_sync_time: DateTime<Utc>,
+    pub source: TimeSource,
+    /// Uncertainty of the time at `uncertainty_since_ms`. It grows with the clock drift afterwards.
+    pub base_uncertainty_ms: u32,
+    pub uncertainty_since_ms: u64,
+}
+
+impl ClockInfo {
+    /// Time is unknown until the first sync.
+    pub const fn new(scale: ClockScale) -> Self {
+        Self {
+            scale,
+            offset_ms: None,
+            last_sync_ms: 0,
+            last_sync_time: DateTime::UNIX_EPOCH,
+            source: TimeSource::Unknown,
+            base_uncertainty_ms: 0,
+            uncertainty_since_ms: 0,
+        }
+    }
+
+    fn valid_last_sync(&self) -> bool {
+        self.last_sync_ms != 0
+    }
+
+    pub fn to_utc(&self, boot_ms: u64) -> Option<DateTime<Utc>> {
+        let unix_ms = self.offset_ms? + self.scale.apply(boot_ms as i64);
+        Some(chrono::NaiveDateTime::from_timestamp_millis(unix_ms)?.and_utc())
+    }
+
+    /// Boot time at which `t` is reached (0 if it was before boot).
+    pub fn to_boot_ms<Tz: chrono::TimeZone>(&self, t: DateTime<Tz>) -> Option<u64> {
+        let unix_ms = t.naive_utc().and_utc().timestamp_millis();
+        let boot_ms = self.scale.inverse().apply(unix_ms - self.offset_ms?);
+        Some(boot_ms.max(0) as u64)
+    }
+
+    /// Worst case error of `to_utc(boot_ms)`. `None` if the time is unknown.
+    pub fn uncertainty_ms(&self, boot_ms: u64) -> Option<u64> {
+        self.offset_ms?;
+        let elapsed_ms = boot_ms.saturating_sub(self.uncertainty_since_ms);
+        let drift_ms = elapsed_ms * MAX_DRIFT_PPM / 1_000_000;
+        Some(self.base_uncertainty_ms as u64 + drift_ms)
+    }
+
+    /// Set the offset so that `boot_ms` corresponds to `datetime`.
+    pub fn set_time(&mut self, boot_ms: u64, datetime: DateTime<Utc>) {
+        self.offset_ms = Some(datetime.timestamp_millis() - self.scale.apply(boot_ms as i64));
+    }
+
+    /// Apply a gps time. Returns the drift (in ms) since the last sync if there was one to
+    /// compare against.
+    pub fn sync(&mut self, boot_ms: u64, datetime: DateTime<Utc>) -> Option<i64> {
+        let mut drift = None;
+        if self.valid_last_sync() {
+            let d = (datetime - self.to_utc(boot_ms).unwrap()).num_milliseconds();
+            drift = Some(d);
+
+            // Only update if the drift is much larger than the measurement error, since for short
+            // sync periods our calculated clock scale may be actually be worse than the default
+            // (found experimentally after ~128h calibration period).
+            if d.abs() >= MIN_CALIBRATION_DRIFT_MS {
+                let clock_time = (boot_ms - self.last_sync_ms) as i64;
+                let real_time = (datetime - self.last_sync_time).num_milliseconds();
+
+                self.scale = ClockScale::from_ratio(real_time, clock_time);
+            }
+        }
+
+        self.set_time(boot_ms, datetime);
+
+        self.last_sync_time = datetime;
+        self.last_sync_ms = boot_ms;
+        self.source = TimeSource::Gps;
+        self.base_uncertainty_ms = GPS_SYNC_UNCERTAINTY_MS;
+        self.uncertainty_since_ms = boot_ms;
+        drift
+    }
+
+    /// Set the time without gps (e.g. when there is no reception for days). The next gps sync
+    /// overrides it.
+    pub fn set_manually(&mut self, boot_ms: u64, datetime: DateTime<Utc>) {
+        self.set_time(boot_ms, datetime);
+
+        // The user set time is far too imprecise to calibrate the clock scale against it.
+        self.last_sync_ms = 0;
+        self.source = TimeSource::User;
+        self.base_uncertainty_ms = USER_SET_UNCERTAINTY_MS;
+        self.uncertainty_since_ms = boot_ms;
+    }
+}
+
+/// Wall clock time of a time message. Without a valid date the date is taken from `current`.
+pub fn reconstruct_time(data: NavTimeUTC, current: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    if data.valid == 0 {
+        return None;
+    }
+
+    let time = chrono::NaiveTime::from_hms_milli_opt(
+        data.hour.into(),
+        data.min.into(),
+        data.sec.into(),
+        data.ms.into(),
+    )?;
+
+    if data.date_valid != 0 {
+        let date =
+            chrono::NaiveDate::from_ymd_opt(data.year.into(), data.month.into(), data.day.into())?;
+
+        Some(date.and_time(time).and_utc())
+    } else {
+        crate::resync_time(current?, time).ok()
+    }
+}
+
+/// Wait for a valid time from the receiver. Returns it together with the boot time it corresponds
+/// to (taking the latency of the message into account), or `None` after `timeout_ms`. The timeout
+/// is only checked when a message arrives.
+pub async fn receive_time(
+    gps: &mut impl TimeMessages,
+    clock: &impl Monotonic,
+    info: &ClockInfo,
+    timeout_ms: u64,
+) -> Option<(u64, DateTime<Utc>)> {
+    let give_up = clock.now_ms() + timeout_ms;
+    loop {
+        if give_up < clock.now_ms() {
+            return None;
+        }
+        let data = gps.next_time().await;
+        let epoch = clock.now_ms().saturating_sub(NAV_TIME_LATENCY_MS);
+        if let Some(time) = reconstruct_time(data, info.to_utc(epoch)) {
+            return Some((epoch, time));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct SyncStats {
+    /// Boot time of the last successful sync
+    pub last_sync_ms: u64,
+    pub last_sync_duration_ms: u64,
+    pub num_fails: u32,
+    pub last_drift_ms: i64,
+    /// Boot time of the next sync
+    pub next_sync_ms: u64,
+}
+
+/// Schedule of the syncs: The interval grows while the clock stays accurate and shrinks if it
+/// drifts.
+pub struct ClockSync {
+    timeout_ms: u64,
+    success_wait_ms: u64,
+    pub stats: SyncStats,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    /// Delay of the first sync after boot.
+    pub const FIRST_SYNC_DELAY_MS: u64 = 60 * 1000;
+
+    pub const fn new() -> Self {
+        Self {
+            timeout_ms: INITIAL_SYNC_TIMEOUT_MS,
+            success_wait_ms: INITIAL_SUCCESS_WAIT_MS,
+            stats: SyncStats {
+                last_sync_ms: 0,
+                last_sync_duration_ms: 0,
+                num_fails: 0,
+                last_drift_ms: 0,
+                next_sync_ms: Self::FIRST_SYNC_DELAY_MS,
+            },
+        }
+    }
+
+    /// How long to wait for a valid time in the next sync.
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+
+    /// Record the result of a sync (the drift as returned by `ClockInfo::sync`) that started at
+    /// `start_ms`. Returns the delay until the next sync.
+    pub fn finish(&mut self, start_ms: u64, now_ms: u64, result: Result<Option<i64>, ()>) -> u64 {
+        let next_sync = match result {
+            Ok(drift) => {
+                self.stats.last_sync_duration_ms = now_ms - start_ms;
+                self.stats.last_sync_ms = now_ms;
+                if let Some(drift) = drift {
+                    self.stats.last_drift_ms = drift;
+                }
+
+                self.timeout_ms = INCREMENTAL_SYNC_TIMEOUT_MS;
+
+                let drift = self.stats.last_drift_ms.abs();
+                if drift < DRIFT_THRESHOLD_LONGER_MS {
+                    self.success_wait_ms *= 2;
+                } else if drift > DRIFT_THRESHOLD_SHORTER_MS {
+                    self.success_wait_ms /= 2;
+                }
+                self.success_wait_ms
+            }
+            Err(()) => {
+                self.stats.num_fails += 1;
+                FAILURE_WAIT_MS
+            }
+        };
+        self.stats.next_sync_ms = now_ms + next_sync;
+        next_sync
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+    use core::task::{Context, Poll};
+
+    const HOUR_MS: u64 = 60 * 60 * 1000;
+
+    /// A clock that runs `ppm` too fast, with the real time controlled by the test.
+    struct DriftingClock {
+        real_ms: Cell<u64>,
+        ppm: i64,
+    }
+
+    impl DriftingClock {
+        fn advance(&self, ms: u64) {
+            self.real_ms.set(self.real_ms.get() + ms);
+        }
+    }
+
+    impl Monotonic for DriftingClock {
+        fn now_ms(&self) -> u64 {
+            let real = self.real_ms.get() as i64;
+            (real + real * self.ppm / 1_000_000) as u64
+        }
+    }
+
+    /// Real time when the clock reads zero.
+    fn start() -> DateTime<Utc> {
+        utc("2024-03-01T12:00:00.250Z")
+    }
+
+    /// Sends a time message for every epoch (once per second), which arrives with some latency.
+    struct Receiver<'a> {
+        clock: &'a DriftingClock,
+        valid: bool,
+    }
+
+    impl TimeMessages for Receiver<'_> {
+        async fn next_time(&mut self) -> NavTimeUTC {
+            use chrono::{Datelike, Timelike};
+            let epoch_ms = (self.clock.real_ms.get() / 1000 + 1) * 1000;
+            self.clock.real_ms.set(epoch_ms + NAV_TIME_LATENCY_MS);
+            let t = start() + chrono::Duration::milliseconds(epoch_ms as i64);
+            NavTimeUTC {
+                run_time: 0,
+                t_acc: 0.0,
+                mse: 0.0,
+                ms: t.timestamp_subsec_millis() as u16,
+                year: t.year() as u16,
+                month: t.month() as u8,
+                day: t.day() as u8,
+                hour: t.hour() as u8,
+                min: t.minute() as u8,
+                sec: t.second() as u8,
+                valid: self.valid as u8,
+                time_src: 0,
+                date_valid: self.valid as u8,
+            }
+        }
+    }
+
+    /// The futures in these tests never have to wait.
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = core::pin::pin!(f);
+        match f
+            .as_mut()
+            .poll(&mut Context::from_waker(core::task::Waker::noop()))
+        {
+            Poll::Ready(r) => r,
+            Poll::Pending => panic!("future is not ready"),
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    /// Difference between the clock and the real time.
+    fn error_ms(info: &ClockInfo, clock: &DriftingClock) -> i64 {
+        let real = start() + chrono::Duration::milliseconds(clock.real_ms.get() as i64);
+        (info.to_utc(clock.now_ms()).unwrap() - real).num_milliseconds()
+    }
+
+    #[test]
+    fn test_reconstruct_time() {
+        let mut data = NavTimeUTC {
+            run_time: 0,
+            t_acc: 0.0,
+            mse: 0.0,
+            ms: 250,
+            year: 2024,
+            month: 3,
+            day: 1,
+            hour: 0,
+            min: 0,
+            sec: 30,
+            valid: 0,
+            time_src: 0,
+            date_valid: 1,
+        };
+        assert!(reconstruct_time(data, None).is_none());
+
+        data.valid = 1;
+        assert_eq!(
+            reconstruct_time(data, None),
+            Some(utc("2024-03-01T00:00:30.250Z"))
+        );
+
+        // Without a date, the current one is used, even across midnight.
+        data.date_valid = 0;
+        assert!(reconstruct_time(data, None).is_none());
+        assert_eq!(
+            reconstruct_time(data, Some(utc("2024-05-10T23:59:50Z"))),
+            Some(utc("2024-05-11T00:00:30.250Z"))
+        );
+    }
+
+    #[test]
+    fn test_calibration() {
+        let clock = DriftingClock {
+            real_ms: Cell::new(0),
+            ppm: 200,
+        };
+        let mut gps = Receiver {
+            clock: &clock,
+            valid: true,
+        };
+        let mut info = ClockInfo::new(ClockScale::one());
+        let mut sync = ClockSync::new();
+        assert!(info.to_utc(clock.now_ms()).is_none());
+
+        let mut sync_once = |info: &mut ClockInfo| {
+            let start = clock.now_ms();
+            let res = block_on(receive_time(&mut gps, &clock, info, sync.timeout_ms()));
+            let drift = res.map(|(boot_ms, t)| info.sync(boot_ms, t));
+            sync.finish(start, clock.now_ms(), drift.ok_or(()))
+        };
+
+        sync_once(&mut info);
+        assert_eq!(info.source, TimeSource::Gps);
+        assert!(error_ms(&info, &clock).abs() <= 2);
+
+        // 200ppm are 7.2s after 10 hours, which are corrected by the next sync.
+        clock.advance(10 * HOUR_MS);
+        let error = error_ms(&info, &clock);
+        assert!((7150..=7250).contains(&error), "{}", error);
+        sync_once(&mut info);
+        assert!(error_ms(&info, &clock).abs() <= 2);
+
+        // The clock is calibrated now.
+        clock.advance(10 * HOUR_MS);
+        let error = error_ms(&info, &clock);
+        assert!(error.abs() <= 5, "{}", error);
+        assert!(info.uncertainty_ms(clock.now_ms()).unwrap() >= GPS_SYNC_UNCERTAINTY_MS as u64);
+
+        let t = utc("2024-03-02T12:00:00.500Z");
+        let boot_ms = info.to_boot_ms(t).unwrap();
+        assert!((info.to_utc(boot_ms).unwrap() - t).num_milliseconds().abs() <= 2);
+    }
+
+    #[test]
+    fn test_no_calibration_from_jitter() {
+        let mut info = ClockInfo::new(ClockScale::new(1_000_010, 1_000_000));
+        assert_eq!(info.sync(1000, start()), None);
+
+        // 150ms off after 10 minutes is rather a late message than a clock that is 250ppm off.
+        let boot_ms = 1000 + 10 * 60 * 1000;
+        let t = start() + chrono::Duration::milliseconds(10 * 60 * 1000 + 150);
+        let expected_drift = 150 - 6;
+        assert_eq!(info.sync(boot_ms, t), Some(expected_drift));
+        assert_eq!(
+            (info.scale.numerator, info.scale.denominator),
+            (1_000_010, 1_000_000)
+        );
+        // The time is taken nevertheless.
+        assert_eq!(info.to_utc(boot_ms), Some(t));
+    }
+
+    #[test]
+    fn test_sync_after_manual_set() {
+        let mut info = ClockInfo::new(ClockScale::one());
+        info.sync(1000, start());
+
+        // Set a minute off, which the next sync must not mistake for drift of the clock.
+        let set_ms = 1000 + HOUR_MS;
+        let set_time = start() + chrono::Duration::milliseconds((HOUR_MS + 60 * 1000) as i64);
+        info.set_manually(set_ms, set_time);
+        assert_eq!(info.source, TimeSource::User);
+        assert_eq!(info.to_utc(set_ms), Some(set_time));
+        assert_eq!(
+            info.uncertainty_ms(set_ms),
+            Some(USER_SET_UNCERTAINTY_MS as u64)
+        );
+
+        let boot_ms = 1000 + 2 * HOUR_MS;
+        let t = start() + chrono::Duration::milliseconds(2 * HOUR_MS as i64);
+        assert_eq!(info.sync(boot_ms, t), None);
+        assert_eq!((info.scale.numerator, info.scale.denominator), (1, 1));
+        assert_eq!(info.source, TimeSource::Gps);
+        assert_eq!(info.to_utc(boot_ms), Some(t));
+
+        // The sync after that calibrates again.
+        let boot_ms = boot_ms + 10 * HOUR_MS;
+        let t = t + chrono::Duration::milliseconds(10 * HOUR_MS as i64 + 3600);
+        assert_eq!(info.sync(boot_ms, t), Some(3600));
+        assert_eq!(
+            (info.scale.numerator, info.scale.denominator),
+            (10 * HOUR_MS as i32 + 3600, 10 * HOUR_MS as i32)
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = DriftingClock {
+            real_ms: Cell::new(0),
+            ppm: 0,
+        };
+        let mut gps = Receiver {
+            clock: &clock,
+            valid: false,
+        };
+        let info = ClockInfo::new(ClockScale::one());
+        let mut sync = ClockSync::new();
+        let res = block_on(receive_time(&mut gps, &clock, &info, sync.timeout_ms()));
+        assert!(res.is_none());
+        assert!(clock.now_ms() >= INITIAL_SYNC_TIMEOUT_MS);
+        assert_eq!(sync.finish(0, clock.now_ms(), Err(())), FAILURE_WAIT_MS);
+        assert_eq!(sync.stats.num_fails, 1);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut sync = ClockSync::new();
+        assert_eq!(sync.timeout_ms(), INITIAL_SYNC_TIMEOUT_MS);
+
+        // First sync (no drift known yet) and accurate clock: Longer intervals
+        assert_eq!(sync.finish(0, 1000, Ok(None)), 2 * INITIAL_SUCCESS_WAIT_MS);
+        assert_eq!(sync.timeout_ms(), INCREMENTAL_SYNC_TIMEOUT_MS);
+        assert_eq!(
+            sync.finish(0, 1000, Ok(Some(1200))),
+            4 * INITIAL_SUCCESS_WAIT_MS
+        );
+
+        // In between: Unchanged
+        assert_eq!(
+            sync.finish(0, 1000, Ok(Some(-7000))),
+            4 * INITIAL_SUCCESS_WAIT_MS
+        );
+
+        // Large drift: Shorter intervals
+        assert_eq!(
+            sync.finish(0, 1000, Ok(Some(20000))),
+            2 * INITIAL_SUCCESS_WAIT_MS
+        );
+        assert_eq!(sync.stats.last_drift_ms, 20000);
+        assert_eq!(sync.stats.next_sync_ms, 1000 + 2 * INITIAL_SUCCESS_WAIT_MS);
+    }
+}
@@ -1,6 +1,10 @@
 #![cfg_attr(not(test), no_std)]
 
+pub mod alarm;
+pub mod clock;
 pub mod gps;
+pub mod sun;
+pub mod tz;
 
 pub fn resync_time(
     base: chrono::DateTime<chrono::Utc>,
@@ -50,9 +54,18 @@ impl ClockScale {
         }
     }
 
-    pub fn apply(&self, time: i32) -> i32 {
-        let dt = self.numerator - self.denominator;
-        time + time * dt / self.denominator
+    /// Scale from the ratio of two (long) durations, keeping as much precision as fits.
+    pub fn from_ratio(mut real_time: i64, mut clock_time: i64) -> Self {
+        while real_time.abs() > i32::MAX as i64 || clock_time.abs() > i32::MAX as i64 {
+            real_time /= 2;
+            clock_time /= 2;
+        }
+        Self::new(real_time as i32, clock_time as i32)
+    }
+
+    pub fn apply(&self, time: i64) -> i64 {
+        let dt = (self.numerator - self.denominator) as i64;
+        time + time * dt / self.denominator as i64
     }
 
     pub fn inverse(&self) -> Self {
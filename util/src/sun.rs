@@ -0,0 +1,174 @@
+//! Sunrise, sunset and twilight times, following the sunrise equation as used by NOAA. Accurate to
+//! about a minute outside of polar regions, which is plenty for a watch.
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::gps::LonLat;
+
+/// Altitude of the sun's center at sunrise and sunset, accounting for refraction and the radius of
+/// the sun's disk.
+const SUNRISE_ALTITUDE: f64 = -0.833;
+/// Altitude of the sun at the start (dawn) and end (dusk) of civil twilight.
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+
+const EARTH_AXIAL_TILT: f64 = 23.4397;
+
+/// 2000-01-01T12:00:00Z, the reference of the equations below.
+const J2000_UNIX_MS: i64 = 946_728_000_000;
+const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// Sun events of one day. Events are `None` if the sun doesn't cross the respective altitude on
+/// that day, i.e. during polar day or night.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Daylight {
+    pub dawn: Option<DateTime<Utc>>,
+    pub sunrise: Option<DateTime<Utc>>,
+    pub noon: DateTime<Utc>,
+    pub sunset: Option<DateTime<Utc>>,
+    pub dusk: Option<DateTime<Utc>>,
+    /// Tells polar day from polar night if there is no sunrise.
+    pub sun_up_at_noon: bool,
+}
+
+impl Daylight {
+    /// Time between sunrise and sunset.
+    pub fn day_length(&self) -> chrono::Duration {
+        match (self.sunrise, self.sunset) {
+            (Some(rise), Some(set)) => set - rise,
+            _ if self.sun_up_at_noon => chrono::Duration::days(1),
+            _ => chrono::Duration::zero(),
+        }
+    }
+}
+
+fn sin_deg(x: f64) -> f64 {
+    libm::sin(x.to_radians())
+}
+
+fn from_j2000_days(days: f64) -> Option<DateTime<Utc>> {
+    let ms = J2000_UNIX_MS + libm::round(days * DAY_MS) as i64;
+    Some(chrono::NaiveDateTime::from_timestamp_millis(ms)?.and_utc())
+}
+
+/// Hour angle (degrees) at which the sun crosses `altitude`, or whether it stays above.
+fn hour_angle(lat: f64, declination_sin: f64, altitude: f64) -> Result<f64, bool> {
+    let declination_cos = libm::sqrt(1.0 - declination_sin * declination_sin);
+    let cos = (sin_deg(altitude) - sin_deg(lat) * declination_sin)
+        / (libm::cos(lat.to_radians()) * declination_cos);
+    if cos < -1.0 {
+        Err(true)
+    } else if cos > 1.0 {
+        Err(false)
+    } else {
+        Ok(libm::acos(cos).to_degrees())
+    }
+}
+
+/// Sun events at `pos` on `date`, which is the local date (the day whose solar noon is closest to
+/// noon UTC shifted by the longitude).
+pub fn daylight(date: NaiveDate, pos: &LonLat) -> Option<Daylight> {
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let days = (date - j2000).num_days() as f64 - pos.lon / 360.0;
+
+    let mean_anomaly = (357.5291 + 0.98560028 * days) % 360.0;
+    let center = 1.9148 * sin_deg(mean_anomaly)
+        + 0.0200 * sin_deg(2.0 * mean_anomaly)
+        + 0.0003 * sin_deg(3.0 * mean_anomaly);
+    let ecliptic_longitude = (mean_anomaly + center + 180.0 + 102.9372) % 360.0;
+    let transit =
+        days + 0.0053 * sin_deg(mean_anomaly) - 0.0069 * sin_deg(2.0 * ecliptic_longitude);
+    let declination_sin = sin_deg(ecliptic_longitude) * sin_deg(EARTH_AXIAL_TILT);
+
+    let events = |altitude| match hour_angle(pos.lat, declination_sin, altitude) {
+        Ok(angle) => Ok((
+            from_j2000_days(transit - angle / 360.0),
+            from_j2000_days(transit + angle / 360.0),
+        )),
+        Err(above) => Err(above),
+    };
+
+    let (sunrise, sunset, sun_up_at_noon) = match events(SUNRISE_ALTITUDE) {
+        Ok((rise, set)) => (rise, set, true),
+        Err(above) => (None, None, above),
+    };
+    let (dawn, dusk) = events(CIVIL_TWILIGHT_ALTITUDE).unwrap_or((None, None));
+
+    Some(Daylight {
+        dawn,
+        sunrise,
+        noon: from_j2000_days(transit)?,
+        sunset,
+        dusk,
+        sun_up_at_noon,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn assert_close(t: Option<DateTime<Utc>>, expected: &str) {
+        let t = t.unwrap();
+        let diff = (t - utc(expected)).num_seconds().abs();
+        assert!(diff <= 120, "{} != {}", t, expected);
+    }
+
+    #[test]
+    fn test_berlin() {
+        let berlin = LonLat {
+            lon: 13.405,
+            lat: 52.52,
+        };
+        let d = daylight(date("2024-06-21"), &berlin).unwrap();
+        assert_close(d.sunrise, "2024-06-21T02:43:00Z");
+        assert_close(d.sunset, "2024-06-21T19:33:00Z");
+        assert_close(d.dawn, "2024-06-21T01:51:00Z");
+        assert_close(d.dusk, "2024-06-21T20:25:00Z");
+        assert_close(Some(d.noon), "2024-06-21T11:08:00Z");
+        assert!(d.day_length() > chrono::Duration::hours(16));
+
+        let d = daylight(date("2024-12-21"), &berlin).unwrap();
+        assert_close(d.sunrise, "2024-12-21T07:15:00Z");
+        assert_close(d.sunset, "2024-12-21T14:54:00Z");
+    }
+
+    #[test]
+    fn test_equator() {
+        let d = daylight(
+            date("2024-03-20"),
+            &LonLat {
+                lon: -78.5,
+                lat: 0.0,
+            },
+        )
+        .unwrap();
+        assert_close(d.sunrise, "2024-03-20T11:18:00Z");
+        assert_close(d.sunset, "2024-03-20T23:24:00Z");
+    }
+
+    #[test]
+    fn test_polar() {
+        let tromso = LonLat {
+            lon: 18.96,
+            lat: 69.65,
+        };
+        let d = daylight(date("2024-06-21"), &tromso).unwrap();
+        assert_eq!((d.sunrise, d.dusk), (None, None));
+        assert!(d.sun_up_at_noon);
+        assert_eq!(d.day_length(), chrono::Duration::days(1));
+
+        // Polar night, but there is still some twilight around noon.
+        let d = daylight(date("2024-12-21"), &tromso).unwrap();
+        assert_eq!((d.sunrise, d.sunset), (None, None));
+        assert!(!d.sun_up_at_noon);
+        assert!(d.dawn.unwrap() < d.noon && d.noon < d.dusk.unwrap());
+    }
+}
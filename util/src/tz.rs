@@ -0,0 +1,472 @@
+//! Time zones described by POSIX TZ strings (e.g. `CET-1CEST,M3.5.0,M10.5.0/3`). They only encode
+//! the current rules of a zone, but that is all a watch needs and they fit into a few bytes.
+
+use chrono::{Datelike, NaiveDate};
+
+/// Day of the year on which daylight saving time starts or ends.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Day {
+    /// `Jn`: 1..=365, February 29th is never counted.
+    JulianNoLeap(u16),
+    /// `n`: 0..=365, February 29th is counted in leap years.
+    Julian(u16),
+    /// `Mm.w.d`: Day `d` (0 = sunday) of week `w` (1..=5, 5 = last) of month `m`.
+    MonthWeekday { month: u8, week: u8, weekday: u8 },
+}
+
+impl Day {
+    fn date(&self, year: i32) -> Option<NaiveDate> {
+        let jan_1 = NaiveDate::from_ymd_opt(year, 1, 1)?;
+        match *self {
+            Day::JulianNoLeap(n) => {
+                let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+                let skip_leap_day = (leap && n >= 60) as u64;
+                jan_1.checked_add_days(chrono::Days::new(n as u64 - 1 + skip_leap_day))
+            }
+            Day::Julian(n) => jan_1.checked_add_days(chrono::Days::new(n as u64)),
+            Day::MonthWeekday {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = NaiveDate::from_ymd_opt(year, month as u32, 1)?;
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday as u32 + 7 - first_weekday) % 7 + (week as u32 - 1) * 7;
+                // Week 5 means the last one, which might be the fourth.
+                loop {
+                    if let Some(d) = NaiveDate::from_ymd_opt(year, month as u32, day) {
+                        return Some(d);
+                    }
+                    day = day.checked_sub(7)?;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Transition {
+    day: Day,
+    /// Local time of day at which the transition happens. Can be negative or exceed 24h.
+    time_s: i32,
+}
+
+impl Transition {
+    /// Unix timestamp of the transition, given the utc offset in effect before it.
+    fn timestamp(&self, year: i32, offset_before_s: i32) -> Option<i64> {
+        let midnight = self.day.date(year)?.and_hms_opt(0, 0, 0)?.and_utc();
+        Some(midnight.timestamp() + self.time_s as i64 - offset_before_s as i64)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Dst {
+    offset_s: i32,
+    start: Transition,
+    end: Transition,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeZone {
+    /// Offset of standard time in seconds east of utc.
+    std_offset_s: i32,
+    dst: Option<Dst>,
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::UTC
+    }
+}
+
+impl TimeZone {
+    pub const UTC: Self = Self::fixed(0);
+
+    /// Zone without daylight saving time. Offset in seconds east of utc.
+    pub const fn fixed(offset_s: i32) -> Self {
+        Self {
+            std_offset_s: offset_s,
+            dst: None,
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut p = Parser { s: s.as_bytes() };
+
+        p.name()?;
+        let std_offset_s = -p.time()?;
+        if p.s.is_empty() {
+            return Some(Self::fixed(std_offset_s));
+        }
+
+        p.name()?;
+        let dst_offset_s = match p.peek()? {
+            b',' => std_offset_s + 60 * 60,
+            _ => -p.time()?,
+        };
+        // Rules are mandatory here. (Without them, the tz database would use the US rules.)
+        let start = p.transition()?;
+        let end = p.transition()?;
+        if !p.s.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            std_offset_s,
+            dst: Some(Dst {
+                offset_s: dst_offset_s,
+                start,
+                end,
+            }),
+        })
+    }
+
+    /// Whether daylight saving time is in effect at the given point in time.
+    pub fn is_dst(&self, utc: chrono::DateTime<chrono::Utc>) -> bool {
+        let Some(dst) = &self.dst else {
+            return false;
+        };
+        let year = utc.year();
+        let (Some(start), Some(end)) = (
+            dst.start.timestamp(year, self.std_offset_s),
+            dst.end.timestamp(year, dst.offset_s),
+        ) else {
+            return false;
+        };
+
+        let t = utc.timestamp();
+        if start < end {
+            start <= t && t < end
+        } else {
+            // Southern hemisphere: dst lasts over new year.
+            !(end <= t && t < start)
+        }
+    }
+
+    /// Offset (in seconds east of utc) of local time at the given point in time.
+    pub fn offset_s(&self, utc: chrono::DateTime<chrono::Utc>) -> i32 {
+        match &self.dst {
+            Some(dst) if self.is_dst(utc) => dst.offset_s,
+            _ => self.std_offset_s,
+        }
+    }
+
+    /// Utc time corresponding to the given local time. Local times around a dst transition that
+    /// are skipped or occur twice are resolved using standard time.
+    pub fn from_local(&self, local: chrono::NaiveDateTime) -> chrono::DateTime<chrono::Utc> {
+        let local = local.and_utc();
+        let guess = local - chrono::Duration::seconds(self.std_offset_s as i64);
+        local - chrono::Duration::seconds(self.offset_s(guess) as i64)
+    }
+
+    pub fn to_local(
+        &self,
+        utc: chrono::DateTime<chrono::Utc>,
+    ) -> chrono::DateTime<chrono::FixedOffset> {
+        let offset = chrono::FixedOffset::east_opt(self.offset_s(utc))
+            .unwrap_or(chrono::FixedOffset::east_opt(0).unwrap());
+        utc.with_timezone(&offset)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.first().copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.s = &self.s[1..];
+            true
+        } else {
+            false
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &[u8] {
+        let n = self.s.iter().position(|c| !f(*c)).unwrap_or(self.s.len());
+        let (taken, rest) = self.s.split_at(n);
+        self.s = rest;
+        taken
+    }
+
+    /// Zone abbreviation, either alphabetic (`CET`) or quoted (`<+03>`). We don't need them, so
+    /// they are skipped.
+    fn name(&mut self) -> Option<()> {
+        if self.eat(b'<') {
+            self.take_while(|c| c != b'>');
+            self.eat(b'>').then_some(())
+        } else {
+            (self.take_while(|c| c.is_ascii_alphabetic()).len() >= 3).then_some(())
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        core::str::from_utf8(digits).ok()?.parse().ok()
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds (hours up to 167 as in the extended format of rfc 8536)
+    fn time(&mut self) -> Option<i32> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let hours = self.number()?;
+        if hours > 167 {
+            return None;
+        }
+        let mut s = hours * 60 * 60;
+        if self.eat(b':') {
+            s += self.number()? * 60;
+            if self.eat(b':') {
+                s += self.number()?;
+            }
+        }
+        Some(sign * s as i32)
+    }
+
+    /// `,day[/time]`
+    fn transition(&mut self) -> Option<Transition> {
+        if !self.eat(b',') {
+            return None;
+        }
+        let day = if self.eat(b'M') {
+            let month = self.number()?;
+            self.eat(b'.').then_some(())?;
+            let week = self.number()?;
+            self.eat(b'.').then_some(())?;
+            let weekday = self.number()?;
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 {
+                return None;
+            }
+            Day::MonthWeekday {
+                month: month as u8,
+                week: week as u8,
+                weekday: weekday as u8,
+            }
+        } else if self.eat(b'J') {
+            let n = self.number()?;
+            if !(1..=365).contains(&n) {
+                return None;
+            }
+            Day::JulianNoLeap(n as u16)
+        } else {
+            let n = self.number()?;
+            if n > 365 {
+                return None;
+            }
+            Day::Julian(n as u16)
+        };
+        let time_s = if self.eat(b'/') {
+            self.time()?
+        } else {
+            2 * 60 * 60
+        };
+        Some(Transition { day, time_s })
+    }
+}
+
+pub struct Zone {
+    /// Identifies the zone in stored settings. Never changed or reused, new zones get the next
+    /// free id wherever they are inserted. 0 means no zone.
+    pub id: u8,
+    pub name: &'static str,
+    pub tz: &'static str,
+}
+
+const fn zone(id: u8, name: &'static str, tz: &'static str) -> Zone {
+    Zone { id, name, tz }
+}
+
+impl Zone {
+    pub fn by_id(id: u8) -> Option<&'static Zone> {
+        ZONES.iter().find(|z| z.id == id)
+    }
+}
+
+/// Id of the zone `delta` places after the one with `id` in `ZONES`, cycling through 0 (no zone)
+/// before the first one.
+pub fn step_zone_id(id: u8, delta: i32) -> u8 {
+    let pos = ZONES.iter().position(|z| z.id == id).map_or(0, |i| i + 1);
+    let new = (pos as i32 + delta).rem_euclid(ZONES.len() as i32 + 1) as usize;
+    match new.checked_sub(1) {
+        Some(i) => ZONES[i].id,
+        None => 0,
+    }
+}
+
+/// Rules of some common zones (from the tz database), sorted by offset.
+pub const ZONES: &[Zone] = &[
+    zone(1, "Honolulu", "HST10"),
+    zone(2, "Anchorage", "AKST9AKDT,M3.2.0,M11.1.0"),
+    zone(3, "Los Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    zone(4, "Phoenix", "MST7"),
+    zone(5, "Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    zone(6, "Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    zone(7, "Mexico City", "CST6"),
+    zone(8, "New York", "EST5EDT,M3.2.0,M11.1.0"),
+    zone(9, "Santiago", "<-04>4<-03>,M9.1.6/24,M4.1.6/24"),
+    zone(10, "Sao Paulo", "<-03>3"),
+    zone(11, "UTC", "UTC0"),
+    zone(12, "London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    zone(13, "Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    zone(14, "Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    zone(15, "Lagos", "WAT-1"),
+    zone(16, "Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    zone(17, "Cairo", "EET-2EEST,M4.5.5/0,M10.5.4/24"),
+    zone(18, "Johannesburg", "SAST-2"),
+    zone(19, "Moscow", "MSK-3"),
+    zone(20, "Istanbul", "<+03>-3"),
+    zone(21, "Dubai", "<+04>-4"),
+    zone(22, "Karachi", "PKT-5"),
+    zone(23, "Kolkata", "IST-5:30"),
+    zone(24, "Bangkok", "<+07>-7"),
+    zone(25, "Shanghai", "CST-8"),
+    zone(26, "Tokyo", "JST-9"),
+    zone(27, "Adelaide", "ACST-9:30ACDT,M10.1.0,M4.1.0/3"),
+    zone(28, "Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    zone(29, "Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(s: &str) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[track_caller]
+    fn check(tz: &str, time: &str, offset_h: f32) {
+        let tz = TimeZone::parse(tz).unwrap();
+        assert_eq!(
+            tz.offset_s(utc(time)),
+            (offset_h * 3600.0) as i32,
+            "{}",
+            time
+        );
+    }
+
+    #[test]
+    fn test_fixed() {
+        check("UTC0", "2024-07-01T00:00:00Z", 0.0);
+        check("IST-5:30", "2024-07-01T00:00:00Z", 5.5);
+        check("<-03>3", "2024-07-01T00:00:00Z", -3.0);
+        check("<+0545>-5:45", "2024-01-01T00:00:00Z", 5.75);
+    }
+
+    #[test]
+    fn test_northern() {
+        let berlin = "CET-1CEST,M3.5.0,M10.5.0/3";
+        check(berlin, "2024-01-15T12:00:00Z", 1.0);
+        check(berlin, "2024-03-31T00:59:59Z", 1.0);
+        check(berlin, "2024-03-31T01:00:00Z", 2.0);
+        check(berlin, "2024-10-27T00:59:59Z", 2.0);
+        check(berlin, "2024-10-27T01:00:00Z", 1.0);
+
+        let new_york = "EST5EDT,M3.2.0,M11.1.0";
+        check(new_york, "2024-03-10T06:59:59Z", -5.0);
+        check(new_york, "2024-03-10T07:00:00Z", -4.0);
+        check(new_york, "2024-11-03T05:59:59Z", -4.0);
+        check(new_york, "2024-11-03T06:00:00Z", -5.0);
+    }
+
+    #[test]
+    fn test_southern() {
+        let sydney = "AEST-10AEDT,M10.1.0,M4.1.0/3";
+        check(sydney, "2024-01-01T00:00:00Z", 11.0);
+        check(sydney, "2024-04-06T15:59:59Z", 11.0);
+        check(sydney, "2024-04-06T16:00:00Z", 10.0);
+        check(sydney, "2024-10-05T15:59:59Z", 10.0);
+        check(sydney, "2024-10-05T16:00:00Z", 11.0);
+    }
+
+    #[test]
+    fn test_julian_and_extended_times() {
+        // Transitions at 24:00 and on the last thursday/friday
+        let cairo = "EET-2EEST,M4.5.5/0,M10.5.4/24";
+        check(cairo, "2024-04-25T21:59:59Z", 2.0);
+        check(cairo, "2024-04-25T22:00:00Z", 3.0);
+        check(cairo, "2024-10-31T20:59:59Z", 3.0);
+        check(cairo, "2024-10-31T21:00:00Z", 2.0);
+
+        // Day 60 is March 1st in every year for `Jn`, but February 29th in leap years for `n`.
+        check("AAA0BBB,J60/0,J300", "2024-02-29T12:00:00Z", 0.0);
+        check("AAA0BBB,J60/0,J300", "2024-03-01T12:00:00Z", 1.0);
+        check("AAA0BBB,59/0,300", "2024-02-29T12:00:00Z", 1.0);
+        check("AAA0BBB,59/0,300", "2023-02-28T12:00:00Z", 0.0);
+    }
+
+    #[test]
+    fn test_from_local() {
+        let berlin = TimeZone::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        for (local, expected) in [
+            ("2024-01-15T12:00:00Z", "2024-01-15T11:00:00Z"),
+            ("2024-07-15T12:00:00Z", "2024-07-15T10:00:00Z"),
+            ("2024-03-31T03:30:00Z", "2024-03-31T01:30:00Z"),
+        ] {
+            let local = utc(local).naive_utc();
+            assert_eq!(berlin.from_local(local), utc(expected));
+            assert_eq!(berlin.to_local(utc(expected)).naive_local(), local);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        for s in [
+            "",
+            "CET",
+            "C-1",
+            "CET-1CEST",
+            "CET-1CEST,M3.5.0",
+            "CET-1CEST,M13.5.0,M10.5.0",
+            "CET-1CEST,M3.5.0,M10.5.0/3,",
+            "<+03-3",
+        ] {
+            assert_eq!(TimeZone::parse(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_zones() {
+        let mut last_offset = i32::MIN;
+        for zone in ZONES {
+            let tz = TimeZone::parse(zone.tz).unwrap_or_else(|| panic!("{}", zone.name));
+            let offset = tz
+                .offset_s(utc("2024-01-01T00:00:00Z"))
+                .min(tz.std_offset_s);
+            assert!(offset >= last_offset, "{}", zone.name);
+            last_offset = offset;
+        }
+    }
+
+    #[test]
+    fn test_zone_ids() {
+        for (i, zone) in ZONES.iter().enumerate() {
+            assert_ne!(zone.id, 0, "{}", zone.name);
+            assert!(ZONES[..i].iter().all(|z| z.id != zone.id), "{}", zone.name);
+            assert_eq!(Zone::by_id(zone.id).unwrap().name, zone.name);
+        }
+        assert!(Zone::by_id(0).is_none());
+    }
+
+    #[test]
+    fn test_step_zone_id() {
+        let first = ZONES[0].id;
+        let last = ZONES[ZONES.len() - 1].id;
+        assert_eq!(step_zone_id(0, 1), first);
+        assert_eq!(step_zone_id(0, -1), last);
+        assert_eq!(step_zone_id(last, 1), 0);
+        assert_eq!(step_zone_id(first, -1), 0);
+        assert_eq!(step_zone_id(first, 1), ZONES[1].id);
+        // Unknown ids (e.g. of a removed zone) start over from no zone.
+        assert_eq!(step_zone_id(255, 1), first);
+    }
+}